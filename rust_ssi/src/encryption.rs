@@ -0,0 +1,307 @@
+mod envelope;
+mod recipients;
+
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use crate::error::{Error, ParseError, Result};
+
+pub use envelope::{Algorithm, Envelope};
+pub use recipients::{RecipientKey, RecipientPublicKey, RecipientSecretKey};
+
+/// Encryption utilities for patient EHR data
+pub struct EHREncryption {
+    key: Key<Aes256Gcm>,
+}
+
+impl EHREncryption {
+    /// Create new encryption instance with a random key
+    pub fn new() -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        let key_bytes: [u8; 32] = rng.gen();
+        let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
+        
+        Ok(Self { key: *key })
+    }
+
+    /// Create an encryption instance from an existing 256-bit key
+    pub fn from_key(key_bytes: &[u8]) -> Result<Self> {
+        if key_bytes.len() != 32 {
+            return Err(Error::crypto(format!("Invalid key length: {}", key_bytes.len())));
+        }
+        Ok(Self { key: *Key::<Aes256Gcm>::from_slice(key_bytes) })
+    }
+
+    /// Encrypt EHR data
+    pub fn encrypt(&self, data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let cipher = Aes256Gcm::new(&self.key);
+        let mut rng = rand::thread_rng();
+        let nonce_bytes: [u8; 12] = rng.gen();
+        let nonce = Nonce::from_slice(&nonce_bytes);
+        
+        let encrypted_data = cipher.encrypt(nonce, data)
+            .map_err(|_| Error::crypto("EHR encryption failed"))?;
+        
+        Ok((encrypted_data, nonce_bytes.to_vec()))
+    }
+
+    /// Decrypt EHR data
+    pub fn decrypt(&self, encrypted_data: &[u8], nonce: &[u8]) -> Result<Vec<u8>> {
+        if nonce.len() != 12 {
+            return Err(Error::crypto(format!("Invalid nonce length: {}", nonce.len())));
+        }
+        let cipher = Aes256Gcm::new(&self.key);
+        let nonce = Nonce::from_slice(nonce);
+        
+        let decrypted_data = cipher.decrypt(nonce, encrypted_data)
+            .map_err(|_| Error::crypto("EHR decryption failed: wrong key or corrupted data"))?;
+        
+        Ok(decrypted_data)
+    }
+
+    /// Encrypt EHR data into an [`Envelope`], authenticating `aad` with it
+    pub fn seal(&self, data: &[u8], aad: &[u8]) -> Result<Envelope> {
+        let cipher = Aes256Gcm::new(&self.key);
+        let nonce_bytes: [u8; 12] = rand::thread_rng().gen();
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: data, aad })
+            .map_err(|_| Error::crypto("EHR encryption failed"))?;
+
+        Ok(Envelope {
+            version: envelope::VERSION,
+            algorithm: Algorithm::Aes256Gcm,
+            key_id: self.key_id(),
+            nonce: nonce_bytes.to_vec(),
+            aad: aad.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt an [`Envelope`] sealed with this key
+    pub fn open(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        if !envelope.key_id.is_empty() && envelope.key_id != self.key_id() {
+            return Err(Error::crypto(format!(
+                "EHR was encrypted with key {}, not {}",
+                envelope.key_id,
+                self.key_id()
+            )));
+        }
+        if envelope.nonce.len() != 12 {
+            return Err(Error::crypto(format!("Invalid nonce length: {}", envelope.nonce.len())));
+        }
+        let cipher = Aes256Gcm::new(&self.key);
+        let payload = Payload { msg: &envelope.ciphertext, aad: &envelope.aad };
+        cipher.decrypt(Nonce::from_slice(&envelope.nonce), payload)
+            .map_err(|_| Error::crypto("EHR decryption failed: wrong key or corrupted data"))
+    }
+
+    /// Id of the key, a prefix of its SHA-256, recorded in envelopes
+    pub fn key_id(&self) -> String {
+        crate::utils::sha256_hash(self.key.as_slice())[2..18].to_string()
+    }
+
+    /// Encrypt an EHR record, binding its [`EHRMetadata`] to the ciphertext
+    /// as associated data
+    pub fn seal_ehr(&self, ehr: &PatientEHR) -> Result<Envelope> {
+        self.seal(&ehr.to_bytes()?, &ehr.metadata().to_bytes()?)
+    }
+
+    /// Decrypt an EHR record sealed with [`seal_ehr`](Self::seal_ehr),
+    /// failing unless it was sealed for exactly the `expected` metadata, or
+    /// if its `valid_until` has passed
+    pub fn open_ehr(&self, envelope: &Envelope, expected: &EHRMetadata) -> Result<PatientEHR> {
+        let found = EHRMetadata::from_bytes(&envelope.aad)?;
+        if &found != expected {
+            return Err(Error::crypto(format!("EHR is bound to {}, expected {}", found, expected)));
+        }
+        // Authenticate against the caller's metadata, not the envelope's
+        let envelope = Envelope { aad: expected.to_bytes()?, ..envelope.clone() };
+        let ehr = PatientEHR::from_bytes(&self.open(&envelope)?)?;
+        if &ehr.metadata() != expected {
+            return Err(Error::crypto(format!("EHR record does not match its metadata {}", expected)));
+        }
+        if ehr.is_expired(chrono::Utc::now().timestamp()) {
+            return Err(Error::Validation(vec![format!(
+                "{} expired at {}",
+                expected,
+                ehr.valid_until.unwrap_or_default()
+            )]));
+        }
+        Ok(ehr)
+    }
+
+    /// Get the encryption key for storage/transmission
+    pub(crate) fn get_key(&self) -> Vec<u8> {
+        self.key.as_slice().to_vec()
+    }
+}
+
+/// Patient EHR data structure
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatientEHR {
+    pub patient_did: String,
+    pub provider_did: String,
+    pub ehr_type: String,
+    pub data: serde_json::Value,
+    pub timestamp: i64,
+    /// Unix time after which the record must no longer be used, such as the
+    /// end of its retention period
+    pub valid_until: Option<i64>,
+}
+
+/// The fields of a [`PatientEHR`] that its ciphertext is bound to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EHRMetadata {
+    pub patient_did: String,
+    pub provider_did: String,
+    pub ehr_type: String,
+    pub timestamp: i64,
+}
+
+impl EHRMetadata {
+    /// The associated data sealed with the record, as JSON in field order
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).map_err(|e| Error::crypto(format!("EHR metadata encoding failed: {}", e)))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        serde_json::from_slice(bytes).map_err(|_| Error::crypto("Envelope does not carry EHR metadata"))
+    }
+}
+
+impl std::fmt::Display for EHRMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} record of {} by {} at {}",
+            self.ehr_type, self.patient_did, self.provider_did, self.timestamp
+        )
+    }
+}
+
+impl PatientEHR {
+    /// Create new EHR record
+    pub fn new(
+        patient_did: String,
+        provider_did: String,
+        ehr_type: String,
+        data: serde_json::Value,
+        valid_until: Option<i64>,
+    ) -> Self {
+        Self {
+            patient_did,
+            provider_did,
+            ehr_type,
+            data,
+            timestamp: chrono::Utc::now().timestamp(),
+            valid_until,
+        }
+    }
+
+    /// Convert to JSON bytes for encryption
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).map_err(|e| ParseError::new("", e.to_string()).into())
+    }
+
+    /// Create from JSON bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        serde_json::from_slice(bytes).map_err(|e| ParseError::new("", e.to_string()).into())
+    }
+
+    /// The fields bound to the record's ciphertext
+    pub fn metadata(&self) -> EHRMetadata {
+        EHRMetadata {
+            patient_did: self.patient_did.clone(),
+            provider_did: self.provider_did.clone(),
+            ehr_type: self.ehr_type.clone(),
+            timestamp: self.timestamp,
+        }
+    }
+
+    /// Whether `valid_until` has passed at the Unix time `now`
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.valid_until.is_some_and(|valid_until| valid_until <= now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let ehr = PatientEHR::new(
+            "did:hedera:testnet:0.0.1234567".to_string(),
+            "did:hedera:testnet:0.0.7654321".to_string(),
+            "EHR".to_string(),
+            serde_json::json!({ "resourceType": "Bundle", "id": "b1" }),
+            None,
+        );
+        let encryption = EHREncryption::new().unwrap();
+        let (ciphertext, nonce) = encryption.encrypt(&ehr.to_bytes().unwrap()).unwrap();
+
+        let restored = EHREncryption::from_key(&encryption.get_key()).unwrap();
+        let plaintext = restored.decrypt(&ciphertext, &nonce).unwrap();
+        assert_eq!(PatientEHR::from_bytes(&plaintext).unwrap().patient_did, ehr.patient_did);
+
+        let other = EHREncryption::new().unwrap();
+        assert!(matches!(other.decrypt(&ciphertext, &nonce), Err(Error::Crypto { .. })));
+        assert!(!ehr.is_expired(i64::MAX));
+        let expiring = PatientEHR { valid_until: Some(ehr.timestamp + 60), ..ehr };
+        assert!(!expiring.is_expired(expiring.timestamp));
+        assert!(expiring.is_expired(expiring.timestamp + 60));
+    }
+
+    #[test]
+    fn test_ehr_bound_to_metadata() {
+        let ehr = |patient: &str| {
+            PatientEHR::new(
+                format!("did:hedera:testnet:{}", patient),
+                "did:hedera:testnet:0.0.7654321".to_string(),
+                "EHR".to_string(),
+                serde_json::json!({ "resourceType": "Bundle", "id": patient }),
+                None,
+            )
+        };
+        let (alice, bob) = (ehr("0.0.1111"), ehr("0.0.2222"));
+        let encryption = EHREncryption::new().unwrap();
+        let sealed = encryption.seal_ehr(&alice).unwrap();
+        let opened = encryption.open_ehr(&sealed, &alice.metadata()).unwrap();
+        assert_eq!((opened.patient_did, opened.data), (alice.patient_did.clone(), alice.data.clone()));
+
+        // Neither another patient's expectation nor a swapped ciphertext opens
+        assert!(matches!(encryption.open_ehr(&sealed, &bob.metadata()), Err(Error::Crypto { .. })));
+        let stale = EHRMetadata { timestamp: alice.timestamp - 1, ..alice.metadata() };
+        assert!(encryption.open_ehr(&sealed, &stale).is_err());
+        let bobs = encryption.seal_ehr(&bob).unwrap();
+        let swapped = Envelope { nonce: bobs.nonce, ciphertext: bobs.ciphertext, ..sealed.clone() };
+        assert!(encryption.open_ehr(&swapped, &alice.metadata()).is_err());
+        let relabelled = Envelope { aad: bob.metadata().to_bytes().unwrap(), ..sealed };
+        assert!(encryption.open_ehr(&relabelled, &bob.metadata()).is_err());
+
+        // Records sealed without metadata are refused
+        let unbound = encryption.seal(&alice.to_bytes().unwrap(), b"").unwrap();
+        assert!(encryption.open_ehr(&unbound, &alice.metadata()).is_err());
+    }
+
+    #[test]
+    fn test_expired_ehr_is_refused() {
+        let ehr = |valid_until| {
+            PatientEHR::new(
+                "did:hedera:testnet:0.0.1111".to_string(),
+                "did:hedera:testnet:0.0.7654321".to_string(),
+                "EHR".to_string(),
+                serde_json::json!({ "resourceType": "Bundle", "id": "b1" }),
+                Some(valid_until),
+            )
+        };
+        let encryption = EHREncryption::new().unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let current = ehr(now + 3600);
+        assert!(encryption.open_ehr(&encryption.seal_ehr(&current).unwrap(), &current.metadata()).is_ok());
+        let expired = ehr(now - 1);
+        let result = encryption.open_ehr(&encryption.seal_ehr(&expired).unwrap(), &expired.metadata());
+        assert!(matches!(result, Err(Error::Validation(problems)) if problems[0].contains("expired")));
+    }
+}
@@ -0,0 +1,180 @@
+//! The binary format of encrypted EHR files. An envelope carries everything
+//! needed to decrypt it except the key:
+//!
+//! ```text
+//! magic "EHRE" | version u8 | algorithm u8 | key id len u16 | key id
+//!              | nonce len u8 | nonce | aad len u32 | aad | ciphertext
+//! ```
+//!
+//! Lengths are big-endian. Data encrypted with [`EHREncryption::encrypt`],
+//! which returns the ciphertext and nonce separately, is wrapped as version 0
+//! with [`Envelope::from_parts`].
+//!
+//! [`EHREncryption::encrypt`]: super::EHREncryption::encrypt
+
+use crate::error::{Error, Result};
+
+/// Leading bytes of every versioned envelope
+pub(crate) const MAGIC: &[u8; 4] = b"EHRE";
+
+/// Version written by [`Envelope::to_bytes`]
+pub(crate) const VERSION: u8 = 1;
+
+/// Version of ciphertexts stored apart from their nonce, without a header
+pub(crate) const LEGACY_VERSION: u8 = 0;
+
+/// Ciphers an envelope can be sealed with, identified by a byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Aes256Gcm = 1,
+}
+
+impl Algorithm {
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Algorithm::Aes256Gcm),
+            id => Err(Error::crypto(format!("Unknown envelope algorithm: {}", id))),
+        }
+    }
+}
+
+/// An encrypted EHR with its metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub algorithm: Algorithm,
+    /// Id of the key the data was encrypted with, empty for legacy files
+    pub key_id: String,
+    pub nonce: Vec<u8>,
+    /// Associated data authenticated along with the ciphertext
+    pub aad: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Encode in the current version
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let key_id_len = u16::try_from(self.key_id.len())
+            .map_err(|_| Error::crypto(format!("Key id is too long: {} bytes", self.key_id.len())))?;
+        let nonce_len = u8::try_from(self.nonce.len())
+            .map_err(|_| Error::crypto(format!("Nonce is too long: {} bytes", self.nonce.len())))?;
+        let aad_len = u32::try_from(self.aad.len())
+            .map_err(|_| Error::crypto(format!("Associated data is too long: {} bytes", self.aad.len())))?;
+
+        let header_len = MAGIC.len() + 9 + self.key_id.len() + self.nonce.len() + self.aad.len();
+        let mut bytes = Vec::with_capacity(header_len + self.ciphertext.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.algorithm as u8);
+        bytes.extend_from_slice(&key_id_len.to_be_bytes());
+        bytes.extend_from_slice(self.key_id.as_bytes());
+        bytes.push(nonce_len);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&aad_len.to_be_bytes());
+        bytes.extend_from_slice(&self.aad);
+        bytes.extend_from_slice(&self.ciphertext);
+        Ok(bytes)
+    }
+
+    /// A version 0 envelope for a ciphertext and nonce returned by
+    /// [`EHREncryption::encrypt`](super::EHREncryption::encrypt)
+    pub fn from_parts(ciphertext: Vec<u8>, nonce: Vec<u8>) -> Self {
+        Self {
+            version: LEGACY_VERSION,
+            algorithm: Algorithm::Aes256Gcm,
+            key_id: String::new(),
+            nonce,
+            aad: Vec::new(),
+            ciphertext,
+        }
+    }
+
+    /// Decode an envelope of any supported version
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            return Err(Error::crypto("Not an encrypted EHR envelope"));
+        };
+        let mut reader = Reader(rest);
+        match reader.take(1)?[0] {
+            VERSION => {
+                let algorithm = Algorithm::from_id(reader.take(1)?[0])?;
+                let key_id_len = u16::from_be_bytes(reader.array()?) as usize;
+                let key_id = String::from_utf8(reader.take(key_id_len)?.to_vec())
+                    .map_err(|_| Error::crypto("Envelope key id is not UTF-8"))?;
+                let nonce_len = reader.take(1)?[0] as usize;
+                let nonce = reader.take(nonce_len)?.to_vec();
+                let aad_len = u32::from_be_bytes(reader.array()?) as usize;
+                let aad = reader.take(aad_len)?.to_vec();
+                Ok(Self { version: VERSION, algorithm, key_id, nonce, aad, ciphertext: reader.0.to_vec() })
+            }
+            version => Err(Error::crypto(format!("Unsupported envelope version: {}", version))),
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::crypto("Envelope is truncated"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EHREncryption;
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let encryption = EHREncryption::new().unwrap();
+        let envelope = encryption.seal(b"{\"resourceType\":\"Bundle\"}", b"patient").unwrap();
+        let bytes = envelope.to_bytes().unwrap();
+        assert!(bytes.starts_with(MAGIC));
+
+        let parsed = Envelope::parse(&bytes).unwrap();
+        assert_eq!(parsed, envelope);
+        assert_eq!((parsed.version, parsed.key_id.as_str()), (VERSION, encryption.key_id().as_str()));
+        assert_eq!(encryption.open(&parsed).unwrap(), b"{\"resourceType\":\"Bundle\"}");
+
+        // The associated data is authenticated, and other keys are refused by id
+        let tampered = Envelope { aad: b"another patient".to_vec(), ..parsed.clone() };
+        assert!(matches!(encryption.open(&tampered), Err(Error::Crypto { .. })));
+        let other = EHREncryption::new().unwrap();
+        assert!(other.open(&parsed).unwrap_err().to_string().contains(&encryption.key_id()));
+    }
+
+    #[test]
+    fn test_legacy_parts_and_malformed() {
+        let encryption = EHREncryption::new().unwrap();
+        let (ciphertext, nonce) = encryption.encrypt(b"legacy").unwrap();
+        let legacy = Envelope::from_parts(ciphertext.clone(), nonce.clone());
+        assert_eq!((legacy.version, legacy.key_id.as_str()), (LEGACY_VERSION, ""));
+        assert_eq!(encryption.open(&legacy).unwrap(), b"legacy");
+
+        // Without its header, data is refused rather than guessed at
+        let headerless = [nonce, ciphertext].concat();
+        assert!(Envelope::parse(&headerless).unwrap_err().to_string().contains("Not an encrypted EHR envelope"));
+
+        let bytes = encryption.seal(b"data", b"").unwrap().to_bytes().unwrap();
+        for truncated in [&bytes[..5], &bytes[..8], &bytes[..bytes.len() - 25]] {
+            assert!(Envelope::parse(truncated).is_err(), "{:?}", truncated);
+        }
+        let mut future = bytes.clone();
+        future[4] = VERSION + 1;
+        assert!(Envelope::parse(&future).unwrap_err().to_string().contains("Unsupported envelope version"));
+        let mut unknown = bytes;
+        unknown[5] = 0;
+        assert!(Envelope::parse(&unknown).is_err());
+        assert!(Envelope::parse(b"short").is_err());
+    }
+}
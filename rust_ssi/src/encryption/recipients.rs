@@ -0,0 +1,219 @@
+//! Sharing an EHR key with recipients. The data key of a record is wrapped
+//! separately for each recipient's X25519 key-agreement key (ECIES: an
+//! ephemeral X25519 exchange, HKDF-SHA256 and AES-256-GCM), so access is
+//! granted by adding a wrapped key, without touching the record itself.
+
+use super::{EHREncryption, Envelope};
+use crate::error::{Error, Result};
+use crate::utils::{bytes_to_hex, hex_to_bytes};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+/// HKDF info of key-wrapping keys
+const WRAP_INFO: &[u8] = b"rust_ssi ehr key wrap v1";
+
+/// A recipient's public key-agreement key, named by its DID verification
+/// method id such as `did:hedera:testnet:0.0.7654321#key-agreement-1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientPublicKey {
+    pub id: String,
+    key: PublicKey,
+}
+
+impl RecipientPublicKey {
+    pub fn from_bytes(id: &str, key: [u8; 32]) -> Self {
+        Self { id: id.to_string(), key: PublicKey::from(key) }
+    }
+
+    /// The key of an X25519 verification method of a DID document, given as
+    /// an OKP `publicKeyJwk`
+    pub fn from_verification_method(method: &serde_json::Value) -> Result<Self> {
+        let id = method["id"].as_str().ok_or_else(|| Error::crypto("Verification method has no id"))?;
+        let jwk = &method["publicKeyJwk"];
+        if jwk["kty"] != "OKP" || jwk["crv"] != "X25519" {
+            return Err(Error::crypto(format!("Verification method {} has no X25519 publicKeyJwk", id)));
+        }
+        let key = jwk["x"]
+            .as_str()
+            .and_then(|x| URL_SAFE_NO_PAD.decode(x).ok())
+            .and_then(|x| <[u8; 32]>::try_from(x).ok())
+            .ok_or_else(|| Error::crypto(format!("Invalid X25519 key in verification method {}", id)))?;
+        Ok(Self::from_bytes(id, key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.key.as_bytes()
+    }
+}
+
+/// A recipient's private key-agreement key
+pub struct RecipientSecretKey {
+    pub id: String,
+    secret: StaticSecret,
+}
+
+impl RecipientSecretKey {
+    /// A new random key for the verification method `id`
+    pub fn generate(id: &str) -> Self {
+        Self { id: id.to_string(), secret: StaticSecret::random_from_rng(rand::thread_rng()) }
+    }
+
+    pub fn from_bytes(id: &str, key: [u8; 32]) -> Self {
+        Self { id: id.to_string(), secret: StaticSecret::from(key) }
+    }
+
+    /// The key material, for saving to a key file
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_key(&self) -> RecipientPublicKey {
+        RecipientPublicKey { id: self.id.clone(), key: PublicKey::from(&self.secret) }
+    }
+}
+
+/// An EHR data key wrapped for one recipient. Byte fields are hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecipientKey {
+    /// Verification method id of the recipient
+    pub recipient: String,
+    /// Id of the wrapped data key, as in the envelopes it decrypts
+    pub key_id: String,
+    pub ephemeral_public_key: String,
+    /// The data key sealed in an [`Envelope`] under the key-wrapping key
+    pub wrapped_key: String,
+}
+
+impl RecipientKey {
+    /// Associated data of the wrapped key, so that it cannot be relabelled
+    /// for another recipient or data key
+    fn aad(&self) -> Vec<u8> {
+        format!("{}\n{}", self.recipient, self.key_id).into_bytes()
+    }
+}
+
+impl EHREncryption {
+    /// Wrap this data key for `recipient`
+    pub fn wrap_for(&self, recipient: &RecipientPublicKey) -> Result<RecipientKey> {
+        let ephemeral = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.key);
+        if !shared.was_contributory() {
+            return Err(Error::crypto(format!("Invalid X25519 key for recipient {}", recipient.id)));
+        }
+        let kek = wrapping_key(shared.as_bytes(), &ephemeral_public, &recipient.key)?;
+
+        let mut wrapped = RecipientKey {
+            recipient: recipient.id.clone(),
+            key_id: self.key_id(),
+            ephemeral_public_key: bytes_to_hex(ephemeral_public.as_bytes()),
+            wrapped_key: String::new(),
+        };
+        wrapped.wrapped_key = bytes_to_hex(&kek.seal(&self.get_key(), &wrapped.aad())?.to_bytes()?);
+        Ok(wrapped)
+    }
+
+    /// The data key wrapped for `secret` among `keys`, from the first of
+    /// its entries that opens
+    pub fn unwrap_for(keys: &[RecipientKey], secret: &RecipientSecretKey) -> Result<Self> {
+        let mut result = Err(Error::crypto(format!("No EHR key is wrapped for {}", secret.id)));
+        for wrapped in keys.iter().filter(|key| key.recipient == secret.id) {
+            result = Self::unwrap(wrapped, secret);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn unwrap(wrapped: &RecipientKey, secret: &RecipientSecretKey) -> Result<Self> {
+        let ephemeral_public = hex_to_bytes(&wrapped.ephemeral_public_key)
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .map(PublicKey::from)
+            .ok_or_else(|| Error::crypto(format!("Invalid ephemeral key for {}", secret.id)))?;
+        let shared = secret.secret.diffie_hellman(&ephemeral_public);
+        let kek = wrapping_key(shared.as_bytes(), &ephemeral_public, &PublicKey::from(&secret.secret))?;
+
+        let envelope = Envelope::parse(&hex_to_bytes(&wrapped.wrapped_key)?)?;
+        if envelope.aad != wrapped.aad() {
+            return Err(Error::crypto(format!("EHR key wrapped for {} was relabelled", secret.id)));
+        }
+        let key = Self::from_key(&kek.open(&envelope)?)?;
+        if key.key_id() != wrapped.key_id {
+            return Err(Error::crypto(format!("EHR key wrapped for {} is not key {}", secret.id, wrapped.key_id)));
+        }
+        Ok(key)
+    }
+}
+
+/// The key-wrapping key of an exchange between an ephemeral and a
+/// recipient key
+fn wrapping_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> Result<EHREncryption> {
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut kek = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut kek)
+        .map_err(|_| Error::crypto("Key-wrapping key derivation failed"))?;
+    EHREncryption::from_key(&kek)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_key_with_recipients() {
+        let data_key = EHREncryption::new().unwrap();
+        let record = data_key.seal(b"{\"resourceType\":\"Bundle\"}", b"").unwrap();
+        let gp = RecipientSecretKey::generate("did:hedera:testnet:0.0.1111#key-agreement-1");
+        let cardiologist = RecipientSecretKey::generate("did:hedera:testnet:0.0.2222#key-agreement-1");
+        let mut keys = vec![data_key.wrap_for(&gp.public_key()).unwrap()];
+
+        // The recipient's key as published in their DID document
+        let x = URL_SAFE_NO_PAD.encode(cardiologist.public_key().as_bytes());
+        let method = serde_json::json!({
+            "id": cardiologist.id,
+            "type": "JsonWebKey2020",
+            "publicKeyJwk": { "kty": "OKP", "crv": "X25519", "x": x },
+        });
+        let published = RecipientPublicKey::from_verification_method(&method).unwrap();
+        assert!(EHREncryption::unwrap_for(&keys, &cardiologist).is_err());
+        keys.push(data_key.wrap_for(&published).unwrap());
+
+        for recipient in [&gp, &cardiologist] {
+            let key = EHREncryption::unwrap_for(&keys, recipient).unwrap();
+            assert_eq!(key.open(&record).unwrap(), b"{\"resourceType\":\"Bundle\"}");
+        }
+        let restored = RecipientSecretKey::from_bytes(&gp.id, gp.to_bytes());
+        assert_eq!(EHREncryption::unwrap_for(&keys, &restored).unwrap().key_id(), data_key.key_id());
+
+        // A key wrapped for one recipient does not open for another
+        let relabelled = RecipientKey { recipient: cardiologist.id.clone(), ..keys[0].clone() };
+        assert!(EHREncryption::unwrap_for(&[relabelled], &cardiologist).is_err());
+        let impostor = RecipientSecretKey::generate(&gp.id);
+        assert!(EHREncryption::unwrap_for(&keys, &impostor).is_err());
+        assert!(data_key.wrap_for(&RecipientPublicKey::from_bytes("did:example:low-order", [0; 32])).is_err());
+        assert!(RecipientPublicKey::from_verification_method(&serde_json::json!({ "id": "did:example:1#k" })).is_err());
+    }
+
+    #[test]
+    fn test_unwrap_skips_entries_that_do_not_open() {
+        let data_key = EHREncryption::new().unwrap();
+        let gp = RecipientSecretKey::generate("did:hedera:testnet:0.0.1111#key-agreement-1");
+        // Wrapped for a key the recipient has since rotated
+        let stale = RecipientSecretKey::generate(&gp.id);
+        let keys = vec![
+            data_key.wrap_for(&stale.public_key()).unwrap(),
+            data_key.wrap_for(&gp.public_key()).unwrap(),
+        ];
+
+        assert_eq!(EHREncryption::unwrap_for(&keys, &gp).unwrap().key_id(), data_key.key_id());
+        assert_eq!(EHREncryption::unwrap_for(&keys, &stale).unwrap().key_id(), data_key.key_id());
+        assert!(EHREncryption::unwrap_for(&keys[..1], &gp).is_err());
+    }
+}
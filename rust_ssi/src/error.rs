@@ -0,0 +1,189 @@
+use std::fmt;
+
+/// Boxed error used as the source of wrapped failures
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Result alias used across the crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A parse failure located by its path inside the bundle JSON,
+/// e.g. `entry[3].resource.component[1].valueQuantity.value`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Errors returned by the crate's public API
+#[derive(Debug)]
+pub enum Error {
+    /// Input could not be parsed into FHIR resources
+    Parse(ParseError),
+    /// A parsed bundle failed validation
+    Validation(Vec<String>),
+    /// A requested record does not exist
+    NotFound { kind: &'static str, id: String },
+    /// A conditional write expected a version that is no longer current
+    Conflict { id: String, expected: String, current: Option<String> },
+    /// Records of a patient under legal hold cannot be deleted
+    LegalHold { patient_id: String },
+    /// The storage backend failed
+    Storage { message: String, source: Option<BoxError> },
+    /// Encryption, decryption or key handling failed
+    Crypto { message: String, source: Option<BoxError> },
+    /// The ledger rejected or could not complete a request
+    Ledger { message: String, retryable: bool, source: Option<BoxError> },
+    /// A document could not be rendered
+    Render { message: String, source: Option<BoxError> },
+    /// Reading or writing a local file failed
+    Io(std::io::Error),
+}
+
+impl Error {
+    pub fn not_found(kind: &'static str, id: impl Into<String>) -> Self {
+        Error::NotFound { kind, id: id.into() }
+    }
+
+    pub fn conflict(id: impl Into<String>, expected: impl Into<String>, current: Option<String>) -> Self {
+        Error::Conflict { id: id.into(), expected: expected.into(), current }
+    }
+
+    pub fn legal_hold(patient_id: impl Into<String>) -> Self {
+        Error::LegalHold { patient_id: patient_id.into() }
+    }
+
+    pub fn storage(message: impl Into<String>) -> Self {
+        Error::Storage { message: message.into(), source: None }
+    }
+
+    pub fn crypto(message: impl Into<String>) -> Self {
+        Error::Crypto { message: message.into(), source: None }
+    }
+
+    pub fn ledger(message: impl Into<String>, retryable: bool) -> Self {
+        Error::Ledger { message: message.into(), retryable, source: None }
+    }
+
+    pub fn render(message: impl Into<String>) -> Self {
+        Error::Render { message: message.into(), source: None }
+    }
+
+    /// Attach the underlying cause to a storage, crypto, ledger or render error
+    pub fn with_source(mut self, cause: impl Into<BoxError>) -> Self {
+        match &mut self {
+            Error::Storage { source, .. }
+            | Error::Crypto { source, .. }
+            | Error::Ledger { source, .. }
+            | Error::Render { source, .. } => *source = Some(cause.into()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Whether repeating the same request may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Ledger { retryable, .. } => *retryable,
+            Error::Io(err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "parse error: {}", err),
+            Error::Validation(errors) => write!(f, "validation failed: {}", errors.join("; ")),
+            Error::NotFound { kind, id } => write!(f, "{} not found: {}", kind, id),
+            Error::Conflict { id, expected, current: Some(current) } => {
+                write!(f, "version conflict on {}: expected version {}, current is {}", id, expected, current)
+            }
+            Error::Conflict { id, expected, current: None } => {
+                write!(f, "version conflict on {}: expected version {}, but it does not exist", id, expected)
+            }
+            Error::LegalHold { patient_id } => write!(f, "records of patient {} are under legal hold", patient_id),
+            Error::Storage { message, .. } => write!(f, "storage error: {}", message),
+            Error::Crypto { message, .. } => write!(f, "crypto error: {}", message),
+            Error::Ledger { message, .. } => write!(f, "ledger error: {}", message),
+            Error::Render { message, .. } => write!(f, "render error: {}", message),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Storage { source, .. }
+            | Error::Crypto { source, .. }
+            | Error::Ledger { source, .. }
+            | Error::Render { source, .. } => source.as_deref().map(|e| e as _),
+            Error::Validation(_) | Error::NotFound { .. } | Error::Conflict { .. } | Error::LegalHold { .. } => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source_chaining() {
+        let io = std::io::Error::new(std::io::ErrorKind::TimedOut, "node unreachable");
+        let err = Error::ledger("file create failed", true).with_source(io);
+
+        assert!(err.is_retryable());
+        assert_eq!(err.to_string(), "ledger error: file create failed");
+        assert_eq!(err.source().unwrap().to_string(), "node unreachable");
+    }
+
+    #[test]
+    fn test_retryable_classification() {
+        assert!(!Error::ledger("INVALID_SIGNATURE", false).is_retryable());
+        assert!(!Error::not_found("bundle", "b1").is_retryable());
+        assert!(!Error::from(ParseError::new("entry[0]", "bad")).is_retryable());
+        assert!(Error::from(std::io::Error::from(std::io::ErrorKind::Interrupted)).is_retryable());
+    }
+}
@@ -1,496 +1,215 @@
+use crate::error::{self, ParseError};
 use crate::models::*;
-use crate::models::observation::ObservationComponent;
-use crate::models::medication::DosageInstruction;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 
-pub struct FHIRHandler {
-    resources: HashMap<String, Value>,
+/// How strictly `FHIRHandler` treats missing elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Missing elements are left out and listed in the parse report
+    #[default]
+    Lenient,
+    /// Missing required elements are rejected
+    Strict,
 }
 
-impl FHIRHandler {
-    pub fn new() -> Self {
-        Self {
-            resources: HashMap::new(),
-        }
-    }
-
-    /// Parse FHIR JSON and create structured FHIR resources
-    pub fn parse_fhir_json(&mut self, json_str: &str) -> Result<Bundle, Box<dyn std::error::Error>> {
-        let json_value: Value = serde_json::from_str(json_str)?;
-        
-        if let Some(bundle) = json_value.as_object() {
-            let mut bundle_struct = Bundle::new(
-                bundle.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                bundle.get("type").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                bundle.get("timestamp").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            );
-
-            if let Some(entries) = bundle.get("entry").and_then(|v| v.as_array()) {
-                for entry in entries {
-                    if let Some(resource) = entry.get("resource") {
-                        let resource_type = resource.get("resourceType")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("Unknown");
-                        
-                        match resource_type {
-                            "Patient" => {
-                                let patient = self.parse_patient(resource)?;
-                                bundle_struct.add_entry(Resource::Patient(patient));
-                            },
-                            "Practitioner" => {
-                                let practitioner = self.parse_practitioner(resource)?;
-                                bundle_struct.add_entry(Resource::Practitioner(practitioner));
-                            },
-                            "Encounter" => {
-                                let encounter = self.parse_encounter(resource)?;
-                                bundle_struct.add_entry(Resource::Encounter(encounter));
-                            },
-                            "Observation" => {
-                                let observation = self.parse_observation(resource)?;
-                                bundle_struct.add_entry(Resource::Observation(observation));
-                            },
-                            "Condition" => {
-                                let condition = self.parse_condition(resource)?;
-                                bundle_struct.add_entry(Resource::Condition(condition));
-                            },
-                            "MedicationRequest" => {
-                                let medication = self.parse_medication_request(resource)?;
-                                bundle_struct.add_entry(Resource::MedicationRequest(medication));
-                            },
-                            _ => {
-                                eprintln!("Unknown resource type: {}", resource_type);
-                            }
-                        }
-                    }
-                }
-            }
+/// A resource kept as raw JSON because it has no typed model
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedResource {
+    pub path: String,
+    pub resource_type: String,
+    pub id: String,
+}
 
-            // Parse signature if present
-            if let Some(signature) = bundle.get("signature") {
-                let signature_struct = self.parse_signature(signature)?;
-                bundle_struct.add_signature(signature_struct);
-            }
+/// Summary of the last bundle parsed by `FHIRHandler`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParseReport {
+    pub resource_counts: BTreeMap<String, usize>,
+    pub unsupported: Vec<UnsupportedResource>,
+    /// Problems tolerated in lenient mode, such as missing required elements
+    pub issues: Vec<ParseError>,
+}
 
-            Ok(bundle_struct)
-        } else {
-            Err("Invalid FHIR Bundle JSON".into())
-        }
+impl ParseReport {
+    pub fn total_resources(&self) -> usize {
+        self.resource_counts.values().sum()
     }
+}
 
-    fn parse_patient(&self, resource: &Value) -> Result<Patient, Box<dyn std::error::Error>> {
-        let id = resource.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let family = resource.get("name")
-            .and_then(|n| n.as_array())
-            .and_then(|arr| arr.first())
-            .and_then(|n| n.get("family"))
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-        
-        let given = resource.get("name")
-            .and_then(|n| n.as_array())
-            .and_then(|arr| arr.first())
-            .and_then(|n| n.get("given"))
-            .and_then(|v| v.as_array())
-            .map(|arr| arr.iter().filter_map(|v| v.as_str()).map(|s| s.to_string()).collect())
-            .unwrap_or(vec![]);
-        
-        let gender = resource.get("gender").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let birth_date = resource.get("birthDate").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        
-        let mut patient = Patient::new(id, family, given, gender, birth_date);
-        
-        // Parse identifiers
-        if let Some(identifiers) = resource.get("identifier").and_then(|v| v.as_array()) {
-            for identifier in identifiers {
-                let system = identifier.get("system").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                let value = identifier.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                patient.add_identifier(system, value);
+impl fmt::Display for ParseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Parse Report:")?;
+        writeln!(f, "  Resources: {}", self.total_resources())?;
+        for (resource_type, count) in &self.resource_counts {
+            writeln!(f, "    {}: {}", resource_type, count)?;
+        }
+        if !self.unsupported.is_empty() {
+            writeln!(f, "  Preserved without a typed model:")?;
+            for resource in &self.unsupported {
+                writeln!(f, "    {} {}/{}", resource.path, resource.resource_type, resource.id)?;
             }
         }
-        
-        // Parse telecom
-        if let Some(telecom) = resource.get("telecom").and_then(|v| v.as_array()) {
-            for contact in telecom {
-                let system = contact.get("system").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                let value = contact.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                let use_value = contact.get("use").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                patient.add_contact(system, value, use_value);
+        if !self.issues.is_empty() {
+            writeln!(f, "  Issues:")?;
+            for issue in &self.issues {
+                writeln!(f, "    {}", issue)?;
             }
         }
-        
-        Ok(patient)
+        Ok(())
     }
+}
 
-    fn parse_practitioner(&self, resource: &Value) -> Result<Practitioner, Box<dyn std::error::Error>> {
-        let id = resource.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let family = resource.get("name")
-            .and_then(|n| n.as_array())
-            .and_then(|arr| arr.first())
-            .and_then(|n| n.get("family"))
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-        
-        let given = resource.get("name")
-            .and_then(|n| n.as_array())
-            .and_then(|arr| arr.first())
-            .and_then(|n| n.get("given"))
-            .and_then(|v| v.as_array())
-            .map(|arr| arr.iter().filter_map(|v| v.as_str()).map(|s| s.to_string()).collect())
-            .unwrap_or(vec![]);
-        
-        let mut practitioner = Practitioner::new(id, family, given);
-        
-        // Parse identifiers
-        if let Some(identifiers) = resource.get("identifier").and_then(|v| v.as_array()) {
-            for identifier in identifiers {
-                let system = identifier.get("system").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                let value = identifier.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                practitioner.add_identifier(system, value);
-            }
-        }
-        
-        Ok(practitioner)
+/// Elements that must be present in strict mode, and are reported as issues
+/// in lenient mode, per resource type.
+///
+/// Paths are relative to the resource; intermediate segments are optional
+/// and `[]` applies the rest of the path to every array item. Besides the
+/// elements FHIR R4 marks as required, quantities must carry a value, as a
+/// quantity without one cannot be displayed. Entries without a resource are
+/// checked while the entries are parsed.
+const REQUIRED_ELEMENTS: &[(&str, &[&str])] = &[
+    ("Bundle", &["type", "signature.type", "signature.when", "signature.who"]),
+    ("Encounter", &["status", "class"]),
+    ("Observation", &["status", "code", "valueQuantity.value", "component[].code", "component[].valueQuantity.value"]),
+    ("Condition", &["subject"]),
+    ("MedicationRequest", &["status", "intent", "medicationCodeableConcept", "subject"]),
+];
+
+pub struct FHIRHandler {
+    mode: ParseMode,
+    report: ParseReport,
+}
+
+impl Default for FHIRHandler {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn parse_encounter(&self, resource: &Value) -> Result<Encounter, Box<dyn std::error::Error>> {
-        let id = resource.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let status = resource.get("status").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        
-        let class = if let Some(class_obj) = resource.get("class") {
-            Coding {
-                system: class_obj.get("system").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                code: class_obj.get("code").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: class_obj.get("display").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            }
-        } else {
-            Coding {
-                system: "".to_string(),
-                code: "".to_string(),
-                display: "".to_string(),
-            }
-        };
-        
-        let subject = Reference {
-            reference: resource.get("subject")
-                .and_then(|s| s.get("reference"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string(),
-            display: None,
-        };
-        
-        let period = if let Some(period_obj) = resource.get("period") {
-            Period {
-                start: period_obj.get("start").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                end: period_obj.get("end").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            }
-        } else {
-            Period {
-                start: "".to_string(),
-                end: "".to_string(),
-            }
-        };
-        
-        let mut encounter = Encounter::new(id, status, class, subject, period);
-        
-        // Parse participant
-        if let Some(participants) = resource.get("participant").and_then(|v| v.as_array()) {
-            for participant in participants {
-                if let Some(individual) = participant.get("individual") {
-                    let reference = Reference {
-                        reference: individual.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                        display: None,
-                    };
-                    encounter.add_participant(reference);
-                }
-            }
-        }
-        
-        // Parse reason code
-        if let Some(reasons) = resource.get("reasonCode").and_then(|v| v.as_array()) {
-            for reason in reasons {
-                let coding = vec![Coding {
-                    system: "".to_string(),
-                    code: "".to_string(),
-                    display: "".to_string(),
-                }];
-                let text = reason.get("text").and_then(|v| v.as_str()).map(|s| s.to_string());
-                encounter.add_reason(coding, text);
-            }
+impl FHIRHandler {
+    pub fn new() -> Self {
+        Self::with_mode(ParseMode::Lenient)
+    }
+
+    /// Create a handler with an explicit parse mode
+    pub fn with_mode(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            report: ParseReport::default(),
         }
-        
-        Ok(encounter)
     }
 
-    fn parse_observation(&self, resource: &Value) -> Result<Observation, Box<dyn std::error::Error>> {
-        let id = resource.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let status = resource.get("status").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        
-        let code = CodeableConcept {
-            coding: vec![],
-            text: resource.get("code").and_then(|c| c.get("text")).and_then(|v| v.as_str()).map(|s| s.to_string()),
-        };
-        
-        let subject = Reference {
-            reference: resource.get("subject")
-                .and_then(|s| s.get("reference"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string(),
-            display: None,
-        };
-        
-        let mut observation = Observation::new(id, status, code, subject);
-        
-        // Parse category
-        if let Some(categories) = resource.get("category").and_then(|v| v.as_array()) {
-            for category in categories {
-                let coding = vec![Coding {
-                    system: category.get("coding")
-                        .and_then(|c| c.as_array())
-                        .and_then(|arr| arr.first())
-                        .and_then(|c| c.get("system"))
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("")
-                        .to_string(),
-                    code: category.get("coding")
-                        .and_then(|c| c.as_array())
-                        .and_then(|arr| arr.first())
-                        .and_then(|c| c.get("code"))
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("")
-                        .to_string(),
-                    display: category.get("coding")
-                        .and_then(|c| c.as_array())
-                        .and_then(|arr| arr.first())
-                        .and_then(|c| c.get("display"))
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("")
-                        .to_string(),
-                }];
-                let category_concept = CodeableConcept { coding, text: None };
-                observation.add_category(category_concept);
-            }
+    /// Report for the most recent call to `parse_fhir_json`
+    pub fn report(&self) -> &ParseReport {
+        &self.report
+    }
+
+    /// Parse FHIR JSON and create structured FHIR resources
+    pub fn parse_fhir_json(&mut self, json_str: &str) -> error::Result<Bundle> {
+        self.report = ParseReport::default();
+        let mut json_value: Value = serde_json::from_str(json_str)
+            .map_err(|e| ParseError::new("", e.to_string()))?;
+
+        if !json_value.is_object() {
+            return Err(ParseError::new("", "Invalid FHIR Bundle JSON: expected an object").into());
         }
-        
-        // Parse encounter
-        if let Some(encounter) = resource.get("encounter") {
-            let encounter_ref = Reference {
-                reference: encounter.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: None,
+        self.check_required(&json_value, "Bundle", "")?;
+
+        let entries = json_value
+            .as_object_mut()
+            .and_then(|bundle| bundle.remove("entry"))
+            .unwrap_or(Value::Null);
+        let mut bundle: Bundle = deserialize_at(json_value, "")?;
+
+        let entries = match entries {
+            Value::Array(entries) => entries,
+            Value::Null => Vec::new(),
+            _ => return Err(ParseError::new("entry", "expected an array").into()),
+        };
+
+        for (index, entry) in entries.into_iter().enumerate() {
+            let entry_path = format!("entry[{}]", index);
+            let Value::Object(mut members) = entry else {
+                return Err(ParseError::new(entry_path, "expected an object").into());
             };
-            observation.set_encounter(encounter_ref);
-        }
-        
-        // Parse effective date time
-        if let Some(effective_date_time) = resource.get("effectiveDateTime") {
-            observation.set_effective_date_time(effective_date_time.as_str().unwrap_or("").to_string());
-        }
-        
-        // Parse performer
-        if let Some(performers) = resource.get("performer").and_then(|v| v.as_array()) {
-            for performer in performers {
-                let reference = Reference {
-                    reference: performer.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                    display: None,
-                };
-                observation.add_performer(reference);
-            }
-        }
-        
-        // Parse components
-        if let Some(components) = resource.get("component").and_then(|v| v.as_array()) {
-            let mut observation_components = vec![];
-            for component in components {
-                let component_code = CodeableConcept {
-                    coding: vec![],
-                    text: component.get("code").and_then(|c| c.get("text")).and_then(|v| v.as_str()).map(|s| s.to_string()),
-                };
-                
-                let value_quantity = if let Some(value_obj) = component.get("valueQuantity") {
-                    Quantity {
-                        value: value_obj.get("value").and_then(|v| v.as_f64()).unwrap_or(0.0),
-                        unit: value_obj.get("unit").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                        system: None,
-                        code: None,
-                    }
-                } else {
-                    Quantity {
-                        value: 0.0,
-                        unit: "".to_string(),
-                        system: None,
-                        code: None,
+            let path = format!("{}.resource", entry_path);
+            let resource = match members.remove("resource") {
+                Some(Value::Null) | None => {
+                    let missing = ParseError::new(path, "missing required element");
+                    if self.mode == ParseMode::Strict {
+                        return Err(missing.into());
                     }
-                };
-                
-                observation_components.push(ObservationComponent {
-                    code: component_code,
-                    value_quantity,
+                    self.report.issues.push(ParseError { message: "entry without a resource was left out".into(), ..missing });
+                    continue;
+                }
+                Some(resource) => self.parse_resource(resource, &path)?,
+            };
+            *self.report.resource_counts.entry(resource.resource_type().to_string()).or_insert(0) += 1;
+            if resource.is_other() {
+                self.report.unsupported.push(UnsupportedResource {
+                    path,
+                    resource_type: resource.resource_type().to_string(),
+                    id: resource.id().to_string(),
                 });
             }
-            observation.set_components(observation_components);
+            let full_url_path = format!("{}.fullUrl", entry_path);
+            bundle.entry.push(BundleEntry {
+                full_url: deserialize_at(members.remove("fullUrl").unwrap_or_default(), &full_url_path)?,
+                resource,
+                search: members.remove("search"),
+                request: members.remove("request"),
+                response: members.remove("response"),
+                extra: members,
+            });
         }
-        
-        Ok(observation)
+
+        Ok(bundle)
     }
 
-    fn parse_condition(&self, resource: &Value) -> Result<Condition, Box<dyn std::error::Error>> {
-        let id = resource.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let recorded_date = resource.get("recordedDate").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        
-        let code = CodeableConcept {
-            coding: vec![],
-            text: resource.get("code").and_then(|c| c.get("text")).and_then(|v| v.as_str()).map(|s| s.to_string()),
-        };
-        
-        let subject = Reference {
-            reference: resource.get("subject")
-                .and_then(|s| s.get("reference"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string(),
-            display: None,
+    fn parse_resource(&mut self, resource: Value, path: &str) -> Result<Resource, ParseError> {
+        let resource_type = resource.get("resourceType")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| ParseError::new(format!("{}.resourceType", path), "missing required element"))?
+            .to_string();
+        self.check_required(&resource, &resource_type, path)?;
+
+        let resource = match resource_type.as_str() {
+            "Patient" => Resource::Patient(deserialize_at(resource, path)?),
+            "Practitioner" => Resource::Practitioner(deserialize_at(resource, path)?),
+            "Encounter" => Resource::Encounter(deserialize_at(resource, path)?),
+            "Observation" => Resource::Observation(deserialize_at(resource, path)?),
+            "Condition" => Resource::Condition(deserialize_at(resource, path)?),
+            "MedicationRequest" => Resource::MedicationRequest(deserialize_at(resource, path)?),
+            _ => Resource::Other { resource_type, raw: resource },
         };
-        
-        let mut condition = Condition::new(id, code, subject, recorded_date);
-        
-        // Parse clinical status
-        if let Some(clinical_status) = resource.get("clinicalStatus") {
-            let coding = vec![Coding {
-                system: clinical_status.get("coding")
-                    .and_then(|c| c.as_array())
-                    .and_then(|arr| arr.first())
-                    .and_then(|c| c.get("system"))
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("")
-                    .to_string(),
-                code: clinical_status.get("coding")
-                    .and_then(|c| c.as_array())
-                    .and_then(|arr| arr.first())
-                    .and_then(|c| c.get("code"))
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("")
-                    .to_string(),
-                display: clinical_status.get("coding")
-                    .and_then(|c| c.as_array())
-                    .and_then(|arr| arr.first())
-                    .and_then(|c| c.get("display"))
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("")
-                    .to_string(),
-            }];
-            let clinical_status_concept = CodeableConcept { coding, text: None };
-            condition.set_clinical_status(clinical_status_concept);
-        }
-        
-        // Parse encounter
-        if let Some(encounter) = resource.get("encounter") {
-            let encounter_ref = Reference {
-                reference: encounter.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: None,
-            };
-            condition.set_encounter(encounter_ref);
-        }
-        
-        // Parse asserter
-        if let Some(asserter) = resource.get("asserter") {
-            let asserter_ref = Reference {
-                reference: asserter.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: None,
-            };
-            condition.set_asserter(asserter_ref);
-        }
-        
-        Ok(condition)
+
+        Ok(resource)
     }
 
-    fn parse_medication_request(&self, resource: &Value) -> Result<MedicationRequest, Box<dyn std::error::Error>> {
-        let id = resource.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let status = resource.get("status").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let intent = resource.get("intent").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let authored_on = resource.get("authoredOn").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        
-        let medication = CodeableConcept {
-            coding: vec![],
-            text: resource.get("medicationCodeableConcept")
-                .and_then(|m| m.get("text"))
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string()),
-        };
-        
-        let subject = Reference {
-            reference: resource.get("subject")
-                .and_then(|s| s.get("reference"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string(),
-            display: None,
-        };
-        
-        let mut medication_request = MedicationRequest::new(id, status, intent, medication, subject, authored_on);
-        
-        // Parse encounter
-        if let Some(encounter) = resource.get("encounter") {
-            let encounter_ref = Reference {
-                reference: encounter.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: None,
-            };
-            medication_request.set_encounter(encounter_ref);
-        }
-        
-        // Parse requester
-        if let Some(requester) = resource.get("requester") {
-            let requester_ref = Reference {
-                reference: requester.get("reference").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: None,
-            };
-            medication_request.set_requester(requester_ref);
+    /// Reject the first required element missing from `value` in strict
+    /// mode, or report every missing one in lenient mode
+    fn check_required(&mut self, value: &Value, resource_type: &str, path: &str) -> Result<(), ParseError> {
+        let required = REQUIRED_ELEMENTS.iter()
+            .find(|(name, _)| *name == resource_type)
+            .map(|(_, elements)| *elements)
+            .unwrap_or(&[]);
+
+        let mut missing = Vec::new();
+        for element in required {
+            let segments: Vec<&str> = element.split('.').collect();
+            find_missing(value, &segments, path, &mut missing);
         }
-        
-        // Parse dosage instructions
-        if let Some(dosage_instructions) = resource.get("dosageInstruction").and_then(|v| v.as_array()) {
-            for instruction in dosage_instructions {
-                let text = instruction.get("text").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                medication_request.add_dosage_instruction(text);
+
+        let mut issues = missing.into_iter().map(|path| ParseError::new(path, "missing required element"));
+        match self.mode {
+            ParseMode::Strict => issues.next().map_or(Ok(()), Err),
+            ParseMode::Lenient => {
+                self.report.issues.extend(issues);
+                Ok(())
             }
         }
-        
-        Ok(medication_request)
-    }
-
-    fn parse_signature(&self, signature: &Value) -> Result<Signature, Box<dyn std::error::Error>> {
-        let signature_types = if let Some(types) = signature.get("type").and_then(|v| v.as_array()) {
-            types.iter().map(|t| SignatureType {
-                system: t.get("system").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                code: t.get("code").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                display: t.get("display").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            }).collect()
-        } else {
-            vec![]
-        };
-        
-        let when = signature.get("when").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let who = Reference {
-            reference: signature.get("who")
-                .and_then(|w| w.get("reference"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string(),
-            display: None,
-        };
-        let data = signature.get("data").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let sig_format = signature.get("sigFormat").and_then(|v| v.as_str()).map(|s| s.to_string());
-        
-        Ok(Signature {
-            signature_type: signature_types,
-            when,
-            who,
-            data,
-            sig_format,
-        })
     }
 
     /// Validate FHIR resources for completeness and correctness
@@ -522,3 +241,189 @@ impl FHIRHandler {
         }
     }
 }
+
+/// Deserialize `value` into `T`, prefixing any error path with `prefix`
+fn deserialize_at<T: DeserializeOwned>(value: Value, prefix: &str) -> Result<T, ParseError> {
+    serde_path_to_error::deserialize(value).map_err(|err| {
+        let inner = err.path().to_string();
+        let path = match (prefix.is_empty(), inner == ".") {
+            (_, true) => prefix.to_string(),
+            (true, false) => inner,
+            (false, false) => format!("{}.{}", prefix, inner),
+        };
+        ParseError::new(path, err.into_inner().to_string())
+    })
+}
+
+/// Walk `segments` below `value` and collect the paths of missing leaf
+/// elements into `missing`. Absent intermediate elements are not reported.
+fn find_missing(value: &Value, segments: &[&str], path: &str, missing: &mut Vec<String>) {
+    let Some((segment, rest)) = segments.split_first() else {
+        return;
+    };
+    let (name, each) = match segment.strip_suffix("[]") {
+        Some(name) => (name, true),
+        None => (*segment, false),
+    };
+    let child_path = if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    };
+
+    let child = match value.get(name) {
+        Some(Value::Null) | None => {
+            if rest.is_empty() {
+                missing.push(child_path);
+            }
+            return;
+        }
+        Some(Value::String(s)) if s.is_empty() && rest.is_empty() => return missing.push(child_path),
+        Some(child) => child,
+    };
+
+    if each {
+        for (index, item) in child.as_array().into_iter().flatten().enumerate() {
+            find_missing(item, rest, &format!("{}[{}]", child_path, index), missing);
+        }
+    } else {
+        find_missing(child, rest, &child_path, missing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    const SAMPLE_BUNDLE: &str = include_str!("../FHIR/FHIRBundle.json");
+
+    fn sample_with(edit: impl FnOnce(&mut Value)) -> String {
+        let mut value: Value = serde_json::from_str(SAMPLE_BUNDLE).unwrap();
+        edit(&mut value);
+        value.to_string()
+    }
+
+    fn parse_error(mode: ParseMode, json: &str) -> ParseError {
+        match FHIRHandler::with_mode(mode).parse_fhir_json(json) {
+            Err(Error::Parse(err)) => err,
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_keeps_codings() {
+        let mut handler = FHIRHandler::new();
+        let bundle = handler.parse_fhir_json(SAMPLE_BUNDLE).unwrap();
+
+        let encounter = bundle.entry.iter().find_map(|e| match &e.resource {
+            Resource::Encounter(encounter) => Some(encounter),
+            _ => None,
+        }).unwrap();
+        assert_eq!(encounter.encounter_type[0].coding[0].code, "185349003");
+        assert!(encounter.reason_code[0].coding.is_empty());
+        assert_eq!(encounter.reason_code[0].text.as_deref(), Some("Annual Physical Exam"));
+
+        let observation = bundle.entry.iter().find_map(|e| match &e.resource {
+            Resource::Observation(observation) => Some(observation),
+            _ => None,
+        }).unwrap();
+        assert_eq!(observation.value_quantity.as_ref().unwrap().value.as_ref().and_then(Decimal::as_f64), Some(120.0));
+    }
+
+    #[test]
+    fn test_type_error_reports_path() {
+        let json = sample_with(|v| {
+            v["entry"][3]["resource"]["component"][1]["valueQuantity"]["value"] = Value::from("eighty");
+        });
+        let err = parse_error(ParseMode::Lenient, &json);
+        assert_eq!(err.path, "entry[3].resource.component[1].valueQuantity.value");
+        assert!(err.message.contains("expected a JSON number"));
+    }
+
+    #[test]
+    fn test_strict_rejects_missing_required_element() {
+        let json = sample_with(|v| {
+            v["entry"][5]["resource"].as_object_mut().unwrap().remove("intent");
+        });
+
+        let mut lenient = FHIRHandler::new();
+        assert!(lenient.parse_fhir_json(&json).is_ok());
+        assert_eq!(lenient.report().issues, vec![ParseError::new("entry[5].resource.intent", "missing required element")]);
+
+        let err = parse_error(ParseMode::Strict, &json);
+        assert_eq!(err.path, "entry[5].resource.intent");
+        assert_eq!(err.to_string(), "entry[5].resource.intent: missing required element");
+    }
+
+    #[test]
+    fn test_strict_checks_nested_elements() {
+        let json = sample_with(|v| {
+            v["entry"][3]["resource"]["component"][0]["valueQuantity"]
+                .as_object_mut().unwrap().remove("value");
+        });
+        let err = parse_error(ParseMode::Strict, &json);
+        assert_eq!(err.path, "entry[3].resource.component[0].valueQuantity.value");
+
+        let mut strict = FHIRHandler::with_mode(ParseMode::Strict);
+        assert!(strict.parse_fhir_json(SAMPLE_BUNDLE).is_ok());
+    }
+
+    #[test]
+    fn test_lenient_reports_missing_elements_without_inventing_them() {
+        let json = sample_with(|v| {
+            v["entry"][3]["resource"]["valueQuantity"].as_object_mut().unwrap().remove("value");
+            v["entry"][4]["resource"].as_object_mut().unwrap().remove("subject");
+            v["entry"].as_array_mut().unwrap().push(serde_json::json!({ "fullUrl": "urn:uuid:empty" }));
+        });
+        let err = parse_error(ParseMode::Strict, &json);
+        assert_eq!(err.path, "entry[3].resource.valueQuantity.value");
+
+        let mut lenient = FHIRHandler::new();
+        let bundle = lenient.parse_fhir_json(&json).unwrap();
+        assert_eq!(bundle.entry.len(), 6);
+        let issues: Vec<String> = lenient.report().issues.iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(issues, [
+            "entry[3].resource.valueQuantity.value: missing required element",
+            "entry[4].resource.subject: missing required element",
+            "entry[6].resource: entry without a resource was left out",
+        ]);
+        assert!(lenient.report().to_string().contains("entry[4].resource.subject"));
+
+        // The missing elements stay missing when written back
+        let written = serde_json::to_value(&bundle).unwrap();
+        assert!(written["entry"][3]["resource"]["valueQuantity"].get("value").is_none());
+        assert!(written["entry"][4]["resource"].get("subject").is_none());
+    }
+
+    #[test]
+    fn test_unsupported_resources_are_preserved_and_reported() {
+        let flag = serde_json::json!({
+            "resourceType": "Flag",
+            "id": "flag-1",
+            "status": "active",
+            "code": { "text": "Fall risk" },
+            "subject": { "reference": "Patient/patient-123" }
+        });
+        let json = sample_with(|v| {
+            v["entry"].as_array_mut().unwrap().push(serde_json::json!({ "resource": flag.clone() }));
+        });
+
+        let mut handler = FHIRHandler::with_mode(ParseMode::Strict);
+        let bundle = handler.parse_fhir_json(&json).unwrap();
+        assert_eq!(bundle.entry.len(), 7);
+        assert_eq!(serde_json::to_value(&bundle.entry[6].resource).unwrap(), flag);
+
+        let report = handler.report();
+        assert_eq!(report.total_resources(), 7);
+        assert_eq!(report.resource_counts["Flag"], 1);
+        assert_eq!(report.unsupported, vec![UnsupportedResource {
+            path: "entry[6].resource".to_string(),
+            resource_type: "Flag".to_string(),
+            id: "flag-1".to_string(),
+        }]);
+
+        let original: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_value(&bundle).unwrap(), original);
+    }
+}
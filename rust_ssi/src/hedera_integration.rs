@@ -1,27 +1,17 @@
-use hedera_sdk_rust::{
-    Client, FileId, FileCreateTransaction, FileAppendTransaction, FileContentsQuery,
-    PrivateKey, AccountId, TransactionResponse, Status
+use hedera::{
+    Client, FileId, FileCreateTransaction, FileAppendTransaction, FileContentsQuery, FileDeleteTransaction,
+    FileInfoQuery, Key, PrivateKey, AccountId, TransactionReceipt, TransactionResponse, Status
 };
-use aes_gcm::{Aes256Gcm, Key, Nonce};
-use aes_gcm::aead::{Aead, NewAead};
-use rand::Rng;
-use serde::{Serialize, Deserialize};
-use std::error::Error;
-
-/// Represents an encrypted EHR file stored on Hedera File Service
-#[derive(Debug, Serialize, Deserialize)]
-pub struct EncryptedEHR {
-    pub file_id: String,
-    pub encryption_key_hash: String,
-    pub mime_type: String,
-    pub size: u64,
-    pub created_timestamp: i64,
-}
+use async_trait::async_trait;
+use crate::error::{Error, Result};
+use crate::ledger::{LedgerFileInfo, LedgerFileStore, LedgerReceipt, LedgerStatus};
+use crate::utils::bytes_to_hex;
+
+pub use crate::ledger::EncryptedEHR;
 
 /// Hedera File Service integration for storing encrypted patient EHR data
 pub struct HederaFileService {
     client: Client,
-    operator_account: AccountId,
     operator_key: PrivateKey,
 }
 
@@ -31,147 +21,195 @@ impl HederaFileService {
         network: &str,
         operator_account: &str,
         operator_private_key: &str,
-    ) -> Result<Self, Box<dyn Error>> {
-        let client = Client::for_name(network)?;
-        let operator_account = operator_account.parse()?;
-        let operator_key = PrivateKey::from_string(operator_private_key)?;
-        
-        Ok(Self {
-            client,
-            operator_account,
-            operator_key,
-        })
+    ) -> Result<Self> {
+        let client = Client::for_name(network)
+            .map_err(|e| Error::ledger(format!("Unknown Hedera network: {}", network), false).with_source(e))?;
+        let operator_account: AccountId = operator_account.parse()
+            .map_err(|e| Error::ledger(format!("Invalid operator account: {}", operator_account), false).with_source(e))?;
+        let operator_key: PrivateKey = operator_private_key.parse()
+            .map_err(|e| Error::crypto("Invalid operator private key").with_source(e))?;
+        client.set_operator(operator_account, operator_key.clone());
+
+        Ok(Self { client, operator_key })
     }
 
-    /// Store encrypted EHR data on Hedera File Service
-    pub async fn store_ehr(
-        &self,
-        encrypted_data: &[u8],
-        mime_type: &str,
-    ) -> Result<EncryptedEHR, Box<dyn Error>> {
-        // Create a new file
-        let file_create_tx = FileCreateTransaction::new()
-            .keys([&self.operator_key.public_key()])
-            .contents(encrypted_data)
-            .max_automatic_token_associations(0);
-
-        let file_create_response = file_create_tx
-            .sign(&self.operator_key)
-            .execute(&self.client)
-            .await?;
+    /// Wait for the receipt of a submitted transaction
+    async fn receipt(&self, action: &str, response: TransactionResponse) -> Result<(TransactionReceipt, String)> {
+        let transaction_id = response.transaction_id.to_string();
+        let receipt = response.get_receipt(&self.client).await
+            .map_err(|e| ledger_error(&format!("{} receipt failed", action), e))?;
+        Ok((receipt, transaction_id))
+    }
+}
 
-        let file_id = file_create_response.get_receipt(&self.client).await?
-            .file_id
-            .ok_or("Failed to get file ID")?;
+#[async_trait]
+impl LedgerFileStore for HederaFileService {
+    async fn create_file(&self, contents: &[u8], memo: &str) -> Result<LedgerReceipt> {
+        let response = FileCreateTransaction::new()
+            .keys([self.operator_key.public_key()])
+            .contents(contents.to_vec())
+            .file_memo(memo)
+            .sign(self.operator_key.clone())
+            .execute(&self.client)
+            .await
+            .map_err(|e| ledger_error("File create failed", e))?;
 
-        // Get file info
-        let file_contents_query = FileContentsQuery::new()
-            .file_id(file_id);
+        let (receipt, transaction_id) = self.receipt("File create", response).await?;
+        let file_id = receipt.file_id
+            .ok_or_else(|| Error::ledger("File create receipt has no file ID", false))?;
+        Ok(LedgerReceipt { status: LedgerStatus::Success, file_id: file_id.to_string(), transaction_id })
+    }
 
-        let file_contents = file_contents_query.execute(&self.client).await?;
+    async fn append_file(&self, file_id: &str, contents: &[u8]) -> Result<LedgerReceipt> {
+        let response = FileAppendTransaction::new()
+            .file_id(parse_file_id(file_id)?)
+            .contents(contents.to_vec())
+            .sign(self.operator_key.clone())
+            .execute(&self.client)
+            .await
+            .map_err(|e| ledger_error("File append failed", e))?;
 
-        Ok(EncryptedEHR {
-            file_id: file_id.to_string(),
-            encryption_key_hash: "".to_string(), // In production, store encrypted key hash
-            mime_type: mime_type.to_string(),
-            size: file_contents.contents.len() as u64,
-            created_timestamp: chrono::Utc::now().timestamp(),
-        })
+        let (_, transaction_id) = self.receipt("File append", response).await?;
+        Ok(LedgerReceipt { status: LedgerStatus::Success, file_id: file_id.to_string(), transaction_id })
     }
 
-    /// Retrieve encrypted EHR data from Hedera File Service
-    pub async fn retrieve_ehr(&self, file_id: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        let file_id: FileId = file_id.parse()?;
-        
-        let file_contents_query = FileContentsQuery::new()
-            .file_id(file_id);
+    async fn read_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        let file_contents = FileContentsQuery::new()
+            .file_id(parse_file_id(file_id)?)
+            .execute(&self.client)
+            .await
+            .map_err(|e| ledger_error("File contents query failed", e))?;
 
-        let file_contents = file_contents_query.execute(&self.client).await?;
-        
         Ok(file_contents.contents)
     }
-}
 
-/// Encryption utilities for patient EHR data
-pub struct EHREncryption {
-    key: Key<Aes256Gcm>,
-}
+    async fn delete_file(&self, file_id: &str) -> Result<LedgerReceipt> {
+        let response = FileDeleteTransaction::new()
+            .file_id(parse_file_id(file_id)?)
+            .sign(self.operator_key.clone())
+            .execute(&self.client)
+            .await
+            .map_err(|e| ledger_error("File delete failed", e))?;
 
-impl EHREncryption {
-    /// Create new encryption instance with a random key
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let mut rng = rand::thread_rng();
-        let key_bytes: [u8; 32] = rng.gen();
-        let key = Key::from_slice(&key_bytes);
-        
-        Ok(Self { key: *key })
+        let (_, transaction_id) = self.receipt("File delete", response).await?;
+        Ok(LedgerReceipt { status: LedgerStatus::Success, file_id: file_id.to_string(), transaction_id })
     }
 
-    /// Encrypt EHR data
-    pub fn encrypt(&self, data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
-        let cipher = Aes256Gcm::new(&self.key);
-        let mut rng = rand::thread_rng();
-        let nonce_bytes: [u8; 12] = rng.gen();
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        
-        let encrypted_data = cipher.encrypt(nonce, data)?;
-        
-        Ok((encrypted_data, nonce_bytes.to_vec()))
+    async fn file_info(&self, file_id: &str) -> Result<LedgerFileInfo> {
+        let info = FileInfoQuery::new()
+            .file_id(parse_file_id(file_id)?)
+            .execute(&self.client)
+            .await
+            .map_err(|e| ledger_error("File info query failed", e))?;
+
+        Ok(LedgerFileInfo {
+            file_id: info.file_id.to_string(),
+            size: info.size,
+            deleted: info.is_deleted,
+            keys: info.keys.keys.iter().map(key_string).collect(),
+            memo: info.file_memo,
+        })
     }
+}
 
-    /// Decrypt EHR data
-    pub fn decrypt(&self, encrypted_data: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-        let cipher = Aes256Gcm::new(&self.key);
-        let nonce = Nonce::from_slice(nonce);
-        
-        let decrypted_data = cipher.decrypt(nonce, encrypted_data)?;
-        
-        Ok(decrypted_data)
+/// A public key as Hedera prints it, or any other key kind as hex protobuf
+fn key_string(key: &Key) -> String {
+    match key {
+        Key::Single(key) => key.to_string(),
+        other => bytes_to_hex(&other.to_bytes()),
     }
+}
 
-    /// Get the encryption key for storage/transmission
-    pub fn get_key(&self) -> Vec<u8> {
-        self.key.as_slice().to_vec()
+fn parse_file_id(file_id: &str) -> Result<FileId> {
+    file_id.parse()
+        .map_err(|e| Error::ledger(format!("Invalid file ID: {}", file_id), false).with_source(e))
+}
+
+/// Map a Hedera SDK error, marking timeouts and busy nodes as retryable.
+/// A status named by [`LedgerStatus`] becomes the source of the error, as
+/// with [`LocalLedger`](crate::ledger::LocalLedger).
+fn ledger_error(context: &str, err: hedera::Error) -> Error {
+    let retryable = matches!(
+        err,
+        hedera::Error::TimedOut(_)
+            | hedera::Error::GrpcStatus(_)
+            | hedera::Error::TransactionPreCheckStatus { status: Status::Busy, .. }
+            | hedera::Error::TransactionPreCheckStatus { status: Status::PlatformTransactionNotCreated, .. }
+    );
+    match response_status(&err).and_then(ledger_status) {
+        Some(status) => {
+            Error::ledger(format!("{}: {}", context, status), retryable || status.is_retryable()).with_source(status)
+        }
+        None => Error::ledger(context, retryable).with_source(err),
     }
 }
 
-/// Patient EHR data structure
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PatientEHR {
-    pub patient_did: String,
-    pub provider_did: String,
-    pub ehr_type: String,
-    pub data: serde_json::Value,
-    pub timestamp: i64,
-    pub valid_until: Option<i64>,
+/// The status a transaction or query was rejected with, if it got that far
+fn response_status(err: &hedera::Error) -> Option<Status> {
+    match err {
+        hedera::Error::TimedOut(last) => response_status(last),
+        hedera::Error::TransactionPreCheckStatus { status, .. }
+        | hedera::Error::QueryPreCheckStatus { status, .. }
+        | hedera::Error::QueryPaymentPreCheckStatus { status, .. }
+        | hedera::Error::QueryNoPaymentPreCheckStatus { status }
+        | hedera::Error::ReceiptStatus { status, .. } => Some(*status),
+        _ => None,
+    }
 }
 
-impl PatientEHR {
-    /// Create new EHR record
-    pub fn new(
-        patient_did: String,
-        provider_did: String,
-        ehr_type: String,
-        data: serde_json::Value,
-        valid_until: Option<i64>,
-    ) -> Self {
-        Self {
-            patient_did,
-            provider_did,
-            ehr_type,
-            data,
-            timestamp: chrono::Utc::now().timestamp(),
-            valid_until,
-        }
+fn ledger_status(status: Status) -> Option<LedgerStatus> {
+    match status {
+        Status::Success => Some(LedgerStatus::Success),
+        Status::InvalidFileId => Some(LedgerStatus::InvalidFileId),
+        Status::FileDeleted => Some(LedgerStatus::FileDeleted),
+        Status::InvalidSignature => Some(LedgerStatus::InvalidSignature),
+        Status::TransactionOversize => Some(LedgerStatus::TransactionOversize),
+        Status::MaxFileSizeExceeded => Some(LedgerStatus::MaxFileSizeExceeded),
+        Status::Busy | Status::PlatformTransactionNotCreated => Some(LedgerStatus::Busy),
+        _ => None,
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[tokio::test]
+    async fn test_new_checks_its_arguments() {
+        let key = PrivateKey::generate_ed25519().to_string();
+        assert!(HederaFileService::new("testnet", "0.0.1001", &key).is_ok());
 
-    /// Convert to JSON bytes for encryption
-    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        Ok(serde_json::to_vec(self)?)
+        let err = HederaFileService::new("nowhere", "0.0.1001", &key).err().unwrap();
+        assert!(matches!(err, Error::Ledger { retryable: false, .. }));
+        assert!(matches!(HederaFileService::new("testnet", "account", &key), Err(Error::Ledger { .. })));
+        assert!(matches!(HederaFileService::new("testnet", "0.0.1001", "not a key"), Err(Error::Crypto { .. })));
     }
 
-    /// Create from JSON bytes
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        Ok(serde_json::from_slice(bytes)?)
+    #[test]
+    fn test_ids_keys_and_errors() {
+        assert_eq!(parse_file_id("0.0.5005").unwrap().to_string(), "0.0.5005");
+        assert!(parse_file_id("file").is_err());
+
+        let public = PrivateKey::generate_ed25519().public_key();
+        assert_eq!(key_string(&Key::Single(public)), public.to_string());
+
+        let status = |err: &Error| err.source().and_then(|source| source.downcast_ref::<LedgerStatus>()).copied();
+        let busy = hedera::Error::QueryNoPaymentPreCheckStatus { status: Status::Busy };
+        let err = ledger_error("File create failed", hedera::Error::TimedOut(Box::new(busy)));
+        assert!(err.is_retryable());
+        assert_eq!(status(&err), Some(LedgerStatus::Busy));
+        let rejected = hedera::Error::ReceiptStatus { status: Status::InvalidSignature, transaction_id: None };
+        let err = ledger_error("File create receipt failed", rejected);
+        assert!(!err.is_retryable());
+        assert_eq!(status(&err), Some(LedgerStatus::InvalidSignature));
+        let deleted = hedera::Error::QueryNoPaymentPreCheckStatus { status: Status::FileDeleted };
+        assert_eq!(status(&ledger_error("File contents query failed", deleted)), Some(LedgerStatus::FileDeleted));
+
+        // Statuses the file service does not name keep the SDK error
+        let payer = hedera::Error::QueryNoPaymentPreCheckStatus { status: Status::InsufficientPayerBalance };
+        let err = ledger_error("File create failed", payer);
+        assert_eq!(status(&err), None);
+        assert!(err.source().unwrap().is::<hedera::Error>());
     }
 }
@@ -0,0 +1,224 @@
+//! Ledger file storage behind the [`LedgerFileStore`] trait, implemented by
+//! the Hedera File Service client (with the `hedera` feature) and by
+//! [`LocalLedger`], an in-process stand-in with the same semantics that
+//! needs no network.
+
+mod local;
+mod upload;
+
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+#[cfg(feature = "encryption")]
+use crate::encryption::{Envelope, RecipientKey};
+use serde::{Deserialize, Serialize};
+
+pub use local::LocalLedger;
+pub use upload::{RetryPolicy, Upload, UploadProgress, CHUNK_SIZE};
+
+/// Largest transaction Hedera accepts. The contents of a create or append
+/// must fit in one transaction.
+pub const MAX_TRANSACTION_SIZE: usize = 6 * 1024;
+
+/// Largest file Hedera stores
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// Memo of the files holding the recipient keys of shared EHRs, one JSON
+/// [`RecipientKey`] per line
+#[cfg(feature = "encryption")]
+pub const RECIPIENT_KEYS_MIME_TYPE: &str = "application/x-ndjson";
+
+/// Response codes of the file service, named as in Hedera's `ResponseCodeEnum`.
+/// A request rejected with one of these returns an [`Error::Ledger`] whose
+/// source is its status; other failures keep the client's own error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum LedgerStatus {
+    Success,
+    /// No file has this id
+    InvalidFileId,
+    /// The file was deleted
+    FileDeleted,
+    /// The transaction was not signed by a key of the file
+    InvalidSignature,
+    /// The transaction is larger than [`MAX_TRANSACTION_SIZE`]
+    TransactionOversize,
+    /// The file would grow larger than [`MAX_FILE_SIZE`]
+    MaxFileSizeExceeded,
+    /// The node is too busy to take the transaction
+    Busy,
+}
+
+impl LedgerStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LedgerStatus::Success => "SUCCESS",
+            LedgerStatus::InvalidFileId => "INVALID_FILE_ID",
+            LedgerStatus::FileDeleted => "FILE_DELETED",
+            LedgerStatus::InvalidSignature => "INVALID_SIGNATURE",
+            LedgerStatus::TransactionOversize => "TRANSACTION_OVERSIZE",
+            LedgerStatus::MaxFileSizeExceeded => "MAX_FILE_SIZE_EXCEEDED",
+            LedgerStatus::Busy => "BUSY",
+        }
+    }
+
+    /// Whether submitting the same transaction again may succeed
+    pub fn is_retryable(self) -> bool {
+        self == LedgerStatus::Busy
+    }
+
+    /// `Ok` on success, else a ledger error for the failed `action`
+    pub fn check(self, action: &str) -> Result<()> {
+        match self {
+            LedgerStatus::Success => Ok(()),
+            status => Err(status.error(action)),
+        }
+    }
+
+    /// The error of `action` failing with this status
+    pub(crate) fn error(self, action: &str) -> Error {
+        Error::ledger(format!("{} failed: {}", action, self), self.is_retryable()).with_source(self)
+    }
+}
+
+impl std::fmt::Display for LedgerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for LedgerStatus {}
+
+/// The receipt of a transaction that reached consensus
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerReceipt {
+    pub status: LedgerStatus,
+    /// The file created or changed, as `shard.realm.num`
+    pub file_id: String,
+    /// `<payer account>@<seconds>.<nanoseconds>` of the transaction's valid start
+    pub transaction_id: String,
+}
+
+/// What the ledger knows about a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerFileInfo {
+    pub file_id: String,
+    pub size: u64,
+    pub deleted: bool,
+    /// Public keys that must sign changes to the file
+    pub keys: Vec<String>,
+    pub memo: String,
+}
+
+/// Represents an encrypted EHR file stored on the ledger
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptedEHR {
+    pub file_id: String,
+    /// Id of the data key, empty unless stored with recipient keys
+    pub encryption_key_hash: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub created_timestamp: i64,
+    /// SHA-256 of the stored contents, checked after upload
+    #[serde(default)]
+    pub content_hash: String,
+    /// File holding the data key wrapped for each recipient
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_keys_file_id: Option<String>,
+}
+
+/// A file service on a ledger. Files are addressed by `0.0.N` ids, readable
+/// by anyone and changed only by transactions signed with one of their keys.
+#[async_trait]
+pub trait LedgerFileStore: Send + Sync {
+    /// Create a file holding `contents`, keyed to the operator
+    async fn create_file(&self, contents: &[u8], memo: &str) -> Result<LedgerReceipt>;
+
+    /// Add `contents` to the end of a file
+    async fn append_file(&self, file_id: &str, contents: &[u8]) -> Result<LedgerReceipt>;
+
+    /// The contents of a file
+    async fn read_file(&self, file_id: &str) -> Result<Vec<u8>>;
+
+    /// Delete a file and its contents
+    async fn delete_file(&self, file_id: &str) -> Result<LedgerReceipt>;
+
+    /// A file's size, keys and memo, also for deleted files
+    async fn file_info(&self, file_id: &str) -> Result<LedgerFileInfo>;
+
+    /// Store encrypted EHR data in a new file, in chunks if it does not fit
+    /// in one transaction
+    async fn store_ehr(&self, encrypted_data: &[u8], mime_type: &str) -> Result<EncryptedEHR> {
+        Upload::new(encrypted_data, mime_type).run(self).await
+    }
+
+    /// Retrieve encrypted EHR data stored with [`store_ehr`](Self::store_ehr)
+    async fn retrieve_ehr(&self, file_id: &str) -> Result<Vec<u8>> {
+        self.read_file(file_id).await
+    }
+
+    /// Store an encrypted EHR whose data key is wrapped for `recipients`.
+    /// The wrapped keys go in a file of their own, so that more recipients
+    /// can be added without re-uploading the record.
+    #[cfg(feature = "encryption")]
+    async fn store_shared_ehr(
+        &self,
+        envelope: &Envelope,
+        mime_type: &str,
+        recipients: &[RecipientKey],
+    ) -> Result<EncryptedEHR> {
+        let mut lines = Vec::new();
+        for recipient in recipients {
+            lines.extend(recipient_key_line(&envelope.key_id, recipient)?);
+        }
+        let mut stored = self.store_ehr(&envelope.to_bytes()?, mime_type).await?;
+        let keys = Upload::new(&lines, RECIPIENT_KEYS_MIME_TYPE).run(self).await?;
+        stored.encryption_key_hash = envelope.key_id.clone();
+        stored.recipient_keys_file_id = Some(keys.file_id);
+        Ok(stored)
+    }
+
+    /// Give one more recipient access to an EHR stored with
+    /// [`store_shared_ehr`](Self::store_shared_ehr)
+    #[cfg(feature = "encryption")]
+    async fn add_recipient(&self, ehr: &EncryptedEHR, recipient: &RecipientKey) -> Result<LedgerReceipt> {
+        let keys_file_id = recipient_keys_file_id(ehr)?;
+        let line = recipient_key_line(&ehr.encryption_key_hash, recipient)?;
+        // A line added twice by a retry is harmless
+        RetryPolicy::default().run(|| self.append_file(keys_file_id, &line)).await
+    }
+
+    /// The data keys wrapped for the recipients of a shared EHR
+    #[cfg(feature = "encryption")]
+    async fn recipient_keys(&self, ehr: &EncryptedEHR) -> Result<Vec<RecipientKey>> {
+        let contents = self.read_file(recipient_keys_file_id(ehr)?).await?;
+        contents
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                serde_json::from_slice(line)
+                    .map_err(|e| Error::crypto(format!("Invalid recipient key of file {}: {}", ehr.file_id, e)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "encryption")]
+/// A line of a recipient keys file, if `recipient` holds the data key `key_id`
+fn recipient_key_line(key_id: &str, recipient: &RecipientKey) -> Result<Vec<u8>> {
+    if recipient.key_id != key_id {
+        return Err(Error::crypto(format!(
+            "Key {} wrapped for {} is not the EHR key {}",
+            recipient.key_id, recipient.recipient, key_id
+        )));
+    }
+    let mut line = serde_json::to_vec(recipient).map_err(|e| Error::crypto(e.to_string()))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+#[cfg(feature = "encryption")]
+fn recipient_keys_file_id(ehr: &EncryptedEHR) -> Result<&str> {
+    ehr.recipient_keys_file_id
+        .as_deref()
+        .ok_or_else(|| Error::crypto(format!("EHR file {} is not shared with recipients", ehr.file_id)))
+}
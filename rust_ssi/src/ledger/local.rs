@@ -0,0 +1,327 @@
+//! An in-process stand-in for the Hedera File Service. It keeps files in
+//! memory and enforces the service's rules: `0.0.N` file ids, writes signed
+//! by one of the file's keys, transaction and file size limits, and deleted
+//! files that stay known but can no longer be read or changed. Tests can
+//! inject transient failures into write transactions.
+
+use super::{LedgerFileInfo, LedgerFileStore, LedgerReceipt, LedgerStatus, MAX_FILE_SIZE, MAX_TRANSACTION_SIZE};
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use rand::RngCore;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Number of the first file created, as on a fresh Hedera network
+const FIRST_FILE_NUM: u64 = 1001;
+
+/// Account that pays for the transactions of [`LocalLedger::new`]
+const DEFAULT_OPERATOR: &str = "0.0.2";
+
+struct LocalFile {
+    contents: Vec<u8>,
+    keys: Vec<String>,
+    memo: String,
+    deleted: bool,
+}
+
+/// A failure injected into a write transaction
+enum Fault {
+    /// Rejected with this status, without taking effect
+    Reject(LedgerStatus),
+    /// Takes effect, but waiting for the receipt times out
+    LoseReceipt,
+}
+
+struct Files {
+    next_num: u64,
+    files: BTreeMap<u64, LocalFile>,
+    faults: VecDeque<Fault>,
+}
+
+/// A client of an in-memory ledger. Clones and [`client`](Self::client)s
+/// share the same files. Keys are modelled as a private key and its SHA-256
+/// digest, which stands in for the public key.
+#[derive(Clone)]
+pub struct LocalLedger {
+    files: Arc<Mutex<Files>>,
+    operator_account: String,
+    public_key: String,
+}
+
+impl Default for LocalLedger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalLedger {
+    /// An empty ledger, with an operator holding a random key
+    pub fn new() -> Self {
+        let mut private_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut private_key);
+        let files = Files { next_num: FIRST_FILE_NUM, files: BTreeMap::new(), faults: VecDeque::new() };
+        Self {
+            files: Arc::new(Mutex::new(files)),
+            operator_account: DEFAULT_OPERATOR.to_string(),
+            public_key: public_key(&private_key),
+        }
+    }
+
+    /// A client of the same ledger whose transactions are paid by
+    /// `operator_account` and signed with `operator_private_key`
+    pub fn client(&self, operator_account: &str, operator_private_key: &[u8]) -> Self {
+        Self {
+            files: Arc::clone(&self.files),
+            operator_account: operator_account.to_string(),
+            public_key: public_key(operator_private_key),
+        }
+    }
+
+    /// The public key this client signs with
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Make the next `count` write transactions fail with `status` without
+    /// taking effect
+    pub fn fail_next(&self, count: usize, status: LedgerStatus) {
+        self.files().faults.extend((0..count).map(|_| Fault::Reject(status)));
+    }
+
+    /// Make the next `count` write transactions take effect but time out
+    /// waiting for their receipts
+    pub fn lose_next_receipts(&self, count: usize) {
+        self.files().faults.extend((0..count).map(|_| Fault::LoseReceipt));
+    }
+
+    fn files(&self) -> MutexGuard<'_, Files> {
+        self.files.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn receipt(&self, num: u64) -> LedgerReceipt {
+        let valid_start = chrono::Utc::now();
+        LedgerReceipt {
+            status: LedgerStatus::Success,
+            file_id: file_id(num),
+            transaction_id: format!(
+                "{}@{}.{:09}",
+                self.operator_account,
+                valid_start.timestamp(),
+                valid_start.timestamp_subsec_nanos()
+            ),
+        }
+    }
+
+    /// Run `change` on a live file signed for by this client
+    fn change(
+        &self,
+        file_id: &str,
+        action: &str,
+        change: impl FnOnce(&mut LocalFile) -> Result<()>,
+    ) -> Result<LedgerReceipt> {
+        let num = file_num(file_id, action)?;
+        self.submit(action, |files| {
+            let file = live_file(files.files.get_mut(&num), action)?;
+            if !file.keys.contains(&self.public_key) {
+                return Err(LedgerStatus::InvalidSignature.error(action));
+            }
+            change(file)?;
+            Ok(num)
+        })
+    }
+
+    /// Run a write transaction on the file `apply` returns the number of,
+    /// unless an injected fault intervenes
+    fn submit(&self, action: &str, apply: impl FnOnce(&mut Files) -> Result<u64>) -> Result<LedgerReceipt> {
+        let mut files = self.files();
+        let fault = files.faults.pop_front();
+        if let Some(Fault::Reject(status)) = fault {
+            return Err(status.error(action));
+        }
+        let num = apply(&mut files)?;
+        match fault {
+            Some(Fault::LoseReceipt) => Err(Error::ledger(format!("{} receipt timed out", action), true)),
+            _ => Ok(self.receipt(num)),
+        }
+    }
+}
+
+#[async_trait]
+impl LedgerFileStore for LocalLedger {
+    async fn create_file(&self, contents: &[u8], memo: &str) -> Result<LedgerReceipt> {
+        check_transaction_size(contents, "File create")?;
+        self.submit("File create", |files| {
+            let num = files.next_num;
+            files.next_num += 1;
+            files.files.insert(num, LocalFile {
+                contents: contents.to_vec(),
+                keys: vec![self.public_key.clone()],
+                memo: memo.to_string(),
+                deleted: false,
+            });
+            Ok(num)
+        })
+    }
+
+    async fn append_file(&self, file_id: &str, contents: &[u8]) -> Result<LedgerReceipt> {
+        check_transaction_size(contents, "File append")?;
+        self.change(file_id, "File append", |file| {
+            if file.contents.len() + contents.len() > MAX_FILE_SIZE {
+                return Err(LedgerStatus::MaxFileSizeExceeded.error("File append"));
+            }
+            file.contents.extend_from_slice(contents);
+            Ok(())
+        })
+    }
+
+    async fn read_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        let num = file_num(file_id, "File contents query")?;
+        let files = self.files();
+        let file = live_file(files.files.get(&num), "File contents query")?;
+        Ok(file.contents.clone())
+    }
+
+    async fn delete_file(&self, file_id: &str) -> Result<LedgerReceipt> {
+        self.change(file_id, "File delete", |file| {
+            file.contents.clear();
+            file.deleted = true;
+            Ok(())
+        })
+    }
+
+    async fn file_info(&self, file_id: &str) -> Result<LedgerFileInfo> {
+        let num = file_num(file_id, "File info query")?;
+        let files = self.files();
+        let Some(file) = files.files.get(&num) else {
+            return Err(LedgerStatus::InvalidFileId.error("File info query"));
+        };
+        Ok(LedgerFileInfo {
+            file_id: file_id.to_string(),
+            size: file.contents.len() as u64,
+            deleted: file.deleted,
+            keys: file.keys.clone(),
+            memo: file.memo.clone(),
+        })
+    }
+}
+
+fn public_key(private_key: &[u8]) -> String {
+    crate::utils::sha256_hash(private_key)
+}
+
+fn file_id(num: u64) -> String {
+    format!("0.0.{}", num)
+}
+
+/// The number of a `0.0.N` file id
+fn file_num(file_id: &str, action: &str) -> Result<u64> {
+    file_id
+        .strip_prefix("0.0.")
+        .and_then(|num| num.parse().ok())
+        .ok_or_else(|| LedgerStatus::InvalidFileId.error(action))
+}
+
+fn live_file<F: std::ops::Deref<Target = LocalFile>>(file: Option<F>, action: &str) -> Result<F> {
+    match file {
+        Some(file) if !file.deleted => Ok(file),
+        Some(_) => Err(LedgerStatus::FileDeleted.error(action)),
+        None => Err(LedgerStatus::InvalidFileId.error(action)),
+    }
+}
+
+fn check_transaction_size(contents: &[u8], action: &str) -> Result<()> {
+    match contents.len() > MAX_TRANSACTION_SIZE {
+        true => Err(LedgerStatus::TransactionOversize.error(action)),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::error::Error as _;
+
+    fn status<T: std::fmt::Debug>(result: Result<T>) -> LedgerStatus {
+        let err = result.expect_err("expected a ledger error");
+        assert!(matches!(err, Error::Ledger { .. }), "expected a ledger error, got {:?}", err);
+        *err.source().and_then(|source| source.downcast_ref::<LedgerStatus>()).expect("ledger errors carry a status")
+    }
+
+    #[tokio::test]
+    async fn test_store_and_retrieve_ehr() {
+        let ledger = LocalLedger::new();
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let data = serde_json::to_vec(&bundle).unwrap();
+
+        let stored = ledger.store_ehr(&data, "application/fhir+json").await.unwrap();
+        assert_eq!((stored.file_id.as_str(), stored.size), ("0.0.1001", data.len() as u64));
+        assert_eq!(ledger.retrieve_ehr(&stored.file_id).await.unwrap(), data);
+
+        // Any client reads the file; only its owner changes it
+        let other = ledger.client("0.0.1234", b"another operator key");
+        assert_eq!(other.read_file("0.0.1001").await.unwrap(), data);
+        assert_eq!(status(other.append_file("0.0.1001", b"x").await), LedgerStatus::InvalidSignature);
+        assert_eq!(status(other.delete_file("0.0.1001").await), LedgerStatus::InvalidSignature);
+
+        let receipt = ledger.append_file("0.0.1001", b"\n").await.unwrap();
+        assert_eq!((receipt.status, receipt.file_id.as_str()), (LedgerStatus::Success, "0.0.1001"));
+        assert!(receipt.transaction_id.starts_with("0.0.2@"));
+        let info = ledger.file_info("0.0.1001").await.unwrap();
+        assert_eq!((info.size, info.memo.as_str()), (data.len() as u64 + 1, "application/fhir+json"));
+        assert_eq!(info.keys, vec![ledger.public_key().to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_limits_and_deleted_files() {
+        let ledger = LocalLedger::new();
+        let oversize = [0; MAX_TRANSACTION_SIZE + 1];
+        assert_eq!(status(ledger.create_file(&oversize, "").await), LedgerStatus::TransactionOversize);
+        let file_id = ledger.create_file(&[0; MAX_TRANSACTION_SIZE], "").await.unwrap().file_id;
+        while ledger.file_info(&file_id).await.unwrap().size + (MAX_TRANSACTION_SIZE as u64) <= MAX_FILE_SIZE as u64 {
+            ledger.append_file(&file_id, &[0; MAX_TRANSACTION_SIZE]).await.unwrap();
+        }
+        assert_eq!(status(ledger.append_file(&file_id, &[0; MAX_TRANSACTION_SIZE]).await), LedgerStatus::MaxFileSizeExceeded);
+
+        ledger.delete_file(&file_id).await.unwrap();
+        let info = ledger.file_info(&file_id).await.unwrap();
+        assert_eq!((info.deleted, info.size), (true, 0));
+        assert_eq!(status(ledger.read_file(&file_id).await), LedgerStatus::FileDeleted);
+        assert_eq!(status(ledger.append_file(&file_id, b"x").await), LedgerStatus::FileDeleted);
+        assert_eq!(status(ledger.delete_file(&file_id).await), LedgerStatus::FileDeleted);
+        assert_eq!(status(ledger.read_file("0.0.9999").await), LedgerStatus::InvalidFileId);
+        assert_eq!(status(ledger.file_info("not-a-file").await), LedgerStatus::InvalidFileId);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_share_ehr_with_new_provider() {
+        use crate::encryption::{EHREncryption, RecipientSecretKey};
+
+        let ledger = LocalLedger::new();
+        let data_key = EHREncryption::new().unwrap();
+        let envelope = data_key.seal(include_bytes!("../../FHIR/FHIRBundle.json"), b"").unwrap();
+        let patient = RecipientSecretKey::generate("did:hedera:testnet:0.0.1111#key-agreement-1");
+        let provider = RecipientSecretKey::generate("did:hedera:testnet:0.0.2222#key-agreement-1");
+
+        let wrapped = data_key.wrap_for(&patient.public_key()).unwrap();
+        let stored = ledger.store_shared_ehr(&envelope, "application/fhir+json", &[wrapped]).await.unwrap();
+        assert_eq!(stored.encryption_key_hash, data_key.key_id());
+        assert!(EHREncryption::unwrap_for(&ledger.recipient_keys(&stored).await.unwrap(), &provider).is_err());
+
+        // The patient unwraps the data key and grants the provider access
+        let record = ledger.retrieve_ehr(&stored.file_id).await.unwrap();
+        let patient_key = EHREncryption::unwrap_for(&ledger.recipient_keys(&stored).await.unwrap(), &patient).unwrap();
+        ledger.add_recipient(&stored, &patient_key.wrap_for(&provider.public_key()).unwrap()).await.unwrap();
+        let keys = ledger.recipient_keys(&stored).await.unwrap();
+        let provider_key = EHREncryption::unwrap_for(&keys, &provider).unwrap();
+        let plaintext = provider_key.open(&crate::encryption::Envelope::parse(&record).unwrap()).unwrap();
+        assert_eq!(plaintext, include_bytes!("../../FHIR/FHIRBundle.json"));
+        assert_eq!(ledger.retrieve_ehr(&stored.file_id).await.unwrap(), record);
+
+        let other_key = EHREncryption::new().unwrap().wrap_for(&provider.public_key()).unwrap();
+        assert!(matches!(ledger.add_recipient(&stored, &other_key).await, Err(Error::Crypto { .. })));
+    }
+}
@@ -0,0 +1,222 @@
+//! Uploads larger than one transaction. The file is created with the first
+//! chunk and the rest appended, retrying transient failures, then checked
+//! against the local data.
+
+use super::{EncryptedEHR, LedgerFileStore};
+use crate::error::{Error, Result};
+use crate::utils::sha256_hash;
+use std::future::Future;
+use std::time::Duration;
+
+/// Contents sent per transaction, as the Hedera SDK chunks file appends
+pub const CHUNK_SIZE: usize = 4096;
+
+/// How often, and how patiently, to resubmit a transaction that failed with
+/// a retryable error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts per transaction, including the first
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each retry after it
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 5, backoff: Duration::from_millis(500) }
+    }
+}
+
+impl RetryPolicy {
+    /// Submit every transaction once
+    pub fn none() -> Self {
+        Self { max_attempts: 1, backoff: Duration::ZERO }
+    }
+
+    /// Run `send` until it succeeds, fails for good or runs out of attempts
+    pub async fn run<T, F: Future<Output = Result<T>>>(&self, mut send: impl FnMut() -> F) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match send().await {
+                Err(err) if err.is_retryable() && attempt < self.max_attempts => {
+                    tokio::time::sleep(self.backoff * 2u32.saturating_pow(attempt - 1)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// How far an upload has got, reported after each chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadProgress {
+    /// Chunks stored so far
+    pub chunk: usize,
+    pub chunks: usize,
+    /// Bytes stored so far
+    pub uploaded: usize,
+    pub total: usize,
+}
+
+type ProgressCallback<'a> = Box<dyn Fn(UploadProgress) + Send + Sync + 'a>;
+
+/// Stores data of any size up to the ledger's file size limit as one file
+pub struct Upload<'a> {
+    data: &'a [u8],
+    mime_type: String,
+    chunk_size: usize,
+    retry: RetryPolicy,
+    progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a> Upload<'a> {
+    pub fn new(data: &'a [u8], mime_type: &str) -> Self {
+        Self {
+            data,
+            mime_type: mime_type.to_string(),
+            chunk_size: CHUNK_SIZE,
+            retry: RetryPolicy::default(),
+            progress: None,
+        }
+    }
+
+    /// Send at most `chunk_size` bytes per transaction
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Call `progress` after each chunk is stored
+    pub fn on_progress(mut self, progress: impl Fn(UploadProgress) + Send + Sync + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Upload the data to a new file on `ledger` and check that the file
+    /// holds exactly the data
+    pub async fn run<L: LedgerFileStore + ?Sized>(&self, ledger: &L) -> Result<EncryptedEHR> {
+        let chunks: Vec<&[u8]> = match self.data.is_empty() {
+            true => vec![self.data],
+            false => self.data.chunks(self.chunk_size).collect(),
+        };
+        let mut uploaded = chunks[0].len();
+
+        // A retried create that had reached consensus leaves an empty-handed
+        // orphan file behind, which is harmless
+        let file_id = self.retry.run(|| ledger.create_file(chunks[0], &self.mime_type)).await?.file_id;
+        self.report(1, chunks.len(), uploaded);
+
+        for (index, chunk) in chunks.iter().enumerate().skip(1) {
+            let expected_size = (uploaded + chunk.len()) as u64;
+            let mut retried = false;
+            self.retry
+                .run(|| {
+                    let retry = std::mem::replace(&mut retried, true);
+                    let file_id = &file_id;
+                    async move {
+                        // An append whose receipt was lost may still have reached consensus
+                        if retry && ledger.file_info(file_id).await?.size >= expected_size {
+                            return Ok(());
+                        }
+                        ledger.append_file(file_id, chunk).await.map(|_| ())
+                    }
+                })
+                .await?;
+            uploaded += chunk.len();
+            self.report(index + 1, chunks.len(), uploaded);
+        }
+
+        let info = ledger.file_info(&file_id).await?;
+        let content_hash = sha256_hash(self.data);
+        let stored_hash = sha256_hash(&ledger.read_file(&file_id).await?);
+        if info.size != self.data.len() as u64 || stored_hash != content_hash {
+            return Err(Error::ledger(
+                format!(
+                    "File {} does not match the uploaded data: {} of {} bytes, hash {}",
+                    file_id,
+                    info.size,
+                    self.data.len(),
+                    stored_hash
+                ),
+                false,
+            ));
+        }
+
+        Ok(EncryptedEHR {
+            file_id,
+            encryption_key_hash: String::new(),
+            mime_type: self.mime_type.clone(),
+            size: info.size,
+            created_timestamp: chrono::Utc::now().timestamp(),
+            content_hash,
+            recipient_keys_file_id: None,
+        })
+    }
+
+    fn report(&self, chunk: usize, chunks: usize, uploaded: usize) {
+        if let Some(progress) = &self.progress {
+            progress(UploadProgress { chunk, chunks, uploaded, total: self.data.len() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{LedgerStatus, LocalLedger};
+    use std::sync::Mutex;
+
+    fn quick_retry() -> RetryPolicy {
+        RetryPolicy { max_attempts: 3, backoff: Duration::ZERO }
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_with_retries() {
+        let ledger = LocalLedger::new();
+        let data: Vec<u8> = (0..5 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        assert_eq!(ledger.store_ehr(&data, "x").await.unwrap().size, data.len() as u64);
+
+        // Two busy creates, then the receipt of the second chunk's append is lost
+        // after the chunk was stored, which must not store it twice
+        ledger.fail_next(2, LedgerStatus::Busy);
+        let progress = Mutex::new(Vec::new());
+        let upload = Upload::new(&data, "application/octet-stream").with_retry(quick_retry()).on_progress(|step| {
+            if step.chunk == 1 {
+                ledger.lose_next_receipts(1);
+            }
+            progress.lock().unwrap().push((step.chunk, step.uploaded));
+        });
+        let stored = upload.run(&ledger).await.unwrap();
+        drop(upload);
+
+        assert_eq!(stored.size, data.len() as u64);
+        assert_eq!(stored.content_hash, sha256_hash(&data));
+        assert_eq!(ledger.retrieve_ehr(&stored.file_id).await.unwrap(), data);
+        let steps = progress.into_inner().unwrap();
+        assert_eq!(steps.len(), 6);
+        assert_eq!(steps.first(), Some(&(1, CHUNK_SIZE)));
+        assert_eq!(steps.last(), Some(&(6, data.len())));
+    }
+
+    #[tokio::test]
+    async fn test_upload_gives_up() {
+        let ledger = LocalLedger::new();
+        let data = vec![7u8; 2 * CHUNK_SIZE];
+
+        ledger.fail_next(3, LedgerStatus::Busy);
+        let err = Upload::new(&data, "").with_retry(quick_retry()).run(&ledger).await.unwrap_err();
+        assert!(err.is_retryable());
+
+        // Statuses that no retry can fix fail on the first attempt
+        ledger.fail_next(1, LedgerStatus::InvalidSignature);
+        let err = Upload::new(&data, "").with_retry(quick_retry()).run(&ledger).await.unwrap_err();
+        assert!(!err.is_retryable());
+        assert!(Upload::new(&data, "").with_retry(RetryPolicy::none()).run(&ledger).await.is_ok());
+    }
+}
@@ -0,0 +1,59 @@
+//! FHIR bundle parsing, rendering, storage and encrypted EHR handling for
+//! the Hybrid Decentralized Identity System.
+//!
+//! Optional subsystems are gated behind Cargo features: `pdf`, `storage`
+//! and `ledger` make up `basic`, and `cli` adds the command-line tool along
+//! with `encryption`; both are on by default. Library users who want to
+//! leave out clap can depend on `basic` alone. `full` adds `mongodb`, and
+//! `hedera` is enabled on its own since building it needs protoc.
+
+pub mod error;
+pub mod models;
+pub mod fhir_handler;
+pub mod utils;
+pub mod verification;
+
+#[cfg(feature = "pdf")]
+pub mod pdf_generator;
+
+#[cfg(feature = "storage")]
+pub mod storage;
+
+#[cfg(feature = "encryption")]
+pub mod encryption;
+
+#[cfg(feature = "ledger")]
+pub mod ledger;
+
+#[cfg(feature = "hedera")]
+pub mod hedera_integration;
+
+pub use error::{Error, ParseError, Result};
+pub use fhir_handler::{FHIRHandler, ParseMode, ParseReport};
+pub use verification::{verify, VerificationPayload};
+
+#[cfg(feature = "pdf")]
+pub use pdf_generator::{PDFGenerator, Templates};
+
+#[cfg(feature = "storage")]
+pub use storage::{
+    Archive, BundleStats, BundleStore, HistoryEntry, MemoryStore, PatientCompartment, Retention, RetentionPolicy, SearchPage,
+    SearchQuery, SqliteStore, Storage,
+};
+
+#[cfg(feature = "mongodb")]
+pub use storage::MongoStore;
+
+#[cfg(all(feature = "storage", feature = "encryption"))]
+pub use storage::MasterKey;
+
+#[cfg(feature = "encryption")]
+pub use encryption::{
+    EHREncryption, EHRMetadata, Envelope, PatientEHR, RecipientKey, RecipientPublicKey, RecipientSecretKey,
+};
+
+#[cfg(feature = "ledger")]
+pub use ledger::{EncryptedEHR, LedgerFileStore, LocalLedger, RetryPolicy, Upload, UploadProgress};
+
+#[cfg(feature = "hedera")]
+pub use hedera_integration::HederaFileService;
@@ -1,108 +1,771 @@
-mod models;
-mod fhir_handler;
-mod pdf_generator;
-
-use models::*;
-use fhir_handler::FHIRHandler;
-use pdf_generator::PDFGenerator;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rust_ssi::models::{Bundle, ClaimType};
+use rust_ssi::storage::OnCollision;
+use rust_ssi::{
+    Archive, BundleStore, EHREncryption, Envelope, Error, FHIRHandler, MasterKey, PDFGenerator, ParseError, ParseMode,
+    Retention, RetentionPolicy, SearchQuery, SqliteStore, Storage, Templates,
+};
 use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("🚀 FHIR to PDF Generator - MVP Version");
-    println!("=====================================\n");
-    
-    // Read FHIR JSON from file
-    let fhir_json = fs::read_to_string("FHIR/FHIRBundle.json")?;
-    println!("📖 Loaded FHIR JSON from file");
-    
-    // Parse FHIR JSON into structured data
-    let mut handler = FHIRHandler::new();
-    let bundle = handler.parse_fhir_json(&fhir_json)?;
-    println!("✅ Successfully parsed FHIR JSON into structured data");
-    
-    // Validate the bundle
-    match handler.validate_bundle(&bundle) {
-        Ok(()) => println!("✅ FHIR Bundle validation passed"),
-        Err(errors) => {
-            println!("❌ FHIR Bundle validation failed:");
-            for error in errors {
-                println!("   - {}", error);
-            }
-        }
-    }
-    
-    // Generate PDF (text file for MVP)
-    let output_path = "patient_visit_summary.txt";
-    let pdf_generator = PDFGenerator::new(output_path.to_string());
-    pdf_generator.generate_pdf(&bundle)?;
-    
-    println!("\n🎉 Successfully generated patient visit summary!");
-    println!("📄 Output file: {}", output_path);
-    
-    // Display some extracted information
-    println!("\n📊 Extracted Information Summary:");
-    println!("--------------------------------");
-    
-    if let Some(patient) = extract_patient(&bundle) {
-        println!("👤 Patient: {} {}", 
-            patient.name.first().map(|n| n.given.join(" ")).unwrap_or_default(),
-            patient.name.first().map(|n| &n.family).unwrap_or(&"".to_string())
-        );
-    }
-    
-    if let Some(practitioner) = extract_practitioner(&bundle) {
-        println!("👨‍⚕️ Practitioner: {} {}", 
-            practitioner.name.first().map(|n| n.prefix.as_ref().unwrap_or(&vec![]).join(" ")).unwrap_or_default(),
-            practitioner.name.first().map(|n| &n.family).unwrap_or(&"".to_string())
-        );
-    }
-    
-    if let Some(encounter) = extract_encounter(&bundle) {
-        println!("🏥 Visit Type: {}", encounter.class.display);
-    }
-    
-    if let Some(medication) = extract_medication_request(&bundle) {
-        println!("💊 Medication: {}", 
-            medication.medication_codeable_concept.text.as_ref().unwrap_or(&"".to_string())
-        );
-    }
-    
-    Ok(())
+/// Exit code when a command fails for any reason other than validation
+const EXIT_FAILURE: u8 = 1;
+/// Exit code when a bundle is malformed or fails validation or verification
+const EXIT_INVALID: u8 = 3;
+
+#[derive(Parser)]
+#[command(name = "rust_ssi", version, about = "FHIR bundle toolkit for the Hybrid Decentralized Identity System")]
+#[command(after_help = "Exit codes: 0 success, 1 error, 2 usage error, 3 invalid bundle (parse, validation or verification failure)")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct Inputs {
+    /// Bundle files or directories of `*.json` bundles; `-` or none reads stdin
+    inputs: Vec<PathBuf>,
+    /// Reject bundles with missing required elements
+    #[arg(long)]
+    strict: bool,
+}
+
+#[derive(Args)]
+struct KeyArgs {
+    /// Hex-encoded 256-bit key file; created with a fresh key when encrypting
+    #[arg(long)]
+    key_file: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Parse bundles and print a parse report or canonical FHIR JSON
+    Parse {
+        #[command(flatten)]
+        inputs: Inputs,
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+        /// Output file, or directory when processing several bundles
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Parse and validate bundles
+    Validate {
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Render a document for each bundle from a claim-type or custom template
+    Render {
+        #[command(flatten)]
+        inputs: Inputs,
+        #[arg(long, value_enum, default_value_t = Format::Pdf)]
+        format: Format,
+        /// Claim type whose default template to use, e.g. PRESCRIPTION or LAB_RESULTS
+        #[arg(long, default_value_t = ClaimType::Ehr)]
+        claim_type: ClaimType,
+        /// Template name, overriding the claim type's default template
+        #[arg(long)]
+        template: Option<String>,
+        /// Directory of `*.jinja` templates added to or replacing the defaults
+        #[arg(long)]
+        template_dir: Option<PathBuf>,
+        /// Issuer DID for the verification QR code
+        #[arg(long, requires = "ledger_ref")]
+        issuer_did: Option<String>,
+        /// Hedera file or topic id for the verification QR code
+        #[arg(long, requires = "issuer_did")]
+        ledger_ref: Option<String>,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Encrypt a file with AES-256-GCM into a self-describing envelope
+    Encrypt {
+        /// File to encrypt; `-` reads stdin
+        input: Option<PathBuf>,
+        #[command(flatten)]
+        key: KeyArgs,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Decrypt a file produced by `encrypt`
+    Decrypt {
+        /// File to decrypt; `-` reads stdin
+        input: Option<PathBuf>,
+        #[command(flatten)]
+        key: KeyArgs,
+        /// Hex nonce of a bare AES-256-GCM ciphertext, kept apart from it as
+        /// EHRs were before envelopes
+        #[arg(long)]
+        nonce: Option<String>,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load bundles into storage and print storage statistics
+    Store {
+        #[command(flatten)]
+        inputs: Inputs,
+        /// SQLite database file to store into; bundles are kept in memory when omitted
+        #[arg(long)]
+        db: Option<PathBuf>,
+        /// Hex-encoded master key file to encrypt the database with; created if missing
+        #[arg(long, requires = "db")]
+        master_key_file: Option<PathBuf>,
+        /// Claim type to tag the bundles with, for claim-type retention rules
+        #[arg(long)]
+        claim_type: Option<ClaimType>,
+    },
+    /// Search resources stored with `store --db` using FHIR search parameters
+    Search {
+        /// SQLite database file to search
+        #[arg(long)]
+        db: PathBuf,
+        /// Resource type to search, e.g. `Observation`
+        resource_type: String,
+        /// Search parameters, e.g. `patient=patient-123&date=ge2024-01-01&_sort=-date`
+        #[arg(default_value = "")]
+        query: String,
+        /// Master key file the database was encrypted with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+    },
+    /// Write everything stored about a patient as one FHIR bundle
+    Everything {
+        /// SQLite database file to read
+        #[arg(long)]
+        db: PathBuf,
+        /// Id of the Patient resource
+        patient_id: String,
+        /// Write a `document` bundle with a Composition instead of a `searchset`
+        #[arg(long)]
+        document: bool,
+        /// Master key file the database was encrypted with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the statistics of a database stored with `store --db`
+    Stats {
+        /// SQLite database file to read
+        #[arg(long)]
+        db: PathBuf,
+        /// Master key file the database was encrypted with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Re-wrap the records of an encrypted database with a new master key
+    RotateKey {
+        /// SQLite database file to rotate
+        #[arg(long)]
+        db: PathBuf,
+        /// Master key file the database is encrypted with
+        #[arg(long)]
+        master_key_file: PathBuf,
+        /// Master key file to rotate to; created with a fresh key if missing
+        #[arg(long)]
+        new_master_key_file: PathBuf,
+    },
+    /// Back up a database to an archive directory of NDJSON files
+    Backup {
+        /// SQLite database file to back up
+        #[arg(long)]
+        db: PathBuf,
+        /// Master key file the database was encrypted with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+        /// Hex-encoded 256-bit key file to encrypt the archive with; created if missing
+        #[arg(long)]
+        archive_key_file: Option<PathBuf>,
+        /// Archive directory, created if missing
+        archive: PathBuf,
+    },
+    /// Restore an archive written by `backup` into a database
+    Restore {
+        /// SQLite database file to restore into; created if missing
+        #[arg(long)]
+        db: PathBuf,
+        /// Master key file to encrypt the database with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+        /// Key file the archive was encrypted with
+        #[arg(long)]
+        archive_key_file: Option<PathBuf>,
+        /// What to do with archived bundles whose id is already stored
+        #[arg(long, value_enum, default_value_t = Collision::Fail)]
+        on_collision: Collision,
+        /// Archive directory
+        archive: PathBuf,
+    },
+    /// Place a patient's records under legal hold, or release them
+    Hold {
+        /// SQLite database file holding the records
+        #[arg(long)]
+        db: PathBuf,
+        /// Master key file the database was encrypted with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+        /// Id of the Patient resource
+        patient_id: String,
+        /// Release the hold instead of placing it
+        #[arg(long)]
+        release: bool,
+    },
+    /// Purge the bundles of a database whose retention period has passed
+    Retention {
+        /// SQLite database file to purge
+        #[arg(long)]
+        db: PathBuf,
+        /// Master key file the database was encrypted with
+        #[arg(long)]
+        master_key_file: Option<PathBuf>,
+        /// JSON retention policy, e.g. `{"default_days": 3650, "rules": [{"resource_type": "Observation", "days": 1825}]}`
+        #[arg(long)]
+        policy: PathBuf,
+        /// Directory to archive purged bundles under before they are purged
+        #[arg(long)]
+        archive_dir: Option<PathBuf>,
+        /// Hex-encoded 256-bit key file to encrypt the archives with; created if missing
+        #[arg(long, requires = "archive_dir")]
+        archive_key_file: Option<PathBuf>,
+    },
+    /// Upload encrypted bundles to Hedera File Service
+    Anchor {
+        #[command(flatten)]
+        inputs: Inputs,
+        #[command(flatten)]
+        key: KeyArgs,
+        /// Hedera network name
+        #[arg(long, default_value = "testnet")]
+        network: String,
+    },
+    /// Check bundles against an expected content hash
+    Verify {
+        #[command(flatten)]
+        inputs: Inputs,
+        /// Expected `0x`-prefixed SHA-256 of the canonical bundle JSON
+        #[arg(long, required_unless_present = "payload", conflicts_with = "payload")]
+        hash: Option<String>,
+        /// Decoded verification QR code payload from a rendered document
+        #[arg(long)]
+        payload: Option<String>,
+    },
+    /// Write bundles back out as canonical FHIR JSON
+    Export {
+        #[command(flatten)]
+        inputs: Inputs,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Json,
+    Text,
+    Pdf,
+    Html,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Text => "txt",
+            Format::Pdf => "pdf",
+            Format::Html => "html",
+        }
+    }
 }
 
-// Helper functions to extract resources from bundle
-fn extract_patient(bundle: &Bundle) -> Option<&Patient> {
-    for entry in &bundle.entry {
-        if let Resource::Patient(patient) = &entry.resource {
-            return Some(patient);
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Collision {
+    Fail,
+    Skip,
+    Overwrite,
+    Rename,
+}
+
+impl From<Collision> for OnCollision {
+    fn from(collision: Collision) -> Self {
+        match collision {
+            Collision::Fail => OnCollision::Fail,
+            Collision::Skip => OnCollision::Skip,
+            Collision::Overwrite => OnCollision::Overwrite,
+            Collision::Rename => OnCollision::Rename,
         }
     }
-    None
 }
 
-fn extract_practitioner(bundle: &Bundle) -> Option<&Practitioner> {
-    for entry in &bundle.entry {
-        if let Resource::Practitioner(practitioner) = &entry.resource {
-            return Some(practitioner);
+/// Why processing a single input failed
+enum Failure {
+    Invalid(String),
+    Error(Error),
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Parse(err) => Failure::Invalid(err.to_string()),
+            err => Failure::Error(err),
         }
     }
-    None
 }
 
-fn extract_encounter(bundle: &Bundle) -> Option<&Encounter> {
-    for entry in &bundle.entry {
-        if let Resource::Encounter(encounter) = &entry.resource {
-            return Some(encounter);
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Error(err.into())
+    }
+}
+
+type Outcome = Result<(), Failure>;
+
+/// A bundle source: a file path or stdin
+struct Source {
+    name: String,
+    path: Option<PathBuf>,
+}
+
+impl Source {
+    fn read(&self) -> io::Result<Vec<u8>> {
+        match &self.path {
+            Some(path) => fs::read(path),
+            None => {
+                let mut buf = Vec::new();
+                io::stdin().read_to_end(&mut buf)?;
+                Ok(buf)
+            }
         }
     }
-    None
+
+    fn read_to_string(&self) -> io::Result<String> {
+        String::from_utf8(self.read()?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn stem(&self) -> String {
+        self.path.as_deref()
+            .and_then(Path::file_stem)
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "stdin".to_string())
+    }
 }
 
-fn extract_medication_request(bundle: &Bundle) -> Option<&MedicationRequest> {
-    for entry in &bundle.entry {
-        if let Resource::MedicationRequest(medication) = &entry.resource {
-            return Some(medication);
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start tokio runtime");
+
+    match runtime.block_on(run(cli.command)) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::from(EXIT_FAILURE)
         }
     }
-    None
+}
+
+async fn run(command: Command) -> Result<ExitCode, Error> {
+    match command {
+        Command::Parse { inputs, format, output } => {
+            if !matches!(format, Format::Json | Format::Text) {
+                return Err(Error::render("parse supports --format json or text"));
+            }
+            let sources = collect_sources(&inputs.inputs)?;
+            let multiple = sources.len() > 1;
+            for_each_bundle(&sources, inputs.strict, |source, bundle, handler| {
+                let content = match format {
+                    Format::Json => pretty_json(bundle),
+                    _ => format!("{}: bundle {} ({})\n{}", source.name, bundle.id, bundle.bundle_type, handler.report()),
+                };
+                write_output(output.as_deref(), multiple, source, format, content.as_bytes())
+            })
+        }
+        Command::Validate { inputs } => {
+            let sources = collect_sources(&inputs.inputs)?;
+            for_each_bundle(&sources, inputs.strict, |source, bundle, handler| {
+                handler.validate_bundle(bundle).map_err(|errors| Failure::Invalid(errors.join("; ")))?;
+                println!("{}: valid", source.name);
+                Ok(())
+            })
+        }
+        Command::Render { inputs, format, claim_type, template, template_dir, issuer_did, ledger_ref, output } => {
+            let sources = collect_sources(&inputs.inputs)?;
+            let multiple = sources.len() > 1;
+            let mut generator = PDFGenerator::new(String::new()).with_claim_type(claim_type);
+            if let Some(dir) = template_dir {
+                generator = generator.with_templates(Templates::from_dir(dir)?);
+            }
+            if let Some(name) = template {
+                generator = generator.with_template(name);
+            }
+            if let (Some(issuer_did), Some(ledger_ref)) = (issuer_did, ledger_ref) {
+                generator = generator.with_verification(issuer_did, ledger_ref);
+            }
+            for_each_bundle(&sources, inputs.strict, |source, bundle, _| {
+                let document = match format {
+                    Format::Pdf => generator.render_pdf(bundle)?,
+                    Format::Html => generator.render_html(bundle)?.into_bytes(),
+                    Format::Text => generator.render_text(bundle)?.into_bytes(),
+                    Format::Json => pretty_json(bundle).into_bytes(),
+                };
+                write_output(output.as_deref(), multiple, source, format, &document)
+            })
+        }
+        Command::Encrypt { input, key, output } => {
+            let source = single_source(input)?;
+            let encryption = load_or_create_key(&key.key_file)?;
+            let envelope = encryption.seal(&source.read()?, b"")?;
+            write_bytes(output.as_deref(), &envelope.to_bytes()?)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Decrypt { input, key, nonce, output } => {
+            let source = single_source(input)?;
+            let encryption = load_key(&key.key_file)?;
+            let envelope = match nonce {
+                Some(nonce) => Envelope::from_parts(source.read()?, rust_ssi::utils::hex_to_bytes(nonce.trim())?),
+                None => Envelope::parse(&source.read()?)?,
+            };
+            write_bytes(output.as_deref(), &encryption.open(&envelope)?)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Store { inputs, db, master_key_file, claim_type } => {
+            let sources = collect_sources(&inputs.inputs)?;
+            let mut bundles = Vec::new();
+            let code = for_each_bundle(&sources, inputs.strict, |_, bundle, _| {
+                let mut bundle = bundle.clone();
+                if let Some(claim_type) = claim_type {
+                    bundle.set_claim_type(claim_type);
+                }
+                bundles.push(bundle);
+                Ok(())
+            })?;
+            let storage = match db {
+                Some(path) => {
+                    let master_key = master_key_file.as_deref().map(load_or_create_master_key).transpose()?;
+                    Storage::from_store(open_store(&path, master_key).await?)
+                }
+                None => Storage::new(),
+            };
+            for bundle in bundles {
+                storage.store_bundle(bundle).await?;
+            }
+            print!("{}", storage.get_statistics().await?);
+            Ok(code)
+        }
+        Command::Search { db, resource_type, query, master_key_file } => {
+            let query = SearchQuery::parse(&resource_type, &query)?;
+            let master_key = master_key_file.as_deref().map(load_master_key).transpose()?;
+            let page = open_store(&db, master_key).await?.search(&query).await?;
+            for found in &page.matches {
+                println!("{}/{}\tbundle {}", found.resource.resource_type(), found.resource.id(), found.bundle_id);
+            }
+            println!("{} of {} matches", page.matches.len(), page.total);
+            if let Some(next) = page.next_offset {
+                println!("next page: _offset={}", next);
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Everything { db, patient_id, document, master_key_file, output } => {
+            let master_key = master_key_file.as_deref().map(load_master_key).transpose()?;
+            let everything = open_store(&db, master_key).await?.patient_everything(&patient_id).await?;
+            let bundle = if document { everything.to_document() } else { everything.to_searchset() };
+            match output {
+                Some(path) => fs::write(path, pretty_json(&bundle))?,
+                None => io::stdout().write_all(pretty_json(&bundle).as_bytes())?,
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Stats { db, master_key_file, format } => {
+            let master_key = master_key_file.as_deref().map(load_master_key).transpose()?;
+            let stats = open_store(&db, master_key).await?.get_statistics().await?;
+            match format {
+                Format::Text => print!("{}", stats),
+                Format::Json => {
+                    let mut json = serde_json::to_string_pretty(&stats).expect("statistics serialization cannot fail");
+                    json.push('\n');
+                    write_bytes(None, json.as_bytes())?;
+                }
+                _ => return Err(Error::render("stats supports --format json or text")),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::RotateKey { db, master_key_file, new_master_key_file } => {
+            let store = open_store(&db, Some(load_master_key(&master_key_file)?)).await?;
+            let new_key = load_or_create_master_key(&new_master_key_file)?;
+            let id = new_key.id().to_string();
+            let rewrapped = store.rotate_master_key(new_key).await?;
+            println!("Re-wrapped {} records with master key {}", rewrapped, id);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Backup { db, master_key_file, archive_key_file, archive } => {
+            let master_key = master_key_file.as_deref().map(load_master_key).transpose()?;
+            let store = open_store(&db, master_key).await?;
+            let mut archive = Archive::new(archive);
+            if let Some(path) = archive_key_file {
+                archive = archive.with_encryption(load_or_create_key(&path)?);
+            }
+            let manifest = archive.export(&store).await?;
+            let resources: usize =
+                manifest.files.iter().filter(|file| file.resource_type != "Bundle").map(|file| file.count).sum();
+            println!("Backed up {} bundles with {} distinct resources", manifest.bundle_count, resources);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Restore { db, master_key_file, archive_key_file, on_collision, archive } => {
+            let master_key = master_key_file.as_deref().map(load_or_create_master_key).transpose()?;
+            let store = open_store(&db, master_key).await?;
+            let mut archive = Archive::new(archive);
+            if let Some(path) = archive_key_file {
+                archive = archive.with_encryption(load_key(&path)?);
+            }
+            print!("{}", archive.import(&store, on_collision.into()).await?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Hold { db, master_key_file, patient_id, release } => {
+            let master_key = master_key_file.as_deref().map(load_master_key).transpose()?;
+            open_store(&db, master_key).await?.set_legal_hold(&patient_id, !release).await?;
+            match release {
+                true => println!("Released the legal hold on patient {}", patient_id),
+                false => println!("Placed patient {} under legal hold", patient_id),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Retention { db, master_key_file, policy, archive_dir, archive_key_file } => {
+            let policy: RetentionPolicy = serde_json::from_str(&fs::read_to_string(&policy)?)
+                .map_err(|e| ParseError::new(policy.display().to_string(), e.to_string()))?;
+            let master_key = master_key_file.as_deref().map(load_master_key).transpose()?;
+            let store = open_store(&db, master_key).await?;
+            let mut retention = Retention::new(policy);
+            if let Some(dir) = archive_dir {
+                retention = retention.archive_to(dir);
+            }
+            if let Some(path) = archive_key_file {
+                retention = retention.with_archive_encryption(load_or_create_key(&path)?);
+            }
+            print!("{}", retention.run(&store).await?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Anchor { inputs, key, network } => anchor(inputs, key, network).await,
+        Command::Verify { inputs, hash, payload } => {
+            let sources = collect_sources(&inputs.inputs)?;
+            for_each_bundle(&sources, inputs.strict, |source, bundle, _| {
+                if let Some(ref payload) = payload {
+                    let verified = rust_ssi::verify(payload, bundle)
+                        .map_err(|err| Failure::Invalid(err.to_string()))?;
+                    println!("{}: verified, issued by {} ({})", source.name, verified.issuer_did, verified.ledger_ref);
+                    return Ok(());
+                }
+                let actual = bundle.content_hash();
+                if !hash.as_deref().is_some_and(|hash| actual.eq_ignore_ascii_case(hash)) {
+                    return Err(Failure::Invalid(format!("hash mismatch: bundle hashes to {}", actual)));
+                }
+                println!("{}: verified", source.name);
+                Ok(())
+            })
+        }
+        Command::Export { inputs, output } => {
+            let sources = collect_sources(&inputs.inputs)?;
+            let multiple = sources.len() > 1;
+            for_each_bundle(&sources, inputs.strict, |source, bundle, _| {
+                write_output(output.as_deref(), multiple, source, Format::Json, pretty_json(bundle).as_bytes())
+            })
+        }
+    }
+}
+
+#[cfg(feature = "hedera")]
+async fn anchor(inputs: Inputs, key: KeyArgs, network: String) -> Result<ExitCode, Error> {
+    use rust_ssi::{HederaFileService, Upload};
+
+    let operator = std::env::var("HEDERA_OPERATOR_ID")
+        .map_err(|_| Error::ledger("HEDERA_OPERATOR_ID is not set", false))?;
+    let operator_key = std::env::var("HEDERA_OPERATOR_KEY")
+        .map_err(|_| Error::ledger("HEDERA_OPERATOR_KEY is not set", false))?;
+    let service = HederaFileService::new(&network, &operator, &operator_key)?;
+    let encryption = load_or_create_key(&key.key_file)?;
+
+    let sources = collect_sources(&inputs.inputs)?;
+    let mut uploads = Vec::new();
+    let code = for_each_bundle(&sources, inputs.strict, |source, bundle, _| {
+        let json = serde_json::to_vec(bundle).map_err(|e| Error::render(e.to_string()))?;
+        let sealed = encryption.seal(&json, b"")?.to_bytes()?;
+        uploads.push((source.name.clone(), bundle.content_hash(), sealed));
+        Ok(())
+    })?;
+
+    for (name, hash, sealed) in uploads {
+        let stored = Upload::new(&sealed, "application/fhir+json")
+            .on_progress(|step| eprint!("\r{}: {}/{} bytes", name, step.uploaded, step.total))
+            .run(&service)
+            .await?;
+        eprintln!();
+        println!("{}: file {} ({})", name, stored.file_id, hash);
+    }
+    Ok(code)
+}
+
+#[cfg(not(feature = "hedera"))]
+async fn anchor(_inputs: Inputs, _key: KeyArgs, _network: String) -> Result<ExitCode, Error> {
+    Err(Error::ledger("rust_ssi was built without the `hedera` feature", false))
+}
+
+/// Expand the command-line inputs into bundle sources, reading stdin when empty
+fn collect_sources(inputs: &[PathBuf]) -> Result<Vec<Source>, Error> {
+    if inputs.is_empty() {
+        return Ok(vec![Source { name: "<stdin>".to_string(), path: None }]);
+    }
+
+    let mut sources = Vec::new();
+    for input in inputs {
+        if input.as_os_str() == "-" {
+            sources.push(Source { name: "<stdin>".to_string(), path: None });
+        } else if input.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(input)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            files.sort();
+            sources.extend(files.into_iter().map(|path| Source {
+                name: path.display().to_string(),
+                path: Some(path),
+            }));
+        } else {
+            sources.push(Source { name: input.display().to_string(), path: Some(input.clone()) });
+        }
+    }
+    Ok(sources)
+}
+
+fn single_source(input: Option<PathBuf>) -> Result<Source, Error> {
+    let mut sources = collect_sources(input.as_slice())?;
+    match sources.len() {
+        1 => Ok(sources.remove(0)),
+        _ => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "expected a single input file"))),
+    }
+}
+
+/// Parse each source and run `action` on it, then print a summary when more
+/// than one bundle was processed. Returns the exit code for the whole run.
+fn for_each_bundle(
+    sources: &[Source],
+    strict: bool,
+    mut action: impl FnMut(&Source, &Bundle, &FHIRHandler) -> Outcome,
+) -> Result<ExitCode, Error> {
+    let mode = if strict { ParseMode::Strict } else { ParseMode::Lenient };
+    let mut handler = FHIRHandler::with_mode(mode);
+    let (mut succeeded, mut invalid, mut failed) = (0, 0, 0);
+
+    for source in sources {
+        let outcome = source.read_to_string()
+            .map_err(Failure::from)
+            .and_then(|json| handler.parse_fhir_json(&json).map_err(Failure::from))
+            .and_then(|bundle| action(source, &bundle, &handler));
+
+        match outcome {
+            Ok(()) => succeeded += 1,
+            Err(Failure::Invalid(reason)) => {
+                invalid += 1;
+                eprintln!("{}: invalid: {}", source.name, reason);
+            }
+            Err(Failure::Error(err)) => {
+                failed += 1;
+                eprintln!("{}: {}", source.name, err);
+            }
+        }
+    }
+
+    if sources.len() > 1 {
+        eprintln!("{} bundle(s) processed: {} succeeded, {} invalid, {} failed",
+            sources.len(), succeeded, invalid, failed);
+    }
+
+    Ok(if failed > 0 {
+        ExitCode::from(EXIT_FAILURE)
+    } else if invalid > 0 {
+        ExitCode::from(EXIT_INVALID)
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Write one result: to stdout, to the given file, or into the given
+/// directory as `<input stem>.<ext>` when processing several bundles
+fn write_output(output: Option<&Path>, multiple: bool, source: &Source, format: Format, content: &[u8]) -> Outcome {
+    let path = match output {
+        Some(dir) if multiple || dir.is_dir() => {
+            fs::create_dir_all(dir)?;
+            Some(dir.join(format!("{}.{}", source.stem(), format.extension())))
+        }
+        other => other.map(Path::to_path_buf),
+    };
+    write_bytes(path.as_deref(), content)?;
+    Ok(())
+}
+
+fn write_bytes(output: Option<&Path>, content: &[u8]) -> Result<(), Error> {
+    match output {
+        Some(path) => fs::write(path, content)?,
+        None => io::stdout().write_all(content)?,
+    }
+    Ok(())
+}
+
+fn pretty_json(bundle: &Bundle) -> String {
+    let mut json = serde_json::to_string_pretty(bundle).expect("bundle serialization cannot fail");
+    json.push('\n');
+    json
+}
+
+/// Open a SQLite store, encrypted with `master_key` if given
+async fn open_store(path: &Path, master_key: Option<MasterKey>) -> Result<SqliteStore, Error> {
+    let store = SqliteStore::open(path)?;
+    match master_key {
+        Some(key) => store.with_encryption(key).await,
+        None => Ok(store),
+    }
+}
+
+fn load_key(path: &Path) -> Result<EHREncryption, Error> {
+    EHREncryption::from_key(&read_key(path)?)
+}
+
+fn load_or_create_key(path: &Path) -> Result<EHREncryption, Error> {
+    EHREncryption::from_key(&read_or_create_key(path)?)
+}
+
+fn load_master_key(path: &Path) -> Result<MasterKey, Error> {
+    MasterKey::from_bytes(&read_key(path)?)
+}
+
+fn load_or_create_master_key(path: &Path) -> Result<MasterKey, Error> {
+    MasterKey::from_bytes(&read_or_create_key(path)?)
+}
+
+/// The key bytes of a hex key file
+fn read_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let hex_key = fs::read_to_string(path)?;
+    rust_ssi::utils::hex_to_bytes(hex_key.trim())
+}
+
+/// The key bytes of a hex key file, generating a new key if there is none
+fn read_or_create_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut file = match create_key_file(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return read_key(path),
+        Err(err) => return Err(err.into()),
+    };
+    let key = rand::random::<[u8; 32]>().to_vec();
+    file.write_all(rust_ssi::utils::bytes_to_hex(&key).as_bytes())?;
+    eprintln!("Generated new key: {}", path.display());
+    Ok(key)
+}
+
+/// Create a key file readable only by its owner, failing if it exists
+fn create_key_file(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
 }
@@ -1,23 +1,66 @@
-use serde::{Deserialize, Serialize};
-use crate::models::{Patient, Practitioner, Encounter, Observation, Condition, MedicationRequest, Reference};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use crate::models::{
+    ClaimType, Coding, Condition, Encounter, MedicationRequest, Meta, Observation, Patient, Practitioner, Reference,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Bundle {
     pub resource_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
-    #[serde(rename = "type")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+    #[serde(rename = "type", default, skip_serializing_if = "String::is_empty")]
     pub bundle_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub entry: Vec<BundleEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub signature: Option<Signature>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct BundleEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_url: Option<String>,
     pub resource: Resource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Value>,
+    /// Other entry elements, such as `link`, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl BundleEntry {
+    pub fn new(resource: Resource) -> Self {
+        Self {
+            full_url: None,
+            resource,
+            search: None,
+            request: None,
+            response: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+/// A FHIR resource inside a bundle entry.
+///
+/// Serializes as the inner resource (which carries its own `resourceType`)
+/// and deserializes by dispatching on `resourceType`. Resource types without
+/// a typed model are kept verbatim as `Other`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum Resource {
     Patient(Patient),
@@ -26,23 +69,110 @@ pub enum Resource {
     Observation(Observation),
     Condition(Condition),
     MedicationRequest(MedicationRequest),
+    #[serde(serialize_with = "serialize_raw")]
+    Other { resource_type: String, raw: Value },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Resource {
+    /// Resource types with a typed model
+    pub const TYPES: &'static [&'static str] = &[
+        "Patient",
+        "Practitioner",
+        "Encounter",
+        "Observation",
+        "Condition",
+        "MedicationRequest",
+    ];
+
+    /// The FHIR `resourceType` of this resource
+    pub fn resource_type(&self) -> &str {
+        match self {
+            Resource::Patient(r) => &r.resource_type,
+            Resource::Practitioner(r) => &r.resource_type,
+            Resource::Encounter(r) => &r.resource_type,
+            Resource::Observation(r) => &r.resource_type,
+            Resource::Condition(r) => &r.resource_type,
+            Resource::MedicationRequest(r) => &r.resource_type,
+            Resource::Other { resource_type, .. } => resource_type,
+        }
+    }
+
+    /// The logical id of this resource
+    pub fn id(&self) -> &str {
+        match self {
+            Resource::Patient(r) => &r.id,
+            Resource::Practitioner(r) => &r.id,
+            Resource::Encounter(r) => &r.id,
+            Resource::Observation(r) => &r.id,
+            Resource::Condition(r) => &r.id,
+            Resource::MedicationRequest(r) => &r.id,
+            Resource::Other { raw, .. } => raw.get("id").and_then(Value::as_str).unwrap_or(""),
+        }
+    }
+
+    /// Whether this resource is kept as raw JSON rather than a typed model
+    pub fn is_other(&self) -> bool {
+        matches!(self, Resource::Other { .. })
+    }
+}
+
+fn serialize_raw<S: Serializer>(_resource_type: &str, raw: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+    raw.serialize(serializer)
+}
+
+impl<'de> Deserialize<'de> for Resource {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let resource_type = value
+            .get("resourceType")
+            .and_then(Value::as_str)
+            .ok_or_else(|| de::Error::missing_field("resourceType"))?
+            .to_string();
+
+        let resource = match resource_type.as_str() {
+            "Patient" => serde_json::from_value(value).map(Resource::Patient),
+            "Practitioner" => serde_json::from_value(value).map(Resource::Practitioner),
+            "Encounter" => serde_json::from_value(value).map(Resource::Encounter),
+            "Observation" => serde_json::from_value(value).map(Resource::Observation),
+            "Condition" => serde_json::from_value(value).map(Resource::Condition),
+            "MedicationRequest" => serde_json::from_value(value).map(Resource::MedicationRequest),
+            _ => Ok(Resource::Other { resource_type, raw: value }),
+        };
+
+        resource.map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Signature {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default, skip_serializing_if = "Vec::is_empty")]
     pub signature_type: Vec<SignatureType>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub when: String,
+    #[serde(default, skip_serializing_if = "Reference::is_empty")]
     pub who: Reference,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub sig_format: Option<String>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SignatureType {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub system: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub code: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub display: String,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 
@@ -52,18 +182,157 @@ impl Bundle {
         Self {
             resource_type: "Bundle".to_string(),
             id,
+            meta: None,
             bundle_type,
             timestamp,
             entry: Vec::new(),
             signature: None,
+            extra: Map::new(),
         }
     }
 
     pub fn add_entry(&mut self, resource: Resource) {
-        self.entry.push(BundleEntry { resource });
+        self.entry.push(BundleEntry::new(resource));
     }
 
     pub fn add_signature(&mut self, signature: Signature) {
         self.signature = Some(signature);
     }
+
+    /// The claim type tagged in `meta`, if any
+    pub fn claim_type(&self) -> Option<ClaimType> {
+        self.meta
+            .iter()
+            .flat_map(|meta| &meta.tag)
+            .find(|tag| tag.system == ClaimType::TAG_SYSTEM)
+            .and_then(|tag| tag.code.parse().ok())
+    }
+
+    /// Tag the bundle with its claim type, replacing any earlier one
+    pub fn set_claim_type(&mut self, claim_type: ClaimType) {
+        let meta = self.meta.get_or_insert_with(Meta::default);
+        meta.tag.retain(|tag| tag.system != ClaimType::TAG_SYSTEM);
+        meta.tag.push(Coding {
+            system: ClaimType::TAG_SYSTEM.to_string(),
+            code: claim_type.as_str().to_string(),
+            display: String::new(),
+            extra: Map::new(),
+        });
+    }
+
+    /// SHA-256 of the bundle's canonical FHIR JSON, as a `0x`-prefixed hex string.
+    /// Everything is covered, including `meta.tag`, except `meta.versionId` and
+    /// `meta.lastUpdated`, which storage rewrites on every version.
+    pub fn content_hash(&self) -> String {
+        let json = match &self.meta {
+            Some(meta) if !meta.version_id.is_empty() || !meta.last_updated.is_empty() => {
+                let meta = Some(Meta { version_id: String::new(), last_updated: String::new(), ..meta.clone() })
+                    .filter(|meta| *meta != Meta::default());
+                serde_json::to_vec(&Bundle { meta, ..self.clone() })
+            }
+            _ => serde_json::to_vec(self),
+        }
+        .expect("bundle serialization cannot fail");
+        crate::utils::sha256_hash(&json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir_handler::FHIRHandler;
+    use serde_json::json;
+
+    const SAMPLE_BUNDLE: &str = include_str!("../../FHIR/FHIRBundle.json");
+
+    #[test]
+    fn test_round_trip_sample_bundle() {
+        let original: Value = serde_json::from_str(SAMPLE_BUNDLE).unwrap();
+
+        let bundle: Bundle = serde_json::from_value(original.clone()).unwrap();
+        assert_eq!(bundle.entry.len(), 6);
+
+        let written = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(written, original);
+
+        let reread: Bundle = serde_json::from_value(written).unwrap();
+        assert_eq!(reread, bundle);
+    }
+
+    #[test]
+    fn test_round_trip_handler_output() {
+        let mut handler = FHIRHandler::new();
+        let bundle = handler.parse_fhir_json(SAMPLE_BUNDLE).unwrap();
+
+        let json = serde_json::to_string(&bundle).unwrap();
+        assert!(json.contains("\"resourceType\":\"Bundle\""));
+        assert!(json.contains("\"birthDate\":\"1980-01-15\""));
+        assert!(json.contains("\"effectiveDateTime\":\"2024-07-30T09:15:00Z\""));
+        assert!(!json.contains("birth_date"));
+
+        let reread: Bundle = serde_json::from_str(&json).unwrap();
+        assert_eq!(reread, bundle);
+    }
+
+    #[test]
+    fn test_round_trip_entry_members_and_unknown_elements() {
+        let mut original: Value = serde_json::from_str(SAMPLE_BUNDLE).unwrap();
+        original["total"] = json!(1);
+        original["link"] = json!([{ "relation": "self", "url": "https://example.org/fhir/Bundle/b1" }]);
+        let entry = &mut original["entry"][0];
+        entry["fullUrl"] = json!("urn:uuid:2f6f7a4e-1c1b-4e44-9f5a-0d3d9b1c7f10");
+        entry["search"] = json!({ "mode": "match", "score": 1 });
+        entry["request"] = json!({ "method": "PUT", "url": "Patient/patient-123" });
+        entry["response"] = json!({ "status": "201 Created", "etag": "W/\"1\"" });
+        entry["link"] = json!([{ "relation": "alternate", "url": "https://example.org/Patient/123" }]);
+        entry["resource"]["text"] = json!({ "status": "generated", "div": "<div>Jane Doe</div>" });
+        entry["resource"]["extension"] = json!([{ "url": "http://example.org/ext", "valueBoolean": true }]);
+
+        // Elements of nested datatypes
+        let patient = &mut original["entry"][0]["resource"];
+        patient["identifier"][0]["use"] = json!("official");
+        patient["identifier"][0]["type"] = json!({ "coding": [{ "system": "http://terminology.hl7.org/CodeSystem/v2-0203", "code": "MR", "userSelected": true }] });
+        patient["identifier"][0]["period"] = json!({ "start": "2001-05-06", "extension": [{ "url": "http://example.org/ext", "valueString": "x" }] });
+        patient["name"][0]["use"] = json!("official");
+        patient["name"][0]["suffix"] = json!(["Jr."]);
+        patient["telecom"][0]["rank"] = json!(1);
+        let observation = &mut original["entry"][3]["resource"];
+        observation["valueQuantity"]["value"] = serde_json::from_str("98.60").unwrap();
+        observation["valueQuantity"]["comparator"] = json!("<");
+        observation["subject"]["type"] = json!("Patient");
+        observation["component"][0]["valueString"] = json!("not measured");
+        observation["component"][0]["interpretation"] = json!([{ "text": "normal" }]);
+        let medication = &mut original["entry"][5]["resource"];
+        medication["dosageInstruction"][0]["sequence"] = json!(1);
+        medication["dosageInstruction"][0]["timing"] = json!({ "code": { "text": "TID" }, "repeat": { "frequency": 3, "period": 1, "periodUnit": "d", "when": ["C"] } });
+        medication["dosageInstruction"][0]["doseAndRate"] = json!([{ "doseQuantity": { "value": 1.50, "unit": "capsule" }, "rateRatio": { "numerator": { "value": 1 } } }]);
+        medication["dispenseRequest"] = json!({ "quantity": { "value": 30, "unit": "capsule" }, "expectedSupplyDuration": { "value": 10, "unit": "days" } });
+        original["entry"][2]["resource"]["participant"][0]["type"] = json!([{ "text": "primary performer" }]);
+        original["entry"][2]["resource"]["class"]["version"] = json!("3.0.0");
+        original["meta"] = json!({ "versionId": "1", "source": "#hospital", "profile": ["http://example.org/StructureDefinition/ehr"] });
+
+        let bundle: Bundle = serde_json::from_value(original.clone()).unwrap();
+        assert_eq!(bundle.entry[0].full_url.as_deref(), Some("urn:uuid:2f6f7a4e-1c1b-4e44-9f5a-0d3d9b1c7f10"));
+        assert_eq!(serde_json::to_value(&bundle).unwrap(), original);
+
+        let parsed = FHIRHandler::new().parse_fhir_json(&original.to_string()).unwrap();
+        assert_eq!(parsed, bundle);
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), original);
+        assert!(serde_json::to_string(&parsed).unwrap().contains(r#""valueQuantity":{"value":98.60,"comparator":"<""#));
+        assert_eq!(serde_json::from_str::<Bundle>(&serde_json::to_string(&parsed).unwrap()).unwrap(), bundle);
+    }
+
+    #[test]
+    fn test_unknown_resource_type_is_preserved() {
+        let original: Value = serde_json::from_str(
+            r#"{"resourceType": "Flag", "id": "f1", "status": "active", "code": {"text": "Fall risk"}}"#,
+        ).unwrap();
+
+        let resource: Resource = serde_json::from_value(original.clone()).unwrap();
+        assert!(resource.is_other());
+        assert_eq!(resource.resource_type(), "Flag");
+        assert_eq!(resource.id(), "f1");
+
+        assert_eq!(serde_json::to_value(&resource).unwrap(), original);
+    }
 }
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use crate::error::ParseError;
+
+/// Healthcare claim types registered for a patient DID
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClaimType {
+    #[default]
+    Ehr,
+    LabResults,
+    Prescription,
+    MedicalImaging,
+    Vaccination,
+    Allergy,
+    MedicalHistory,
+    Insurance,
+    Consent,
+    Other,
+}
+
+impl ClaimType {
+    /// `meta.tag` system of the tag naming a bundle's claim type
+    pub const TAG_SYSTEM: &'static str = "urn:rust-ssi:claim-type";
+
+    pub const ALL: [ClaimType; 10] = [
+        ClaimType::Ehr,
+        ClaimType::LabResults,
+        ClaimType::Prescription,
+        ClaimType::MedicalImaging,
+        ClaimType::Vaccination,
+        ClaimType::Allergy,
+        ClaimType::MedicalHistory,
+        ClaimType::Insurance,
+        ClaimType::Consent,
+        ClaimType::Other,
+    ];
+
+    /// The registry name, e.g. `LAB_RESULTS`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClaimType::Ehr => "EHR",
+            ClaimType::LabResults => "LAB_RESULTS",
+            ClaimType::Prescription => "PRESCRIPTION",
+            ClaimType::MedicalImaging => "MEDICAL_IMAGING",
+            ClaimType::Vaccination => "VACCINATION",
+            ClaimType::Allergy => "ALLERGY",
+            ClaimType::MedicalHistory => "MEDICAL_HISTORY",
+            ClaimType::Insurance => "INSURANCE",
+            ClaimType::Consent => "CONSENT",
+            ClaimType::Other => "OTHER",
+        }
+    }
+}
+
+impl fmt::Display for ClaimType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ClaimType {
+    type Err = ParseError;
+
+    /// Accepts registry names case-insensitively, with `-` or `_` separators
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_uppercase().replace('-', "_");
+        ClaimType::ALL
+            .into_iter()
+            .find(|claim_type| claim_type.as_str() == name)
+            .ok_or_else(|| ParseError::new("", format!("unknown claim type '{}'", s)))
+    }
+}
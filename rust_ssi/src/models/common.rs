@@ -1,61 +1,207 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Number, Value};
+use std::fmt;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Identifier {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub system: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub value: String,
-    // #[serde(rename = "use")]
-    // pub use_field: Option<String>,
-    // #[serde(rename = "type")]
-    // pub identifier_type: Option<String>,
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+    pub use_field: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub identifier_type: Option<CodeableConcept>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct HumanName {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub family: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub given: Vec<String>,
-    // #[serde(rename = "use")]
-    // pub use_field: Option<String>,
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+    pub use_field: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub prefix: Option<Vec<String>>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ContactPoint {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub system: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub value: String,
-    #[serde(rename = "use")]
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
     pub use_field: Option<String>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CodeableConcept {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub coding: Vec<Coding>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub text: Option<String>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl CodeableConcept {
+    pub fn is_empty(&self) -> bool {
+        self.coding.is_empty() && self.text.is_none() && self.extra.is_empty()
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Coding {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub system: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub code: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub display: String,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Coding {
+    pub fn is_empty(&self) -> bool {
+        self.system.is_empty() && self.code.is_empty() && self.display.is_empty() && self.extra.is_empty()
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Reference {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub display: Option<String>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Reference {
+    pub fn is_empty(&self) -> bool {
+        self.reference.is_empty() && self.display.is_none() && self.extra.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Quantity {
-    pub value: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparator: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub unit: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub code: Option<String>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Quantity {
+    pub fn is_empty(&self) -> bool {
+        self.value.is_none()
+            && self.comparator.is_none()
+            && self.unit.is_empty()
+            && self.system.is_none()
+            && self.code.is_none()
+            && self.extra.is_empty()
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Period {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub start: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub end: String,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Period {
+    pub fn is_empty(&self) -> bool {
+        self.start.is_empty() && self.end.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Resource metadata maintained by the server
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub version_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub last_updated: String,
+    /// Labels such as the bundle's claim type, kept across versions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tag: Vec<Coding>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A FHIR decimal, kept with the digits it was written with so that
+/// `98.60` is written back as `98.60` rather than `98.6`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decimal(Number);
+
+impl Decimal {
+    /// The decimal for `value`, written without a fractional part when it is
+    /// a whole number, or `None` if it is not finite
+    pub fn from_f64(value: f64) -> Option<Self> {
+        if value.fract() == 0.0 && value.abs() < 1e15 {
+            Some(Decimal(Number::from(value as i64)))
+        } else {
+            Number::from_f64(value).map(Decimal)
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        self.0.as_f64()
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Number::deserialize(deserializer).map(Decimal)
+    }
 }
@@ -1,17 +1,30 @@
 use serde::{Deserialize, Serialize};
-use super::common::{CodeableConcept, Coding, Reference};
+use serde_json::{Map, Value};
+use super::common::{CodeableConcept, Reference};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Condition {
     pub resource_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub clinical_status: Option<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub verification_status: Option<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "CodeableConcept::is_empty")]
     pub code: CodeableConcept,
+    #[serde(default, skip_serializing_if = "Reference::is_empty")]
     pub subject: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub encounter: Option<Reference>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub recorded_date: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub asserter: Option<Reference>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl Condition {
@@ -26,6 +39,7 @@ impl Condition {
             encounter: None,
             recorded_date,
             asserter: None,
+            extra: Map::new(),
         }
     }
 
@@ -1,23 +1,40 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use super::common::{Coding, CodeableConcept, Reference, Period};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Encounter {
     pub resource_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub status: String,
+    #[serde(default, skip_serializing_if = "Coding::is_empty")]
     pub class: Coding,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default, skip_serializing_if = "Vec::is_empty")]
     pub encounter_type: Vec<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "Reference::is_empty")]
     pub subject: Reference,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub participant: Vec<EncounterParticipant>,
+    #[serde(default, skip_serializing_if = "Period::is_empty")]
     pub period: Period,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub reason_code: Vec<CodeableConcept>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct EncounterParticipant {
+    #[serde(default, skip_serializing_if = "Reference::is_empty")]
     pub individual: Reference,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl Encounter {
@@ -32,18 +49,19 @@ impl Encounter {
             participant: Vec::new(),
             period,
             reason_code: Vec::new(),
+            extra: Map::new(),
         }
     }
 
     pub fn add_type(&mut self, coding: Vec<Coding>, text: Option<String>) {
-        self.encounter_type.push(CodeableConcept { coding, text });
+        self.encounter_type.push(CodeableConcept { coding, text, extra: Map::new() });
     }
 
     pub fn add_participant(&mut self, individual: Reference) {
-        self.participant.push(EncounterParticipant { individual });
+        self.participant.push(EncounterParticipant { individual, extra: Map::new() });
     }
 
     pub fn add_reason(&mut self, coding: Vec<Coding>, text: Option<String>) {
-        self.reason_code.push(CodeableConcept { coding, text });
+        self.reason_code.push(CodeableConcept { coding, text, extra: Map::new() });
     }
 }
@@ -1,55 +1,104 @@
 use serde::{Deserialize, Serialize};
-use super::common::{CodeableConcept, Coding, Reference, Quantity, Period};
+use serde_json::{Map, Value};
+use super::common::{CodeableConcept, Reference, Quantity, Period};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct MedicationRequest {
     pub resource_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub status: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub intent: String,
+    #[serde(default, skip_serializing_if = "CodeableConcept::is_empty")]
     pub medication_codeable_concept: CodeableConcept,
+    #[serde(default, skip_serializing_if = "Reference::is_empty")]
     pub subject: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub encounter: Option<Reference>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub authored_on: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub requester: Option<Reference>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub dosage_instruction: Vec<DosageInstruction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub dispense_request: Option<DispenseRequest>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DosageInstruction {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub timing: Option<Timing>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub route: Option<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub dose_and_rate: Option<Vec<DoseAndRate>>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Timing {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub repeat: Option<TimingRepeat>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TimingRepeat {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub frequency: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub period: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub period_unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub duration: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub duration_unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub bounds_period: Option<Period>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DoseAndRate {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
     pub dose_type: Option<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub dose_quantity: Option<Quantity>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DispenseRequest {
+    #[serde(default, skip_serializing_if = "Quantity::is_empty")]
     pub quantity: Quantity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub number_of_repeats_allowed: Option<u32>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl MedicationRequest {
@@ -73,6 +122,7 @@ impl MedicationRequest {
             requester: None,
             dosage_instruction: Vec::new(),
             dispense_request: None,
+            extra: Map::new(),
         }
     }
 
@@ -90,6 +140,7 @@ impl MedicationRequest {
             timing: None,
             route: None,
             dose_and_rate: None,
+            extra: Map::new(),
         });
     }
 
@@ -97,6 +148,7 @@ impl MedicationRequest {
         self.dispense_request = Some(DispenseRequest {
             quantity,
             number_of_repeats_allowed: number_of_repeats,
+            extra: Map::new(),
         });
     }
 }
@@ -6,9 +6,10 @@ pub mod medication;
 pub mod encounter;
 pub mod condition;
 pub mod bundle;
+pub mod claim;
 
 // Re-export common types to avoid duplication
-pub use common::{Identifier, HumanName, ContactPoint, CodeableConcept, Coding, Reference, Quantity, Period};
+pub use common::{Identifier, HumanName, ContactPoint, CodeableConcept, Coding, Reference, Quantity, Period, Meta, Decimal};
 pub use patient::Patient;
 pub use practitioner::Practitioner;
 pub use observation::Observation;
@@ -16,3 +17,4 @@ pub use medication::MedicationRequest;
 pub use encounter::Encounter;
 pub use condition::Condition;
 pub use bundle::{Bundle, BundleEntry, Resource, Signature, SignatureType};
+pub use claim::ClaimType;
@@ -1,25 +1,46 @@
 use serde::{Deserialize, Serialize};
-use super::common::{CodeableConcept, Coding, Reference, Quantity};
+use serde_json::{Map, Value};
+use super::common::{CodeableConcept, Decimal, Reference, Quantity};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Observation {
     pub resource_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub status: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub category: Vec<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "CodeableConcept::is_empty")]
     pub code: CodeableConcept,
+    #[serde(default, skip_serializing_if = "Reference::is_empty")]
     pub subject: Reference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub encounter: Option<Reference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub effective_date_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub performer: Vec<Reference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub component: Option<Vec<ObservationComponent>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub value_quantity: Option<Quantity>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ObservationComponent {
+    #[serde(default, skip_serializing_if = "CodeableConcept::is_empty")]
     pub code: CodeableConcept,
+    #[serde(default, skip_serializing_if = "Quantity::is_empty")]
     pub value_quantity: Quantity,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl Observation {
@@ -36,6 +57,7 @@ impl Observation {
             performer: Vec::new(),
             component: None,
             value_quantity: None,
+            extra: Map::new(),
         }
     }
 
@@ -61,10 +83,10 @@ impl Observation {
 
     pub fn set_value_quantity(&mut self, value: f64, unit: String) {
         self.value_quantity = Some(Quantity {
-            value,
+            value: Decimal::from_f64(value),
             unit,
             system: Some("http://unitsofmeasure.org".to_string()),
-            code: None,
+            ..Quantity::default()
         });
     }
 }
@@ -1,13 +1,22 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use super::common::{Identifier, HumanName, ContactPoint};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Practitioner {
     pub resource_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub identifier: Vec<Identifier>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub name: Vec<HumanName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub telecom: Option<Vec<ContactPoint>>,
+    /// Elements without a typed field, kept as they were read
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl Practitioner {
@@ -19,10 +28,12 @@ impl Practitioner {
             name: vec![HumanName {
                 family,
                 given,
-                //use_field: Some("official".to_string()),
+                use_field: None,
                 prefix: Some(vec!["Dr.".to_string()]),
+                extra: Map::new(),
             }],
             telecom: None,
+            extra: Map::new(),
         }
     }
 
@@ -30,8 +41,9 @@ impl Practitioner {
         self.identifier.push(Identifier {
             system,
             value,
-            //use_field: Some("official".to_string()),
-            //identifier_type: None,
+            use_field: None,
+            identifier_type: None,
+            extra: Map::new(),
         });
     }
 
@@ -40,7 +52,7 @@ impl Practitioner {
             self.telecom = Some(Vec::new());
         }
         if let Some(ref mut telecom) = self.telecom {
-            telecom.push(ContactPoint { system, value, use_field: Some(use_value) });
+            telecom.push(ContactPoint { system, value, use_field: Some(use_value), extra: Map::new() });
         }
     }
 }
@@ -1,214 +1,245 @@
+use crate::error::{Error, Result};
 use crate::models::*;
+use crate::verification::VerificationPayload;
 use std::fs::File;
 use std::io::Write;
 
+mod html;
+mod templates;
+mod writer;
+
+pub use templates::{parse_markup, template_name, Templates};
+
+/// A rendered visit summary: a title followed by titled sections
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub title: String,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub heading: String,
+    pub rows: Vec<Row>,
+}
+
+/// One block of a section: a labelled value, free text, a table or a QR code
+#[derive(Debug, Clone, PartialEq)]
+pub enum Row {
+    Field { label: String, value: String },
+    Text(String),
+    Table { columns: Vec<String>, rows: Vec<Vec<String>> },
+    Qr(QrMatrix),
+}
+
+/// An encoded QR code: `width` x `width` modules, row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct QrMatrix {
+    pub payload: String,
+    pub width: usize,
+    pub modules: Vec<bool>,
+}
+
+impl QrMatrix {
+    pub fn encode(payload: &str) -> Result<Self> {
+        let code = qrcode::QrCode::new(payload.as_bytes())
+            .map_err(|err| Error::render("verification payload does not fit in a QR code").with_source(err))?;
+        Ok(Self {
+            payload: payload.to_string(),
+            width: code.width(),
+            modules: code.to_colors().into_iter().map(|color| color == qrcode::Color::Dark).collect(),
+        })
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.width + x]
+    }
+}
+
+impl Section {
+    fn new(heading: &str) -> Self {
+        Self {
+            heading: heading.to_string(),
+            rows: Vec::new(),
+        }
+    }
+
+    fn field(&mut self, label: impl Into<String>, value: impl Into<String>) {
+        self.rows.push(Row::Field { label: label.into(), value: value.into() });
+    }
+}
+
+/// Details printed in the page header and footer
+struct PageInfo<'a> {
+    bundle_id: &'a str,
+    generated_at: &'a str,
+}
+
+/// Renders bundles through a document template as text, HTML or PDF
 pub struct PDFGenerator {
     output_path: String,
+    generated_at: Option<String>,
+    templates: Templates,
+    template: String,
+    claim_type: ClaimType,
+    verification: Option<(String, String)>,
 }
 
 impl PDFGenerator {
     pub fn new(output_path: String) -> Self {
-        Self { output_path }
+        Self {
+            output_path,
+            generated_at: None,
+            templates: Templates::new(),
+            template: template_name(ClaimType::Ehr),
+            claim_type: ClaimType::Ehr,
+            verification: None,
+        }
     }
 
-    /// Generate a PDF from FHIR Bundle data
-    pub fn generate_pdf(&self, bundle: &Bundle) -> Result<(), Box<dyn std::error::Error>> {
-        // For MVP, we'll generate a simple text-based PDF-like document
-        // In production, you'd use a proper PDF library like printpdf or wkhtmltopdf
-        
-        let mut content = String::new();
-        
-        // Add header
-        content.push_str("PATIENT VISIT SUMMARY & PRESCRIPTION\n");
-        content.push_str("=====================================\n\n");
-        
-        // Extract and format patient information
-        if let Some(patient) = self.extract_patient(bundle) {
-            content.push_str("PATIENT INFORMATION\n");
-            content.push_str("-------------------\n");
-            content.push_str(&format!("Name: {}\n", self.format_patient_name(&patient)));
-            content.push_str(&format!("Gender: {}\n", patient.gender));
-            content.push_str(&format!("Date of Birth: {}\n", patient.birth_date));
-            
-            if !patient.identifier.is_empty() {
-                content.push_str(&format!("MRN: {}\n", patient.identifier[0].value));
-            }
-            
-            if let Some(ref telecom) = patient.telecom {
-                for contact in telecom {
-                    content.push_str(&format!("{}: {}\n", contact.system, contact.value));
-                }
-            }
-            content.push_str("\n");
-        }
-        
-        // Extract and format practitioner information
-        if let Some(practitioner) = self.extract_practitioner(bundle) {
-            content.push_str("PRACTITIONER INFORMATION\n");
-            content.push_str("------------------------\n");
-            content.push_str(&format!("Doctor: {}\n", self.format_practitioner_name(&practitioner)));
-            
-            if !practitioner.identifier.is_empty() {
-                content.push_str(&format!("NPI: {}\n", practitioner.identifier[0].value));
-            }
-            content.push_str("\n");
-        }
-        
-        // Extract and format encounter information
-        if let Some(encounter) = self.extract_encounter(bundle) {
-            content.push_str("VISIT DETAILS\n");
-            content.push_str("-------------\n");
-            content.push_str(&format!("Encounter ID: {}\n", encounter.id));
-            content.push_str(&format!("Status: {}\n", encounter.status));
-            content.push_str(&format!("Class: {}\n", encounter.class.display));
-            
-            if !encounter.reason_code.is_empty() {
-                if let Some(ref text) = encounter.reason_code[0].text {
-                    content.push_str(&format!("Reason: {}\n", text));
-                }
-            }
-            content.push_str("\n");
-        }
-        
-        // Extract and format observations
-        if let Some(observation) = self.extract_observation(bundle) {
-            content.push_str("OBSERVATIONS\n");
-            content.push_str("------------\n");
-            content.push_str(&format!("Type: {}\n", observation.code.text.as_ref().unwrap_or(&"".to_string())));
-            
-            if let Some(ref components) = observation.component {
-                for component in components {
-                    content.push_str(&format!("{}: {} {}\n", 
-                        component.code.text.as_ref().unwrap_or(&"".to_string()),
-                        component.value_quantity.value,
-                        component.value_quantity.unit));
-                }
-            }
-            content.push_str("\n");
-        }
-        
-        // Extract and format conditions
-        if let Some(condition) = self.extract_condition(bundle) {
-            content.push_str("DIAGNOSIS\n");
-            content.push_str("---------\n");
-            content.push_str(&format!("Condition: {}\n", condition.code.text.as_ref().unwrap_or(&"".to_string())));
-            content.push_str(&format!("Recorded Date: {}\n", condition.recorded_date));
-            content.push_str("\n");
-        }
-        
-        // Extract and format medication request
-        if let Some(medication) = self.extract_medication_request(bundle) {
-            content.push_str("PRESCRIPTION\n");
-            content.push_str("------------\n");
-            content.push_str(&format!("Medication: {}\n", 
-                medication.medication_codeable_concept.text.as_ref().unwrap_or(&"".to_string())));
-            content.push_str(&format!("Status: {}\n", medication.status));
-            content.push_str(&format!("Intent: {}\n", medication.intent));
-            content.push_str(&format!("Authored On: {}\n", medication.authored_on));
-            
-            if !medication.dosage_instruction.is_empty() {
-                content.push_str(&format!("Instructions: {}\n", medication.dosage_instruction[0].text));
-            }
-            content.push_str("\n");
-        }
-        
-        // Add signature information
-        if let Some(ref signature) = bundle.signature {
-            content.push_str("DIGITAL SIGNATURE\n");
-            content.push_str("-----------------\n");
-            content.push_str(&format!("Signed By: {}\n", signature.who.reference));
-            content.push_str(&format!("Date: {}\n", signature.when));
-            content.push_str(&format!("Signature Hash: {}\n", signature.data));
-        }
-        
-        // Write to file (for MVP, we'll create a text file that can be converted to PDF)
+    /// Use a fixed generation timestamp in the page footer instead of the current time
+    pub fn with_generated_at(mut self, timestamp: impl Into<String>) -> Self {
+        self.generated_at = Some(timestamp.into());
+        self
+    }
+
+    /// Render with the default template for `claim_type`
+    pub fn with_claim_type(mut self, claim_type: ClaimType) -> Self {
+        self.claim_type = claim_type;
+        self.template = template_name(claim_type);
+        self
+    }
+
+    /// Render with the named template, e.g. a custom `referral` layout
+    pub fn with_template(mut self, name: impl Into<String>) -> Self {
+        self.template = name.into();
+        self
+    }
+
+    /// Replace the template set, e.g. with `Templates::from_dir`
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Append a verification section with a QR code carrying the bundle hash,
+    /// the issuer DID and the Hedera file or topic reference
+    pub fn with_verification(mut self, issuer_did: impl Into<String>, ledger_ref: impl Into<String>) -> Self {
+        self.verification = Some((issuer_did.into(), ledger_ref.into()));
+        self
+    }
+
+    /// Generate a PDF from FHIR Bundle data and write it to the output path
+    pub fn generate_pdf(&self, bundle: &Bundle) -> Result<()> {
+        let pdf = self.render_pdf(bundle)?;
         let mut file = File::create(&self.output_path)?;
-        file.write_all(content.as_bytes())?;
-        
-        println!("Generated document: {}", self.output_path);
-        println!("Note: For MVP, this is a text file. In production, use a PDF library.");
-        
+        file.write_all(&pdf)?;
         Ok(())
     }
-    
-        fn extract_patient<'a>(&self, bundle: &'a Bundle) -> Option<&'a Patient> {
-        for entry in &bundle.entry {
-            if let Resource::Patient(patient) = &entry.resource {
-                return Some(patient);
-            }
-        }
-        None
-    }
 
-    fn extract_practitioner<'a>(&self, bundle: &'a Bundle) -> Option<&'a Practitioner> {
-        for entry in &bundle.entry {
-            if let Resource::Practitioner(practitioner) = &entry.resource {
-                return Some(practitioner);
-            }
-        }
-        None
+    /// Render the document as PDF bytes
+    pub fn render_pdf(&self, bundle: &Bundle) -> Result<Vec<u8>> {
+        let generated_at = self.generated_at();
+        let document = self.build_document_at(bundle, &generated_at)?;
+        let info = PageInfo {
+            bundle_id: &bundle.id,
+            generated_at: &generated_at,
+        };
+        Ok(writer::write_pdf(&document, &info))
     }
 
-    fn extract_encounter<'a>(&self, bundle: &'a Bundle) -> Option<&'a Encounter> {
-        for entry in &bundle.entry {
-            if let Resource::Encounter(encounter) = &entry.resource {
-                return Some(encounter);
-            }
-        }
-        None
+    /// Render the document as a standalone HTML page
+    pub fn render_html(&self, bundle: &Bundle) -> Result<String> {
+        let generated_at = self.generated_at();
+        let document = self.build_document_at(bundle, &generated_at)?;
+        let info = PageInfo {
+            bundle_id: &bundle.id,
+            generated_at: &generated_at,
+        };
+        Ok(html::write_html(&document, &info))
     }
 
-    fn extract_observation<'a>(&self, bundle: &'a Bundle) -> Option<&'a Observation> {
-        for entry in &bundle.entry {
-            if let Resource::Observation(observation) = &entry.resource {
-                return Some(observation);
+    /// Render the document as plain text
+    pub fn render_text(&self, bundle: &Bundle) -> Result<String> {
+        let document = self.build_document(bundle)?;
+        let mut content = String::new();
+        
+        // Add header
+        content.push_str(&document.title.to_uppercase());
+        content.push('\n');
+        content.push_str(&"=".repeat(document.title.len()));
+        content.push_str("\n\n");
+
+        let sections: Vec<String> = document.sections.iter().map(|section| {
+            let mut text = format!("{}\n{}\n", section.heading.to_uppercase(), "-".repeat(section.heading.len()));
+            for row in &section.rows {
+                match row {
+                    Row::Field { label, value } => text.push_str(&format!("{}: {}\n", label, value)),
+                    Row::Text(line) => text.push_str(&format!("{}\n", line)),
+                    Row::Table { columns, rows } => text.push_str(&text_table(columns, rows)),
+                    Row::Qr(code) => text.push_str(&format!("Verification Code: {}\n", code.payload)),
+                }
             }
-        }
-        None
+            text
+        }).collect();
+        content.push_str(&sections.join("\n"));
+        
+        Ok(content)
     }
 
-    fn extract_condition<'a>(&self, bundle: &'a Bundle) -> Option<&'a Condition> {
-        for entry in &bundle.entry {
-            if let Resource::Condition(condition) = &entry.resource {
-                return Some(condition);
-            }
-        }
-        None
+    /// Render the selected template for the bundle
+    pub fn build_document(&self, bundle: &Bundle) -> Result<Document> {
+        self.build_document_at(bundle, &self.generated_at())
     }
 
-    fn extract_medication_request<'a>(&self, bundle: &'a Bundle) -> Option<&'a MedicationRequest> {
-        for entry in &bundle.entry {
-            if let Resource::MedicationRequest(medication) = &entry.resource {
-                return Some(medication);
-            }
+    fn build_document_at(&self, bundle: &Bundle, generated_at: &str) -> Result<Document> {
+        let mut document = self.templates.render(&self.template, bundle, self.claim_type, generated_at)?;
+
+        if let Some((ref issuer_did, ref ledger_ref)) = self.verification {
+            let payload = VerificationPayload::for_bundle(bundle, issuer_did.as_str(), ledger_ref.as_str());
+            let mut section = Section::new("Verification");
+            section.field("Bundle Hash", payload.sha256.as_str());
+            section.field("Issuer", issuer_did.as_str());
+            section.field("Ledger Reference", ledger_ref.as_str());
+            section.rows.push(Row::Qr(QrMatrix::encode(&payload.encode())?));
+            document.sections.push(section);
         }
-        None
+        Ok(document)
     }
-    
-    fn format_patient_name(&self, patient: &Patient) -> String {
-        if let Some(name) = patient.name.first() {
-            let given = name.given.join(" ");
-            format!("{} {}", given, name.family)
-        } else {
-            "Unknown".to_string()
-        }
+
+    fn generated_at(&self) -> String {
+        self.generated_at.clone().unwrap_or_else(crate::utils::get_current_timestamp)
     }
-    
-    fn format_practitioner_name(&self, practitioner: &Practitioner) -> String {
-        if let Some(name) = practitioner.name.first() {
-            let given = name.given.join(" ");
-            let prefix = name.prefix.as_ref().map(|p| p.join(" ")).unwrap_or_default();
-            format!("{} {} {}", prefix, given, name.family)
-        } else {
-            "Unknown".to_string()
-        }
+}
+
+/// Lay out a table as space-padded columns under a dashed header rule
+fn text_table(columns: &[String], rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = columns.iter().enumerate().map(|(i, column)| {
+        rows.iter()
+            .filter_map(|row| row.get(i))
+            .map(|cell| cell.chars().count())
+            .fold(column.chars().count(), usize::max)
+    }).collect();
+
+    let line = |cells: &[String]| {
+        let padded: Vec<String> = cells.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    let mut text = line(columns);
+    text.push_str(&line(&rule));
+    for row in rows {
+        text.push_str(&line(row));
     }
+    text
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::*;
     
     #[test]
     fn test_pdf_generator_creation() {
@@ -226,8 +257,151 @@ mod tests {
             "1980-01-15".to_string(),
         );
         
-        let generator = PDFGenerator::new("test.txt".to_string());
-        let formatted_name = generator.format_patient_name(&patient);
+        let formatted_name = templates::format_human_name(&patient.name[0]);
         assert_eq!(formatted_name, "John Michael Doe");
     }
+
+    fn sample_bundle() -> Bundle {
+        let json = include_str!("../FHIR/FHIRBundle.json");
+        crate::FHIRHandler::new().parse_fhir_json(json).unwrap()
+    }
+
+    #[test]
+    fn test_render_text_sections() {
+        let generator = PDFGenerator::new("test.txt".to_string());
+        let text = generator.render_text(&sample_bundle()).unwrap();
+
+        assert!(text.starts_with("PATIENT VISIT SUMMARY & PRESCRIPTION\n====================================\n\n"));
+        assert!(text.contains("PATIENT INFORMATION\n-------------------\nName: John Michael Doe\n"));
+        assert!(text.contains("Blood Pressure - Systolic   120    mmHg  2024-07-30T09:15:00Z\n"));
+        assert!(text.contains("PRESCRIPTION\n------------\nMedication: Amoxicillin 250 mg capsule\n"));
+    }
+
+    #[test]
+    fn test_render_all_resources_by_date() {
+        let extra = r#"[
+            {"resourceType": "Observation", "id": "temp", "status": "final", "code": {"text": "Temperature"},
+             "effectiveDateTime": "2024-07-30T09:05:00Z", "valueQuantity": {"value": 37.2, "unit": "C"}},
+            {"resourceType": "Observation", "id": "pulse", "status": "final", "code": {"text": "Pulse"},
+             "effectiveDateTime": "2024-07-30T09:20:00Z", "valueQuantity": {"value": 72, "unit": "/min"}},
+            {"resourceType": "Observation", "id": "smoking", "status": "final", "code": {"text": "Smoking status"},
+             "effectiveInstant": "2024-07-30T08:55:00Z", "valueCodeableConcept": {"text": "Never smoker"}},
+            {"resourceType": "Observation", "id": "glucose", "status": "final", "code": {"text": "Glucose"},
+             "effectivePeriod": {"start": "2024-07-29T22:00:00Z", "end": "2024-07-30T08:00:00Z"},
+             "valueQuantity": {"value": 5.40, "comparator": "<", "unit": "mmol/L"}},
+            {"resourceType": "Observation", "id": "urine", "status": "final", "code": {"text": "Urinalysis"},
+             "effectiveDateTime": "2024-07-30T09:30:00Z",
+             "component": [{"code": {"text": "Colour"}, "valueString": "Yellow"},
+                           {"code": {"text": "Protein"}, "valueBoolean": false},
+                           {"code": {"text": "Nitrite"}, "dataAbsentReason": {"text": "Error"}}]},
+            {"resourceType": "MedicationRequest", "id": "rx-0", "status": "active", "intent": "order",
+             "medicationCodeableConcept": {"text": "Ibuprofen 200 mg tablet"}, "authoredOn": "2024-07-30T10:40:00Z",
+             "dosageInstruction": [{"text": "As needed for pain",
+                "timing": {"repeat": {"frequency": 2, "period": 1, "periodUnit": "d", "duration": 5, "durationUnit": "d"}},
+                "route": {"text": "Oral"},
+                "doseAndRate": [{"doseQuantity": {"value": 1, "unit": "tablet"}}]}],
+             "dispenseRequest": {"quantity": {"value": 10, "unit": "tablet"}, "numberOfRepeatsAllowed": 1}}
+        ]"#;
+        let mut bundle = sample_bundle();
+        for resource in serde_json::from_str::<Vec<Resource>>(extra).unwrap() {
+            bundle.add_entry(resource);
+        }
+
+        let generator = PDFGenerator::new("test.txt".to_string());
+        let text = generator.render_text(&bundle).unwrap();
+
+        let temperature = text.find("Temperature").unwrap();
+        let systolic = text.find("Blood Pressure - Systolic").unwrap();
+        let pulse = text.find("Pulse").unwrap();
+        assert!(temperature < systolic && systolic < pulse);
+        assert!(text.contains("37.2"));
+        assert!(text.contains("Glucose                     <5.4                  mmol/L  2024-07-29T22:00:00Z to 2024-07-30T08:00:00Z\n"));
+        assert!(text.contains("Smoking status              Never smoker                  2024-07-30T08:55:00Z\n"));
+        assert!(text.contains("Urinalysis - Colour         Yellow                        2024-07-30T09:30:00Z\n"));
+        assert!(text.contains("Urinalysis - Protein        no"));
+        assert!(text.contains("Urinalysis - Nitrite        not recorded (Error)"));
+        assert!(text.find("Smoking status").unwrap() < temperature);
+
+        let ibuprofen = text.find("Medication: Ibuprofen").unwrap();
+        let amoxicillin = text.find("Medication: Amoxicillin").unwrap();
+        assert!(ibuprofen < amoxicillin);
+        assert!(text.contains("Dose: 1 tablet\nRoute: Oral\nFrequency: 2 times per day\nDuration: 5 days\n"));
+        assert!(text.contains("Dispense: 10 tablet\nRepeats Allowed: 1\n"));
+    }
+
+    #[test]
+    fn test_render_html_from_claim_template() {
+        let generator = PDFGenerator::new("test.html".to_string())
+            .with_claim_type(ClaimType::Prescription)
+            .with_generated_at("2024-07-30T11:00:00Z");
+        let html = generator.render_html(&sample_bundle()).unwrap();
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h1>Prescription</h1>"));
+        assert!(html.contains("<h2>Prescriber</h2>"));
+        assert!(html.contains("<dt>Medication</dt><dd>Amoxicillin 250 mg capsule</dd>"));
+        assert!(html.contains("<span>Bundle mvp-visit-bundle</span>"));
+        assert!(!html.contains("<h2>Observations</h2>"));
+
+        let text = generator.render_text(&sample_bundle()).unwrap();
+        assert!(text.starts_with("PRESCRIPTION\n"));
+    }
+
+    #[test]
+    fn test_render_pdf() {
+        let generator = PDFGenerator::new("test.pdf".to_string())
+            .with_generated_at("2024-07-30T11:00:00Z");
+        let pdf = generator.render_pdf(&sample_bundle()).unwrap();
+        let body = String::from_utf8_lossy(&pdf);
+
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(body.contains("/BaseFont /Helvetica-Bold"));
+        assert!(body.contains("(Patient Information) Tj"));
+        assert!(body.contains("(Bundle mvp-visit-bundle) Tj"));
+        assert!(body.contains("(Generated 2024-07-30T11:00:00Z) Tj"));
+        assert!(body.contains("(Page 1 of 1) Tj"));
+        assert!(body.contains("(Blood Pressure - Diastolic) Tj"));
+    }
+
+    #[test]
+    fn test_verification_qr_code() {
+        let bundle = sample_bundle();
+        let generator = PDFGenerator::new("test.pdf".to_string())
+            .with_verification("did:hedera:testnet:0.0.7654321", "0.0.9999999");
+
+        let document = generator.build_document(&bundle).unwrap();
+        let section = document.sections.last().unwrap();
+        assert_eq!(section.heading, "Verification");
+        let Some(Row::Qr(code)) = section.rows.last() else { panic!("missing QR code") };
+        assert_eq!(code.modules.len(), code.width * code.width);
+
+        let verified = crate::verify(&code.payload, &bundle).unwrap();
+        assert_eq!(verified.issuer_did, "did:hedera:testnet:0.0.7654321");
+        assert_eq!(verified.ledger_ref, "0.0.9999999");
+
+        let pdf = generator.render_pdf(&bundle).unwrap();
+        let body = String::from_utf8_lossy(&pdf);
+        assert!(body.contains("(Verification) Tj"));
+        assert!(body.contains(" re\nf\n"));
+        assert!(generator.render_html(&bundle).unwrap().contains("<svg class=\"qr\""));
+    }
+
+    #[test]
+    fn test_render_pdf_breaks_pages() {
+        let mut bundle = sample_bundle();
+        let mut patient = Patient::new("p-2".to_string(), "Roe".to_string(), vec!["Jane".to_string()],
+            "female".to_string(), "1990-02-01".to_string());
+        for i in 0..80 {
+            patient.add_contact("phone".to_string(), format!("+1-555-000-{:04}", i), "home".to_string());
+        }
+        bundle.entry[0].resource = Resource::Patient(patient);
+
+        let generator = PDFGenerator::new("test.pdf".to_string());
+        let pdf = generator.render_pdf(&bundle).unwrap();
+        let body = String::from_utf8_lossy(&pdf);
+
+        assert!(body.contains("(Page 1 of 3) Tj"));
+        assert!(body.contains("(Page 3 of 3) Tj"));
+        assert!(!body.contains("of 4) Tj"));
+    }
 }
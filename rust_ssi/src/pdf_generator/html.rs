@@ -0,0 +1,116 @@
+//! Writes a `Document` as a standalone HTML page with inline styles.
+
+use super::{Document, PageInfo, QrMatrix, Row};
+use std::fmt::Write;
+
+const STYLE: &str = "\
+body { font-family: Helvetica, Arial, sans-serif; font-size: 10pt; color: #000; max-width: 48em; margin: 2em auto; }
+header, footer { display: flex; justify-content: space-between; font-size: 8pt; color: #4d4d4d; }
+header { border-bottom: 0.5px solid #4d4d4d; padding-bottom: 4px; }
+footer { border-top: 0.5px solid #4d4d4d; padding-top: 4px; margin-top: 2em; }
+h1 { font-size: 18pt; }
+h2 { font-size: 12pt; border-bottom: 0.5px solid #999; padding-bottom: 2px; }
+dl { display: grid; grid-template-columns: 130pt auto; margin: 0; }
+dt { font-weight: bold; }
+dd { margin: 0; }
+table { border-collapse: collapse; }
+th { text-align: left; border-bottom: 0.5px solid #999; }
+th, td { padding: 0 12pt 0 0; }";
+
+pub fn write_html(document: &Document, info: &PageInfo) -> String {
+    let mut html = String::new();
+    let title = escape(&document.title);
+
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(html, "<title>{}</title>", title);
+    let _ = writeln!(html, "<style>\n{}\n</style>\n</head>\n<body>", STYLE);
+    let _ = writeln!(html, "<header><span>{}</span><span>Bundle {}</span></header>", title, escape(info.bundle_id));
+    let _ = writeln!(html, "<h1>{}</h1>", title);
+
+    for section in &document.sections {
+        html.push_str("<section>\n");
+        if !section.heading.is_empty() {
+            let _ = writeln!(html, "<h2>{}</h2>", escape(&section.heading));
+        }
+
+        let mut in_list = false;
+        for row in &section.rows {
+            let is_field = matches!(row, Row::Field { .. });
+            if is_field != in_list {
+                html.push_str(if is_field { "<dl>\n" } else { "</dl>\n" });
+                in_list = is_field;
+            }
+            match row {
+                Row::Field { label, value } => {
+                    let _ = writeln!(html, "<dt>{}</dt><dd>{}</dd>", escape(label), escape(value));
+                }
+                Row::Text(text) => {
+                    let _ = writeln!(html, "<p>{}</p>", escape(text));
+                }
+                Row::Table { columns, rows } => write_table(&mut html, columns, rows),
+                Row::Qr(code) => write_qr(&mut html, code),
+            }
+        }
+        if in_list {
+            html.push_str("</dl>\n");
+        }
+        html.push_str("</section>\n");
+    }
+
+    let _ = writeln!(html, "<footer><span>Generated {}</span></footer>", escape(info.generated_at));
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn write_table(html: &mut String, columns: &[String], rows: &[Vec<String>]) {
+    html.push_str("<table>\n<thead><tr>");
+    for column in columns {
+        let _ = write!(html, "<th>{}</th>", escape(column));
+    }
+    html.push_str("</tr></thead>\n<tbody>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(cell));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</tbody>\n</table>\n");
+}
+
+/// Inline SVG with one unit per module and a four-module quiet zone
+fn write_qr(html: &mut String, code: &QrMatrix) {
+    let size = code.width + 8;
+    let mut path = String::new();
+    for y in 0..code.width {
+        for x in 0..code.width {
+            if code.is_dark(x, y) {
+                let _ = write!(path, "M{},{}h1v1h-1z", x + 4, y + 4);
+            }
+        }
+    }
+    let _ = writeln!(
+        html,
+        "<svg class=\"qr\" xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\" width=\"120\" height=\"120\" \
+         shape-rendering=\"crispEdges\"><title>{}</title><rect width=\"{size}\" height=\"{size}\" fill=\"#fff\"/>\
+         <path d=\"{}\" fill=\"#000\"/></svg>",
+        escape(&code.payload),
+        path,
+        size = size,
+    );
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,588 @@
+//! Template-driven document layouts.
+//!
+//! Templates are rendered with minijinja into a small line-based markup,
+//! which is then parsed into a [`Document`] so that the same template
+//! produces text, HTML and PDF output:
+//!
+//! ```text
+//! # Document title
+//! ## Section heading
+//! - Label: value
+//! | Column | Column |
+//! | cell   | cell   |
+//! Any other line is a paragraph of free text.
+//! ```
+//!
+//! Every interpolated value is escaped so that data cannot add structure:
+//! newlines become spaces, and `\`, `|`, `:` and a leading `#` or `-` are
+//! written with a backslash, which the parser removes again.
+//!
+//! Templates receive the parsed bundle as `bundle` (FHIR JSON), along with
+//! `patient`, `practitioner`, `signature`, the date-ordered lists
+//! `encounters`, `observations`, `conditions` and `medications`, every
+//! resource grouped by type in `resources`, plus `claim_type` and
+//! `generated_at`.
+
+use super::{Document, Row, Section};
+use crate::error::{Error, Result};
+use crate::models::medication::DosageInstruction;
+use crate::models::*;
+use minijinja::value::{Value, ViaDeserialize};
+use minijinja::{Environment, Output, State};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Templates shipped with the crate, keyed by name. Names starting with `_`
+/// are partials included by the others.
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    ("_patient", include_str!("templates/_patient.jinja")),
+    ("_practitioner", include_str!("templates/_practitioner.jinja")),
+    ("_encounters", include_str!("templates/_encounters.jinja")),
+    ("_observations", include_str!("templates/_observations.jinja")),
+    ("_conditions", include_str!("templates/_conditions.jinja")),
+    ("_medications", include_str!("templates/_medications.jinja")),
+    ("_signature", include_str!("templates/_signature.jinja")),
+    ("ehr", include_str!("templates/ehr.jinja")),
+    ("lab_results", include_str!("templates/lab_results.jinja")),
+    ("prescription", include_str!("templates/prescription.jinja")),
+    ("medical_imaging", include_str!("templates/medical_imaging.jinja")),
+    ("vaccination", include_str!("templates/vaccination.jinja")),
+    ("allergy", include_str!("templates/allergy.jinja")),
+    ("medical_history", include_str!("templates/medical_history.jinja")),
+    ("insurance", include_str!("templates/insurance.jinja")),
+    ("consent", include_str!("templates/consent.jinja")),
+    ("other", include_str!("templates/other.jinja")),
+];
+
+/// Name of the default template for a claim type, e.g. `lab_results`
+pub fn template_name(claim_type: ClaimType) -> String {
+    claim_type.as_str().to_ascii_lowercase()
+}
+
+/// A set of named document templates
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Templates {
+    /// The default templates, one per claim type
+    pub fn new() -> Self {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_formatter(markup_formatter);
+        env.add_filter("concept", concept_filter);
+        env.add_filter("quantity", quantity_filter);
+        env.add_filter("period", period_filter);
+        env.add_filter("name", name_filter);
+        env.add_filter("dosage", dosage_filter);
+        env.add_filter("value", value_filter);
+        env.add_filter("unit", unit_filter);
+        env.add_filter("effective", effective_filter);
+
+        for (name, source) in DEFAULT_TEMPLATES {
+            env.add_template(name, source).expect("default templates are valid");
+        }
+        Self { env }
+    }
+
+    /// The defaults, with every `*.jinja` file in `dir` added or replacing
+    /// the template named after its file stem
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let mut templates = Self::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "jinja") {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                templates.add_template(name, fs::read_to_string(&path)?)?;
+            }
+        }
+        Ok(templates)
+    }
+
+    /// Add a template, replacing any existing template with the same name
+    pub fn add_template(&mut self, name: impl Into<String>, source: impl Into<String>) -> Result<()> {
+        let name = name.into();
+        self.env
+            .add_template_owned(name.clone(), source.into())
+            .map_err(|err| Error::render(format!("invalid template '{}'", name)).with_source(err))
+    }
+
+    /// Names of all templates, excluding partials
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.env.templates()
+            .map(|(name, _)| name.to_string())
+            .filter(|name| !name.starts_with('_'))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Render the named template for `bundle` and parse the result
+    pub fn render(&self, name: &str, bundle: &Bundle, claim_type: ClaimType, generated_at: &str) -> Result<Document> {
+        let template = self.env
+            .get_template(name)
+            .map_err(|err| Error::render(format!("unknown template '{}'", name)).with_source(err))?;
+        let markup = template
+            .render(template_value(Context::new(bundle, claim_type, generated_at)))
+            .map_err(|err| Error::render(format!("template '{}' failed", name)).with_source(err))?;
+        Ok(parse_markup(&markup))
+    }
+}
+
+#[derive(Serialize)]
+struct Context<'a> {
+    bundle: &'a Bundle,
+    claim_type: ClaimType,
+    generated_at: &'a str,
+    patient: Option<&'a Patient>,
+    practitioner: Option<&'a Practitioner>,
+    signature: Option<&'a Signature>,
+    encounters: Vec<&'a Encounter>,
+    observations: Vec<&'a Observation>,
+    conditions: Vec<&'a Condition>,
+    medications: Vec<&'a MedicationRequest>,
+    resources: BTreeMap<&'a str, Vec<&'a Resource>>,
+}
+
+impl<'a> Context<'a> {
+    fn new(bundle: &'a Bundle, claim_type: ClaimType, generated_at: &'a str) -> Self {
+        let mut context = Context {
+            bundle,
+            claim_type,
+            generated_at,
+            patient: None,
+            practitioner: None,
+            signature: bundle.signature.as_ref(),
+            encounters: Vec::new(),
+            observations: Vec::new(),
+            conditions: Vec::new(),
+            medications: Vec::new(),
+            resources: BTreeMap::new(),
+        };
+
+        for entry in &bundle.entry {
+            let resource = &entry.resource;
+            context.resources.entry(resource.resource_type()).or_default().push(resource);
+            match resource {
+                Resource::Patient(patient) => { context.patient.get_or_insert(patient); }
+                Resource::Practitioner(practitioner) => { context.practitioner.get_or_insert(practitioner); }
+                Resource::Encounter(encounter) => context.encounters.push(encounter),
+                Resource::Observation(observation) => context.observations.push(observation),
+                Resource::Condition(condition) => context.conditions.push(condition),
+                Resource::MedicationRequest(medication) => context.medications.push(medication),
+                Resource::Other { .. } => {}
+            }
+        }
+
+        sort_by_date(&mut context.encounters, |encounter| &encounter.period.start);
+        sort_by_date(&mut context.observations, |observation| {
+            observation.effective_date_time.as_deref()
+                .or_else(|| observation.extra.get("effectiveInstant").and_then(serde_json::Value::as_str))
+                .or_else(|| observation.extra.get("effectivePeriod").and_then(|period| period.get("start")?.as_str()))
+                .unwrap_or_default()
+        });
+        sort_by_date(&mut context.conditions, |condition| &condition.recorded_date);
+        sort_by_date(&mut context.medications, |medication| &medication.authored_on);
+        context
+    }
+}
+
+/// `context` as a template value. serde_json writes numbers as maps that
+/// keep their digits, so they are turned back into template numbers here.
+fn template_value(context: Context) -> Value {
+    fn convert(value: serde_json::Value) -> Value {
+        match value {
+            serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+                (Some(value), _) => Value::from(value),
+                (None, Some(value)) => Value::from(value),
+                (None, None) => Value::from(number.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::Array(items) => items.into_iter().map(convert).collect(),
+            serde_json::Value::Object(members) => {
+                members.into_iter().map(|(name, value)| (name, convert(value))).collect::<BTreeMap<_, _>>().into()
+            }
+            other => Value::from_serialize(other),
+        }
+    }
+    convert(serde_json::to_value(context).expect("context serialization cannot fail"))
+}
+
+/// Write interpolated values escaped, so that they cannot start a heading,
+/// field or table row, or add cells, lines or labels
+fn markup_formatter(out: &mut Output, _state: &State, value: &Value) -> std::result::Result<(), minijinja::Error> {
+    let text = value.to_string();
+    let text = text.trim_start();
+    let mut escaped = String::with_capacity(text.len() + 1);
+    if text.starts_with(['#', '-']) {
+        escaped.push('\\');
+    }
+    for c in text.chars() {
+        match c {
+            '\\' | '|' | ':' => escaped.extend(['\\', c]),
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    out.write_str(&escaped)?;
+    Ok(())
+}
+
+/// Split `text` on at most `limit - 1` unescaped `separator`s and unescape
+/// the parts
+fn split_unescaped(text: &str, separator: char, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => part.extend(chars.next()),
+            c if c == separator && parts.len() + 1 < limit => parts.push(std::mem::take(&mut part)),
+            c => part.push(c),
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+fn unescape(text: &str) -> String {
+    split_unescaped(text, '\\', 1).remove(0)
+}
+
+/// Sort oldest first; FHIR dates compare correctly as strings. Undated items go last.
+fn sort_by_date<T>(items: &mut [&T], date: impl Fn(&T) -> &str) {
+    items.sort_by(|a, b| {
+        let (a, b) = (date(a), date(b));
+        (a.is_empty(), a).cmp(&(b.is_empty(), b))
+    });
+}
+
+/// Parse rendered template markup into a document. Sections and tables
+/// without content are dropped.
+pub fn parse_markup(markup: &str) -> Document {
+    let mut title = String::new();
+    let mut sections: Vec<Section> = Vec::new();
+
+    for line in markup.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(heading) = line.strip_prefix("## ") {
+            sections.push(Section::new(&unescape(heading.trim())));
+            continue;
+        }
+        if let Some(text) = line.strip_prefix("# ") {
+            title = unescape(text.trim());
+            continue;
+        }
+
+        if sections.is_empty() {
+            sections.push(Section::new(""));
+        }
+        let section = sections.last_mut().expect("a section was just ensured");
+
+        if let Some(field) = line.strip_prefix("- ") {
+            match split_unescaped(field, ':', 2).as_slice() {
+                [label, value] => section.field(label.trim(), value.trim()),
+                _ => section.rows.push(Row::Text(unescape(field.trim()))),
+            }
+        } else if let Some(inner) = line.strip_prefix('|') {
+            let mut cells = split_unescaped(inner, '|', usize::MAX);
+            // A closing `|` leaves nothing after it
+            if cells.len() > 1 && cells.last().is_some_and(String::is_empty) {
+                cells.pop();
+            }
+            let cells: Vec<String> = cells.iter().map(|cell| cell.trim().to_string()).collect();
+            match section.rows.last_mut() {
+                Some(Row::Table { rows, .. }) => rows.push(cells),
+                _ => section.rows.push(Row::Table { columns: cells, rows: Vec::new() }),
+            }
+        } else {
+            section.rows.push(Row::Text(unescape(line)));
+        }
+    }
+
+    for section in &mut sections {
+        section.rows.retain(|row| !matches!(row, Row::Table { rows, .. } if rows.is_empty()));
+    }
+    sections.retain(|section| !section.rows.is_empty());
+    Document { title, sections }
+}
+
+fn concept_filter(concept: ViaDeserialize<Option<CodeableConcept>>) -> String {
+    concept.0.as_ref().map(concept_text).unwrap_or_default()
+}
+
+fn quantity_filter(quantity: ViaDeserialize<Option<Quantity>>) -> String {
+    quantity.0.as_ref().map(format_quantity).unwrap_or_default()
+}
+
+fn period_filter(period: ViaDeserialize<Option<Period>>) -> String {
+    period.0.as_ref().map(format_period).unwrap_or_default()
+}
+
+fn name_filter(name: ViaDeserialize<Option<HumanName>>) -> String {
+    name.0.as_ref().map(format_human_name).unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// Labelled lines describing a dosage instruction, as `[label, value]` pairs
+fn dosage_filter(dosage: ViaDeserialize<Option<DosageInstruction>>) -> Value {
+    Value::from_serialize(dosage.0.as_ref().map(dosage_fields).unwrap_or_default())
+}
+
+/// The `value[x]` of an observation or component, or why it is absent
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObservationValue {
+    value_quantity: Option<Quantity>,
+    value_codeable_concept: Option<CodeableConcept>,
+    value_string: Option<String>,
+    value_boolean: Option<bool>,
+    value_integer: Option<i64>,
+    value_date_time: Option<String>,
+    value_time: Option<String>,
+    value_period: Option<Period>,
+    data_absent_reason: Option<CodeableConcept>,
+}
+
+/// The `effective[x]` of an observation
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObservationEffective {
+    effective_date_time: Option<String>,
+    effective_instant: Option<String>,
+    effective_period: Option<Period>,
+}
+
+/// The value of an observation or component, without the unit of a quantity
+fn value_filter(item: ViaDeserialize<ObservationValue>) -> String {
+    let item = item.0;
+    if let Some(value) = item.value_quantity.as_ref().and_then(|quantity| quantity.value.as_ref()) {
+        let comparator = item.value_quantity.as_ref().and_then(|quantity| quantity.comparator.as_deref());
+        return format!("{}{}", comparator.unwrap_or_default(), value);
+    }
+    if let Some(concept) = item.value_codeable_concept {
+        return concept_text(&concept);
+    }
+    if let Some(flag) = item.value_boolean {
+        return if flag { "yes" } else { "no" }.to_string();
+    }
+    if let Some(value) = item.value_integer {
+        return value.to_string();
+    }
+    if let Some(period) = item.value_period {
+        return format_period(&period);
+    }
+    if let Some(text) = item.value_string.or(item.value_date_time).or(item.value_time) {
+        return text;
+    }
+    match item.data_absent_reason {
+        Some(reason) => format!("not recorded ({})", concept_text(&reason)),
+        None => "not recorded".to_string(),
+    }
+}
+
+/// The unit of an observation or component with a quantity value
+fn unit_filter(item: ViaDeserialize<ObservationValue>) -> String {
+    item.0.value_quantity.map(|quantity| quantity.unit).unwrap_or_default()
+}
+
+fn effective_filter(observation: ViaDeserialize<ObservationEffective>) -> String {
+    let observation = observation.0;
+    observation.effective_date_time
+        .or(observation.effective_instant)
+        .or_else(|| observation.effective_period.as_ref().map(format_period))
+        .unwrap_or_default()
+}
+
+fn concept_text(concept: &CodeableConcept) -> String {
+    concept.text.clone()
+        .or_else(|| concept.coding.iter().find(|coding| !coding.display.is_empty()).map(|coding| coding.display.clone()))
+        .or_else(|| concept.coding.first().map(|coding| coding.code.clone()))
+        .unwrap_or_default()
+}
+
+fn format_quantity(quantity: &Quantity) -> String {
+    match &quantity.value {
+        Some(value) => format!("{} {}", value, quantity.unit).trim_end().to_string(),
+        None => String::new(),
+    }
+}
+
+fn format_period(period: &Period) -> String {
+    match (period.start.is_empty(), period.end.is_empty()) {
+        (false, false) => format!("{} to {}", period.start, period.end),
+        (false, true) => format!("from {}", period.start),
+        (true, false) => format!("until {}", period.end),
+        (true, true) => String::new(),
+    }
+}
+
+/// Prefixes, given names and family name separated by spaces
+pub(super) fn format_human_name(name: &HumanName) -> String {
+    name.prefix.iter().flatten()
+        .chain(&name.given)
+        .chain(std::iter::once(&name.family))
+        .filter(|part| !part.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Expand a FHIR unit of time (`d`, `wk`, ...) into a word, pluralised for `count`
+fn time_unit(unit: &str, count: u32) -> String {
+    let word = match unit {
+        "s" => "second",
+        "min" => "minute",
+        "h" => "hour",
+        "d" => "day",
+        "wk" => "week",
+        "mo" => "month",
+        "a" => "year",
+        other => other,
+    };
+    if count == 1 { word.to_string() } else { format!("{}s", word) }
+}
+
+fn dosage_fields(dosage: &DosageInstruction) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut field = |label: &str, value: String| fields.push((label.to_string(), value));
+
+    if !dosage.text.is_empty() {
+        field("Instructions", dosage.text.clone());
+    }
+    for dose in dosage.dose_and_rate.iter().flatten() {
+        if let Some(ref quantity) = dose.dose_quantity {
+            field("Dose", format_quantity(quantity));
+        }
+    }
+    if let Some(ref route) = dosage.route {
+        field("Route", concept_text(route));
+    }
+    if let Some(repeat) = dosage.timing.as_ref().and_then(|timing| timing.repeat.as_ref()) {
+        if let Some(frequency) = repeat.frequency {
+            let period = repeat.period.unwrap_or(1);
+            let unit = repeat.period_unit.as_deref().unwrap_or("d");
+            let times = if frequency == 1 { "once".to_string() } else { format!("{} times", frequency) };
+            let every = if period == 1 {
+                format!("per {}", time_unit(unit, 1))
+            } else {
+                format!("every {} {}", period, time_unit(unit, period))
+            };
+            field("Frequency", format!("{} {}", times, every));
+        }
+        if let Some(duration) = repeat.duration {
+            let unit = repeat.duration_unit.as_deref().unwrap_or("d");
+            field("Duration", format!("{} {}", duration, time_unit(unit, duration)));
+        }
+        if let Some(ref bounds) = repeat.bounds_period {
+            field("Course", format_period(bounds));
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_markup() {
+        let document = parse_markup(
+            "# Referral\n\n## Patient\n- Name: Jane Roe\n- Note: seen at 10:30\nFree text\n\n\
+             ## Results\n| Test | Value |\n| Hb | 13.5 |\n\n## Empty\n| Test | Value |\n",
+        );
+
+        assert_eq!(document.title, "Referral");
+        assert_eq!(document.sections.len(), 2);
+        assert_eq!(document.sections[0].rows, vec![
+            Row::Field { label: "Name".to_string(), value: "Jane Roe".to_string() },
+            Row::Field { label: "Note".to_string(), value: "seen at 10:30".to_string() },
+            Row::Text("Free text".to_string()),
+        ]);
+        assert_eq!(document.sections[1].rows, vec![Row::Table {
+            columns: vec!["Test".to_string(), "Value".to_string()],
+            rows: vec![vec!["Hb".to_string(), "13.5".to_string()]],
+        }]);
+    }
+
+    #[test]
+    fn test_values_cannot_add_structure() {
+        let mut bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        for entry in &mut bundle.entry {
+            match &mut entry.resource {
+                Resource::Patient(patient) => patient.name[0].family = "Doe | Smith\n## Injected".to_string(),
+                Resource::Condition(condition) => condition.code.text = Some("- Diagnosis: none".to_string()),
+                _ => {}
+            }
+        }
+
+        let mut templates = Templates::new();
+        templates.add_template("notes", "# {{ patient.name[0].family }}\n## Notes\n{{ conditions[0].code.text }}\n\
+            - {{ conditions[0].code.text }}: 10:30\n| A | B |\n| {{ patient.name[0].family }} | x |\n").unwrap();
+        let document = templates.render("notes", &bundle, ClaimType::Other, "").unwrap();
+        assert_eq!(document.title, "Doe | Smith ## Injected");
+        assert_eq!(document.sections.len(), 1);
+        assert_eq!(document.sections[0].rows, vec![
+            Row::Text("- Diagnosis: none".to_string()),
+            Row::Field { label: "- Diagnosis: none".to_string(), value: "10:30".to_string() },
+            Row::Table {
+                columns: vec!["A".to_string(), "B".to_string()],
+                rows: vec![vec!["Doe | Smith ## Injected".to_string(), "x".to_string()]],
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_default_templates_for_every_claim_type() {
+        let templates = Templates::new();
+        let mut bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        bundle.add_entry(serde_json::from_str(
+            r#"{"resourceType": "Immunization", "id": "imm-1", "status": "completed",
+                "vaccineCode": {"coding": [{"code": "207", "display": "COVID-19 mRNA"}]},
+                "occurrenceDateTime": "2024-01-02"}"#,
+        ).unwrap());
+
+        let vaccination = templates.render("vaccination", &bundle, ClaimType::Vaccination, "").unwrap();
+        let immunizations = vaccination.sections.iter().find(|section| section.heading == "Immunizations").unwrap();
+        assert_eq!(immunizations.rows, vec![Row::Table {
+            columns: vec!["Vaccine".to_string(), "Date".to_string(), "Lot".to_string(), "Status".to_string()],
+            rows: vec![vec!["COVID-19 mRNA".to_string(), "2024-01-02".to_string(), String::new(), "completed".to_string()]],
+        }]);
+
+        for claim_type in ClaimType::ALL {
+            let document = templates
+                .render(&template_name(claim_type), &bundle, claim_type, "2024-07-30T11:00:00Z")
+                .unwrap();
+            assert!(!document.title.is_empty(), "{} has no title", claim_type);
+            assert!(!document.sections.is_empty(), "{} is empty", claim_type);
+        }
+    }
+
+    #[test]
+    fn test_custom_template_and_errors() {
+        let mut templates = Templates::new();
+        templates.add_template("referral", "# Referral\n## To\n- Patient: {{ patient.name[0]|name }}\n").unwrap();
+        assert!(templates.names().contains(&"referral".to_string()));
+        assert!(templates.add_template("broken", "{% if %}").is_err());
+
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let document = templates.render("referral", &bundle, ClaimType::Other, "").unwrap();
+        assert_eq!(document.sections[0].rows[0], Row::Field {
+            label: "Patient".to_string(),
+            value: "John Michael Doe".to_string(),
+        });
+        assert!(matches!(templates.render("missing", &bundle, ClaimType::Other, ""), Err(Error::Render { .. })));
+    }
+}
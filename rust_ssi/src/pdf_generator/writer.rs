@@ -0,0 +1,401 @@
+//! Lays out a `Document` on A4 pages and writes it as a PDF using the
+//! standard Helvetica fonts, so no font files need to be embedded.
+
+use super::{Document, PageInfo, QrMatrix, Row};
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str, TextStr};
+
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN_X: f32 = 56.0;
+const CONTENT_TOP: f32 = PAGE_HEIGHT - 84.0;
+const CONTENT_BOTTOM: f32 = 72.0;
+const LABEL_WIDTH: f32 = 130.0;
+const CELL_PADDING: f32 = 12.0;
+const QR_SIZE: f32 = 120.0;
+
+const TITLE_SIZE: f32 = 18.0;
+const HEADING_SIZE: f32 = 12.0;
+const BODY_SIZE: f32 = 10.0;
+const SMALL_SIZE: f32 = 8.0;
+const LINE_HEIGHT: f32 = 14.0;
+
+#[derive(Clone, Copy)]
+enum Font {
+    Regular,
+    Bold,
+}
+
+impl Font {
+    fn resource_name(self) -> Name<'static> {
+        match self {
+            Font::Regular => Name(b"F1"),
+            Font::Bold => Name(b"F2"),
+        }
+    }
+}
+
+enum Op {
+    Text { x: f32, y: f32, font: Font, size: f32, gray: f32, text: String },
+    Rule { y: f32, gray: f32 },
+    Rect { x: f32, y: f32, width: f32, height: f32 },
+}
+
+/// Places text top to bottom, starting a new page when the current one is full
+struct Layout {
+    pages: Vec<Vec<Op>>,
+    y: f32,
+}
+
+impl Layout {
+    fn new() -> Self {
+        Self {
+            pages: vec![Vec::new()],
+            y: CONTENT_TOP,
+        }
+    }
+
+    /// Make room for `height` points, breaking the page if necessary.
+    /// Returns whether a new page was started.
+    fn reserve(&mut self, height: f32) -> bool {
+        if self.y - height < CONTENT_BOTTOM {
+            self.pages.push(Vec::new());
+            self.y = CONTENT_TOP;
+            return true;
+        }
+        false
+    }
+
+    fn push(&mut self, op: Op) {
+        self.pages.last_mut().expect("layout always has a page").push(op);
+    }
+
+    fn text(&mut self, x: f32, font: Font, size: f32, text: String) {
+        self.push(Op::Text { x, y: self.y, font, size, gray: 0.0, text });
+    }
+
+    fn title(&mut self, title: &str) {
+        self.reserve(TITLE_SIZE + LINE_HEIGHT);
+        self.y -= TITLE_SIZE;
+        self.text(MARGIN_X, Font::Bold, TITLE_SIZE, title.to_string());
+        self.y -= LINE_HEIGHT;
+    }
+
+    fn heading(&mut self, heading: &str) {
+        // Keep a heading together with at least its first row
+        self.reserve(LINE_HEIGHT * 3.0);
+        self.y -= LINE_HEIGHT;
+        self.text(MARGIN_X, Font::Bold, HEADING_SIZE, heading.to_string());
+        self.y -= 4.0;
+        self.push(Op::Rule { y: self.y, gray: 0.6 });
+        self.y -= LINE_HEIGHT;
+    }
+
+    fn field(&mut self, label: &str, value: &str) {
+        let value_x = MARGIN_X + LABEL_WIDTH;
+        let lines = wrap(value, Font::Regular, BODY_SIZE, PAGE_WIDTH - MARGIN_X - value_x);
+        for (index, line) in lines.into_iter().enumerate() {
+            self.reserve(LINE_HEIGHT);
+            if index == 0 {
+                self.text(MARGIN_X, Font::Bold, BODY_SIZE, format!("{}:", label));
+            }
+            self.text(value_x, Font::Regular, BODY_SIZE, line);
+            self.y -= LINE_HEIGHT;
+        }
+    }
+
+    fn paragraph(&mut self, text: &str) {
+        for line in wrap(text, Font::Regular, BODY_SIZE, PAGE_WIDTH - 2.0 * MARGIN_X) {
+            self.reserve(LINE_HEIGHT);
+            self.text(MARGIN_X, Font::Regular, BODY_SIZE, line);
+            self.y -= LINE_HEIGHT;
+        }
+    }
+
+    /// Draw a QR code as filled squares, one per dark module
+    fn qr(&mut self, code: &QrMatrix) {
+        self.reserve(QR_SIZE + LINE_HEIGHT);
+        self.y -= 4.0;
+        let module = QR_SIZE / code.width as f32;
+        for y in 0..code.width {
+            for x in 0..code.width {
+                if code.is_dark(x, y) {
+                    self.push(Op::Rect {
+                        x: MARGIN_X + x as f32 * module,
+                        y: self.y - (y + 1) as f32 * module,
+                        width: module,
+                        height: module,
+                    });
+                }
+            }
+        }
+        self.y -= QR_SIZE + LINE_HEIGHT;
+    }
+
+    /// Draw a table, repeating the header row at the top of each new page
+    fn table(&mut self, columns: &[String], rows: &[Vec<String>]) {
+        let widths = column_widths(columns, rows);
+        let header: Vec<Vec<String>> = columns.iter().map(|column| vec![column.clone()]).collect();
+
+        self.reserve(LINE_HEIGHT * 2.0);
+        self.table_row(&header, &widths, Font::Bold);
+        for row in rows {
+            let cells: Vec<Vec<String>> = row.iter().zip(&widths)
+                .map(|(cell, width)| wrap(cell, Font::Regular, BODY_SIZE, width - CELL_PADDING))
+                .collect();
+            let height = cells.iter().map(Vec::len).max().unwrap_or(1) as f32 * LINE_HEIGHT;
+            if self.reserve(height) {
+                self.table_row(&header, &widths, Font::Bold);
+            }
+            self.table_row(&cells, &widths, Font::Regular);
+        }
+    }
+
+    fn table_row(&mut self, cells: &[Vec<String>], widths: &[f32], font: Font) {
+        let top = self.y;
+        let mut bottom = top;
+        let mut x = MARGIN_X;
+        for (lines, width) in cells.iter().zip(widths) {
+            self.y = top;
+            for line in lines {
+                self.text(x, font, BODY_SIZE, line.clone());
+                self.y -= LINE_HEIGHT;
+            }
+            bottom = bottom.min(self.y);
+            x += width;
+        }
+        self.y = bottom;
+        if let Font::Bold = font {
+            self.push(Op::Rule { y: self.y + LINE_HEIGHT - 3.0, gray: 0.6 });
+        }
+    }
+}
+
+/// Size columns to their content, shrinking the first column when the
+/// table would be wider than the page
+fn column_widths(columns: &[String], rows: &[Vec<String>]) -> Vec<f32> {
+    let available = PAGE_WIDTH - 2.0 * MARGIN_X;
+    let mut widths: Vec<f32> = columns.iter().enumerate().map(|(i, column)| {
+        rows.iter()
+            .filter_map(|row| row.get(i))
+            .map(|cell| text_width(cell, Font::Regular, BODY_SIZE))
+            .fold(text_width(column, Font::Bold, BODY_SIZE), f32::max) + CELL_PADDING
+    }).collect();
+
+    let total: f32 = widths.iter().sum();
+    if total > available {
+        let rest = total - widths[0];
+        widths[0] = (available - rest).max(available / widths.len() as f32);
+    }
+    widths
+}
+
+/// Lay out `document` and serialize it to PDF bytes
+pub fn write_pdf(document: &Document, info: &PageInfo) -> Vec<u8> {
+    let mut layout = Layout::new();
+    layout.title(&document.title);
+    for section in &document.sections {
+        layout.heading(&section.heading);
+        for row in &section.rows {
+            match row {
+                Row::Field { label, value } => layout.field(label, value),
+                Row::Text(text) => layout.paragraph(text),
+                Row::Table { columns, rows } => layout.table(columns, rows),
+                Row::Qr(code) => layout.qr(code),
+            }
+        }
+    }
+
+    let page_count = layout.pages.len();
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let regular_id = Ref::new(3);
+    let bold_id = Ref::new(4);
+    let info_id = Ref::new(5);
+    let page_ids: Vec<Ref> = (0..page_count).map(|i| Ref::new(6 + 2 * i as i32)).collect();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id).kids(page_ids.iter().copied()).count(page_count as i32);
+    pdf.type1_font(regular_id)
+        .base_font(Name(b"Helvetica"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+    pdf.type1_font(bold_id)
+        .base_font(Name(b"Helvetica-Bold"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+
+    let mut doc_info = pdf.document_info(info_id);
+    doc_info.title(TextStr(&document.title));
+    doc_info.subject(TextStr(&format!("FHIR Bundle {}", info.bundle_id)));
+    doc_info.creator(TextStr("rust_ssi"));
+    doc_info.finish();
+
+    for (index, (ops, page_id)) in layout.pages.iter_mut().zip(&page_ids).enumerate() {
+        add_header_footer(ops, &document.title, info, index + 1, page_count);
+
+        let content_id = Ref::new(page_id.get() + 1);
+        let mut page = pdf.page(*page_id);
+        page.media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        page.resources()
+            .fonts()
+            .pair(Font::Regular.resource_name(), regular_id)
+            .pair(Font::Bold.resource_name(), bold_id);
+        page.finish();
+
+        pdf.stream(content_id, &render_ops(ops));
+    }
+
+    pdf.finish()
+}
+
+fn add_header_footer(ops: &mut Vec<Op>, title: &str, info: &PageInfo, page: usize, page_count: usize) {
+    let header_y = PAGE_HEIGHT - 40.0;
+    let footer_y = 40.0;
+    let small = |x: f32, y: f32, font: Font, text: String| Op::Text { x, y, font, size: SMALL_SIZE, gray: 0.3, text };
+    let right = |text: &str| PAGE_WIDTH - MARGIN_X - text_width(text, Font::Regular, SMALL_SIZE);
+
+    let bundle = format!("Bundle {}", info.bundle_id);
+    ops.push(small(MARGIN_X, header_y, Font::Bold, title.to_string()));
+    ops.push(small(right(&bundle), header_y, Font::Regular, bundle));
+    ops.push(Op::Rule { y: header_y - 6.0, gray: 0.3 });
+
+    let generated = format!("Generated {}", info.generated_at);
+    let numbering = format!("Page {} of {}", page, page_count);
+    ops.push(Op::Rule { y: footer_y + 12.0, gray: 0.3 });
+    ops.push(small(MARGIN_X, footer_y, Font::Regular, generated));
+    ops.push(small(right(&numbering), footer_y, Font::Regular, numbering));
+}
+
+fn render_ops(ops: &[Op]) -> Vec<u8> {
+    let mut content = Content::new();
+    for op in ops {
+        match op {
+            Op::Text { x, y, font, size, gray, text } => {
+                content.set_fill_gray(*gray);
+                content.begin_text();
+                content.set_font(font.resource_name(), *size);
+                content.next_line(*x, *y);
+                content.show(Str(&encode(text)));
+                content.end_text();
+            }
+            Op::Rect { x, y, width, height } => {
+                content.set_fill_gray(0.0);
+                content.rect(*x, *y, *width, *height);
+                content.fill_nonzero();
+            }
+            Op::Rule { y, gray } => {
+                content.set_stroke_gray(*gray);
+                content.set_line_width(0.5);
+                content.move_to(MARGIN_X, *y);
+                content.line_to(PAGE_WIDTH - MARGIN_X, *y);
+                content.stroke();
+            }
+        }
+    }
+    content.finish()
+}
+
+/// Break `text` into lines no wider than `max_width` points
+fn wrap(text: &str, font: Font, size: f32, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let candidate = if line.is_empty() { word.to_string() } else { format!("{} {}", line, word) };
+        if text_width(&candidate, font, size) <= max_width {
+            line = candidate;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        // Hard-break words that do not fit on a line of their own
+        for ch in word.chars() {
+            line.push(ch);
+            if text_width(&line, font, size) > max_width && line.chars().count() > 1 {
+                let last = line.pop().expect("line has at least two chars");
+                lines.push(std::mem::take(&mut line));
+                line.push(last);
+            }
+        }
+    }
+
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+fn text_width(text: &str, font: Font, size: f32) -> f32 {
+    let units: u32 = text.chars().map(|ch| char_width(ch, font) as u32).sum();
+    units as f32 * size / 1000.0
+}
+
+/// Advance widths (1/1000 em) for printable ASCII, from the Adobe AFM metrics
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278,
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556,
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778,
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556,
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556,
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+const HELVETICA_BOLD_WIDTHS: [u16; 95] = [
+    278, 333, 474, 556, 556, 889, 722, 238, 333, 333, 389, 584, 278, 333, 278, 278,
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 333, 333, 584, 584, 584, 611,
+    975, 722, 722, 722, 722, 667, 611, 778, 722, 278, 556, 722, 611, 833, 722, 778,
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 333, 278, 333, 584, 556,
+    333, 556, 611, 556, 611, 556, 333, 611, 611, 278, 278, 556, 278, 889, 611, 611,
+    611, 611, 389, 556, 333, 611, 556, 778, 556, 556, 500, 389, 280, 389, 584,
+];
+
+fn char_width(ch: char, font: Font) -> u16 {
+    let widths = match font {
+        Font::Regular => &HELVETICA_WIDTHS,
+        Font::Bold => &HELVETICA_BOLD_WIDTHS,
+    };
+    match ch {
+        ' '..='~' => widths[ch as usize - 32],
+        _ => 556,
+    }
+}
+
+/// Encode text as WinAnsi, replacing characters the encoding cannot represent
+fn encode(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|ch| match ch {
+            ' '..='~' | '\u{a0}'..='\u{ff}' => ch as u8,
+            '\u{20ac}' => 0x80,
+            '\u{2018}' => 0x91,
+            '\u{2019}' => 0x92,
+            '\u{201c}' => 0x93,
+            '\u{201d}' => 0x94,
+            '\u{2022}' => 0x95,
+            '\u{2013}' => 0x96,
+            '\u{2014}' => 0x97,
+            _ => b'?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_respects_width() {
+        let text = "Take 1 capsule by mouth three times a day for 10 days with food";
+        let lines = wrap(text, Font::Regular, BODY_SIZE, 120.0);
+
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| text_width(line, Font::Regular, BODY_SIZE) <= 120.0));
+        assert_eq!(lines.join(" "), text);
+    }
+
+    #[test]
+    fn test_encode_win_ansi() {
+        assert_eq!(encode("Café – ok ✓"), b"Caf\xe9 \x96 ok ?".to_vec());
+    }
+}
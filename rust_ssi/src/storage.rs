@@ -1,183 +1,306 @@
+//! Bundle storage behind the [`BundleStore`] trait, with an in-memory
+//! backend, an embedded SQLite backend that persists across restarts and,
+//! with the `mongodb` feature, a MongoDB backend. [`Storage`] is a cloneable
+//! handle for sharing one store between tasks.
+//!
+//! With the `encryption` feature, the persistent backends can seal every
+//! record under a [`MasterKey`] so that no PHI reaches the disk in cleartext.
+//!
+//! An [`Archive`] backs up any store to portable NDJSON files and restores
+//! them into any other.
+//!
+//! A [`Retention`] policy purges bundles once their retention period has
+//! passed, except those of patients placed under legal hold.
+
+use crate::error::Result;
 use crate::models::*;
-use serde_json::Value;
-use std::collections::HashMap;
+use async_trait::async_trait;
+use std::ops::Deref;
+use std::sync::Arc;
+
+mod backup;
+mod codec;
+mod compartment;
+mod memory;
+mod retention;
+mod search;
+mod sqlite;
+mod stats;
+
+#[cfg(feature = "mongodb")]
+mod mongo;
+
+#[cfg(test)]
+mod conformance;
+
+pub use backup::{Archive, ArchiveFile, ArchiveManifest, ImportReport, OnCollision, ARCHIVE_FORMAT};
+pub use compartment::{PatientCompartment, PATIENT_COMPARTMENT};
+pub use memory::MemoryStore;
+pub use retention::{Retention, RetentionPolicy, RetentionReport, RetentionRule, RetentionScope};
+pub use search::{SearchMatch, SearchPage, SearchQuery, DEFAULT_COUNT};
+pub use sqlite::SqliteStore;
+pub use stats::BundleStats;
+
+#[cfg(feature = "mongodb")]
+pub use mongo::MongoStore;
 
-// TODO: Add MongoDB driver dependencies in Cargo.toml
-// use mongodb::{Client, Collection, Database};
-// use bson::{doc, Document};
+#[cfg(feature = "encryption")]
+pub use codec::MasterKey;
 
+/// A cloneable handle to a bundle store. Clones share the same store, so it
+/// can be handed to any number of tokio tasks or request handlers; the
+/// [`BundleStore`] methods are reached through `Deref`.
+#[derive(Clone)]
 pub struct Storage {
-    // TODO: Add MongoDB client
-    // client: Client,
-    // database: Database,
-    // For MVP, we'll use in-memory storage
-    bundles: HashMap<String, Bundle>,
+    store: Arc<dyn BundleStore>,
 }
 
 impl Storage {
+    /// A handle to a new, empty in-memory store
     pub fn new() -> Self {
-        Self {
-            bundles: HashMap::new(),
-        }
+        Self::from_store(MemoryStore::new())
     }
 
-    /// Store a FHIR Bundle in the database
-    pub async fn store_bundle(&mut self, bundle: Bundle) -> Result<String, Box<dyn std::error::Error>> {
-        let bundle_id = bundle.id.clone();
-        self.bundles.insert(bundle_id.clone(), bundle);
-        
-        println!("💾 Stored FHIR Bundle: {}", bundle_id);
-        Ok(bundle_id)
+    pub fn from_store(store: impl BundleStore + 'static) -> Self {
+        Self { store: Arc::new(store) }
     }
+}
 
-    /// Retrieve a FHIR Bundle by ID
-    pub async fn get_bundle(&self, bundle_id: &str) -> Result<Option<&Bundle>, Box<dyn std::error::Error>> {
-        Ok(self.bundles.get(bundle_id))
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+impl Deref for Storage {
+    type Target = dyn BundleStore;
 
-    /// List all stored bundles
-    pub async fn list_bundles(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        Ok(self.bundles.keys().cloned().collect())
+    fn deref(&self) -> &Self::Target {
+        &*self.store
     }
+}
 
-    /// Delete a FHIR Bundle
-    pub async fn delete_bundle(&mut self, bundle_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let existed = self.bundles.remove(bundle_id).is_some();
-        if existed {
-            println!("🗑️ Deleted FHIR Bundle: {}", bundle_id);
-        }
-        Ok(existed)
+/// Operations every bundle storage backend provides. Backends are safe to
+/// call concurrently from many tasks.
+///
+/// Every write creates a new version of the bundle. Deleting records a
+/// tombstone version, so earlier versions remain readable through the history.
+#[async_trait]
+pub trait BundleStore: Send + Sync {
+    /// Store a FHIR Bundle as a new version, setting `meta.versionId` and
+    /// `meta.lastUpdated`
+    async fn store_bundle(&self, bundle: Bundle) -> Result<String> {
+        self.store_bundle_if_match(bundle, None).await
     }
 
-    /// Search bundles by patient ID
-    pub async fn search_by_patient(&self, patient_id: &str) -> Result<Vec<&Bundle>, Box<dyn std::error::Error>> {
-        let mut results = Vec::new();
-        
-        for bundle in self.bundles.values() {
-            for entry in &bundle.entry {
-                if let Resource::Patient(patient) = &entry.resource {
-                    if patient.id == patient_id {
-                        results.push(bundle);
-                        break;
-                    }
-                }
-            }
-        }
-        
-        Ok(results)
+    /// Store a new version only if the bundle's current `meta.versionId` is
+    /// `if_match`, like an HTTP `If-Match` request; `None` always writes.
+    /// Fails with [`Error::Conflict`](crate::Error::Conflict) if another
+    /// write got there first.
+    async fn store_bundle_if_match(&self, bundle: Bundle, if_match: Option<&str>) -> Result<String>;
+
+    /// Retrieve the current version of a bundle; `None` if it never existed or was deleted
+    async fn get_bundle(&self, bundle_id: &str) -> Result<Option<Bundle>>;
+
+    /// List the ids of all bundles that are not deleted, in ascending order
+    async fn list_bundles(&self) -> Result<Vec<String>>;
+
+    /// Delete a FHIR Bundle by recording a tombstone, returning whether it existed
+    async fn delete_bundle(&self, bundle_id: &str) -> Result<bool> {
+        self.delete_bundle_if_match(bundle_id, None).await
     }
 
-    /// Search bundles by practitioner ID
-    pub async fn search_by_practitioner(&self, practitioner_id: &str) -> Result<Vec<&Bundle>, Box<dyn std::error::Error>> {
-        let mut results = Vec::new();
-        
-        for bundle in self.bundles.values() {
-            for entry in &bundle.entry {
-                if let Resource::Practitioner(practitioner) = &entry.resource {
-                    if practitioner.id == practitioner_id {
-                        results.push(bundle);
-                        break;
-                    }
-                }
-            }
-        }
-        
-        Ok(results)
+    /// Delete a bundle only if its current `meta.versionId` is `if_match`;
+    /// `None` always deletes. Fails with
+    /// [`Error::LegalHold`](crate::Error::LegalHold) if the bundle holds or
+    /// refers to a patient under legal hold.
+    async fn delete_bundle_if_match(&self, bundle_id: &str, if_match: Option<&str>) -> Result<bool>;
+
+    /// Erase every version of a bundle, leaving no history behind, and return
+    /// whether it had any. Fails like a delete if any version holds or refers
+    /// to a patient under legal hold.
+    async fn purge_bundle(&self, bundle_id: &str) -> Result<bool>;
+
+    /// Place a patient's records under legal hold, or release them
+    async fn set_legal_hold(&self, patient_id: &str, held: bool) -> Result<()>;
+
+    /// Whether a patient's records are under legal hold
+    async fn is_on_legal_hold(&self, patient_id: &str) -> Result<bool>;
+
+    /// Bundles containing the Patient with this id, ordered by bundle id
+    async fn search_by_patient(&self, patient_id: &str) -> Result<Vec<Bundle>>;
+
+    /// Bundles containing the Practitioner with this id, ordered by bundle id
+    async fn search_by_practitioner(&self, practitioner_id: &str) -> Result<Vec<Bundle>>;
+
+    /// Statistics over the current version of every bundle, maintained as
+    /// bundles are written rather than computed by scanning the store
+    async fn get_statistics(&self) -> Result<BundleStats>;
+
+    /// Search the resources in the current version of every bundle
+    async fn search(&self, query: &SearchQuery) -> Result<SearchPage>;
+
+    /// The patient and every resource referring to it across all bundles,
+    /// like `Patient/$everything`. Fails with
+    /// [`Error::NotFound`](crate::Error::NotFound) if no bundle holds the patient.
+    async fn patient_everything(&self, patient_id: &str) -> Result<PatientCompartment> {
+        compartment::gather(self, patient_id).await
+    }
+
+    /// Every version of a bundle, including tombstones, newest first
+    async fn bundle_history(&self, bundle_id: &str) -> Result<Vec<HistoryEntry<Bundle>>>;
+
+    /// Every version of a resource across the bundles that contained it, newest first
+    async fn resource_history(&self, resource_type: &str, resource_id: &str) -> Result<Vec<HistoryEntry<Resource>>>;
+
+    /// Read a specific version of a bundle; `None` if there is no such version or it is a tombstone
+    async fn get_bundle_version(&self, bundle_id: &str, version_id: &str) -> Result<Option<Bundle>> {
+        let history = self.bundle_history(bundle_id).await?;
+        Ok(history
+            .into_iter()
+            .find(|entry| entry.version_id == version_id)
+            .and_then(|entry| entry.content))
+    }
+}
+
+/// One version in a bundle or resource history
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry<T> {
+    pub bundle_id: String,
+    pub version_id: String,
+    pub last_updated: String,
+    /// `None` for a deletion tombstone
+    pub content: Option<T>,
+}
+
+impl<T> HistoryEntry<T> {
+    pub fn is_deleted(&self) -> bool {
+        self.content.is_none()
+    }
+}
+
+/// Check an `If-Match` version against the current version of a bundle,
+/// `None` if it does not exist or is deleted
+fn check_version(bundle_id: &str, if_match: Option<&str>, current: Option<u64>) -> Result<()> {
+    match if_match {
+        Some(expected) if current.map(|version| version.to_string()).as_deref() != Some(expected) => Err(
+            crate::error::Error::conflict(bundle_id, expected, current.map(|version| version.to_string())),
+        ),
+        _ => Ok(()),
     }
+}
 
-    /// Get bundle statistics
-    pub async fn get_statistics(&self) -> Result<BundleStats, Box<dyn std::error::Error>> {
-        let total_bundles = self.bundles.len();
-        let mut patient_count = 0;
-        let mut practitioner_count = 0;
-        let mut encounter_count = 0;
-        let mut observation_count = 0;
-        let mut condition_count = 0;
-        let mut medication_count = 0;
-        
-        for bundle in self.bundles.values() {
-            for entry in &bundle.entry {
-                match &entry.resource {
-                    Resource::Patient(_) => patient_count += 1,
-                    Resource::Practitioner(_) => practitioner_count += 1,
-                    Resource::Encounter(_) => encounter_count += 1,
-                    Resource::Observation(_) => observation_count += 1,
-                    Resource::Condition(_) => condition_count += 1,
-                    Resource::MedicationRequest(_) => medication_count += 1,
-                }
+/// Fail if any patient of `bundles` is under legal hold according to `held`
+fn check_legal_hold<'a>(bundles: impl IntoIterator<Item = &'a Bundle>, held: impl Fn(&str) -> Result<bool>) -> Result<()> {
+    for bundle in bundles {
+        for patient_id in search::bundle_patients(bundle) {
+            if held(&patient_id)? {
+                return Err(crate::error::Error::legal_hold(patient_id));
             }
         }
-        
-        Ok(BundleStats {
-            total_bundles,
-            patient_count,
-            practitioner_count,
-            encounter_count,
-            observation_count,
-            condition_count,
-            medication_count,
-        })
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub struct BundleStats {
-    pub total_bundles: usize,
-    pub patient_count: usize,
-    pub practitioner_count: usize,
-    pub encounter_count: usize,
-    pub observation_count: usize,
-    pub condition_count: usize,
-    pub medication_count: usize,
+/// Set `meta` on a bundle being written as `version`, keeping its tags, and
+/// return the timestamp used
+fn stamp_version(bundle: &mut Bundle, version: u64) -> String {
+    let last_updated = crate::utils::get_current_timestamp();
+    let meta = bundle.meta.get_or_insert_with(Meta::default);
+    meta.version_id = version.to_string();
+    meta.last_updated = last_updated.clone();
+    last_updated
 }
 
-impl std::fmt::Display for BundleStats {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Bundle Statistics:\n")?;
-        write!(f, "  Total Bundles: {}\n", self.total_bundles)?;
-        write!(f, "  Patients: {}\n", self.patient_count)?;
-        write!(f, "  Practitioners: {}\n", self.practitioner_count)?;
-        write!(f, "  Encounters: {}\n", self.encounter_count)?;
-        write!(f, "  Observations: {}\n", self.observation_count)?;
-        write!(f, "  Conditions: {}\n", self.condition_count)?;
-        write!(f, "  Medications: {}\n", self.medication_count)?;
-        Ok(())
+/// Build a resource's history from the histories of the bundles that ever
+/// contained it. A bundle version that drops the resource, or deletes the
+/// bundle, after one that contained it counts as a deletion of the resource.
+fn resource_history_from(
+    resource_type: &str,
+    resource_id: &str,
+    bundle_histories: Vec<Vec<HistoryEntry<Bundle>>>,
+) -> Vec<HistoryEntry<Resource>> {
+    let mut history = Vec::new();
+
+    for mut bundle_history in bundle_histories {
+        bundle_history.sort_by_key(|entry| entry.version_id.parse::<u64>().unwrap_or(0));
+        let mut contained = false;
+        for entry in bundle_history {
+            let resource = entry.content.as_ref().and_then(|bundle| {
+                bundle.entry.iter().map(|entry| &entry.resource).find(|resource| {
+                    resource.resource_type() == resource_type && resource.id() == resource_id
+                })
+            });
+            if resource.is_some() || contained {
+                history.push(HistoryEntry {
+                    bundle_id: entry.bundle_id.clone(),
+                    version_id: entry.version_id.clone(),
+                    last_updated: entry.last_updated.clone(),
+                    content: resource.cloned(),
+                });
+            }
+            contained = resource.is_some();
+        }
     }
+
+    history.sort_by(|a, b| {
+        let key = |entry: &HistoryEntry<Resource>| {
+            (entry.last_updated.clone(), entry.version_id.parse::<u64>().unwrap_or(0), entry.bundle_id.clone())
+        };
+        key(b).cmp(&key(a))
+    });
+    history
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::*;
-    
-    #[tokio::test]
-    async fn test_storage_operations() {
-        let mut storage = Storage::new();
-        
-        // Create a test bundle
-        let bundle = Bundle::new(
-            "test-bundle-1".to_string(),
-            "document".to_string(),
-            "2024-07-30T10:30:00Z".to_string(),
-        );
-        
-        // Test store and retrieve
-        let bundle_id = storage.store_bundle(bundle).await.unwrap();
-        assert_eq!(bundle_id, "test-bundle-1");
-        
-        let retrieved = storage.get_bundle(&bundle_id).await.unwrap();
-        assert!(retrieved.is_some());
-        
-        // Test list bundles
-        let bundles = storage.list_bundles().await.unwrap();
-        assert_eq!(bundles.len(), 1);
-        assert_eq!(bundles[0], "test-bundle-1");
-        
-        // Test delete
-        let deleted = storage.delete_bundle(&bundle_id).await.unwrap();
-        assert!(deleted);
-        
-        let retrieved_after_delete = storage.get_bundle(&bundle_id).await.unwrap();
-        assert!(retrieved_after_delete.is_none());
+    use crate::error::Error;
+
+    async fn concurrent_writers(storage: Storage) {
+        let writers: Vec<_> = (0..16)
+            .map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let bundle = Bundle::new(format!("bundle-{:02}", i), "document".to_string(), String::new());
+                    storage.store_bundle(bundle).await.unwrap();
+                    storage.get_bundle(&format!("bundle-{:02}", i)).await.unwrap().unwrap()
+                })
+            })
+            .collect();
+        for writer in writers {
+            assert_eq!(writer.await.unwrap().meta.unwrap().version_id, "1");
+        }
+        assert_eq!(storage.list_bundles().await.unwrap().len(), 16);
+
+        // Two editors read version 1 and race to save; exactly one wins
+        let editors: Vec<_> = (0..2)
+            .map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let mut bundle = storage.get_bundle("bundle-00").await.unwrap().unwrap();
+                    bundle.timestamp = format!("edit {}", i);
+                    storage.store_bundle_if_match(bundle, Some("1")).await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for editor in editors {
+            results.push(editor.await.unwrap());
+        }
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results.iter().any(|result| matches!(result, Err(Error::Conflict { .. }))));
+        assert_eq!(storage.bundle_history("bundle-00").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shared_memory_store() {
+        concurrent_writers(Storage::new()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shared_sqlite_store() {
+        let dir = tempfile::tempdir().unwrap();
+        concurrent_writers(Storage::from_store(SqliteStore::open(dir.path().join("shared.db")).unwrap())).await;
     }
 }
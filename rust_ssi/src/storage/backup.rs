@@ -0,0 +1,525 @@
+//! Backups of a store as a portable archive: a directory holding one NDJSON
+//! file of FHIR resources per resource type, the bundles that group them,
+//! and a manifest with the SHA-256 hash of every file.
+//!
+//! Only the current version of each bundle is archived. Importing stores
+//! each bundle as a new version, so histories restart in the target store.
+
+use super::BundleStore;
+use crate::error::{Error, ParseError, Result};
+use crate::models::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(feature = "encryption")]
+use crate::encryption::EHREncryption;
+
+/// `format` of the archives written by this version
+pub const ARCHIVE_FORMAT: &str = "rust_ssi-archive/1";
+
+const MANIFEST: &str = "manifest.json";
+const BUNDLES: &str = "bundles.ndjson";
+
+/// Length of the nonce leading each encrypted file
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// Describes the files of an archive. Written last, so an interrupted
+/// export leaves no manifest behind.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub format: String,
+    pub created: String,
+    /// Whether every file other than the manifest is encrypted
+    pub encrypted: bool,
+    pub bundle_count: usize,
+    pub files: Vec<ArchiveFile>,
+}
+
+/// One file of an archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub name: String,
+    /// Type of the resources in the file, `Bundle` for the bundle file
+    pub resource_type: String,
+    /// Number of lines
+    pub count: usize,
+    /// [`crate::utils::sha256_hash`] of the file as written, encrypted or not
+    pub sha256: String,
+}
+
+/// One line of `bundles.ndjson`: a bundle without its entries, which are
+/// the resources whose NDJSON lines have these hashes, in order
+#[derive(Serialize, Deserialize)]
+struct BundleLine {
+    bundle: Bundle,
+    entries: Vec<String>,
+}
+
+/// What to do with an archived bundle whose id is already in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnCollision {
+    /// Fail before importing anything
+    Fail,
+    /// Keep the stored bundle
+    Skip,
+    /// Store the archived bundle as a new version of the stored one
+    Overwrite,
+    /// Store the archived bundle under the first free id `<id>-<n>`
+    Rename,
+}
+
+/// The outcome of an import, by bundle id
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /// Bundles stored under their own id, including overwritten ones
+    pub imported: Vec<String>,
+    /// Bundles that replaced a stored bundle
+    pub overwritten: Vec<String>,
+    /// Archived id and the id the bundle was stored under
+    pub renamed: Vec<(String, String)>,
+    /// Bundles left out because their id was in use
+    pub skipped: Vec<String>,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Imported {} bundles", self.imported.len() + self.renamed.len())?;
+        writeln!(f, "  Overwritten: {}", self.overwritten.len())?;
+        for (from, to) in &self.renamed {
+            writeln!(f, "  Renamed: {} -> {}", from, to)?;
+        }
+        for id in &self.skipped {
+            writeln!(f, "  Skipped: {}", id)?;
+        }
+        Ok(())
+    }
+}
+
+/// An archive directory, optionally encrypted with an [`EHREncryption`] key
+pub struct Archive {
+    dir: PathBuf,
+    #[cfg(feature = "encryption")]
+    encryption: Option<EHREncryption>,
+}
+
+impl Archive {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+
+    /// Encrypt exported files with `encryption`, and decrypt imported ones
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, encryption: EHREncryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Write the current version of every bundle in `store`, creating the
+    /// directory if needed. Files of an earlier export that are not part of
+    /// this one are left in place but not listed in the manifest.
+    pub async fn export<S: BundleStore + ?Sized>(&self, store: &S) -> Result<ArchiveManifest> {
+        let mut bundles = Vec::new();
+        for bundle_id in store.list_bundles().await? {
+            // Skip bundles deleted since they were listed
+            bundles.extend(store.get_bundle(&bundle_id).await?);
+        }
+        self.export_bundles(bundles)
+    }
+
+    /// Write `bundles`, creating the directory if needed. Fails before
+    /// writing anything if a resource type cannot name a file.
+    pub fn export_bundles(&self, bundles: impl IntoIterator<Item = Bundle>) -> Result<ArchiveManifest> {
+        let mut resources: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut written = HashSet::new();
+        let mut lines = Vec::new();
+        let mut problems = Vec::new();
+        for mut bundle in bundles {
+            let mut entries = Vec::with_capacity(bundle.entry.len());
+            for entry in std::mem::take(&mut bundle.entry) {
+                if resource_file_name(entry.resource.resource_type()).is_none() {
+                    problems.push(format!(
+                        "bundle {} holds a resource of type '{}', which cannot name an archive file",
+                        bundle.id,
+                        entry.resource.resource_type()
+                    ));
+                }
+                let line = serde_json::to_string(&entry.resource).expect("resource serialization cannot fail");
+                let hash = crate::utils::sha256_hash(line.as_bytes());
+                if written.insert(hash.clone()) {
+                    resources.entry(entry.resource.resource_type().to_string()).or_default().push(line);
+                }
+                entries.push(hash);
+            }
+            lines.push(serde_json::to_string(&BundleLine { bundle, entries }).expect("bundle serialization cannot fail"));
+        }
+        if !problems.is_empty() {
+            return Err(Error::Validation(problems));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let bundle_count = lines.len();
+        let mut files = Vec::with_capacity(resources.len() + 1);
+        files.push(self.write_file(BUNDLES.to_string(), "Bundle".to_string(), &lines)?);
+        for (resource_type, lines) in resources {
+            let name = resource_file_name(&resource_type).expect("resource types are checked above");
+            files.push(self.write_file(name, resource_type, &lines)?);
+        }
+
+        let manifest = ArchiveManifest {
+            format: ARCHIVE_FORMAT.to_string(),
+            created: crate::utils::get_current_timestamp(),
+            encrypted: self.encrypted(),
+            bundle_count,
+            files,
+        };
+        let json = serde_json::to_string_pretty(&manifest).expect("manifest serialization cannot fail");
+        fs::write(self.dir.join(MANIFEST), json)?;
+        Ok(manifest)
+    }
+
+    fn write_file(&self, name: String, resource_type: String, lines: &[String]) -> Result<ArchiveFile> {
+        let content = self.seal(ndjson(lines))?;
+        fs::write(self.dir.join(&name), &content)?;
+        Ok(ArchiveFile {
+            name,
+            resource_type,
+            count: lines.len(),
+            sha256: crate::utils::sha256_hash(&content),
+        })
+    }
+
+    /// Read and check the manifest
+    pub fn manifest(&self) -> Result<ArchiveManifest> {
+        let json = fs::read_to_string(self.dir.join(MANIFEST))?;
+        let manifest: ArchiveManifest = serde_json::from_str(&json)
+            .map_err(|err| Error::Parse(ParseError::new(MANIFEST, err.to_string())))?;
+        if manifest.format != ARCHIVE_FORMAT {
+            return Err(Error::Parse(ParseError::new(
+                format!("{}.format", MANIFEST),
+                format!("unsupported archive format {}", manifest.format),
+            )));
+        }
+        Ok(manifest)
+    }
+
+    /// Store every archived bundle in `store`. The whole archive is read and
+    /// checked against its manifest first, so nothing is stored from an
+    /// archive with a missing, altered or inconsistent file.
+    pub async fn import<S: BundleStore + ?Sized>(&self, store: &S, on_collision: OnCollision) -> Result<ImportReport> {
+        let manifest = self.manifest()?;
+        let bundles = self.read(&manifest)?;
+
+        let mut collisions = Vec::new();
+        for bundle in &bundles {
+            if store.get_bundle(&bundle.id).await?.is_some() {
+                collisions.push(bundle.id.clone());
+            }
+        }
+        if on_collision == OnCollision::Fail {
+            if let Some(id) = collisions.first() {
+                let current = store.get_bundle(id).await?.and_then(|bundle| bundle.meta).map(|meta| meta.version_id);
+                return Err(Error::conflict(id.clone(), "none", current));
+            }
+        }
+
+        // Ids a renamed bundle must not take: those stored, and those of the
+        // archive, which may not be stored yet
+        let mut taken: HashSet<String> = bundles.iter().map(|bundle| bundle.id.clone()).collect();
+        let mut report = ImportReport::default();
+        for mut bundle in bundles {
+            let id = bundle.id.clone();
+            if !collisions.contains(&id) {
+                store.store_bundle(bundle).await?;
+                report.imported.push(id);
+                continue;
+            }
+            match on_collision {
+                OnCollision::Fail | OnCollision::Skip => report.skipped.push(id),
+                OnCollision::Overwrite => {
+                    store.store_bundle(bundle).await?;
+                    report.overwritten.push(id.clone());
+                    report.imported.push(id);
+                }
+                OnCollision::Rename => {
+                    let mut suffix = 1;
+                    while taken.contains(&format!("{}-{}", id, suffix))
+                        || store.get_bundle(&format!("{}-{}", id, suffix)).await?.is_some()
+                    {
+                        suffix += 1;
+                    }
+                    bundle.id = format!("{}-{}", id, suffix);
+                    taken.insert(bundle.id.clone());
+                    report.renamed.push((id, bundle.id.clone()));
+                    store.store_bundle(bundle).await?;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Read every file listed in `manifest` and rebuild the bundles
+    fn read(&self, manifest: &ArchiveManifest) -> Result<Vec<Bundle>> {
+        let mut problems = Vec::new();
+        let mut contents = Vec::with_capacity(manifest.files.len());
+        for file in &manifest.files {
+            if !is_file_name(&file.name) {
+                problems.push(format!("{} is not the name of a file in the archive", file.name));
+                continue;
+            }
+            let content = fs::read(self.dir.join(&file.name))?;
+            if crate::utils::sha256_hash(&content) != file.sha256 {
+                problems.push(format!("{} does not match its hash in the manifest", file.name));
+                continue;
+            }
+            let content = String::from_utf8(self.open(content, manifest.encrypted)?)
+                .map_err(|err| Error::Parse(ParseError::new(file.name.as_str(), err.to_string())))?;
+            let lines = content.lines().count();
+            if lines != file.count {
+                problems.push(format!("{} has {} lines, the manifest lists {}", file.name, lines, file.count));
+            }
+            contents.push((file, content));
+        }
+        if !problems.is_empty() {
+            return Err(Error::Validation(problems));
+        }
+
+        let mut resources = HashMap::new();
+        let mut bundle_lines = Vec::new();
+        for (file, content) in &contents {
+            for (number, line) in content.lines().enumerate() {
+                let path = format!("{}:{}", file.name, number + 1);
+                if file.name == BUNDLES {
+                    let line: BundleLine = serde_json::from_str(line)
+                        .map_err(|err| Error::Parse(ParseError::new(path, err.to_string())))?;
+                    bundle_lines.push(line);
+                    continue;
+                }
+                let resource: Resource = serde_json::from_str(line)
+                    .map_err(|err| Error::Parse(ParseError::new(path.as_str(), err.to_string())))?;
+                if resource.resource_type() != file.resource_type {
+                    problems.push(format!("{} holds a {}, not a {}", path, resource.resource_type(), file.resource_type));
+                }
+                resources.insert(crate::utils::sha256_hash(line.as_bytes()), resource);
+            }
+        }
+        if bundle_lines.len() != manifest.bundle_count {
+            problems.push(format!("{} bundles found, the manifest lists {}", bundle_lines.len(), manifest.bundle_count));
+        }
+
+        let mut bundles = Vec::with_capacity(bundle_lines.len());
+        for BundleLine { mut bundle, entries } in bundle_lines {
+            for hash in entries {
+                match resources.get(&hash) {
+                    Some(resource) => bundle.add_entry(resource.clone()),
+                    None => problems.push(format!("bundle {} refers to a resource not in the archive: {}", bundle.id, hash)),
+                }
+            }
+            bundles.push(bundle);
+        }
+        if !problems.is_empty() {
+            return Err(Error::Validation(problems));
+        }
+        Ok(bundles)
+    }
+
+    fn encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.encryption.is_some();
+        #[cfg(not(feature = "encryption"))]
+        return false;
+    }
+
+    /// Encrypt a file's content as the nonce followed by the ciphertext
+    fn seal(&self, content: Vec<u8>) -> Result<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            let (ciphertext, nonce) = encryption.encrypt(&content)?;
+            return Ok([nonce, ciphertext].concat());
+        }
+        Ok(content)
+    }
+
+    fn open(&self, content: Vec<u8>, encrypted: bool) -> Result<Vec<u8>> {
+        if !encrypted {
+            return Ok(content);
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            if content.len() < NONCE_LEN {
+                return Err(Error::crypto("encrypted archive file is too short"));
+            }
+            let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+            return encryption.decrypt(ciphertext, nonce);
+        }
+        Err(Error::crypto("archive is encrypted; import it with its key"))
+    }
+}
+
+/// The file holding resources of `resource_type`, if the type is a plain
+/// name that cannot clash with the bundle file. A nested `Bundle` resource
+/// goes to `Bundle.ndjson`, beside `bundles.ndjson`.
+fn resource_file_name(resource_type: &str) -> Option<String> {
+    let name = format!("{}.ndjson", resource_type);
+    let plain = !resource_type.is_empty() && resource_type.chars().all(|c| c.is_ascii_alphabetic());
+    (plain && !name.eq_ignore_ascii_case(BUNDLES)).then_some(name)
+}
+
+/// Whether `name` names a file directly inside the archive directory
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) && !name.contains(['/', '\\'])
+}
+
+fn ndjson(lines: &[String]) -> Vec<u8> {
+    lines.iter().flat_map(|line| [line.as_bytes(), b"\n"]).flatten().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStore, SqliteStore};
+
+    fn sample_store_bundles() -> (Bundle, Bundle) {
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let mut copy = Bundle::new("bp-copy".to_string(), "collection".to_string(), String::new());
+        copy.add_entry(bundle.entry[3].resource.clone());
+        (bundle, copy)
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_into_another_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let (bundle, copy) = sample_store_bundles();
+        let source = MemoryStore::new();
+        source.store_bundle(bundle.clone()).await.unwrap();
+        source.store_bundle(copy.clone()).await.unwrap();
+
+        let manifest = Archive::new(dir.path()).export(&source).await.unwrap();
+        assert_eq!(manifest.bundle_count, 2);
+        let observations = manifest.files.iter().find(|file| file.resource_type == "Observation").unwrap();
+        // The observation shared by both bundles is written once
+        assert_eq!((observations.name.as_str(), observations.count), ("Observation.ndjson", 1));
+        let line = fs::read_to_string(dir.path().join("Patient.ndjson")).unwrap();
+        assert_eq!(serde_json::from_str::<Resource>(line.trim()).unwrap(), bundle.entry[0].resource);
+
+        let target = SqliteStore::open_in_memory().unwrap();
+        let report = Archive::new(dir.path()).import(&target, OnCollision::Fail).await.unwrap();
+        assert_eq!(report.imported, vec!["bp-copy", "mvp-visit-bundle"]);
+        let imported = target.get_bundle(&bundle.id).await.unwrap().unwrap();
+        assert_eq!(imported.content_hash(), bundle.content_hash());
+        assert_eq!(target.get_bundle("bp-copy").await.unwrap().unwrap().entry, copy.entry);
+
+        // Importing again collides with every bundle
+        let archive = Archive::new(dir.path());
+        assert!(matches!(archive.import(&target, OnCollision::Fail).await, Err(Error::Conflict { .. })));
+        let report = archive.import(&target, OnCollision::Skip).await.unwrap();
+        assert_eq!(report.skipped.len(), 2);
+        let report = archive.import(&target, OnCollision::Rename).await.unwrap();
+        assert_eq!(report.renamed[1], ("mvp-visit-bundle".to_string(), "mvp-visit-bundle-1".to_string()));
+        let report = archive.import(&target, OnCollision::Overwrite).await.unwrap();
+        assert_eq!(report.overwritten.len(), 2);
+        let stored = target.get_bundle(&bundle.id).await.unwrap().unwrap();
+        assert_eq!(stored.meta.unwrap().version_id, "2");
+        assert_eq!(target.list_bundles().await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_rename_avoids_ids_of_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, copy) = sample_store_bundles();
+        let source = MemoryStore::new();
+        for id in ["a", "a-1"] {
+            source.store_bundle(Bundle { id: id.to_string(), ..copy.clone() }).await.unwrap();
+        }
+        Archive::new(dir.path()).export(&source).await.unwrap();
+
+        let target = MemoryStore::new();
+        target.store_bundle(Bundle { id: "a".to_string(), ..copy.clone() }).await.unwrap();
+        let report = Archive::new(dir.path()).import(&target, OnCollision::Rename).await.unwrap();
+        assert_eq!(report.renamed, vec![("a".to_string(), "a-2".to_string())]);
+        assert_eq!(report.imported, vec!["a-1"]);
+        for id in ["a", "a-1", "a-2"] {
+            assert_eq!(target.get_bundle(id).await.unwrap().unwrap().meta.unwrap().version_id, "1");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resource_types_cannot_misname_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = dir.path().join("archive");
+        let (bundle, _) = sample_store_bundles();
+        let mut message = Bundle::new("message".to_string(), "message".to_string(), String::new());
+        message.add_entry(Resource::Other {
+            resource_type: "Bundle".to_string(),
+            raw: serde_json::json!({ "resourceType": "Bundle", "id": "inner", "type": "collection" }),
+        });
+        message.add_entry(bundle.entry[0].resource.clone());
+
+        let manifest = Archive::new(&archive_dir).export_bundles([message.clone()]).unwrap();
+        let names: Vec<_> = manifest.files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, vec!["bundles.ndjson", "Bundle.ndjson", "Patient.ndjson"]);
+        let target = MemoryStore::new();
+        Archive::new(&archive_dir).import(&target, OnCollision::Fail).await.unwrap();
+        assert_eq!(target.get_bundle("message").await.unwrap().unwrap().entry, message.entry);
+
+        // A type that is not a plain name is refused before anything is written
+        let mut escape = message.clone();
+        escape.add_entry(Resource::Other { resource_type: "../x".to_string(), raw: serde_json::json!({ "resourceType": "../x" }) });
+        let result = Archive::new(dir.path().join("other")).export_bundles([escape]);
+        assert!(matches!(result, Err(Error::Validation(problems)) if problems[0].contains("'../x'")));
+        assert!(!dir.path().join("x.ndjson").exists() && !dir.path().join("other").exists());
+
+        // Names in the manifest must stay inside the archive
+        fs::write(dir.path().join("Patient.ndjson"), fs::read(archive_dir.join("Patient.ndjson")).unwrap()).unwrap();
+        let manifest = fs::read_to_string(archive_dir.join(MANIFEST)).unwrap().replace("\"Patient.ndjson\"", "\"../Patient.ndjson\"");
+        fs::write(archive_dir.join(MANIFEST), manifest).unwrap();
+        let result = Archive::new(&archive_dir).import(&MemoryStore::new(), OnCollision::Fail).await;
+        assert!(matches!(result, Err(Error::Validation(problems)) if problems[0].contains("../Patient.ndjson")));
+    }
+
+    #[tokio::test]
+    async fn test_import_rejects_altered_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = MemoryStore::new();
+        source.store_bundle(sample_store_bundles().0).await.unwrap();
+        Archive::new(dir.path()).export(&source).await.unwrap();
+
+        let path = dir.path().join("Condition.ndjson");
+        let altered = fs::read_to_string(&path).unwrap().replace("Hypertension", "Hypotension");
+        fs::write(&path, altered).unwrap();
+        let target = MemoryStore::new();
+        let result = Archive::new(dir.path()).import(&target, OnCollision::Fail).await;
+        assert!(matches!(result, Err(Error::Validation(problems)) if problems[0].contains("Condition.ndjson")));
+        assert!(target.list_bundles().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = MemoryStore::new();
+        source.store_bundle(sample_store_bundles().0).await.unwrap();
+        let key = EHREncryption::new().unwrap();
+        let archive = || Archive::new(dir.path()).with_encryption(EHREncryption::from_key(&key.get_key()).unwrap());
+
+        assert!(archive().export(&source).await.unwrap().encrypted);
+        let raw = fs::read(dir.path().join("Patient.ndjson")).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("patient-123"));
+
+        let target = MemoryStore::new();
+        let result = Archive::new(dir.path()).import(&target, OnCollision::Fail).await;
+        assert!(matches!(result, Err(Error::Crypto { .. })));
+        let wrong = Archive::new(dir.path()).with_encryption(EHREncryption::new().unwrap());
+        assert!(matches!(wrong.import(&target, OnCollision::Fail).await, Err(Error::Crypto { .. })));
+        assert_eq!(archive().import(&target, OnCollision::Fail).await.unwrap().imported, vec!["mvp-visit-bundle"]);
+    }
+}
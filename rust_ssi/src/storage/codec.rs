@@ -0,0 +1,464 @@
+//! How backends write records and search terms: as plain JSON or, with the
+//! `encryption` feature and a [`MasterKey`], sealed so that no PHI is stored
+//! in cleartext.
+//!
+//! Each sealed record is encrypted with its own AES-256-GCM data key, stored
+//! next to it wrapped by the master key, so rotating the master key re-wraps
+//! the data keys and leaves the payloads untouched. Resource ids and search
+//! terms are stored as keyed hashes and dates by year only: the index still
+//! answers equality lookups and narrows date ranges, and `search::execute`
+//! re-checks every match against the decrypted resource.
+
+use super::search::{IndexTerm, ValueRange};
+use crate::error::{Error, ParseError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "encryption")]
+use crate::encryption::EHREncryption;
+#[cfg(feature = "encryption")]
+use std::ops::Bound;
+#[cfg(feature = "encryption")]
+use std::sync::{Arc, RwLock};
+
+/// A record as written by a backend. Records stored before encryption was
+/// enabled read as `Plain`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(super) enum Stored<T> {
+    Sealed(SealedRecord),
+    Plain(T),
+}
+
+/// A record encrypted under its own data key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct SealedRecord {
+    #[serde(flatten)]
+    key: WrappedKey,
+    /// Hex nonce followed by the ciphertext of the record's JSON
+    data: String,
+}
+
+/// A key encrypted by a master key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct WrappedKey {
+    /// Id of the master key that wrapped `dek`
+    kid: String,
+    /// Hex nonce followed by the ciphertext of the key
+    dek: String,
+}
+
+/// Turns records, resource ids and search terms into what a backend stores
+#[derive(Clone, Default)]
+pub(super) struct Codec {
+    #[cfg(feature = "encryption")]
+    keys: Option<Arc<KeyRing>>,
+    /// Refuse to write, because the store is encrypted and no key was given
+    locked: bool,
+}
+
+impl Codec {
+    /// A codec for an encrypted store opened without its master key, which
+    /// must not gain unencrypted records
+    pub fn locked() -> Self {
+        Self {
+            #[cfg(feature = "encryption")]
+            keys: None,
+            locked: true,
+        }
+    }
+
+    /// `value` as it should be stored
+    pub fn seal<T: Serialize>(&self, value: T) -> Result<Stored<T>> {
+        #[cfg(feature = "encryption")]
+        if let Some(keys) = &self.keys {
+            return keys.seal(&value).map(Stored::Sealed);
+        }
+        if self.locked {
+            return Err(Error::crypto("store is encrypted; open it with its master key to write"));
+        }
+        Ok(Stored::Plain(value))
+    }
+
+    pub fn open<T: DeserializeOwned>(&self, stored: Stored<T>) -> Result<T> {
+        let record = match stored {
+            Stored::Plain(value) => return Ok(value),
+            Stored::Sealed(record) => record,
+        };
+        #[cfg(feature = "encryption")]
+        if let Some(keys) = &self.keys {
+            return serde_json::from_slice(&keys.open(&record)?)
+                .map_err(|err| ParseError::new("", format!("stored record is corrupt: {}", err)).into());
+        }
+        Err(Error::crypto(format!(
+            "record is encrypted with master key {}; open the store with that key",
+            record.key.kid
+        )))
+    }
+
+    /// `value` as a JSON string for a text column
+    pub fn encode<T: Serialize>(&self, value: T) -> Result<String> {
+        Ok(serde_json::to_string(&self.seal(value)?).expect("record serialization cannot fail"))
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, json: &str) -> Result<T> {
+        let stored: Stored<T> = serde_json::from_str(json)
+            .map_err(|err| ParseError::new("", format!("stored record is corrupt: {}", err)))?;
+        self.open(stored)
+    }
+
+    /// A resource id, or a `Type/id` reference, as it should be stored
+    pub fn resource_id(&self, id: &str) -> String {
+        self.term(IndexTerm {
+            param: "_id".to_string(),
+            value: id.to_string(),
+        })
+        .value
+    }
+
+    /// A search term as it should be stored
+    pub fn term(&self, term: IndexTerm) -> IndexTerm {
+        #[cfg(feature = "encryption")]
+        if let Some(keys) = &self.keys {
+            let value = keys.term_value(&term.param, &term.value);
+            return IndexTerm { value, ..term };
+        }
+        term
+    }
+
+    /// The stored values to look up for a search on `param` over `range`.
+    /// May cover more than `range`, so matches must be re-checked.
+    pub fn range(&self, param: &str, range: &ValueRange) -> ValueRange {
+        #[cfg(feature = "encryption")]
+        if let Some(keys) = &self.keys {
+            return keys.range(param, range);
+        }
+        let _ = param;
+        range.clone()
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub use self::keys::MasterKey;
+
+#[cfg(feature = "encryption")]
+use self::keys::KeyRing;
+
+#[cfg(feature = "encryption")]
+mod keys {
+    use super::*;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    /// Search parameters stored as written; write times are not PHI
+    const CLEAR_PARAMS: &[&str] = &["_lastUpdated"];
+
+    /// Search parameters whose values are kept by year only
+    const YEAR_PARAMS: &[&str] = &["date"];
+
+    /// The key that wraps the data key of every stored record. Its id, a
+    /// prefix of the key's SHA-256, is stored next to each wrapped key.
+    pub struct MasterKey {
+        id: String,
+        cipher: EHREncryption,
+    }
+
+    impl MasterKey {
+        /// A master key from 32 bytes of key material
+        pub fn from_bytes(key: &[u8]) -> Result<Self> {
+            let cipher = EHREncryption::from_key(key)?;
+            Ok(Self { id: cipher.key_id(), cipher })
+        }
+
+        /// A new random master key
+        pub fn generate() -> Result<Self> {
+            Self::from_bytes(&EHREncryption::new()?.get_key())
+        }
+
+        pub fn id(&self) -> &str {
+            &self.id
+        }
+
+        /// The key material, for saving to a key file
+        pub fn to_bytes(&self) -> Vec<u8> {
+            self.cipher.get_key()
+        }
+
+        fn wrap(&self, key: &[u8]) -> Result<WrappedKey> {
+            Ok(WrappedKey {
+                kid: self.id.clone(),
+                dek: seal_bytes(&self.cipher, key)?,
+            })
+        }
+
+        fn unwrap(&self, wrapped: &WrappedKey) -> Result<Vec<u8>> {
+            open_bytes(&self.cipher, &wrapped.dek)
+        }
+    }
+
+    /// The master keys of an encrypted store and the key its search terms
+    /// are hashed with
+    pub(in crate::storage) struct KeyRing {
+        /// The current master key first, then keys being rotated away from
+        masters: RwLock<Vec<MasterKey>>,
+        /// Fixed for the life of the store, so stored hashes stay valid
+        index_key: Vec<u8>,
+    }
+
+    impl Codec {
+        /// A codec that seals with `master`. `index_keys` are the store's
+        /// index keys as saved from [`Codec::wrapped_index_key`], one per
+        /// master key; a new store has none and gets a new index key.
+        pub fn encrypted(master: MasterKey, index_keys: &[String]) -> Result<Self> {
+            let wrapped = index_keys
+                .iter()
+                .map(|stored| {
+                    serde_json::from_str::<WrappedKey>(stored)
+                        .map_err(|err| ParseError::new("", format!("stored index key is corrupt: {}", err)).into())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let index_key = match wrapped.iter().find(|wrapped| wrapped.kid == master.id) {
+                Some(wrapped) => master.unwrap(wrapped)?,
+                None if wrapped.is_empty() => EHREncryption::new()?.get_key(),
+                None => {
+                    let kids: Vec<&str> = wrapped.iter().map(|wrapped| wrapped.kid.as_str()).collect();
+                    return Err(Error::crypto(format!(
+                        "store is encrypted with master key {}, not {}",
+                        kids.join(" or "),
+                        master.id
+                    )));
+                }
+            };
+            let keys = KeyRing {
+                masters: RwLock::new(vec![master]),
+                index_key,
+            };
+            Ok(Self {
+                keys: Some(Arc::new(keys)),
+                locked: false,
+            })
+        }
+
+        fn key_ring(&self) -> Result<&KeyRing> {
+            self.keys.as_deref().ok_or_else(|| Error::crypto("store is not encrypted"))
+        }
+
+        /// Id of the master key new records are sealed with
+        pub fn master_key_id(&self) -> Result<String> {
+            Ok(self.key_ring()?.masters()[0].id.clone())
+        }
+
+        /// The index key wrapped by the current master key, to save under
+        /// [`Codec::master_key_id`]
+        pub fn wrapped_index_key(&self) -> Result<String> {
+            let keys = self.key_ring()?;
+            let wrapped = keys.masters()[0].wrap(&keys.index_key)?;
+            Ok(serde_json::to_string(&wrapped).expect("key serialization cannot fail"))
+        }
+
+        /// Seal new records with `master` from now on, keeping the previous
+        /// keys to read records until [`Codec::finish_rotation`]
+        pub fn begin_rotation(&self, master: MasterKey) -> Result<()> {
+            let keys = self.key_ring()?;
+            let mut masters = keys.masters.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            masters.retain(|key| key.id != master.id);
+            masters.insert(0, master);
+            Ok(())
+        }
+
+        /// Go back to sealing with the master key in use before
+        /// [`Codec::begin_rotation`]
+        pub fn abort_rotation(&self) -> Result<()> {
+            let keys = self.key_ring()?;
+            let mut masters = keys.masters.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if masters.len() > 1 {
+                masters.remove(0);
+            }
+            Ok(())
+        }
+
+        /// Forget every master key but the current one
+        pub fn finish_rotation(&self) -> Result<()> {
+            let keys = self.key_ring()?;
+            keys.masters.write().unwrap_or_else(|poisoned| poisoned.into_inner()).truncate(1);
+            Ok(())
+        }
+
+        /// Wrap a sealed record's data key with the current master key,
+        /// returning whether it changed
+        pub fn rewrap(&self, record: &mut SealedRecord) -> Result<bool> {
+            let keys = self.key_ring()?;
+            let masters = keys.masters();
+            if record.key.kid == masters[0].id {
+                return Ok(false);
+            }
+            let data_key = keys.data_key(&masters, &record.key)?;
+            record.key = masters[0].wrap(&data_key.get_key())?;
+            Ok(true)
+        }
+
+        /// [`Codec::rewrap`] for a record stored as JSON; `None` if it is
+        /// plain or already wrapped by the current key
+        pub fn rewrap_json(&self, json: &str) -> Result<Option<String>> {
+            let stored: Stored<serde_json::Value> = serde_json::from_str(json)
+                .map_err(|err| ParseError::new("", format!("stored record is corrupt: {}", err)))?;
+            let Stored::Sealed(mut record) = stored else {
+                return Ok(None);
+            };
+            Ok(self
+                .rewrap(&mut record)?
+                .then(|| serde_json::to_string(&record).expect("record serialization cannot fail")))
+        }
+    }
+
+    impl KeyRing {
+        fn masters(&self) -> std::sync::RwLockReadGuard<'_, Vec<MasterKey>> {
+            self.masters.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+
+        pub(super) fn seal<T: Serialize>(&self, value: &T) -> Result<SealedRecord> {
+            let json = serde_json::to_vec(value).expect("record serialization cannot fail");
+            let data_key = EHREncryption::new()?;
+            Ok(SealedRecord {
+                key: self.masters()[0].wrap(&data_key.get_key())?,
+                data: seal_bytes(&data_key, &json)?,
+            })
+        }
+
+        pub(super) fn open(&self, record: &SealedRecord) -> Result<Vec<u8>> {
+            let data_key = self.data_key(&self.masters(), &record.key)?;
+            open_bytes(&data_key, &record.data)
+        }
+
+        fn data_key(&self, masters: &[MasterKey], wrapped: &WrappedKey) -> Result<EHREncryption> {
+            let master = masters
+                .iter()
+                .find(|master| master.id == wrapped.kid)
+                .ok_or_else(|| Error::crypto(format!("record is encrypted with unknown master key {}", wrapped.kid)))?;
+            EHREncryption::from_key(&master.unwrap(wrapped)?)
+        }
+
+        pub(super) fn term_value(&self, param: &str, value: &str) -> String {
+            if CLEAR_PARAMS.contains(&param) {
+                return value.to_string();
+            }
+            if YEAR_PARAMS.contains(&param) {
+                return value.get(..4).unwrap_or(value).to_string();
+            }
+            let mut mac = Hmac::<Sha256>::new_from_slice(&self.index_key).expect("HMAC accepts any key length");
+            mac.update(param.as_bytes());
+            mac.update(&[0]);
+            mac.update(value.as_bytes());
+            crate::utils::bytes_to_hex(&mac.finalize().into_bytes())
+        }
+
+        pub(super) fn range(&self, param: &str, range: &ValueRange) -> ValueRange {
+            let year = |bound: &Bound<String>| match bound {
+                Bound::Included(value) | Bound::Excluded(value) => Bound::Included(self.term_value(param, value)),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            match range {
+                _ if CLEAR_PARAMS.contains(&param) => range.clone(),
+                // Years sort like the dates they come from
+                _ if YEAR_PARAMS.contains(&param) => (year(&range.0), year(&range.1)),
+                (Bound::Included(low), Bound::Included(high)) if low == high => {
+                    let value = Bound::Included(self.term_value(param, low));
+                    (value.clone(), value)
+                }
+                _ => (Bound::Unbounded, Bound::Unbounded),
+            }
+        }
+    }
+
+    fn seal_bytes(cipher: &EHREncryption, plaintext: &[u8]) -> Result<String> {
+        let (ciphertext, mut sealed) = cipher.encrypt(plaintext)?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(crate::utils::bytes_to_hex(&sealed))
+    }
+
+    fn open_bytes(cipher: &EHREncryption, sealed: &str) -> Result<Vec<u8>> {
+        let sealed = crate::utils::hex_to_bytes(sealed)?;
+        if sealed.len() < 12 {
+            return Err(Error::crypto("sealed record is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        cipher.decrypt(ciphertext, nonce)
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+    use crate::models::Bundle;
+
+    fn sample_bundle() -> Bundle {
+        crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap()
+    }
+
+    fn term(codec: &Codec, param: &str, value: &str) -> String {
+        codec.term(IndexTerm { param: param.to_string(), value: value.to_string() }).value
+    }
+
+    #[test]
+    fn test_sealed_records_hide_phi() {
+        let codec = Codec::encrypted(MasterKey::generate().unwrap(), &[]).unwrap();
+        let json = codec.encode(sample_bundle()).unwrap();
+        assert!(!json.contains("patient-123") && !json.contains("Doe"));
+        assert_eq!(codec.decode::<Bundle>(&json).unwrap(), sample_bundle());
+
+        // Plain records from before encryption was enabled still read
+        let plain = Codec::default().encode(sample_bundle()).unwrap();
+        assert_eq!(codec.decode::<Bundle>(&plain).unwrap(), sample_bundle());
+        assert!(matches!(Codec::default().decode::<Bundle>(&json), Err(Error::Crypto { .. })));
+    }
+
+    #[test]
+    fn test_rotation_rewraps_without_reencrypting() {
+        let master = MasterKey::generate().unwrap();
+        let codec = Codec::encrypted(MasterKey::from_bytes(&master.to_bytes()).unwrap(), &[]).unwrap();
+        let json = codec.encode(sample_bundle()).unwrap();
+        let identifier = term(&codec, "identifier", "MRN-0012345");
+
+        let new_master = MasterKey::generate().unwrap();
+        let new_bytes = new_master.to_bytes();
+        codec.begin_rotation(new_master).unwrap();
+        let rotated = codec.rewrap_json(&json).unwrap().unwrap();
+        let index_key = codec.wrapped_index_key().unwrap();
+        codec.finish_rotation().unwrap();
+        assert_eq!(codec.rewrap_json(&rotated).unwrap(), None);
+
+        let data = |json: &str| match serde_json::from_str::<Stored<serde_json::Value>>(json).unwrap() {
+            Stored::Sealed(record) => record.data,
+            Stored::Plain(_) => panic!("expected a sealed record"),
+        };
+        assert_eq!(data(&rotated), data(&json));
+        assert_eq!(codec.decode::<Bundle>(&rotated).unwrap(), sample_bundle());
+        assert!(matches!(codec.decode::<Bundle>(&json), Err(Error::Crypto { .. })));
+
+        // Reopening needs the new key, and search terms hash the same
+        let stale = Codec::encrypted(master, std::slice::from_ref(&index_key));
+        assert!(matches!(stale, Err(Error::Crypto { .. })));
+        let reopened = Codec::encrypted(MasterKey::from_bytes(&new_bytes).unwrap(), &[index_key]).unwrap();
+        assert_eq!(term(&reopened, "identifier", "MRN-0012345"), identifier);
+    }
+
+    #[test]
+    fn test_terms_are_hashed_and_dates_kept_by_year() {
+        let codec = Codec::encrypted(MasterKey::generate().unwrap(), &[]).unwrap();
+
+        assert_ne!(term(&codec, "identifier", "MRN-0012345"), "MRN-0012345");
+        assert_eq!(term(&codec, "identifier", "MRN-0012345"), term(&codec, "identifier", "MRN-0012345"));
+        assert_ne!(term(&codec, "identifier", "x"), term(&codec, "code", "x"));
+        assert_eq!(term(&codec, "date", "2024-07-30T09:15:00Z"), "2024");
+        assert_eq!(term(&codec, "_lastUpdated", "2024-08-01T00:00:00Z"), "2024-08-01T00:00:00Z");
+
+        let dates = (Bound::Included("2024-07-30".to_string()), Bound::Excluded("2025-01".to_string()));
+        assert_eq!(codec.range("date", &dates), (Bound::Included("2024".to_string()), Bound::Included("2025".to_string())));
+        let exact = (Bound::Included("final".to_string()), Bound::Included("final".to_string()));
+        assert_eq!(codec.range("status", &exact).0, Bound::Included(term(&codec, "status", "final")));
+        let unbounded = (Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(codec.range("_id", &unbounded), unbounded);
+    }
+}
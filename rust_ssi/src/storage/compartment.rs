@@ -0,0 +1,146 @@
+//! The patient compartment: the Patient and every stored resource that
+//! refers to it, gathered across bundles for `Patient/$everything`.
+
+use super::{BundleStore, SearchMatch, SearchQuery};
+use crate::error::{Error, Result};
+use crate::models::*;
+use serde_json::{json, Value};
+
+/// Resource types in the patient compartment. Members are found by their
+/// `patient` search term, read from `subject` or `patient` references.
+pub const PATIENT_COMPARTMENT: &[&str] = &[
+    "AllergyIntolerance",
+    "CarePlan",
+    "Condition",
+    "DiagnosticReport",
+    "DocumentReference",
+    "Encounter",
+    "Flag",
+    "Immunization",
+    "MedicationRequest",
+    "MedicationStatement",
+    "Observation",
+    "Procedure",
+    "ServiceRequest",
+];
+
+/// Everything stored about one patient. A resource held by several bundles
+/// appears once, as read from the most recently updated of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatientCompartment {
+    pub patient: SearchMatch,
+    /// Compartment members, grouped by resource type in the order of
+    /// [`PATIENT_COMPARTMENT`] and ordered by id within each type
+    pub resources: Vec<SearchMatch>,
+}
+
+impl PatientCompartment {
+    /// A `searchset` bundle of the patient followed by its resources
+    pub fn to_searchset(&self) -> Bundle {
+        let mut bundle = Bundle::new(
+            crate::utils::generate_random_id(),
+            "searchset".to_string(),
+            crate::utils::get_current_timestamp(),
+        );
+        for found in std::iter::once(&self.patient).chain(&self.resources) {
+            bundle.add_entry(found.resource.clone());
+        }
+        bundle
+    }
+
+    /// A `document` bundle led by a Composition with one section per
+    /// resource type, followed by the patient and its resources
+    pub fn to_document(&self) -> Bundle {
+        let mut document = self.to_searchset();
+        document.bundle_type = "document".to_string();
+
+        let patient_reference = format!("Patient/{}", self.patient.resource.id());
+        let mut sections: Vec<(&str, Vec<Value>)> = Vec::new();
+        for found in &self.resources {
+            let resource_type = found.resource.resource_type();
+            let reference = json!({ "reference": format!("{}/{}", resource_type, found.resource.id()) });
+            match sections.last_mut() {
+                Some((section_type, entries)) if *section_type == resource_type => entries.push(reference),
+                _ => sections.push((resource_type, vec![reference])),
+            }
+        }
+        let composition = json!({
+            "resourceType": "Composition",
+            "id": crate::utils::generate_random_id(),
+            "status": "final",
+            "type": { "text": "Patient record" },
+            "subject": { "reference": patient_reference },
+            "date": document.timestamp,
+            "title": "Patient record",
+            "section": sections
+                .into_iter()
+                .map(|(title, entries)| json!({ "title": title, "entry": entries }))
+                .collect::<Vec<_>>(),
+        });
+        document.entry.insert(0, BundleEntry::new(Resource::Other {
+            resource_type: "Composition".to_string(),
+            raw: composition,
+        }));
+        document
+    }
+}
+
+/// Gather the compartment of `patient_id` with searches on `store`
+pub(super) async fn gather<S: BundleStore + ?Sized>(store: &S, patient_id: &str) -> Result<PatientCompartment> {
+    let patient = search_all(store, SearchQuery::new("Patient").with("_id", patient_id)?)
+        .await?
+        .pop()
+        .ok_or_else(|| Error::not_found("Patient", patient_id))?;
+
+    let mut resources = Vec::new();
+    for resource_type in PATIENT_COMPARTMENT {
+        resources.extend(search_all(store, SearchQuery::new(*resource_type).with("patient", patient_id)?).await?);
+    }
+    Ok(PatientCompartment { patient, resources })
+}
+
+/// Every page of matches for `query`
+async fn search_all<S: BundleStore + ?Sized>(store: &S, query: SearchQuery) -> Result<Vec<SearchMatch>> {
+    let mut matches = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = store.search(&query.clone().with("_offset", &offset.to_string())?).await?;
+        matches.extend(page.matches);
+        match page.next_offset {
+            Some(next) => offset = next,
+            None => return Ok(matches),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_sections_follow_resource_types() {
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let found = |index: usize| SearchMatch {
+            bundle_id: bundle.id.clone(),
+            last_updated: String::new(),
+            entry_index: index,
+            resource: bundle.entry[index].resource.clone(),
+        };
+        let compartment = PatientCompartment {
+            patient: found(0),
+            resources: vec![found(4), found(2), found(3)],
+        };
+
+        let document = compartment.to_document();
+        let Resource::Other { raw, .. } = &document.entry[0].resource else {
+            panic!("expected a Composition");
+        };
+        assert_eq!(raw["subject"]["reference"], "Patient/patient-123");
+        let titles: Vec<&str> = raw["section"].as_array().unwrap().iter().map(|section| section["title"].as_str().unwrap()).collect();
+        assert_eq!(titles, vec!["Condition", "Encounter", "Observation"]);
+        assert_eq!(raw["section"][2]["entry"][0]["reference"], "Observation/observation-bp");
+        assert_eq!(document.entry[1].resource.id(), "patient-123");
+    }
+}
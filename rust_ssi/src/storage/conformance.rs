@@ -0,0 +1,355 @@
+//! Behaviour every `BundleStore` backend must share. Each backend's tests
+//! call [`run`] with an empty store.
+
+use super::{BundleStats, BundleStore, SearchPage, SearchQuery};
+use crate::error::Error;
+use crate::models::*;
+
+/// The bundle's `meta.versionId`, with `meta` cleared so the rest can be compared
+fn split_version(bundle: Option<Bundle>) -> (Bundle, String) {
+    let mut bundle = bundle.expect("bundle should exist");
+    let meta = bundle.meta.take().expect("stored bundles carry meta");
+    assert!(!meta.last_updated.is_empty());
+    (bundle, meta.version_id)
+}
+
+fn sample_bundle() -> Bundle {
+    crate::FHIRHandler::new()
+        .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+        .unwrap()
+}
+
+/// A bundle with its own patient and the sample bundle's practitioner
+fn second_bundle() -> Bundle {
+    let sample = sample_bundle();
+    let mut bundle = Bundle::new("second-visit".to_string(), "document".to_string(), "2024-08-02T09:00:00Z".to_string());
+    bundle.add_entry(Resource::Patient(Patient::new(
+        "patient-456".to_string(),
+        "Roe".to_string(),
+        vec!["Jane".to_string()],
+        "female".to_string(),
+        "1990-02-01".to_string(),
+    )));
+    bundle.add_entry(sample.entry[1].resource.clone());
+    bundle
+}
+
+/// Two coded lab results for the sample bundle's patient
+fn lab_bundle() -> Bundle {
+    let mut bundle = Bundle::new("lab-results".to_string(), "collection".to_string(), "2024-09-15T08:00:00Z".to_string());
+    for (id, status, code, date) in [
+        ("hba1c-1", "final", "4548-4", "2024-08-01T08:00:00Z"),
+        ("hba1c-2", "preliminary", "4548-4", "2024-09-15T08:00:00Z"),
+        ("glucose-1", "final", "2345-7", "2024-09-15T08:05:00Z"),
+    ] {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "id": id,
+            "status": status,
+            "code": { "coding": [{ "system": "http://loinc.org", "code": code }] },
+            "subject": { "reference": "Patient/patient-123" },
+            "effectiveDateTime": date,
+        }))
+        .unwrap();
+        bundle.add_entry(Resource::Observation(observation));
+    }
+    bundle
+}
+
+pub async fn run<S: BundleStore + ?Sized>(store: &S) {
+    empty_store(store).await;
+    round_trip(store).await;
+    search(store).await;
+    replace_and_statistics(store).await;
+    delete(store).await;
+    history(store).await;
+    fhir_search(store).await;
+    conditional_writes(store).await;
+    patient_everything(store).await;
+    legal_hold_and_purge(store).await;
+}
+
+async fn empty_store<S: BundleStore + ?Sized>(store: &S) {
+    assert!(store.list_bundles().await.unwrap().is_empty());
+    assert!(store.get_bundle("mvp-visit-bundle").await.unwrap().is_none());
+    assert!(!store.delete_bundle("mvp-visit-bundle").await.unwrap());
+    assert_eq!(store.get_statistics().await.unwrap(), BundleStats::default());
+}
+
+async fn round_trip<S: BundleStore + ?Sized>(store: &S) {
+    let sample = sample_bundle();
+    assert_eq!(store.store_bundle(second_bundle()).await.unwrap(), "second-visit");
+    assert_eq!(store.store_bundle(sample.clone()).await.unwrap(), "mvp-visit-bundle");
+
+    let (stored, version) = split_version(store.get_bundle("mvp-visit-bundle").await.unwrap());
+    assert_eq!((stored, version.as_str()), (sample, "1"));
+    assert_eq!(store.list_bundles().await.unwrap(), vec!["mvp-visit-bundle", "second-visit"]);
+}
+
+async fn search<S: BundleStore + ?Sized>(store: &S) {
+    let ids = |bundles: Vec<Bundle>| bundles.into_iter().map(|bundle| bundle.id).collect::<Vec<_>>();
+
+    assert_eq!(ids(store.search_by_patient("patient-123").await.unwrap()), vec!["mvp-visit-bundle"]);
+    assert_eq!(ids(store.search_by_patient("patient-456").await.unwrap()), vec!["second-visit"]);
+    assert!(store.search_by_patient("dr-smith").await.unwrap().is_empty());
+    assert_eq!(
+        ids(store.search_by_practitioner("dr-smith").await.unwrap()),
+        vec!["mvp-visit-bundle", "second-visit"]
+    );
+}
+
+async fn replace_and_statistics<S: BundleStore + ?Sized>(store: &S) {
+    let mut replacement = second_bundle();
+    replacement.entry.truncate(1);
+    store.store_bundle(replacement.clone()).await.unwrap();
+
+    let (stored, version) = split_version(store.get_bundle("second-visit").await.unwrap());
+    assert_eq!((stored, version.as_str()), (replacement, "2"));
+    assert!(store.search_by_practitioner("dr-smith").await.unwrap().iter().all(|bundle| bundle.id != "second-visit"));
+
+    let stats = store.get_statistics().await.unwrap();
+    let counts = [
+        stats.total_bundles,
+        stats.patient_count,
+        stats.practitioner_count,
+        stats.encounter_count,
+        stats.observation_count,
+        stats.condition_count,
+        stats.medication_count,
+        stats.other_count,
+    ];
+    assert_eq!(counts, [2, 2, 1, 1, 1, 1, 1, 0]);
+    let json = serde_json::to_value(&stats).unwrap();
+    assert_eq!(json["bundles_per_day"], serde_json::json!({ "2024-07-30": 1, "2024-08-02": 1 }));
+    assert_eq!(json["resources_per_practitioner"], serde_json::json!({ "dr-smith": 2 }));
+    assert_eq!(json["encounters_per_class"], serde_json::json!({ "AMB": 1 }));
+    assert_eq!(stats.top_conditions(5), vec![("Hypertension", 1)]);
+    assert_eq!(stats.top_medications(5), vec![("Amoxicillin 250 mg capsule", 1)]);
+    let patients: Vec<&str> = stats.bytes_per_patient.keys().map(String::as_str).collect();
+    assert_eq!(patients, vec!["patient-123", "patient-456"]);
+    assert!(stats.bytes_per_patient["patient-123"] > stats.bytes_per_patient["patient-456"]);
+    assert_eq!(serde_json::from_value::<BundleStats>(json).unwrap(), stats);
+}
+
+async fn delete<S: BundleStore + ?Sized>(store: &S) {
+    assert!(store.delete_bundle("second-visit").await.unwrap());
+    assert!(!store.delete_bundle("second-visit").await.unwrap());
+    assert!(store.get_bundle("second-visit").await.unwrap().is_none());
+    assert!(store.search_by_patient("patient-456").await.unwrap().is_empty());
+    assert_eq!(store.list_bundles().await.unwrap(), vec!["mvp-visit-bundle"]);
+
+    let stats = store.get_statistics().await.unwrap();
+    assert_eq!((stats.total_bundles, stats.patient_count), (1, 1));
+    assert!(!stats.bundles_per_day.contains_key("2024-08-02"));
+    assert!(!stats.bytes_per_patient.contains_key("patient-456"));
+}
+
+async fn history<S: BundleStore + ?Sized>(store: &S) {
+    let history = store.bundle_history("second-visit").await.unwrap();
+    let versions: Vec<_> = history.iter().map(|entry| (entry.version_id.as_str(), entry.is_deleted())).collect();
+    assert_eq!(versions, vec![("3", true), ("2", false), ("1", false)]);
+    assert!(store.bundle_history("no-such-bundle").await.unwrap().is_empty());
+
+    let (first, _) = split_version(store.get_bundle_version("second-visit", "1").await.unwrap());
+    assert_eq!(first, second_bundle());
+    assert!(store.get_bundle_version("second-visit", "3").await.unwrap().is_none());
+    assert!(store.get_bundle_version("second-visit", "4").await.unwrap().is_none());
+
+    // Storing again after a delete continues the version sequence
+    store.store_bundle(second_bundle()).await.unwrap();
+    let (_, version) = split_version(store.get_bundle("second-visit").await.unwrap());
+    assert_eq!(version, "4");
+
+    // dr-smith left second-visit in version 2 and came back in version 4
+    let history = store.resource_history("Practitioner", "dr-smith").await.unwrap();
+    let mut versions: Vec<_> = history
+        .iter()
+        .map(|entry| (entry.bundle_id.as_str(), entry.version_id.as_str(), entry.is_deleted()))
+        .collect();
+    versions.sort();
+    assert_eq!(versions, vec![
+        ("mvp-visit-bundle", "1", false),
+        ("second-visit", "1", false),
+        ("second-visit", "2", true),
+        ("second-visit", "4", false),
+    ]);
+    assert!(history.iter().flat_map(|entry| &entry.content).all(|resource| resource.id() == "dr-smith"));
+
+    let history = store.resource_history("Patient", "patient-456").await.unwrap();
+    let versions: Vec<_> = history.iter().map(|entry| (entry.version_id.as_str(), entry.is_deleted())).collect();
+    assert_eq!(versions, vec![("4", false), ("3", true), ("2", false), ("1", false)]);
+}
+
+async fn fhir_search<S: BundleStore + ?Sized>(store: &S) {
+    store.store_bundle(lab_bundle()).await.unwrap();
+    let search = |resource_type: &str, query: &str| SearchQuery::parse(resource_type, query).unwrap();
+    let ids = |page: SearchPage| page.matches.into_iter().map(|found| found.resource.id().to_string()).collect::<Vec<_>>();
+
+    let page = store.search(&search("Observation", "patient=patient-123")).await.unwrap();
+    assert_eq!(page.total, 4);
+    assert_eq!(ids(page), vec!["glucose-1", "hba1c-1", "hba1c-2", "observation-bp"]);
+    assert_eq!(
+        ids(store.search(&search("Observation", "subject=Patient/patient-123&code=http://loinc.org|4548-4")).await.unwrap()),
+        vec!["hba1c-1", "hba1c-2"]
+    );
+    assert_eq!(
+        ids(store.search(&search("Observation", "date=ge2024-08-01&date=le2024-09-15&_sort=-date")).await.unwrap()),
+        vec!["glucose-1", "hba1c-2", "hba1c-1"]
+    );
+    assert_eq!(ids(store.search(&search("Observation", "date=2024-07-30")).await.unwrap()), vec!["observation-bp"]);
+    assert_eq!(
+        ids(store.search(&search("Observation", "status=final,preliminary&code=2345-7,4548-4&date=lt2024-09")).await.unwrap()),
+        vec!["hba1c-1"]
+    );
+    assert!(store.search(&search("Observation", "patient=patient-456")).await.unwrap().matches.is_empty());
+
+    // Tokens and chains through the patient's identifier
+    let mrn = "urn:oid:1.2.36.146.595.217.0.1|MRN-0012345";
+    assert_eq!(ids(store.search(&search("Patient", &format!("identifier={}", mrn))).await.unwrap()), vec!["patient-123"]);
+    assert_eq!(
+        ids(store.search(&search("Observation", &format!("subject.identifier={}&status=final", mrn))).await.unwrap()),
+        vec!["glucose-1", "hba1c-1", "observation-bp"]
+    );
+    assert!(store.search(&search("Observation", "patient.identifier=urn:other|1")).await.unwrap().matches.is_empty());
+
+    // dr-smith appears in two bundles but is one resource
+    assert_eq!(store.search(&search("Practitioner", "_id=dr-smith")).await.unwrap().total, 1);
+    assert_eq!(store.search(&SearchQuery::new("Patient")).await.unwrap().total, 2);
+    assert_eq!(store.search(&search("Patient", "_lastUpdated=ge2000-01-01")).await.unwrap().total, 2);
+
+    // Paging
+    let first = store.search(&search("Observation", "_sort=date&_count=3")).await.unwrap();
+    assert_eq!((first.total, first.next_offset), (4, Some(3)));
+    assert_eq!(ids(first), vec!["observation-bp", "hba1c-1", "hba1c-2"]);
+    let last = store.search(&search("Observation", "_sort=date&_count=3&_offset=3")).await.unwrap();
+    assert_eq!((last.offset, last.next_offset), (3, None));
+    assert_eq!(ids(last), vec!["glucose-1"]);
+
+    // Deleted bundles and dropped resources leave the index
+    store.delete_bundle("lab-results").await.unwrap();
+    assert_eq!(ids(store.search(&search("Observation", "patient=patient-123")).await.unwrap()), vec!["observation-bp"]);
+    let mut replacement = second_bundle();
+    replacement.entry.truncate(1);
+    store.store_bundle(replacement).await.unwrap();
+    assert_eq!(store.search(&search("Practitioner", "_id=dr-smith")).await.unwrap().matches[0].bundle_id, "mvp-visit-bundle");
+
+    // Resources without an id are told apart by their entry
+    let mut unlabelled = Bundle::new("unlabelled".to_string(), "collection".to_string(), String::new());
+    for code in ["8867-4", "9279-1"] {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": code }] },
+            "subject": { "reference": "Patient/patient-456" },
+        }))
+        .unwrap();
+        unlabelled.add_entry(Resource::Observation(observation));
+    }
+    store.store_bundle(unlabelled).await.unwrap();
+    let page = store.search(&search("Observation", "patient=patient-456")).await.unwrap();
+    assert_eq!(page.matches.iter().map(|found| found.entry_index).collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(store.search(&SearchQuery::new("Observation")).await.unwrap().total, 3);
+    store.delete_bundle("unlabelled").await.unwrap();
+}
+
+async fn conditional_writes<S: BundleStore + ?Sized>(store: &S) {
+    fn conflict<T>(result: crate::error::Result<T>) -> Option<String> {
+        match result {
+            Err(Error::Conflict { current, .. }) => current,
+            _ => panic!("expected a version conflict"),
+        }
+    }
+
+    // mvp-visit-bundle is at version 1; a second editor still holding version 1 loses
+    let (mut edited, version) = split_version(store.get_bundle("mvp-visit-bundle").await.unwrap());
+    edited.timestamp = "2024-08-03T12:00:00Z".to_string();
+    store.store_bundle_if_match(edited.clone(), Some(&version)).await.unwrap();
+    assert_eq!(conflict(store.store_bundle_if_match(edited.clone(), Some(&version)).await), Some("2".to_string()));
+    assert_eq!(conflict(store.delete_bundle_if_match("mvp-visit-bundle", Some("1")).await), Some("2".to_string()));
+    let (stored, version) = split_version(store.get_bundle("mvp-visit-bundle").await.unwrap());
+    assert_eq!((stored.timestamp.as_str(), version.as_str()), ("2024-08-03T12:00:00Z", "2"));
+
+    // A deleted or missing bundle matches no version
+    assert!(store.delete_bundle_if_match("mvp-visit-bundle", Some("2")).await.unwrap());
+    assert_eq!(conflict(store.store_bundle_if_match(edited.clone(), Some("2")).await), None);
+    assert_eq!(conflict(store.delete_bundle_if_match("no-such-bundle", Some("1")).await), None);
+    assert!(!store.delete_bundle_if_match("no-such-bundle", None).await.unwrap());
+    store.store_bundle_if_match(edited, None).await.unwrap();
+    assert_eq!(split_version(store.get_bundle("mvp-visit-bundle").await.unwrap()).1, "4");
+}
+
+async fn patient_everything<S: BundleStore + ?Sized>(store: &S) {
+    // The lab results and a copy of the blood pressure reading, which counts once
+    store.store_bundle(lab_bundle()).await.unwrap();
+    let mut copy = Bundle::new("bp-copy".to_string(), "collection".to_string(), String::new());
+    copy.add_entry(sample_bundle().entry[3].resource.clone());
+    store.store_bundle(copy).await.unwrap();
+
+    let everything = store.patient_everything("patient-123").await.unwrap();
+    assert_eq!(everything.patient.resource.id(), "patient-123");
+    let members: Vec<_> = everything
+        .resources
+        .iter()
+        .map(|found| format!("{}/{}", found.resource.resource_type(), found.resource.id()))
+        .collect();
+    assert_eq!(members, vec![
+        "Condition/condition-hypertension",
+        "Encounter/encounter-456",
+        "MedicationRequest/prescription-789",
+        "Observation/glucose-1",
+        "Observation/hba1c-1",
+        "Observation/hba1c-2",
+        "Observation/observation-bp",
+    ]);
+
+    let searchset = everything.to_searchset();
+    assert_eq!((searchset.bundle_type.as_str(), searchset.entry.len()), ("searchset", 8));
+    let document = everything.to_document();
+    assert_eq!(document.bundle_type, "document");
+    assert_eq!(document.entry[0].resource.resource_type(), "Composition");
+    assert_eq!(document.entry.len(), 9);
+
+    // A patient without other resources, and one that does not exist
+    assert!(store.patient_everything("patient-456").await.unwrap().resources.is_empty());
+    assert!(matches!(store.patient_everything("no-such-patient").await, Err(Error::NotFound { .. })));
+}
+
+async fn legal_hold_and_purge<S: BundleStore + ?Sized>(store: &S) {
+    fn held<T: std::fmt::Debug>(result: crate::error::Result<T>) {
+        assert!(matches!(result, Err(Error::LegalHold { .. })), "expected a legal hold, got {:?}", result);
+    }
+
+    let before = store.get_statistics().await.unwrap();
+    let mut bundle = Bundle::new("held-visit".to_string(), "collection".to_string(), "2024-10-01T09:00:00Z".to_string());
+    bundle.add_entry(Resource::Patient(Patient::new(
+        "patient-789".to_string(),
+        "Doe".to_string(),
+        vec!["Sam".to_string()],
+        "other".to_string(),
+        "1975-05-05".to_string(),
+    )));
+    bundle.set_claim_type(ClaimType::Prescription);
+    store.store_bundle(bundle.clone()).await.unwrap();
+    store.store_bundle(bundle).await.unwrap();
+    let stored = store.get_bundle("held-visit").await.unwrap().unwrap();
+    assert_eq!(stored.claim_type(), Some(ClaimType::Prescription));
+
+    // A held patient's bundles can be neither deleted nor purged
+    assert!(!store.is_on_legal_hold("patient-789").await.unwrap());
+    store.set_legal_hold("patient-789", true).await.unwrap();
+    store.set_legal_hold("patient-789", true).await.unwrap();
+    assert!(store.is_on_legal_hold("patient-789").await.unwrap());
+    held(store.delete_bundle("held-visit").await);
+    held(store.purge_bundle("held-visit").await);
+    assert!(store.get_bundle("held-visit").await.unwrap().is_some());
+
+    // Once released, purging removes the bundle and every trace of its history
+    store.set_legal_hold("patient-789", false).await.unwrap();
+    assert!(!store.is_on_legal_hold("patient-789").await.unwrap());
+    assert!(store.purge_bundle("held-visit").await.unwrap());
+    assert!(!store.purge_bundle("held-visit").await.unwrap());
+    assert!(store.get_bundle("held-visit").await.unwrap().is_none());
+    assert!(store.bundle_history("held-visit").await.unwrap().is_empty());
+    assert!(store.resource_history("Patient", "patient-789").await.unwrap().is_empty());
+    assert_eq!(store.get_statistics().await.unwrap(), before);
+}
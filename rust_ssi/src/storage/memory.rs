@@ -0,0 +1,278 @@
+use super::codec::Codec;
+use super::search::{self, find_matches, index_terms, ResourceKey, SearchIndex, ValueRange};
+use super::{
+    check_legal_hold, check_version, resource_history_from, stamp_version, BundleStats, BundleStore, HistoryEntry, SearchMatch,
+    SearchPage, SearchQuery,
+};
+use crate::error::Result;
+use crate::models::*;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Search terms of current resources: `(resource type, parameter)` to value to resources
+type TermIndex = BTreeMap<(String, String), BTreeMap<String, BTreeSet<ResourceKey>>>;
+
+/// Keeps every version of every bundle in a map; nothing survives the process.
+/// Reads share a lock and writes take it exclusively. Nothing is written to
+/// disk, so bundles are kept unencrypted.
+#[derive(Default)]
+pub struct MemoryStore {
+    bundles: RwLock<Bundles>,
+}
+
+#[derive(Default)]
+struct Bundles {
+    /// Versions of each bundle, oldest first
+    histories: BTreeMap<String, Vec<HistoryEntry<Bundle>>>,
+    index: TermIndex,
+    /// Statistics over the current versions
+    stats: BundleStats,
+    /// Patients under legal hold
+    holds: BTreeSet<String>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A panic while holding the lock happens before any map is changed, so
+    // the contents stay consistent
+    fn read(&self) -> RwLockReadGuard<'_, Bundles> {
+        self.bundles.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Bundles> {
+        self.bundles.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Bundles {
+    /// Add or remove the search terms of every resource in `bundle`
+    fn index_bundle(&mut self, bundle: &Bundle, insert: bool) {
+        let last_updated = bundle.meta.as_ref().map(|meta| meta.last_updated.as_str()).unwrap_or_default();
+        for entry in &bundle.entry {
+            let resource_type = entry.resource.resource_type();
+            let key = ResourceKey {
+                bundle_id: bundle.id.clone(),
+                resource_id: entry.resource.id().to_string(),
+            };
+            for term in index_terms(&entry.resource, last_updated) {
+                let values = self.index.entry((resource_type.to_string(), term.param)).or_default();
+                if insert {
+                    values.entry(term.value).or_default().insert(key.clone());
+                } else if let Some(keys) = values.get_mut(&term.value) {
+                    keys.remove(&key);
+                    if keys.is_empty() {
+                        values.remove(&term.value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Current versions of the bundles that are not deleted
+    fn current(&self) -> impl Iterator<Item = &Bundle> {
+        self.histories
+            .values()
+            .filter_map(|history| history.last().and_then(|entry| entry.content.as_ref()))
+    }
+
+    fn current_bundle(&self, bundle_id: &str) -> Option<&Bundle> {
+        self.histories.get(bundle_id)?.last()?.content.as_ref()
+    }
+
+    /// Version number of the current bundle, `None` if it does not exist or is deleted
+    fn current_version(&self, bundle_id: &str) -> Option<u64> {
+        let history = self.histories.get(bundle_id)?;
+        history.last()?.content.as_ref()?;
+        Some(history.len() as u64)
+    }
+
+    fn search_by(&self, resource_type: &str, resource_id: &str) -> Vec<Bundle> {
+        let bundle_ids: BTreeSet<&str> = self
+            .index
+            .get(&(resource_type.to_string(), "_id".to_string()))
+            .and_then(|values| values.get(resource_id))
+            .into_iter()
+            .flatten()
+            .map(|key| key.bundle_id.as_str())
+            .collect();
+        bundle_ids.into_iter().filter_map(|bundle_id| self.current_bundle(bundle_id)).cloned().collect()
+    }
+
+    fn check_legal_hold<'a>(&self, bundles: impl IntoIterator<Item = &'a Bundle>) -> Result<()> {
+        check_legal_hold(bundles, |patient_id| Ok(self.holds.contains(patient_id)))
+    }
+
+    fn push_version(&mut self, bundle_id: &str, content: Option<Bundle>) -> String {
+        if let Some(previous) = self.current_bundle(bundle_id).cloned() {
+            self.index_bundle(&previous, false);
+            self.stats.remove_bundle(&previous);
+        }
+        let version = self.histories.get(bundle_id).map_or(0, Vec::len) as u64 + 1;
+        let (last_updated, content) = match content {
+            Some(mut bundle) => (stamp_version(&mut bundle, version), Some(bundle)),
+            None => (crate::utils::get_current_timestamp(), None),
+        };
+        if let Some(bundle) = &content {
+            self.index_bundle(bundle, true);
+            self.stats.add_bundle(bundle);
+        }
+        self.histories.entry(bundle_id.to_string()).or_default().push(HistoryEntry {
+            bundle_id: bundle_id.to_string(),
+            version_id: version.to_string(),
+            last_updated,
+            content,
+        });
+        version.to_string()
+    }
+}
+
+#[async_trait]
+impl BundleStore for MemoryStore {
+    async fn store_bundle_if_match(&self, bundle: Bundle, if_match: Option<&str>) -> Result<String> {
+        let bundle_id = bundle.id.clone();
+        let mut bundles = self.write();
+        check_version(&bundle_id, if_match, bundles.current_version(&bundle_id))?;
+        bundles.push_version(&bundle_id, Some(bundle));
+        Ok(bundle_id)
+    }
+
+    async fn get_bundle(&self, bundle_id: &str) -> Result<Option<Bundle>> {
+        Ok(self.read().current_bundle(bundle_id).cloned())
+    }
+
+    async fn list_bundles(&self) -> Result<Vec<String>> {
+        Ok(self.read().current().map(|bundle| bundle.id.clone()).collect())
+    }
+
+    async fn delete_bundle_if_match(&self, bundle_id: &str, if_match: Option<&str>) -> Result<bool> {
+        let mut bundles = self.write();
+        let current = bundles.current_version(bundle_id);
+        check_version(bundle_id, if_match, current)?;
+        bundles.check_legal_hold(bundles.current_bundle(bundle_id))?;
+        if current.is_some() {
+            bundles.push_version(bundle_id, None);
+        }
+        Ok(current.is_some())
+    }
+
+    async fn purge_bundle(&self, bundle_id: &str) -> Result<bool> {
+        let mut bundles = self.write();
+        let Some(history) = bundles.histories.get(bundle_id) else {
+            return Ok(false);
+        };
+        bundles.check_legal_hold(history.iter().filter_map(|entry| entry.content.as_ref()))?;
+        if let Some(current) = bundles.current_bundle(bundle_id).cloned() {
+            bundles.index_bundle(&current, false);
+            bundles.stats.remove_bundle(&current);
+        }
+        bundles.histories.remove(bundle_id);
+        Ok(true)
+    }
+
+    async fn set_legal_hold(&self, patient_id: &str, held: bool) -> Result<()> {
+        let mut bundles = self.write();
+        if held {
+            bundles.holds.insert(patient_id.to_string());
+        } else {
+            bundles.holds.remove(patient_id);
+        }
+        Ok(())
+    }
+
+    async fn is_on_legal_hold(&self, patient_id: &str) -> Result<bool> {
+        Ok(self.read().holds.contains(patient_id))
+    }
+
+    async fn search_by_patient(&self, patient_id: &str) -> Result<Vec<Bundle>> {
+        Ok(self.read().search_by("Patient", patient_id))
+    }
+
+    async fn search_by_practitioner(&self, practitioner_id: &str) -> Result<Vec<Bundle>> {
+        Ok(self.read().search_by("Practitioner", practitioner_id))
+    }
+
+    async fn get_statistics(&self) -> Result<BundleStats> {
+        Ok(self.read().stats.clone())
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchPage> {
+        search::execute(self, query).await
+    }
+
+    async fn bundle_history(&self, bundle_id: &str) -> Result<Vec<HistoryEntry<Bundle>>> {
+        let mut history = self.read().histories.get(bundle_id).cloned().unwrap_or_default();
+        history.reverse();
+        Ok(history)
+    }
+
+    async fn resource_history(&self, resource_type: &str, resource_id: &str) -> Result<Vec<HistoryEntry<Resource>>> {
+        let histories = self.read().histories.values().cloned().collect();
+        Ok(resource_history_from(resource_type, resource_id, histories))
+    }
+}
+
+#[async_trait]
+impl SearchIndex for MemoryStore {
+    async fn lookup(&self, resource_type: &str, param: &str, range: &ValueRange) -> Result<BTreeSet<ResourceKey>> {
+        let bundles = self.read();
+        let Some(values) = bundles.index.get(&(resource_type.to_string(), param.to_string())) else {
+            return Ok(BTreeSet::new());
+        };
+        let range = (range.0.as_ref().map(String::as_str), range.1.as_ref().map(String::as_str));
+        Ok(values.range::<str, _>(range).flat_map(|(_, keys)| keys).cloned().collect())
+    }
+
+    async fn load(&self, resource_type: &str, keys: &BTreeSet<ResourceKey>) -> Result<Vec<SearchMatch>> {
+        let bundles = self.read();
+        Ok(keys
+            .iter()
+            .filter_map(|key| Some((bundles.current_bundle(&key.bundle_id)?, key)))
+            .flat_map(|(bundle, key)| find_matches(bundle, resource_type, key, &Codec::default()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn test_storage_operations() {
+        let storage = MemoryStore::new();
+        
+        // Create a test bundle
+        let bundle = Bundle::new(
+            "test-bundle-1".to_string(),
+            "document".to_string(),
+            "2024-07-30T10:30:00Z".to_string(),
+        );
+        
+        // Test store and retrieve
+        let bundle_id = storage.store_bundle(bundle).await.unwrap();
+        assert_eq!(bundle_id, "test-bundle-1");
+        
+        let retrieved = storage.get_bundle(&bundle_id).await.unwrap();
+        assert!(retrieved.is_some());
+        
+        // Test list bundles
+        let bundles = storage.list_bundles().await.unwrap();
+        assert_eq!(bundles.len(), 1);
+        assert_eq!(bundles[0], "test-bundle-1");
+        
+        // Test delete
+        let deleted = storage.delete_bundle(&bundle_id).await.unwrap();
+        assert!(deleted);
+        
+        let retrieved_after_delete = storage.get_bundle(&bundle_id).await.unwrap();
+        assert!(retrieved_after_delete.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        crate::storage::conformance::run(&MemoryStore::new()).await;
+    }
+}
@@ -0,0 +1,1003 @@
+use super::codec::{Codec, Stored};
+use super::search::{self, collect_references, index_terms, resource_date, IndexTerm, ResourceKey, SearchIndex, ValueRange};
+use super::{
+    check_legal_hold, check_version, resource_history_from, stamp_version, BundleStats, BundleStore, HistoryEntry, SearchMatch,
+    SearchPage, SearchQuery,
+};
+use crate::error::{Error, Result};
+use crate::models::*;
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::{Client, Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+#[cfg(feature = "encryption")]
+use super::codec::{MasterKey, SealedRecord};
+
+/// Stores the current version of each bundle in the `bundles` collection and
+/// each of its resources in `resources`, indexed by patient, practitioner,
+/// resource type, date and FHIR search terms. Every version, including
+/// deletion tombstones, is kept in `bundle_versions`.
+///
+/// A unique index on `bundle_versions` orders concurrent writes to a bundle.
+/// The `bundles` and `resources` collections are then updated without a
+/// transaction, so they may briefly lag the newest version, but a write never
+/// overwrites a newer one.
+///
+/// With [`MongoStore::with_encryption`], bundles and resources are sealed and
+/// the ids, dates and search terms beside them are keyed hashes or years.
+/// `store_keys` holds the hash key, wrapped by each master key in use.
+///
+/// `statistics` holds one [`BundleStats`] document, updated whenever a
+/// version becomes current in `bundles`, and `legal_holds` one document per
+/// patient whose bundles cannot be deleted.
+pub struct MongoStore {
+    bundles: Collection<BundleDocument>,
+    resources: Collection<ResourceDocument>,
+    versions: Collection<VersionDocument>,
+    keys: Collection<KeyDocument>,
+    statistics: Collection<StatisticsDocument>,
+    holds: Collection<HoldDocument>,
+    codec: Codec,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionDocument {
+    bundle_id: String,
+    version: i64,
+    last_updated: String,
+    /// `None` for a deletion tombstone
+    bundle: Option<Stored<Bundle>>,
+    /// `Type/id` of each resource in the bundle, as stored resource ids
+    #[serde(default)]
+    resources: Vec<String>,
+}
+
+impl VersionDocument {
+    fn new(codec: &Codec, bundle_id: &str, version: i64, last_updated: String, bundle: Option<&Bundle>) -> Result<Self> {
+        let resources = bundle
+            .iter()
+            .flat_map(|bundle| &bundle.entry)
+            .map(|entry| codec.resource_id(&format!("{}/{}", entry.resource.resource_type(), entry.resource.id())))
+            .collect();
+        Ok(Self {
+            bundle_id: bundle_id.to_string(),
+            version,
+            last_updated,
+            bundle: bundle.map(|bundle| codec.seal(bundle.clone())).transpose()?,
+            resources,
+        })
+    }
+
+    fn into_entry(self, codec: &Codec) -> Result<HistoryEntry<Bundle>> {
+        Ok(HistoryEntry {
+            bundle_id: self.bundle_id,
+            version_id: self.version.to_string(),
+            last_updated: self.last_updated,
+            content: self.bundle.map(|bundle| codec.open(bundle)).transpose()?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleDocument {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(default)]
+    version: i64,
+    /// `None` once the bundle is deleted, so an older write cannot recreate it
+    bundle: Option<Stored<Bundle>>,
+}
+
+/// The statistics of the store, with a revision for optimistic updates
+#[derive(Serialize, Deserialize)]
+struct StatisticsDocument {
+    #[serde(rename = "_id")]
+    id: String,
+    revision: i64,
+    /// [`BundleStats`] as encoded by the store's codec, kept as a string
+    /// because its keys may contain characters that field names cannot
+    stats: String,
+}
+
+/// A patient under legal hold
+#[derive(Serialize, Deserialize)]
+struct HoldDocument {
+    /// The patient id as stored
+    #[serde(rename = "_id")]
+    id: String,
+    /// The patient id, sealed so that encrypting a store can rehash `id`
+    patient: Stored<String>,
+}
+
+/// `_id` of the statistics document
+const STATISTICS_ID: &str = "bundles";
+
+/// The index key wrapped by one master key
+#[derive(Serialize, Deserialize)]
+struct KeyDocument {
+    #[serde(rename = "_id")]
+    kid: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceDocument {
+    bundle_id: String,
+    #[serde(default)]
+    version: i64,
+    resource_type: String,
+    resource_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    patient_id: Option<String>,
+    #[serde(default)]
+    practitioner_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+    #[serde(default)]
+    last_updated: String,
+    /// Search terms, matched with `$elemMatch`
+    #[serde(default)]
+    terms: Vec<IndexTerm>,
+    /// Position of the resource's entry in the bundle
+    #[serde(default)]
+    entry_index: i64,
+    resource: Stored<Resource>,
+}
+
+impl ResourceDocument {
+    fn new(
+        codec: &Codec,
+        bundle_id: &str,
+        version: i64,
+        last_updated: &str,
+        entry_index: usize,
+        resource: &Resource,
+    ) -> Result<Self> {
+        let json = serde_json::to_value(resource).expect("resource serialization cannot fail");
+        let own_id = |resource_type: &str| {
+            (resource.resource_type() == resource_type).then(|| resource.id().to_string())
+        };
+
+        let patient_id = own_id("Patient").or_else(|| {
+            ["subject", "patient"]
+                .iter()
+                .filter_map(|field| json.get(field)?.get("reference")?.as_str())
+                .find_map(|reference| reference.strip_prefix("Patient/"))
+                .map(str::to_string)
+        });
+
+        let mut practitioner_ids: Vec<String> = own_id("Practitioner").into_iter().collect();
+        collect_references(&json, "Practitioner/", &mut practitioner_ids);
+        practitioner_ids.sort();
+        practitioner_ids.dedup();
+
+        let date = resource_date(&json).map(|date| {
+            codec.term(IndexTerm { param: "date".to_string(), value: date.to_string() }).value
+        });
+
+        Ok(Self {
+            bundle_id: bundle_id.to_string(),
+            version,
+            resource_type: resource.resource_type().to_string(),
+            resource_id: codec.resource_id(resource.id()),
+            patient_id: patient_id.map(|id| codec.resource_id(&id)),
+            practitioner_ids: practitioner_ids.iter().map(|id| codec.resource_id(id)).collect(),
+            date,
+            last_updated: last_updated.to_string(),
+            terms: index_terms(resource, last_updated).into_iter().map(|term| codec.term(term)).collect(),
+            entry_index: entry_index as i64,
+            resource: codec.seal(resource.clone())?,
+        })
+    }
+}
+
+impl MongoStore {
+    /// Connect to `uri` and use `database`, creating indexes if needed
+    pub async fn connect(uri: &str, database: &str) -> Result<Self> {
+        let client = Client::with_uri_str(uri)
+            .await
+            .map_err(mongo_error(format!("cannot connect to {}", uri)))?;
+        Self::with_database(client.database(database)).await
+    }
+
+    /// Use an existing database handle, creating indexes if needed
+    pub async fn with_database(database: Database) -> Result<Self> {
+        let mut store = Self {
+            bundles: database.collection("bundles"),
+            resources: database.collection("resources"),
+            versions: database.collection("bundle_versions"),
+            keys: database.collection("store_keys"),
+            statistics: database.collection("statistics"),
+            holds: database.collection("legal_holds"),
+            codec: Codec::default(),
+        };
+
+        let indexes = [
+            doc! { "bundleId": 1 },
+            doc! { "resourceType": 1, "resourceId": 1 },
+            doc! { "patientId": 1, "resourceType": 1 },
+            doc! { "practitionerIds": 1 },
+            doc! { "resourceType": 1, "date": 1 },
+            doc! { "resourceType": 1, "terms.param": 1, "terms.value": 1 },
+        ];
+        store
+            .resources
+            .create_indexes(indexes.into_iter().map(|keys| IndexModel::builder().keys(keys).build()))
+            .await
+            .map_err(mongo_error("cannot create indexes"))?;
+
+        let unique = mongodb::options::IndexOptions::builder().unique(true).build();
+        let version_indexes = [
+            IndexModel::builder().keys(doc! { "bundleId": 1, "version": -1 }).options(unique).build(),
+            IndexModel::builder()
+                .keys(doc! { "bundle.entry.resource.resourceType": 1, "bundle.entry.resource.id": 1 })
+                .build(),
+            IndexModel::builder().keys(doc! { "resources": 1 }).build(),
+        ];
+        store
+            .versions
+            .create_indexes(version_indexes)
+            .await
+            .map_err(mongo_error("cannot create indexes"))?;
+
+        let encrypted = store
+            .keys
+            .count_documents(doc! {})
+            .await
+            .map_err(mongo_error("cannot read store keys"))?;
+        if encrypted > 0 {
+            store.codec = Codec::locked();
+        }
+
+        // Statistics of stores that already hold bundles are rebuilt when first read
+        let empty = store
+            .bundles
+            .find_one(doc! {})
+            .await
+            .map_err(mongo_error("cannot read bundles"))?
+            .is_none();
+        if empty {
+            store
+                .statistics
+                .update_one(doc! { "_id": STATISTICS_ID }, doc! { "$setOnInsert": { "revision": 0_i64, "stats": "{}" } })
+                .upsert(true)
+                .await
+                .map_err(mongo_error("cannot save statistics"))?;
+        }
+        Ok(store)
+    }
+
+    /// Seal every bundle under `master`, including any already stored
+    /// unencrypted. Fails if the store is encrypted with a different key.
+    #[cfg(feature = "encryption")]
+    pub async fn with_encryption(mut self, master: MasterKey) -> Result<Self> {
+        let index_keys: Vec<KeyDocument> = self
+            .keys
+            .find(doc! {})
+            .await
+            .map_err(mongo_error("cannot read store keys"))?
+            .try_collect()
+            .await
+            .map_err(mongo_error("cannot read store keys"))?;
+        let index_keys: Vec<String> = index_keys.into_iter().map(|key| key.value).collect();
+        self.codec = Codec::encrypted(master, &index_keys)?;
+        if index_keys.is_empty() {
+            self.save_index_key().await?;
+        }
+        // Also finishes sealing a store whose first encrypted open was interrupted
+        self.seal_existing().await?;
+        Ok(self)
+    }
+
+    /// Wrap the data key of every stored record with `master` instead of the
+    /// current master key; record bodies are not re-encrypted. Returns the
+    /// number of records re-wrapped.
+    ///
+    /// Until this returns, the store keeps the index key under both master
+    /// keys. If it fails, open the store with either key and rotate again.
+    #[cfg(feature = "encryption")]
+    pub async fn rotate_master_key(&self, master: MasterKey) -> Result<usize> {
+        self.codec.begin_rotation(master)?;
+        self.save_index_key().await?;
+        let mut rewrapped = 0;
+        rewrapped += rewrap_field(&self.codec, self.bundles.clone_with_type(), "bundle").await?;
+        rewrapped += rewrap_field(&self.codec, self.versions.clone_with_type(), "bundle").await?;
+        rewrapped += rewrap_field(&self.codec, self.resources.clone_with_type(), "resource").await?;
+        rewrapped += rewrap_field(&self.codec, self.holds.clone_with_type(), "patient").await?;
+        rewrapped += self.rewrap_statistics().await?;
+
+        self.keys
+            .delete_many(doc! { "_id": { "$ne": self.codec.master_key_id()? } })
+            .await
+            .map_err(mongo_error("cannot save store keys"))?;
+        self.codec.finish_rotation()?;
+        Ok(rewrapped)
+    }
+
+    /// Save the index key wrapped by the current master key
+    #[cfg(feature = "encryption")]
+    async fn save_index_key(&self) -> Result<()> {
+        let key = KeyDocument {
+            kid: self.codec.master_key_id()?,
+            value: self.codec.wrapped_index_key()?,
+        };
+        self.keys
+            .replace_one(doc! { "_id": &key.kid }, key)
+            .upsert(true)
+            .await
+            .map_err(mongo_error("cannot save store keys"))?;
+        Ok(())
+    }
+
+    /// Seal the versions and bundles still stored unencrypted, and reindex
+    /// their resources with hashed ids and terms
+    #[cfg(feature = "encryption")]
+    async fn seal_existing(&self) -> Result<()> {
+        let plain = doc! { "bundle": { "$ne": null }, "bundle.kid": { "$exists": false } };
+        let error = || mongo_error("cannot seal stored bundles");
+
+        let versions: Vec<VersionDocument> =
+            self.versions.find(plain.clone()).await.map_err(error())?.try_collect().await.map_err(error())?;
+        for document in versions {
+            let bundle = document.bundle.map(|bundle| self.codec.open(bundle)).transpose()?;
+            let sealed = VersionDocument::new(
+                &self.codec,
+                &document.bundle_id,
+                document.version,
+                document.last_updated,
+                bundle.as_ref(),
+            )?;
+            self.versions
+                .replace_one(doc! { "bundleId": &document.bundle_id, "version": document.version }, sealed)
+                .await
+                .map_err(error())?;
+        }
+
+        let bundles: Vec<BundleDocument> =
+            self.bundles.find(plain).await.map_err(error())?.try_collect().await.map_err(error())?;
+        for document in bundles {
+            let Some(bundle) = document.bundle.map(|bundle| self.codec.open(bundle)).transpose()? else {
+                continue;
+            };
+            let resources = self.resource_documents(&document.id, document.version, &bundle)?;
+            let sealed = BundleDocument {
+                id: document.id.clone(),
+                version: document.version,
+                bundle: Some(self.codec.seal(bundle)?),
+            };
+            self.bundles
+                .replace_one(doc! { "_id": &document.id, "version": document.version }, sealed)
+                .await
+                .map_err(error())?;
+            self.resources
+                .delete_many(doc! { "bundleId": &document.id, "version": document.version })
+                .await
+                .map_err(error())?;
+            if !resources.is_empty() {
+                self.resources.insert_many(resources).await.map_err(error())?;
+            }
+        }
+
+        let holds: Vec<HoldDocument> = self
+            .holds
+            .find(doc! { "patient.kid": { "$exists": false } })
+            .await
+            .map_err(error())?
+            .try_collect()
+            .await
+            .map_err(error())?;
+        for document in holds {
+            let patient_id = self.codec.open(document.patient)?;
+            self.holds
+                .replace_one(doc! { "_id": self.codec.resource_id(&patient_id) }, self.hold_document(&patient_id)?)
+                .upsert(true)
+                .await
+                .map_err(error())?;
+            self.holds.delete_one(doc! { "_id": &document.id }).await.map_err(error())?;
+        }
+
+        // Re-encoding seals statistics that are still unencrypted
+        self.update_statistics(None, None).await
+    }
+
+    /// Re-wrap the statistics if they are not wrapped by the current master
+    /// key, returning how many records were re-wrapped
+    #[cfg(feature = "encryption")]
+    async fn rewrap_statistics(&self) -> Result<usize> {
+        let error = || mongo_error("cannot re-wrap statistics");
+        loop {
+            let Some(document) = self.statistics.find_one(doc! { "_id": STATISTICS_ID }).await.map_err(error())? else {
+                return Ok(0);
+            };
+            let Some(stats) = self.codec.rewrap_json(&document.stats)? else {
+                return Ok(0);
+            };
+            let updated = self
+                .statistics
+                .update_one(
+                    doc! { "_id": STATISTICS_ID, "revision": document.revision },
+                    doc! { "$set": { "stats": stats, "revision": document.revision + 1 } },
+                )
+                .await
+                .map_err(error())?;
+            if updated.matched_count == 1 {
+                return Ok(1);
+            }
+        }
+    }
+
+    fn hold_document(&self, patient_id: &str) -> Result<HoldDocument> {
+        Ok(HoldDocument {
+            id: self.codec.resource_id(patient_id),
+            patient: self.codec.seal(patient_id.to_string())?,
+        })
+    }
+
+    async fn check_legal_hold(&self, bundles: &[Bundle]) -> Result<()> {
+        let mut held = Vec::new();
+        for patient_id in bundles.iter().flat_map(search::bundle_patients) {
+            if self.is_on_legal_hold(&patient_id).await? {
+                held.push(patient_id);
+            }
+        }
+        check_legal_hold(bundles, |patient_id| Ok(held.iter().any(|held| held == patient_id)))
+    }
+
+    fn resource_documents(&self, bundle_id: &str, version: i64, bundle: &Bundle) -> Result<Vec<ResourceDocument>> {
+        let last_updated = bundle.meta.as_ref().map(|meta| meta.last_updated.as_str()).unwrap_or_default();
+        bundle
+            .entry
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| ResourceDocument::new(&self.codec, bundle_id, version, last_updated, index, &entry.resource))
+            .collect()
+    }
+
+    /// Record the next version of `bundle_id`, checking `if_match` against
+    /// the current one; `None` records a tombstone. Returns the version
+    /// number, or `None` when deleting a bundle that does not exist.
+    async fn push_version(
+        &self,
+        bundle_id: &str,
+        mut bundle: Option<Bundle>,
+        if_match: Option<&str>,
+    ) -> Result<Option<(i64, Option<Bundle>)>> {
+        loop {
+            let latest = self
+                .versions
+                .find_one(doc! { "bundleId": bundle_id })
+                .sort(doc! { "version": -1 })
+                .await
+                .map_err(mongo_error(format!("cannot read versions of bundle {}", bundle_id)))?;
+            let current = latest
+                .as_ref()
+                .filter(|document| document.bundle.is_some())
+                .map(|document| document.version as u64);
+            check_version(bundle_id, if_match, current)?;
+            if bundle.is_none() && current.is_none() {
+                return Ok(None);
+            }
+
+            let version = latest.map_or(1, |document| document.version + 1);
+            let last_updated = match bundle.as_mut() {
+                Some(bundle) => stamp_version(bundle, version as u64),
+                None => crate::utils::get_current_timestamp(),
+            };
+            let document = VersionDocument::new(&self.codec, bundle_id, version, last_updated, bundle.as_ref())?;
+            match self.versions.insert_one(document).await {
+                Ok(_) => return Ok(Some((version, bundle))),
+                // Another writer took this version number; re-read and check again
+                Err(err) if is_duplicate_key(&err) => continue,
+                Err(err) => {
+                    let message = format!("cannot record version {} of bundle {}", version, bundle_id);
+                    return Err(Error::storage(message).with_source(err));
+                }
+            }
+        }
+    }
+
+    /// Make `version` the current version of a bundle in `bundles` and
+    /// `resources`, unless a newer version got there first
+    async fn project(&self, bundle_id: &str, version: i64, bundle: Option<Bundle>) -> Result<()> {
+        let resources = match &bundle {
+            Some(bundle) => self.resource_documents(bundle_id, version, bundle)?,
+            None => Vec::new(),
+        };
+
+        let sealed = bundle.as_ref().map(|bundle| self.codec.seal(bundle.clone())).transpose()?;
+        let document = BundleDocument { id: bundle_id.to_string(), version, bundle: sealed };
+        let previous = match self
+            .bundles
+            .find_one_and_replace(doc! { "_id": bundle_id, "version": { "$lt": version } }, document)
+            .upsert(true)
+            .return_document(mongodb::options::ReturnDocument::Before)
+            .await
+        {
+            Ok(previous) => previous.and_then(|document| document.bundle).map(|bundle| self.codec.open(bundle)),
+            // The filter missed because a newer version is already current
+            Err(err) if is_duplicate_key(&err) => return Ok(()),
+            Err(err) => return Err(Error::storage(format!("cannot store bundle {}", bundle_id)).with_source(err)),
+        };
+        self.update_statistics(previous.transpose()?.as_ref(), bundle.as_ref()).await?;
+
+        let index_error = || mongo_error(format!("cannot index bundle {}", bundle_id));
+        if !resources.is_empty() {
+            self.resources.insert_many(resources).await.map_err(index_error())?;
+        }
+        self.resources
+            .delete_many(doc! { "bundleId": bundle_id, "version": { "$lt": version } })
+            .await
+            .map_err(index_error())?;
+
+        // A newer version that became current meanwhile may have cleaned up
+        // before our resources were inserted
+        let current = self.bundles.find_one(doc! { "_id": bundle_id }).await.map_err(index_error())?;
+        if current.is_some_and(|document| document.version > version) {
+            self.resources
+                .delete_many(doc! { "bundleId": bundle_id, "version": version })
+                .await
+                .map_err(index_error())?;
+        }
+        Ok(())
+    }
+
+    /// Replace `previous` by `current` in the saved statistics, retrying if
+    /// another writer updates them first. Missing statistics are left to be
+    /// rebuilt in full when first read.
+    async fn update_statistics(&self, previous: Option<&Bundle>, current: Option<&Bundle>) -> Result<()> {
+        let error = || mongo_error("cannot update statistics");
+        loop {
+            let Some(document) = self.statistics.find_one(doc! { "_id": STATISTICS_ID }).await.map_err(error())? else {
+                return Ok(());
+            };
+            let mut stats: BundleStats = self.codec.decode(&document.stats)?;
+            if let Some(previous) = previous {
+                stats.remove_bundle(previous);
+            }
+            if let Some(current) = current {
+                stats.add_bundle(current);
+            }
+            let updated = self
+                .statistics
+                .update_one(
+                    doc! { "_id": STATISTICS_ID, "revision": document.revision },
+                    doc! { "$set": { "stats": self.codec.encode(&stats)?, "revision": document.revision + 1 } },
+                )
+                .await
+                .map_err(error())?;
+            if updated.matched_count == 1 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Bundles whose resources match `filter`, ordered by bundle id
+    async fn bundles_matching(&self, filter: Document) -> Result<Vec<Bundle>> {
+        let ids = self
+            .resources
+            .distinct("bundleId", filter)
+            .await
+            .map_err(mongo_error("search failed"))?;
+        let documents: Vec<BundleDocument> = self
+            .bundles
+            .find(doc! { "_id": { "$in": ids }, "bundle": { "$ne": null } })
+            .sort(doc! { "_id": 1 })
+            .await
+            .map_err(mongo_error("search failed"))?
+            .try_collect()
+            .await
+            .map_err(mongo_error("search failed"))?;
+        documents
+            .into_iter()
+            .filter_map(|document| document.bundle)
+            .map(|bundle| self.codec.open(bundle))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl BundleStore for MongoStore {
+    async fn store_bundle_if_match(&self, bundle: Bundle, if_match: Option<&str>) -> Result<String> {
+        let bundle_id = bundle.id.clone();
+        if let Some((version, bundle)) = self.push_version(&bundle_id, Some(bundle), if_match).await? {
+            self.project(&bundle_id, version, bundle).await?;
+        }
+        Ok(bundle_id)
+    }
+
+    async fn get_bundle(&self, bundle_id: &str) -> Result<Option<Bundle>> {
+        let document = self
+            .bundles
+            .find_one(doc! { "_id": bundle_id })
+            .await
+            .map_err(mongo_error(format!("cannot read bundle {}", bundle_id)))?;
+        document.and_then(|document| document.bundle).map(|bundle| self.codec.open(bundle)).transpose()
+    }
+
+    async fn list_bundles(&self) -> Result<Vec<String>> {
+        let ids = self
+            .bundles
+            .distinct("_id", doc! { "bundle": { "$ne": null } })
+            .await
+            .map_err(mongo_error("cannot list bundles"))?;
+        let mut ids: Vec<String> = ids.into_iter().filter_map(|id| id.as_str().map(str::to_string)).collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn delete_bundle_if_match(&self, bundle_id: &str, if_match: Option<&str>) -> Result<bool> {
+        if let Some(current) = self.get_bundle(bundle_id).await? {
+            self.check_legal_hold(&[current]).await?;
+        }
+        match self.push_version(bundle_id, None, if_match).await? {
+            Some((version, _)) => {
+                self.project(bundle_id, version, None).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn purge_bundle(&self, bundle_id: &str) -> Result<bool> {
+        let versions = self.bundle_history(bundle_id).await?;
+        let contents: Vec<Bundle> = versions.iter().filter_map(|entry| entry.content.clone()).collect();
+        self.check_legal_hold(&contents).await?;
+
+        let error = || mongo_error(format!("cannot purge bundle {}", bundle_id));
+        let current = self.bundles.find_one_and_delete(doc! { "_id": bundle_id }).await.map_err(error())?;
+        self.versions.delete_many(doc! { "bundleId": bundle_id }).await.map_err(error())?;
+        self.resources.delete_many(doc! { "bundleId": bundle_id }).await.map_err(error())?;
+        let current = current.and_then(|document| document.bundle).map(|bundle| self.codec.open(bundle)).transpose()?;
+        self.update_statistics(current.as_ref(), None).await?;
+        Ok(!versions.is_empty())
+    }
+
+    async fn set_legal_hold(&self, patient_id: &str, held: bool) -> Result<()> {
+        let error = || mongo_error(format!("cannot update legal hold on patient {}", patient_id));
+        let filter = doc! { "_id": self.codec.resource_id(patient_id) };
+        if held {
+            self.holds.replace_one(filter, self.hold_document(patient_id)?).upsert(true).await.map_err(error())?;
+        } else {
+            self.holds.delete_one(filter).await.map_err(error())?;
+        }
+        Ok(())
+    }
+
+    async fn is_on_legal_hold(&self, patient_id: &str) -> Result<bool> {
+        let hold = self
+            .holds
+            .find_one(doc! { "_id": self.codec.resource_id(patient_id) })
+            .await
+            .map_err(mongo_error("cannot read legal holds"))?;
+        Ok(hold.is_some())
+    }
+
+    async fn search_by_patient(&self, patient_id: &str) -> Result<Vec<Bundle>> {
+        self.bundles_matching(doc! { "resourceType": "Patient", "resourceId": self.codec.resource_id(patient_id) })
+            .await
+    }
+
+    async fn search_by_practitioner(&self, practitioner_id: &str) -> Result<Vec<Bundle>> {
+        let practitioner_id = self.codec.resource_id(practitioner_id);
+        self.bundles_matching(doc! { "resourceType": "Practitioner", "resourceId": practitioner_id }).await
+    }
+
+    async fn get_statistics(&self) -> Result<BundleStats> {
+        let error = || mongo_error("cannot read statistics");
+        if let Some(document) = self.statistics.find_one(doc! { "_id": STATISTICS_ID }).await.map_err(error())? {
+            return self.codec.decode(&document.stats);
+        }
+
+        let mut stats = BundleStats::default();
+        let mut documents = self.bundles.find(doc! { "bundle": { "$ne": null } }).await.map_err(error())?;
+        while let Some(document) = documents.try_next().await.map_err(error())? {
+            if let Some(bundle) = document.bundle {
+                stats.add_bundle(&self.codec.open(bundle)?);
+            }
+        }
+        let document = StatisticsDocument {
+            id: STATISTICS_ID.to_string(),
+            revision: 0,
+            stats: self.codec.encode(&stats)?,
+        };
+        match self.statistics.insert_one(document).await {
+            Ok(_) => Ok(stats),
+            // Another reader rebuilt them first
+            Err(err) if is_duplicate_key(&err) => Ok(stats),
+            Err(err) => Err(Error::storage("cannot save statistics").with_source(err)),
+        }
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchPage> {
+        search::execute(self, query).await
+    }
+
+    async fn bundle_history(&self, bundle_id: &str) -> Result<Vec<HistoryEntry<Bundle>>> {
+        let documents: Vec<VersionDocument> = self
+            .versions
+            .find(doc! { "bundleId": bundle_id })
+            .sort(doc! { "version": -1 })
+            .await
+            .map_err(mongo_error(format!("cannot read history of bundle {}", bundle_id)))?
+            .try_collect()
+            .await
+            .map_err(mongo_error(format!("cannot read history of bundle {}", bundle_id)))?;
+        documents.into_iter().map(|document| document.into_entry(&self.codec)).collect()
+    }
+
+    async fn resource_history(&self, resource_type: &str, resource_id: &str) -> Result<Vec<HistoryEntry<Resource>>> {
+        // Versions written before `resources` existed are found by their content
+        let reference = self.codec.resource_id(&format!("{}/{}", resource_type, resource_id));
+        let filter = doc! { "$or": [
+            { "resources": reference },
+            { "bundle.entry": { "$elemMatch": { "resource.resourceType": resource_type, "resource.id": resource_id } } },
+        ] };
+        let bundle_ids = self
+            .versions
+            .distinct("bundleId", filter)
+            .await
+            .map_err(mongo_error("cannot read resource history"))?;
+
+        let mut histories = Vec::with_capacity(bundle_ids.len());
+        for bundle_id in bundle_ids.iter().filter_map(|id| id.as_str()) {
+            histories.push(self.bundle_history(bundle_id).await?);
+        }
+        Ok(resource_history_from(resource_type, resource_id, histories))
+    }
+}
+
+#[async_trait]
+impl SearchIndex for MongoStore {
+    async fn lookup(&self, resource_type: &str, param: &str, range: &ValueRange) -> Result<BTreeSet<ResourceKey>> {
+        let range = self.codec.range(param, range);
+        let mut value = Document::new();
+        for (bound, inclusive, exclusive) in [(&range.0, "$gte", "$gt"), (&range.1, "$lte", "$lt")] {
+            match bound {
+                Bound::Included(bound) => value.insert(inclusive, bound),
+                Bound::Excluded(bound) => value.insert(exclusive, bound),
+                Bound::Unbounded => None,
+            };
+        }
+        let mut term = doc! { "param": param };
+        if !value.is_empty() {
+            term.insert("value", value);
+        }
+
+        let documents: Vec<Document> = self
+            .resources
+            .clone_with_type::<Document>()
+            .find(doc! { "resourceType": resource_type, "terms": { "$elemMatch": term } })
+            .projection(doc! { "_id": 0, "bundleId": 1, "resourceId": 1 })
+            .await
+            .map_err(mongo_error("search failed"))?
+            .try_collect()
+            .await
+            .map_err(mongo_error("search failed"))?;
+        Ok(documents
+            .iter()
+            .filter_map(|document| {
+                Some(ResourceKey {
+                    bundle_id: document.get_str("bundleId").ok()?.to_string(),
+                    resource_id: document.get_str("resourceId").ok()?.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    async fn load(&self, resource_type: &str, keys: &BTreeSet<ResourceKey>) -> Result<Vec<SearchMatch>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<Bson> = keys
+            .iter()
+            .map(|key| Bson::Document(doc! { "bundleId": &key.bundle_id, "resourceId": &key.resource_id }))
+            .collect();
+        let documents: Vec<ResourceDocument> = self
+            .resources
+            .find(doc! { "resourceType": resource_type, "$or": keys })
+            .await
+            .map_err(mongo_error("search failed"))?
+            .try_collect()
+            .await
+            .map_err(mongo_error("search failed"))?;
+        documents
+            .into_iter()
+            .map(|document| {
+                Ok(SearchMatch {
+                    bundle_id: document.bundle_id,
+                    last_updated: document.last_updated,
+                    entry_index: document.entry_index as usize,
+                    resource: self.codec.open(document.resource)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Re-wrap the sealed record in `field` of every document in `collection`
+/// whose data key is not wrapped by the current master key
+#[cfg(feature = "encryption")]
+async fn rewrap_field(codec: &Codec, collection: Collection<Document>, field: &str) -> Result<usize> {
+    let error = || mongo_error("cannot re-wrap stored records");
+    let filter = doc! { format!("{}.kid", field): { "$exists": true, "$ne": codec.master_key_id()? } };
+    let mut documents = collection.find(filter).projection(doc! { "_id": 1, field: 1 }).await.map_err(error())?;
+
+    let mut rewrapped = 0;
+    while let Some(document) = documents.try_next().await.map_err(error())? {
+        let record = document.get_document(field).cloned().unwrap_or_default();
+        let mut record: SealedRecord = mongodb::bson::from_document(record)
+            .map_err(|err| Error::storage("cannot re-wrap stored records").with_source(err))?;
+        if codec.rewrap(&mut record)? {
+            let record = mongodb::bson::to_bson(&record).expect("record serialization cannot fail");
+            collection
+                .update_one(doc! { "_id": document.get("_id") }, doc! { "$set": { field: record } })
+                .await
+                .map_err(error())?;
+            rewrapped += 1;
+        }
+    }
+    Ok(rewrapped)
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(&*err.kind, ErrorKind::Write(WriteFailure::WriteError(write)) if write.code == 11000)
+}
+
+fn mongo_error(context: impl Into<String>) -> impl FnOnce(mongodb::error::Error) -> Error {
+    move |err| Error::storage(context).with_source(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    /// A MongoDB server for tests: `MONGODB_TEST_URI` if set, otherwise a
+    /// throwaway `mongod` started from `PATH` on a free port
+    struct TestServer {
+        uri: String,
+        child: Option<Child>,
+        _dir: Option<tempfile::TempDir>,
+    }
+
+    impl TestServer {
+        fn start() -> Option<Self> {
+            if let Ok(uri) = std::env::var("MONGODB_TEST_URI") {
+                return Some(Self { uri, child: None, _dir: None });
+            }
+
+            let dir = tempfile::tempdir().ok()?;
+            let port = TcpListener::bind("127.0.0.1:0").ok()?.local_addr().ok()?.port();
+            let child = Command::new("mongod")
+                .arg("--dbpath").arg(dir.path())
+                .args(["--bind_ip", "127.0.0.1", "--port", &port.to_string()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let server = Self {
+                uri: format!("mongodb://127.0.0.1:{}", port),
+                child: Some(child),
+                _dir: Some(dir),
+            };
+
+            let deadline = Instant::now() + Duration::from_secs(20);
+            while TcpStream::connect(("127.0.0.1", port)).is_err() {
+                if Instant::now() > deadline {
+                    return None;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Some(server)
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            if let Some(child) = self.child.as_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    #[test]
+    fn test_resource_document_keys() {
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let documents: Vec<ResourceDocument> = bundle
+            .entry
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| ResourceDocument::new(&Codec::default(), &bundle.id, 1, "2024-08-01T00:00:00Z", index, &entry.resource))
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let medication = documents.iter().find(|document| document.resource_type == "MedicationRequest").unwrap();
+        assert_eq!(medication.patient_id.as_deref(), Some("patient-123"));
+        assert_eq!(medication.practitioner_ids, vec!["dr-smith"]);
+        assert_eq!(medication.date.as_deref(), Some("2024-07-30T10:45:00Z"));
+
+        let encounter = documents.iter().find(|document| document.resource_type == "Encounter").unwrap();
+        assert_eq!(encounter.date.as_deref(), Some("2024-07-30T09:00:00Z"));
+        assert_eq!(documents[0].patient_id.as_deref(), Some("patient-123"));
+    }
+
+    #[test]
+    fn test_bson_round_trip() {
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let codec = Codec::default();
+        let document = mongodb::bson::to_document(&BundleDocument { id: bundle.id.clone(), version: 1, bundle: Some(codec.seal(bundle.clone()).unwrap()) }).unwrap();
+        let read: BundleDocument = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(codec.open(read.bundle.unwrap()).unwrap(), bundle);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_sealed_documents_hide_phi() {
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let codec = Codec::encrypted(MasterKey::generate().unwrap(), &[]).unwrap();
+        let resource = ResourceDocument::new(&codec, &bundle.id, 1, "2024-08-01T00:00:00Z", 5, &bundle.entry[5].resource).unwrap();
+        let version = VersionDocument::new(&codec, &bundle.id, 1, String::new(), Some(&bundle)).unwrap();
+
+        for document in [mongodb::bson::to_document(&resource).unwrap(), mongodb::bson::to_document(&version).unwrap()] {
+            let json = serde_json::to_string(&document).unwrap();
+            for phi in ["patient-123", "dr-smith", "Doe", "2024-07-30"] {
+                assert!(!json.contains(phi), "{} in {}", phi, json);
+            }
+        }
+        assert_eq!(resource.date.as_deref(), Some("2024"));
+        let read: VersionDocument = mongodb::bson::from_document(mongodb::bson::to_document(&version).unwrap()).unwrap();
+        assert_eq!(read.into_entry(&codec).unwrap().content, Some(bundle));
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let Some(server) = TestServer::start() else {
+            eprintln!("skipping MongoDB conformance: set MONGODB_TEST_URI or put mongod on PATH");
+            return;
+        };
+        let database = format!("rust_ssi_test_{}", crate::utils::generate_random_id());
+        let store = MongoStore::connect(&server.uri, &database).await.unwrap();
+
+        crate::storage::conformance::run(&store).await;
+
+        let client = Client::with_uri_str(&server.uri).await.unwrap();
+        client.database(&database).drop().await.unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted_conformance() {
+        let Some(server) = TestServer::start() else {
+            eprintln!("skipping MongoDB conformance: set MONGODB_TEST_URI or put mongod on PATH");
+            return;
+        };
+        let database = format!("rust_ssi_test_{}", crate::utils::generate_random_id());
+        let store = MongoStore::connect(&server.uri, &database).await.unwrap();
+        let store = store.with_encryption(MasterKey::generate().unwrap()).await.unwrap();
+
+        crate::storage::conformance::run(&store).await;
+        store.rotate_master_key(MasterKey::generate().unwrap()).await.unwrap();
+        for bundle_id in store.list_bundles().await.unwrap() {
+            assert!(store.get_bundle(&bundle_id).await.unwrap().is_some());
+        }
+
+        let client = Client::with_uri_str(&server.uri).await.unwrap();
+        client.database(&database).drop().await.unwrap();
+    }
+}
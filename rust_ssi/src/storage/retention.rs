@@ -0,0 +1,337 @@
+//! Retention of stored bundles: how long each kind of record must be kept,
+//! and a task that purges bundles, with their history, once that time has
+//! passed. Bundles of patients under legal hold are never purged.
+
+use super::backup::Archive;
+use super::search::bundle_patients;
+use super::{BundleStore, Storage};
+use crate::error::{Error, Result};
+use crate::models::*;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[cfg(feature = "encryption")]
+use crate::encryption::EHREncryption;
+
+/// How long bundles are kept, counted from their `timestamp`, or from
+/// `meta.lastUpdated` when they have none. A bundle is kept for the longest
+/// period of the rules it matches, or for the default period if it matches
+/// none. Loads from JSON such as
+/// `{"default_days": 3650, "rules": [{"claim_type": "PRESCRIPTION", "days": 1825}]}`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Days to keep bundles that no rule matches; `None` keeps them forever
+    #[serde(default)]
+    pub default_days: Option<u32>,
+    #[serde(default)]
+    pub rules: Vec<RetentionRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetentionRule {
+    #[serde(flatten)]
+    pub scope: RetentionScope,
+    pub days: u32,
+}
+
+/// The bundles a rule applies to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionScope {
+    /// Bundles holding a resource of this type
+    ResourceType(String),
+    /// Bundles tagged with this claim type; untagged bundles count as EHR
+    ClaimType(ClaimType),
+}
+
+impl RetentionScope {
+    fn matches(&self, bundle: &Bundle) -> bool {
+        match self {
+            RetentionScope::ResourceType(resource_type) => {
+                bundle.entry.iter().any(|entry| entry.resource.resource_type() == resource_type)
+            }
+            RetentionScope::ClaimType(claim_type) => bundle.claim_type().unwrap_or_default() == *claim_type,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep bundles that no rule matches for `days`
+    pub fn with_default_days(mut self, days: u32) -> Self {
+        self.default_days = Some(days);
+        self
+    }
+
+    /// Keep bundles holding a resource of `resource_type` for at least `days`
+    pub fn with_resource_type(mut self, resource_type: &str, days: u32) -> Self {
+        let scope = RetentionScope::ResourceType(resource_type.to_string());
+        self.rules.push(RetentionRule { scope, days });
+        self
+    }
+
+    /// Keep bundles of `claim_type` for at least `days`
+    pub fn with_claim_type(mut self, claim_type: ClaimType, days: u32) -> Self {
+        self.rules.push(RetentionRule { scope: RetentionScope::ClaimType(claim_type), days });
+        self
+    }
+
+    /// Days to keep `bundle`; `None` keeps it forever
+    pub fn retention_days(&self, bundle: &Bundle) -> Option<u32> {
+        let matching = self.rules.iter().filter(|rule| rule.scope.matches(bundle)).map(|rule| rule.days).max();
+        matching.or(self.default_days)
+    }
+
+    /// When `bundle` may be purged; `None` if it is kept forever or has no
+    /// readable timestamp. As a Unix timestamp, this is the `valid_until` to
+    /// give a [`PatientEHR`](crate::PatientEHR) of the bundle, which
+    /// [`EHREncryption::open_ehr`](crate::EHREncryption::open_ehr) refuses to
+    /// open once it has passed.
+    pub fn expires_at(&self, bundle: &Bundle) -> Option<DateTime<Utc>> {
+        let days = self.retention_days(bundle)?;
+        let start = Some(bundle.timestamp.as_str())
+            .filter(|timestamp| !timestamp.is_empty())
+            .or_else(|| bundle.meta.as_ref().map(|meta| meta.last_updated.as_str()))?;
+        Some(parse_instant(start)? + Duration::days(days.into()))
+    }
+}
+
+/// An RFC 3339 instant, or a date taken as midnight UTC
+fn parse_instant(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(instant) = DateTime::parse_from_rfc3339(value) {
+        return Some(instant.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// The outcome of one retention pass, by bundle id
+#[derive(Debug, Default, PartialEq)]
+pub struct RetentionReport {
+    pub purged: Vec<String>,
+    /// Expired bundles kept because a patient of theirs is under legal hold
+    pub held: Vec<String>,
+    /// Archive the purged bundles were written to
+    pub archive: Option<PathBuf>,
+}
+
+impl std::fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Purged {} expired bundles", self.purged.len())?;
+        if let Some(archive) = &self.archive {
+            writeln!(f, "  Archived to: {}", archive.display())?;
+        }
+        for bundle_id in &self.held {
+            writeln!(f, "  Under legal hold: {}", bundle_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies a [`RetentionPolicy`] to a store, once or on a schedule
+pub struct Retention {
+    policy: RetentionPolicy,
+    archive_dir: Option<PathBuf>,
+    #[cfg(feature = "encryption")]
+    archive_encryption: Option<EHREncryption>,
+}
+
+impl Retention {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        Self {
+            policy,
+            archive_dir: None,
+            #[cfg(feature = "encryption")]
+            archive_encryption: None,
+        }
+    }
+
+    /// Archive expired bundles before purging them, in a new subdirectory
+    /// of `dir` for each pass
+    pub fn archive_to(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.archive_dir = Some(dir.into());
+        self
+    }
+
+    /// Encrypt the archives of expired bundles
+    #[cfg(feature = "encryption")]
+    pub fn with_archive_encryption(mut self, encryption: EHREncryption) -> Self {
+        self.archive_encryption = Some(encryption);
+        self
+    }
+
+    /// Purge the bundles of `store` that have expired by now
+    pub async fn run<S: BundleStore + ?Sized>(&self, store: &S) -> Result<RetentionReport> {
+        self.run_at(store, Utc::now()).await
+    }
+
+    /// Purge the bundles of `store` that have expired by `now`
+    pub async fn run_at<S: BundleStore + ?Sized>(&self, store: &S, now: DateTime<Utc>) -> Result<RetentionReport> {
+        let mut report = RetentionReport::default();
+        let mut expired = Vec::new();
+        for bundle_id in store.list_bundles().await? {
+            let Some(bundle) = store.get_bundle(&bundle_id).await? else {
+                continue;
+            };
+            if self.policy.expires_at(&bundle).is_none_or(|expiry| expiry > now) {
+                continue;
+            }
+            let mut held = false;
+            for patient_id in bundle_patients(&bundle) {
+                held |= store.is_on_legal_hold(&patient_id).await?;
+            }
+            match held {
+                true => report.held.push(bundle_id),
+                false => expired.push(bundle),
+            }
+        }
+        if expired.is_empty() {
+            return Ok(report);
+        }
+
+        if let Some(dir) = &self.archive_dir {
+            let path = dir.join(format!("retention-{}", now.format("%Y%m%dT%H%M%SZ")));
+            self.archive(path.clone())?.export_bundles(expired.iter().cloned())?;
+            report.archive = Some(path);
+        }
+        for bundle in expired {
+            match store.purge_bundle(&bundle.id).await {
+                Ok(_) => report.purged.push(bundle.id),
+                // Placed under hold since it was checked
+                Err(Error::LegalHold { .. }) => report.held.push(bundle.id),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Run a pass on `storage` every `period`, starting now, until the task
+    /// is aborted, and hand the outcome of each pass to `on_pass`. Failed
+    /// passes are retried at the next tick.
+    pub fn spawn(
+        self,
+        storage: Storage,
+        period: std::time::Duration,
+        on_pass: impl Fn(Result<RetentionReport>) + Send + 'static,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(period);
+            loop {
+                ticks.tick().await;
+                let report = self.run(&*storage).await;
+                on_pass(report);
+            }
+        })
+    }
+
+    fn archive(&self, path: PathBuf) -> Result<Archive> {
+        let archive = Archive::new(path);
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.archive_encryption {
+            return Ok(archive.with_encryption(EHREncryption::from_key(&encryption.get_key())?));
+        }
+        Ok(archive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStore, OnCollision};
+
+    fn sample_bundle() -> Bundle {
+        crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap()
+    }
+
+    fn patient_bundle(id: &str, patient_id: &str, timestamp: &str) -> Bundle {
+        let mut bundle = Bundle::new(id.to_string(), "collection".to_string(), timestamp.to_string());
+        bundle.add_entry(Resource::Patient(Patient::new(
+            patient_id.to_string(),
+            "Roe".to_string(),
+            vec!["Jane".to_string()],
+            "female".to_string(),
+            "1990-02-01".to_string(),
+        )));
+        bundle
+    }
+
+    #[test]
+    fn test_longest_matching_rule_wins() {
+        let policy: RetentionPolicy = serde_json::from_str(
+            r#"{"default_days": 30, "rules": [
+                {"resource_type": "Observation", "days": 3650},
+                {"claim_type": "PRESCRIPTION", "days": 1825}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy,
+            RetentionPolicy::new()
+                .with_default_days(30)
+                .with_resource_type("Observation", 3650)
+                .with_claim_type(ClaimType::Prescription, 1825)
+        );
+
+        let mut sample = sample_bundle();
+        assert_eq!(policy.retention_days(&sample), Some(3650));
+        sample.entry.retain(|entry| entry.resource.resource_type() != "Observation");
+        assert_eq!(policy.retention_days(&sample), Some(30));
+        sample.set_claim_type(ClaimType::Prescription);
+        assert_eq!(policy.retention_days(&sample), Some(1825));
+        assert_eq!(policy.expires_at(&sample).unwrap().to_rfc3339(), "2029-07-29T10:30:00+00:00");
+        assert_eq!(RetentionPolicy::new().retention_days(&sample), None);
+
+        let dated = patient_bundle("dated", "patient-1", "2024-01-01");
+        assert_eq!(policy.expires_at(&dated).unwrap().to_rfc3339(), "2024-01-31T00:00:00+00:00");
+    }
+
+    #[tokio::test]
+    async fn test_pass_archives_and_purges_expired_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryStore::new();
+        store.store_bundle(sample_bundle()).await.unwrap();
+        store.store_bundle(patient_bundle("held-visit", "patient-456", "2024-08-02T09:00:00Z")).await.unwrap();
+        store.store_bundle(patient_bundle("recent", "patient-789", "2025-06-01T09:00:00Z")).await.unwrap();
+        store.set_legal_hold("patient-456", true).await.unwrap();
+
+        let retention = Retention::new(RetentionPolicy::new().with_default_days(365)).archive_to(dir.path());
+        let now = "2025-09-01T00:00:00Z".parse().unwrap();
+        let report = retention.run_at(&store, now).await.unwrap();
+        assert_eq!(report.purged, vec!["mvp-visit-bundle"]);
+        assert_eq!(report.held, vec!["held-visit"]);
+        assert!(store.bundle_history("mvp-visit-bundle").await.unwrap().is_empty());
+        assert_eq!(store.list_bundles().await.unwrap(), vec!["held-visit", "recent"]);
+
+        // The archive restores what was purged
+        let archived = MemoryStore::new();
+        let archive = Archive::new(report.archive.unwrap());
+        archive.import(&archived, OnCollision::Fail).await.unwrap();
+        assert_eq!(archived.list_bundles().await.unwrap(), vec!["mvp-visit-bundle"]);
+
+        store.set_legal_hold("patient-456", false).await.unwrap();
+        let report = Retention::new(RetentionPolicy::new().with_default_days(365)).run_at(&store, now).await.unwrap();
+        assert_eq!((report.purged, report.archive), (vec!["held-visit".to_string()], None));
+    }
+
+    #[tokio::test]
+    async fn test_spawned_passes_report_to_the_caller() {
+        let storage = Storage::new();
+        storage.store_bundle(sample_bundle()).await.unwrap();
+
+        let (sender, mut reports) = tokio::sync::mpsc::unbounded_channel();
+        let retention = Retention::new(RetentionPolicy::new().with_default_days(30));
+        let task = retention.spawn(storage.clone(), std::time::Duration::from_millis(10), move |report| {
+            let _ = sender.send(report);
+        });
+        assert_eq!(reports.recv().await.unwrap().unwrap().purged, vec!["mvp-visit-bundle"]);
+        assert_eq!(reports.recv().await.unwrap().unwrap(), RetentionReport::default());
+        task.abort();
+        assert!(storage.list_bundles().await.unwrap().is_empty());
+    }
+}
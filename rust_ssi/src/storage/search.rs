@@ -0,0 +1,596 @@
+//! FHIR search over stored resources.
+//!
+//! Backends keep a secondary index of `(resource type, parameter, value)`
+//! terms for every resource in the current version of each bundle, built by
+//! [`index_terms`]. [`execute`] answers a [`SearchQuery`] with range lookups on
+//! that index, then sorts and pages the matching resources. An encrypted
+//! store's index may return more resources than asked for, so every match is
+//! checked against the terms of the loaded resource.
+
+use super::codec::Codec;
+use crate::error::{Error, ParseError, Result};
+use crate::models::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/// Page size when `_count` is not given
+pub const DEFAULT_COUNT: usize = 50;
+
+/// Fields holding the clinically relevant date of a resource, in order of preference
+const DATE_FIELDS: &[&str] = &["effectiveDateTime", "authoredOn", "recordedDate", "occurrenceDateTime", "issued"];
+
+/// Reference parameters, with the resource type a chain on them resolves to
+/// when the search does not name one (`subject:Practitioner.identifier`)
+const REFERENCE_PARAMS: &[(&str, &str)] = &[("subject", "Patient"), ("patient", "Patient")];
+const TOKEN_PARAMS: &[&str] = &["_id", "code", "status", "identifier"];
+const DATE_PARAMS: &[&str] = &["date", "_lastUpdated"];
+const SORT_PARAMS: &[&str] = &["_id", "_lastUpdated", "date", "status"];
+
+/// Sorts after every character used in a FHIR date, so `date + DATE_END` is
+/// the greatest string that starts with `date`
+const DATE_END: char = '~';
+
+/// Index values between two bounds
+pub(super) type ValueRange = (Bound<String>, Bound<String>);
+
+/// One searchable value of a resource
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub(super) struct IndexTerm {
+    pub param: String,
+    pub value: String,
+}
+
+/// A resource in the current version of a bundle
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct ResourceKey {
+    pub bundle_id: String,
+    /// The resource id as the backend stores it
+    pub resource_id: String,
+}
+
+/// The secondary index a backend answers searches from
+#[async_trait]
+pub(super) trait SearchIndex: Sync {
+    /// Resources of `resource_type` with a `param` term inside `range`, or
+    /// possibly more
+    async fn lookup(&self, resource_type: &str, param: &str, range: &ValueRange) -> Result<BTreeSet<ResourceKey>>;
+
+    /// Load the resources of `resource_type` behind `keys`
+    async fn load(&self, resource_type: &str, keys: &BTreeSet<ResourceKey>) -> Result<Vec<SearchMatch>>;
+}
+
+/// A resource found by a search, with the bundle it was read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    pub bundle_id: String,
+    /// `meta.lastUpdated` of the bundle version holding the resource
+    pub last_updated: String,
+    /// Position of the resource's entry in the bundle
+    pub entry_index: usize,
+    pub resource: Resource,
+}
+
+/// One page of search results
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchPage {
+    /// Matches across all pages
+    pub total: usize,
+    pub offset: usize,
+    pub matches: Vec<SearchMatch>,
+    /// `_offset` of the following page, if there is one
+    pub next_offset: Option<usize>,
+}
+
+/// A FHIR search such as `Observation?patient=patient-123&date=ge2024-01-01&_sort=-date`.
+///
+/// Supported parameters are `_id`, `subject`, `patient`, `code`, `status`,
+/// `identifier` (as `system|value`), `date` and `_lastUpdated` (with the `eq`,
+/// `gt`, `lt`, `ge` and `le` prefixes), and reference chains such as
+/// `subject.identifier`. Repeated parameters must all match; comma-separated
+/// values match any. `_sort`, `_count` and `_offset` control paging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchQuery {
+    resource_type: String,
+    clauses: Vec<Clause>,
+    sort: Vec<(String, bool)>,
+    count: usize,
+    offset: usize,
+}
+
+/// Resources with a `param` value inside any of `ranges`. With a chain, the
+/// resources referencing a `chain.target_type` that matches instead.
+#[derive(Debug, Clone, PartialEq)]
+struct Clause {
+    param: String,
+    ranges: Vec<ValueRange>,
+    chain: Option<Chain>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Chain {
+    reference: String,
+    target_type: String,
+}
+
+impl SearchQuery {
+    /// Every resource of `resource_type`
+    pub fn new(resource_type: impl Into<String>) -> Self {
+        Self {
+            resource_type: resource_type.into(),
+            clauses: Vec::new(),
+            sort: Vec::new(),
+            count: DEFAULT_COUNT,
+            offset: 0,
+        }
+    }
+
+    /// Parse a URL query string such as `code=http://loinc.org|8480-6&_count=10`
+    pub fn parse(resource_type: &str, query: &str) -> Result<Self> {
+        let query = query.strip_prefix('?').unwrap_or(query);
+        form_urlencoded::parse(query.as_bytes()).try_fold(Self::new(resource_type), |search, (name, value)| {
+            search.with(&name, &value)
+        })
+    }
+
+    /// Add one `name=value` search parameter
+    pub fn with(mut self, name: &str, value: &str) -> Result<Self> {
+        let error = |message: String| -> Error { ParseError::new(name, message).into() };
+
+        match name {
+            "_count" | "_offset" => {
+                let number = value
+                    .parse()
+                    .map_err(|_| error(format!("expected a number, found {:?}", value)))?;
+                if name == "_count" {
+                    self.count = number;
+                } else {
+                    self.offset = number;
+                }
+            }
+            "_sort" => {
+                for key in value.split(',') {
+                    let (param, descending) = match key.strip_prefix('-') {
+                        Some(param) => (param, true),
+                        None => (key, false),
+                    };
+                    if !SORT_PARAMS.contains(&param) {
+                        return Err(error(format!("cannot sort by {:?}", param)));
+                    }
+                    self.sort.push((param.to_string(), descending));
+                }
+            }
+            _ => {
+                let (param, chain) = match name.split_once('.') {
+                    Some((reference, param)) => (param, Some(parse_chain(reference).map_err(error)?)),
+                    None => (name, None),
+                };
+                let ranges = value
+                    .split(',')
+                    .map(|value| value_range(param, value))
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(error)?;
+                self.clauses.push(Clause {
+                    param: param.to_string(),
+                    ranges,
+                    chain,
+                });
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn resource_type(&self) -> &str {
+        &self.resource_type
+    }
+
+    /// Keep one match per resource, then sort and cut out the requested page
+    fn page(&self, matches: Vec<SearchMatch>) -> SearchPage {
+        // A resource shared by several bundles is read from the most recently
+        // updated one. Resources without an id are told apart by their entry.
+        let mut latest: BTreeMap<(String, Option<(String, usize)>), SearchMatch> = BTreeMap::new();
+        for found in matches {
+            let key = match found.resource.id() {
+                "" => (String::new(), Some((found.bundle_id.clone(), found.entry_index))),
+                id => (id.to_string(), None),
+            };
+            match latest.entry(key) {
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(found);
+                }
+                btree_map::Entry::Occupied(mut entry) => {
+                    let current = entry.get();
+                    let newer = found.last_updated.cmp(&current.last_updated)
+                        .then_with(|| current.bundle_id.cmp(&found.bundle_id));
+                    if newer == Ordering::Greater {
+                        entry.insert(found);
+                    }
+                }
+            }
+        }
+
+        let mut keyed: Vec<(Vec<String>, SearchMatch)> = latest
+            .into_values()
+            .map(|found| (self.sort.iter().map(|(param, _)| sort_value(&found, param)).collect(), found))
+            .collect();
+        keyed.sort_by(|(a_keys, a), (b_keys, b)| {
+            let by_keys = self.sort.iter().zip(a_keys.iter().zip(b_keys)).fold(Ordering::Equal, |order, ((_, descending), (a, b))| {
+                order.then_with(|| if *descending { b.cmp(a) } else { a.cmp(b) })
+            });
+            by_keys
+                .then_with(|| a.resource.id().cmp(b.resource.id()))
+                .then_with(|| a.bundle_id.cmp(&b.bundle_id))
+                .then_with(|| a.entry_index.cmp(&b.entry_index))
+        });
+
+        let total = keyed.len();
+        let matches = keyed
+            .into_iter()
+            .skip(self.offset)
+            .take(self.count)
+            .map(|(_, found)| found)
+            .collect();
+        let end = self.offset + self.count;
+        SearchPage {
+            total,
+            offset: self.offset,
+            matches,
+            next_offset: (self.count > 0 && end < total).then_some(end),
+        }
+    }
+}
+
+impl Clause {
+    /// Whether the resource of `found` has a `param` value inside one of the ranges
+    fn matches(&self, found: &SearchMatch) -> bool {
+        index_terms(&found.resource, &found.last_updated)
+            .iter()
+            .filter(|term| term.param == self.param)
+            .any(|term| self.ranges.iter().any(|range| range_contains(range, &term.value)))
+    }
+}
+
+fn range_contains(range: &ValueRange, value: &str) -> bool {
+    let above = match &range.0 {
+        Bound::Included(low) => value >= low.as_str(),
+        Bound::Excluded(low) => value > low.as_str(),
+        Bound::Unbounded => true,
+    };
+    let below = match &range.1 {
+        Bound::Included(high) => value <= high.as_str(),
+        Bound::Excluded(high) => value < high.as_str(),
+        Bound::Unbounded => true,
+    };
+    above && below
+}
+
+fn parse_chain(reference: &str) -> std::result::Result<Chain, String> {
+    let (reference, target_type) = match reference.split_once(':') {
+        Some((reference, target_type)) => (reference, Some(target_type)),
+        None => (reference, None),
+    };
+    let default_type = REFERENCE_PARAMS
+        .iter()
+        .find(|(param, _)| *param == reference)
+        .map(|(_, target_type)| *target_type)
+        .ok_or_else(|| format!("cannot chain through {:?}", reference))?;
+    Ok(Chain {
+        reference: reference.to_string(),
+        target_type: target_type.unwrap_or(default_type).to_string(),
+    })
+}
+
+/// The index values a search value matches. Dates match every value they
+/// are a prefix of, so `2024-07` covers the whole month.
+fn value_range(param: &str, value: &str) -> std::result::Result<ValueRange, String> {
+    if value.is_empty() {
+        return Err("empty search value".to_string());
+    }
+
+    if DATE_PARAMS.contains(&param) {
+        let (prefix, date) = match value.get(..2) {
+            Some(prefix) if prefix.chars().all(|ch| ch.is_ascii_lowercase()) => (prefix, &value[2..]),
+            _ => ("eq", value),
+        };
+        if !date.starts_with(|ch: char| ch.is_ascii_digit()) {
+            return Err(format!("invalid date {:?}", date));
+        }
+        let end = format!("{}{}", date, DATE_END);
+        let date = date.to_string();
+        return match prefix {
+            "eq" => Ok((Bound::Included(date), Bound::Included(end))),
+            "ge" => Ok((Bound::Included(date), Bound::Unbounded)),
+            "gt" => Ok((Bound::Excluded(end), Bound::Unbounded)),
+            "le" => Ok((Bound::Unbounded, Bound::Included(end))),
+            "lt" => Ok((Bound::Unbounded, Bound::Excluded(date))),
+            _ => Err(format!("unsupported date prefix {:?}", prefix)),
+        };
+    }
+
+    if TOKEN_PARAMS.contains(&param) || REFERENCE_PARAMS.iter().any(|(reference, _)| *reference == param) {
+        Ok((Bound::Included(value.to_string()), Bound::Included(value.to_string())))
+    } else {
+        Err(format!("unsupported search parameter {:?}", param))
+    }
+}
+
+fn sort_value(found: &SearchMatch, param: &str) -> String {
+    match param {
+        "_id" => found.resource.id().to_string(),
+        "_lastUpdated" => found.last_updated.clone(),
+        _ => {
+            let json = serde_json::to_value(&found.resource).expect("resource serialization cannot fail");
+            let value = match param {
+                "date" => resource_date(&json),
+                _ => json.get(param).and_then(Value::as_str),
+            };
+            value.unwrap_or_default().to_string()
+        }
+    }
+}
+
+/// The clinically relevant date of a resource as written in its JSON
+pub(super) fn resource_date(json: &Value) -> Option<&str> {
+    DATE_FIELDS
+        .iter()
+        .find_map(|field| json.get(field)?.as_str())
+        .or_else(|| json.get("period")?.get("start")?.as_str())
+}
+
+/// The search terms a backend indexes for `resource`, read from a bundle
+/// version last updated at `last_updated`
+pub(super) fn index_terms(resource: &Resource, last_updated: &str) -> Vec<IndexTerm> {
+    let json = serde_json::to_value(resource).expect("resource serialization cannot fail");
+    let mut terms = Vec::new();
+
+    // Every resource has an `_id` term, empty without an id, so that a
+    // search without parameters finds it
+    terms.push(IndexTerm {
+        param: "_id".to_string(),
+        value: resource.id().to_string(),
+    });
+    let mut add = |param: &str, value: &str| {
+        if !value.is_empty() {
+            terms.push(IndexTerm {
+                param: param.to_string(),
+                value: value.to_string(),
+            });
+        }
+    };
+    add("_lastUpdated", last_updated);
+    add("status", json.get("status").and_then(Value::as_str).unwrap_or_default());
+    add("date", resource_date(&json).unwrap_or_default());
+
+    // References match with or without their resource type: `Patient/123` or `123`
+    let reference = |field: &str| json.get(field)?.get("reference")?.as_str();
+    if let Some(subject) = reference("subject") {
+        add("subject", subject);
+        add("subject", subject.rsplit('/').next().unwrap_or_default());
+    }
+    if let Some(patient) = ["subject", "patient"].iter().find_map(|field| reference(field)?.strip_prefix("Patient/")) {
+        add("patient", &format!("Patient/{}", patient));
+        add("patient", patient);
+    }
+
+    // Tokens match as `code`, `system|code`, `system|` and, without a system, `|code`
+    let mut add_token = |param: &str, system: &str, code: &str| {
+        if code.is_empty() {
+            return;
+        }
+        add(param, code);
+        if system.is_empty() {
+            add(param, &format!("|{}", code));
+        } else {
+            add(param, &format!("{}|{}", system, code));
+            add(param, &format!("{}|", system));
+        }
+    };
+    let string = |value: &Value, field: &str| value.get(field).and_then(Value::as_str).unwrap_or_default().to_string();
+    let codings = ["code", "medicationCodeableConcept"]
+        .iter()
+        .filter_map(|field| json.get(field)?.get("coding")?.as_array())
+        .flatten();
+    for coding in codings {
+        add_token("code", &string(coding, "system"), &string(coding, "code"));
+    }
+    for identifier in json.get("identifier").and_then(Value::as_array).into_iter().flatten() {
+        add_token("identifier", &string(identifier, "system"), &string(identifier, "value"));
+    }
+
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+/// Answer `query` from `index`
+pub(super) async fn execute<I: SearchIndex + ?Sized>(index: &I, query: &SearchQuery) -> Result<SearchPage> {
+    let resource_type = query.resource_type.as_str();
+    let mut keys: Option<BTreeSet<ResourceKey>> = None;
+
+    for clause in &query.clauses {
+        let found = match &clause.chain {
+            None => lookup_any(index, resource_type, &clause.param, &clause.ranges).await?,
+            Some(chain) => {
+                let targets = lookup_any(index, &chain.target_type, &clause.param, &clause.ranges).await?;
+                let references: BTreeSet<String> = index
+                    .load(&chain.target_type, &targets)
+                    .await?
+                    .into_iter()
+                    .filter(|target| clause.matches(target))
+                    .map(|target| format!("{}/{}", chain.target_type, target.resource.id()))
+                    .collect();
+                let ranges: Vec<ValueRange> = references
+                    .into_iter()
+                    .map(|reference| (Bound::Included(reference.clone()), Bound::Included(reference)))
+                    .collect();
+                lookup_any(index, resource_type, &chain.reference, &ranges).await?
+            }
+        };
+        let found = match keys {
+            Some(keys) => keys.intersection(&found).cloned().collect(),
+            None => found,
+        };
+        let empty = found.is_empty();
+        keys = Some(found);
+        if empty {
+            break;
+        }
+    }
+
+    let keys = match keys {
+        Some(keys) => keys,
+        None => index.lookup(resource_type, "_id", &(Bound::Unbounded, Bound::Unbounded)).await?,
+    };
+    let matches = index
+        .load(resource_type, &keys)
+        .await?
+        .into_iter()
+        .filter(|found| query.clauses.iter().all(|clause| clause.chain.is_some() || clause.matches(found)))
+        .collect();
+    Ok(query.page(matches))
+}
+
+async fn lookup_any<I: SearchIndex + ?Sized>(
+    index: &I,
+    resource_type: &str,
+    param: &str,
+    ranges: &[ValueRange],
+) -> Result<BTreeSet<ResourceKey>> {
+    let mut keys = BTreeSet::new();
+    for range in ranges {
+        keys.extend(index.lookup(resource_type, param, range).await?);
+    }
+    Ok(keys)
+}
+
+/// Ids of the patients a bundle holds or refers to
+pub(super) fn bundle_patients(bundle: &Bundle) -> BTreeSet<String> {
+    let mut patients = Vec::new();
+    for entry in &bundle.entry {
+        if entry.resource.resource_type() == "Patient" {
+            patients.push(entry.resource.id().to_string());
+        }
+        let json = serde_json::to_value(&entry.resource).expect("resource serialization cannot fail");
+        collect_references(&json, "Patient/", &mut patients);
+    }
+    patients.into_iter().collect()
+}
+
+/// Collect the ids of every `reference` in `json` that starts with `prefix`
+pub(super) fn collect_references(json: &Value, prefix: &str, ids: &mut Vec<String>) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("reference", Value::String(reference)) => {
+                        if let Some(id) = reference.strip_prefix(prefix) {
+                            ids.push(id.to_string());
+                        }
+                    }
+                    _ => collect_references(value, prefix, ids),
+                }
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| collect_references(item, prefix, ids)),
+        _ => {}
+    }
+}
+
+/// The bundle entries holding the resources behind `key`, as search
+/// matches; several for resources without an id
+pub(super) fn find_matches(bundle: &Bundle, resource_type: &str, key: &ResourceKey, codec: &Codec) -> Vec<SearchMatch> {
+    let last_updated = bundle.meta.as_ref().map(|meta| meta.last_updated.clone()).unwrap_or_default();
+    bundle
+        .entry
+        .iter()
+        .map(|entry| &entry.resource)
+        .enumerate()
+        .filter(|(_, resource)| {
+            resource.resource_type() == resource_type && codec.resource_id(resource.id()) == key.resource_id
+        })
+        .map(|(entry_index, resource)| SearchMatch {
+            bundle_id: bundle.id.clone(),
+            last_updated: last_updated.clone(),
+            entry_index,
+            resource: resource.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_bundle() -> Bundle {
+        crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap()
+    }
+
+    fn values(terms: &[IndexTerm], param: &str) -> Vec<String> {
+        terms.iter().filter(|term| term.param == param).map(|term| term.value.clone()).collect()
+    }
+
+    #[test]
+    fn test_index_terms() {
+        let bundle = sample_bundle();
+        let patient = index_terms(&bundle.entry[0].resource, "2024-08-01T00:00:00Z");
+        assert_eq!(values(&patient, "_id"), vec!["patient-123"]);
+        assert_eq!(values(&patient, "identifier"), vec![
+            "MRN-0012345",
+            "urn:oid:1.2.36.146.595.217.0.1|",
+            "urn:oid:1.2.36.146.595.217.0.1|MRN-0012345",
+        ]);
+        assert_eq!(values(&patient, "_lastUpdated"), vec!["2024-08-01T00:00:00Z"]);
+
+        let encounter = index_terms(&bundle.entry[2].resource, "");
+        assert_eq!(values(&encounter, "patient"), vec!["Patient/patient-123", "patient-123"]);
+        assert_eq!(values(&encounter, "subject"), vec!["Patient/patient-123", "patient-123"]);
+        assert_eq!(values(&encounter, "date"), vec!["2024-07-30T09:00:00Z"]);
+        assert_eq!(values(&encounter, "status"), vec!["finished"]);
+        assert!(values(&encounter, "_lastUpdated").is_empty());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = SearchQuery::parse("Observation", "?subject.identifier=sys%7C42&date=ge2024-01,lt2023&_sort=-date,_id&_count=5").unwrap();
+        assert_eq!(query.count, 5);
+        assert_eq!(query.sort, vec![("date".to_string(), true), ("_id".to_string(), false)]);
+        assert_eq!(query.clauses[0].chain, Some(Chain { reference: "subject".to_string(), target_type: "Patient".to_string() }));
+        assert_eq!(query.clauses[0].ranges, vec![(Bound::Included("sys|42".to_string()), Bound::Included("sys|42".to_string()))]);
+        assert_eq!(query.clauses[1].ranges, vec![
+            (Bound::Included("2024-01".to_string()), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded("2023".to_string())),
+        ]);
+
+        let chained = SearchQuery::new("Encounter").with("subject:Practitioner._id", "dr-smith").unwrap();
+        assert_eq!(chained.clauses[0].chain.as_ref().unwrap().target_type, "Practitioner");
+    }
+
+    #[test]
+    fn test_parse_query_errors() {
+        for query in ["name=Doe", "date=xx2024", "date=soon", "_count=ten", "_sort=name", "code=", "performer.identifier=x"] {
+            assert!(matches!(SearchQuery::parse("Observation", query), Err(Error::Parse(_))), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_date_ranges_cover_prefixes() {
+        let stored = "2024-07-30T09:15:00Z";
+        for (search, expected) in [
+            ("2024-07-30", true),
+            ("2024-07", true),
+            ("2024-07-31", false),
+            ("le2024-07-30", true),
+            ("lt2024-07-30", false),
+            ("ge2024-07-30", true),
+            ("gt2024-07-30", false),
+            ("gt2024-07-29", true),
+        ] {
+            assert_eq!(range_contains(&value_range("date", search).unwrap(), stored), expected, "{}", search);
+        }
+    }
+}
@@ -0,0 +1,868 @@
+use super::codec::Codec;
+use super::search::{self, find_matches, index_terms, ResourceKey, SearchIndex, ValueRange};
+use super::{
+    check_legal_hold, check_version, resource_history_from, stamp_version, BundleStats, BundleStore, HistoryEntry, SearchMatch,
+    SearchPage, SearchQuery,
+};
+use crate::error::{Error, Result};
+use crate::models::*;
+use async_trait::async_trait;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+use super::codec::MasterKey;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS bundles (
+        id TEXT PRIMARY KEY,
+        json TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS bundle_resources (
+        bundle_id TEXT NOT NULL REFERENCES bundles(id) ON DELETE CASCADE,
+        resource_type TEXT NOT NULL,
+        resource_id TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS bundle_resources_by_resource
+        ON bundle_resources (resource_type, resource_id);
+    CREATE INDEX IF NOT EXISTS bundle_resources_by_bundle
+        ON bundle_resources (bundle_id);
+    CREATE TABLE IF NOT EXISTS search_index (
+        bundle_id TEXT NOT NULL REFERENCES bundles(id) ON DELETE CASCADE,
+        resource_type TEXT NOT NULL,
+        resource_id TEXT NOT NULL,
+        param TEXT NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS search_index_by_value
+        ON search_index (resource_type, param, value);
+    CREATE INDEX IF NOT EXISTS search_index_by_bundle
+        ON search_index (bundle_id);
+    CREATE TABLE IF NOT EXISTS bundle_versions (
+        bundle_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        last_updated TEXT NOT NULL,
+        json TEXT,
+        PRIMARY KEY (bundle_id, version)
+    );
+    CREATE TABLE IF NOT EXISTS resource_versions (
+        bundle_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        resource_type TEXT NOT NULL,
+        resource_id TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS resource_versions_by_resource
+        ON resource_versions (resource_type, resource_id);
+    CREATE TABLE IF NOT EXISTS store_keys (
+        kid TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS legal_holds (
+        patient_id TEXT PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS statistics (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        json TEXT NOT NULL
+    );
+    -- Statistics of stores that already hold bundles are rebuilt when first read
+    INSERT INTO statistics (id, json)
+        SELECT 1, '{}' WHERE NOT EXISTS (SELECT 1 FROM bundles) AND NOT EXISTS (SELECT 1 FROM statistics);
+    -- Bundles stored before versioning become version 1
+    INSERT INTO resource_versions (bundle_id, version, resource_type, resource_id)
+        SELECT bundle_id, 1, resource_type, resource_id FROM bundle_resources
+        WHERE bundle_id NOT IN (SELECT bundle_id FROM bundle_versions);
+    INSERT INTO bundle_versions (bundle_id, version, last_updated, json)
+        SELECT id, 1, '', json FROM bundles
+        WHERE id NOT IN (SELECT bundle_id FROM bundle_versions);
+";
+
+/// Persists bundles in an embedded SQLite database file. `bundles` and
+/// `bundle_resources` hold the current version of each live bundle for
+/// searches and statistics, and `search_index` their FHIR search terms.
+/// `bundle_versions` keeps every version, with a `NULL` body for deletions.
+/// `statistics` holds one row of [`BundleStats`], updated by every write,
+/// and `legal_holds` the ids of patients whose bundles cannot be deleted.
+///
+/// With [`SqliteStore::with_encryption`], bundle bodies are sealed and the
+/// resource and patient ids and search terms in the other tables are keyed
+/// hashes.
+/// `store_keys` holds the hash key, wrapped by the master key.
+///
+/// Database files are opened in WAL mode with a pool of connections, so
+/// reads run alongside each other and alongside a write. Queries run on
+/// tokio's blocking threads.
+pub struct SqliteStore {
+    pool: Pool<SqliteConnectionManager>,
+    codec: Codec,
+}
+
+/// Connections pooled for a database file
+const POOL_SIZE: u32 = 8;
+
+impl SqliteStore {
+    /// Open or create the database at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let context = format!("cannot open {}", path.as_ref().display());
+        let manager = SqliteConnectionManager::file(path.as_ref()).with_init(init_connection);
+        let pool = Pool::builder().max_size(POOL_SIZE).build(manager).map_err(pool_error(context.clone()))?;
+        pool.get()
+            .map_err(pool_error(context.clone()))?
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .map_err(sql_error(context))?;
+        Self::init(pool)
+    }
+
+    /// A private database that lives only as long as the store
+    pub fn open_in_memory() -> Result<Self> {
+        // Every connection to `:memory:` is a database of its own, so the
+        // pool keeps exactly one for the life of the store
+        let manager = SqliteConnectionManager::memory().with_init(init_connection);
+        let pool = Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(manager)
+            .map_err(pool_error("cannot open in-memory database"))?;
+        Self::init(pool)
+    }
+
+    fn init(pool: Pool<SqliteConnectionManager>) -> Result<Self> {
+        let conn = connection(&pool)?;
+        conn.execute_batch(SCHEMA).map_err(sql_error("cannot create schema"))?;
+        index_unindexed(&conn)?;
+        let encrypted: bool = conn
+            .query_row("SELECT EXISTS (SELECT 1 FROM store_keys)", [], |row| row.get(0))
+            .map_err(sql_error("cannot read store keys"))?;
+        drop(conn);
+        Ok(Self {
+            pool,
+            codec: if encrypted { Codec::locked() } else { Codec::default() },
+        })
+    }
+
+    /// Seal every bundle under `master`, including any already stored
+    /// unencrypted. Fails if the store is encrypted with a different key.
+    #[cfg(feature = "encryption")]
+    pub async fn with_encryption(mut self, master: MasterKey) -> Result<Self> {
+        self.codec = self
+            .with_conn(move |conn, _| {
+                let tx = write_transaction(conn)?;
+                let index_keys = stored_keys(&tx)?;
+                let codec = Codec::encrypted(master, &index_keys)?;
+                if index_keys.is_empty() {
+                    save_index_key(&tx, &codec)?;
+                    seal_existing(&tx, &codec)?;
+                }
+                tx.commit().map_err(sql_error("cannot commit transaction"))?;
+                Ok(codec)
+            })
+            .await?;
+        Ok(self)
+    }
+
+    /// Wrap the data key of every stored record with `master` instead of the
+    /// current master key, in one transaction. Record bodies are not
+    /// re-encrypted. Returns the number of records re-wrapped.
+    #[cfg(feature = "encryption")]
+    pub async fn rotate_master_key(&self, master: MasterKey) -> Result<usize> {
+        self.with_conn(move |conn, codec| {
+            let tx = write_transaction(conn)?;
+            codec.begin_rotation(master)?;
+            let rewrapped = rewrap_all(&tx, codec)
+                .and_then(|count| tx.commit().map_err(sql_error("cannot commit transaction")).map(|_| count));
+            match rewrapped {
+                Ok(_) => codec.finish_rotation()?,
+                Err(_) => codec.abort_rotation()?,
+            }
+            rewrapped
+        })
+        .await
+    }
+
+    /// Run `task` with a pooled connection on a blocking thread
+    async fn with_conn<T, F>(&self, task: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection, &Codec) -> Result<T> + Send + 'static,
+    {
+        let (pool, codec) = (self.pool.clone(), self.codec.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut conn = connection(&pool)?;
+            task(&mut conn, &codec)
+        })
+        .await
+        .map_err(|err| Error::storage("database task failed").with_source(err))?
+    }
+
+    async fn search_by(&self, resource_type: &'static str, resource_id: &str) -> Result<Vec<Bundle>> {
+        let resource_id = resource_id.to_string();
+        self.with_conn(move |conn, codec| {
+            let mut statement = conn
+                .prepare(
+                    "SELECT DISTINCT b.id, b.json FROM bundles b
+                     JOIN bundle_resources r ON r.bundle_id = b.id
+                     WHERE r.resource_type = ?1 AND r.resource_id = ?2
+                     ORDER BY b.id",
+                )
+                .map_err(sql_error("cannot prepare search"))?;
+            let rows = statement
+                .query_map(params![resource_type, codec.resource_id(&resource_id)], |row| row.get::<_, String>(1))
+                .map_err(sql_error("search failed"))?;
+
+            rows.map(|json| codec.decode(&json.map_err(sql_error("search failed"))?)).collect()
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl BundleStore for SqliteStore {
+    async fn store_bundle_if_match(&self, mut bundle: Bundle, if_match: Option<&str>) -> Result<String> {
+        let if_match = if_match.map(str::to_string);
+        self.with_conn(move |conn, codec| {
+            let tx = write_transaction(conn)?;
+
+            check_version(&bundle.id, if_match.as_deref(), current_version(&tx, &bundle.id)?)?;
+            let version = next_version(&tx, &bundle.id)?;
+            let last_updated = stamp_version(&mut bundle, version);
+            let json = codec.encode(&bundle)?;
+            let previous = current_bundle(&tx, codec, &bundle.id)?;
+
+            tx.execute(
+                "INSERT INTO bundle_versions (bundle_id, version, last_updated, json) VALUES (?1, ?2, ?3, ?4)",
+                params![bundle.id, version, last_updated, json],
+            )
+            .and_then(|_| tx.execute("DELETE FROM bundles WHERE id = ?1", params![bundle.id]))
+            .and_then(|_| tx.execute("INSERT INTO bundles (id, json) VALUES (?1, ?2)", params![bundle.id, json]))
+            .map_err(sql_error(format!("cannot store bundle {}", bundle.id)))?;
+            insert_resource_versions(&tx, codec, &bundle, version)?;
+            insert_current_resources(&tx, codec, &bundle)?;
+            update_statistics(&tx, codec, previous.as_ref(), Some(&bundle))?;
+
+            tx.commit().map_err(sql_error("cannot commit transaction"))?;
+            Ok(bundle.id)
+        })
+        .await
+    }
+
+    async fn get_bundle(&self, bundle_id: &str) -> Result<Option<Bundle>> {
+        let bundle_id = bundle_id.to_string();
+        self.with_conn(move |conn, codec| current_bundle(conn, codec, &bundle_id)).await
+    }
+
+    async fn list_bundles(&self) -> Result<Vec<String>> {
+        self.with_conn(|conn, _| {
+            let mut statement = conn
+                .prepare("SELECT id FROM bundles ORDER BY id")
+                .map_err(sql_error("cannot list bundles"))?;
+            let ids = statement
+                .query_map([], |row| row.get(0))
+                .and_then(|rows| rows.collect())
+                .map_err(sql_error("cannot list bundles"))?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn delete_bundle_if_match(&self, bundle_id: &str, if_match: Option<&str>) -> Result<bool> {
+        let (bundle_id, if_match) = (bundle_id.to_string(), if_match.map(str::to_string));
+        self.with_conn(move |conn, codec| {
+            let tx = write_transaction(conn)?;
+
+            check_version(&bundle_id, if_match.as_deref(), current_version(&tx, &bundle_id)?)?;
+            let Some(previous) = current_bundle(&tx, codec, &bundle_id)? else {
+                return Ok(false);
+            };
+            check_legal_hold([&previous], |patient_id| is_held(&tx, codec, patient_id))?;
+            let deleted = tx
+                .execute("DELETE FROM bundles WHERE id = ?1", params![bundle_id])
+                .map_err(sql_error(format!("cannot delete bundle {}", bundle_id)))?;
+            if deleted == 0 {
+                return Ok(false);
+            }
+            let version = next_version(&tx, &bundle_id)?;
+            tx.execute(
+                "INSERT INTO bundle_versions (bundle_id, version, last_updated, json) VALUES (?1, ?2, ?3, NULL)",
+                params![bundle_id, version, crate::utils::get_current_timestamp()],
+            )
+            .map_err(sql_error(format!("cannot delete bundle {}", bundle_id)))?;
+            update_statistics(&tx, codec, Some(&previous), None)?;
+
+            tx.commit().map_err(sql_error("cannot commit transaction"))?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn purge_bundle(&self, bundle_id: &str) -> Result<bool> {
+        let bundle_id = bundle_id.to_string();
+        self.with_conn(move |conn, codec| {
+            let tx = write_transaction(conn)?;
+
+            let versions = history(&tx, codec, &bundle_id)?;
+            check_legal_hold(versions.iter().filter_map(|entry| entry.content.as_ref()), |patient_id| {
+                is_held(&tx, codec, patient_id)
+            })?;
+            let current = current_bundle(&tx, codec, &bundle_id)?;
+            tx.execute("DELETE FROM bundles WHERE id = ?1", params![bundle_id])
+                .and_then(|_| tx.execute("DELETE FROM bundle_versions WHERE bundle_id = ?1", params![bundle_id]))
+                .and_then(|_| tx.execute("DELETE FROM resource_versions WHERE bundle_id = ?1", params![bundle_id]))
+                .map_err(sql_error(format!("cannot purge bundle {}", bundle_id)))?;
+            update_statistics(&tx, codec, current.as_ref(), None)?;
+
+            tx.commit().map_err(sql_error("cannot commit transaction"))?;
+            Ok(!versions.is_empty())
+        })
+        .await
+    }
+
+    async fn set_legal_hold(&self, patient_id: &str, held: bool) -> Result<()> {
+        let sql = match held {
+            true => "INSERT OR IGNORE INTO legal_holds (patient_id) VALUES (?1)",
+            false => "DELETE FROM legal_holds WHERE patient_id = ?1",
+        };
+        let patient_id = patient_id.to_string();
+        self.with_conn(move |conn, codec| {
+            conn.execute(sql, params![codec.resource_id(&patient_id)])
+                .map_err(sql_error(format!("cannot update legal hold on patient {}", patient_id)))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_on_legal_hold(&self, patient_id: &str) -> Result<bool> {
+        let patient_id = patient_id.to_string();
+        self.with_conn(move |conn, codec| is_held(conn, codec, &patient_id)).await
+    }
+
+    async fn search_by_patient(&self, patient_id: &str) -> Result<Vec<Bundle>> {
+        self.search_by("Patient", patient_id).await
+    }
+
+    async fn search_by_practitioner(&self, practitioner_id: &str) -> Result<Vec<Bundle>> {
+        self.search_by("Practitioner", practitioner_id).await
+    }
+
+    async fn get_statistics(&self) -> Result<BundleStats> {
+        self.with_conn(|conn, codec| {
+            if let Some(json) = saved_statistics(conn)? {
+                return codec.decode(&json);
+            }
+
+            let tx = write_transaction(conn)?;
+            let mut stats = BundleStats::default();
+            let mut statement = tx
+                .prepare("SELECT json FROM bundles")
+                .map_err(sql_error("cannot read bundles"))?;
+            let rows: Vec<String> = statement
+                .query_map([], |row| row.get(0))
+                .and_then(|rows| rows.collect())
+                .map_err(sql_error("cannot read bundles"))?;
+            drop(statement);
+            for json in rows {
+                stats.add_bundle(&codec.decode(&json)?);
+            }
+            save_statistics(&tx, codec, &stats)?;
+            tx.commit().map_err(sql_error("cannot commit transaction"))?;
+            Ok(stats)
+        })
+        .await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchPage> {
+        search::execute(self, query).await
+    }
+
+    async fn bundle_history(&self, bundle_id: &str) -> Result<Vec<HistoryEntry<Bundle>>> {
+        let bundle_id = bundle_id.to_string();
+        self.with_conn(move |conn, codec| history(conn, codec, &bundle_id)).await
+    }
+
+    async fn resource_history(&self, resource_type: &str, resource_id: &str) -> Result<Vec<HistoryEntry<Resource>>> {
+        let (resource_type, resource_id) = (resource_type.to_string(), resource_id.to_string());
+        self.with_conn(move |conn, codec| {
+            let mut statement = conn
+                .prepare(
+                    "SELECT DISTINCT bundle_id FROM resource_versions
+                     WHERE resource_type = ?1 AND resource_id = ?2",
+                )
+                .map_err(sql_error("cannot read resource history"))?;
+            let bundle_ids: Vec<String> = statement
+                .query_map(params![resource_type, codec.resource_id(&resource_id)], |row| row.get(0))
+                .and_then(|rows| rows.collect())
+                .map_err(sql_error("cannot read resource history"))?;
+
+            let histories = bundle_ids
+                .iter()
+                .map(|bundle_id| history(conn, codec, bundle_id))
+                .collect::<Result<_>>()?;
+            Ok(resource_history_from(&resource_type, &resource_id, histories))
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl SearchIndex for SqliteStore {
+    async fn lookup(&self, resource_type: &str, param: &str, range: &ValueRange) -> Result<BTreeSet<ResourceKey>> {
+        let mut sql = "SELECT DISTINCT bundle_id, resource_id FROM search_index
+                       WHERE resource_type = ?1 AND param = ?2"
+            .to_string();
+        let mut values = vec![resource_type.to_string(), param.to_string()];
+        let range = self.codec.range(param, range);
+        for (bound, inclusive, exclusive) in [(&range.0, ">=", ">"), (&range.1, "<=", "<")] {
+            let (operator, value) = match bound {
+                Bound::Included(value) => (inclusive, value),
+                Bound::Excluded(value) => (exclusive, value),
+                Bound::Unbounded => continue,
+            };
+            values.push(value.clone());
+            sql.push_str(&format!(" AND value {} ?{}", operator, values.len()));
+        }
+
+        self.with_conn(move |conn, _| {
+            let mut statement = conn.prepare(&sql).map_err(sql_error("cannot prepare search"))?;
+            let keys = statement
+                .query_map(params_from_iter(values), |row| {
+                    Ok(ResourceKey {
+                        bundle_id: row.get(0)?,
+                        resource_id: row.get(1)?,
+                    })
+                })
+                .and_then(|rows| rows.collect())
+                .map_err(sql_error("search failed"))?;
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn load(&self, resource_type: &str, keys: &BTreeSet<ResourceKey>) -> Result<Vec<SearchMatch>> {
+        let mut by_bundle: BTreeMap<&str, Vec<&ResourceKey>> = BTreeMap::new();
+        for key in keys {
+            by_bundle.entry(&key.bundle_id).or_default().push(key);
+        }
+
+        let mut matches = Vec::with_capacity(keys.len());
+        for (bundle_id, keys) in by_bundle {
+            let Some(bundle) = self.get_bundle(bundle_id).await? else {
+                continue;
+            };
+            matches.extend(keys.into_iter().flat_map(|key| find_matches(&bundle, resource_type, key, &self.codec)));
+        }
+        Ok(matches)
+    }
+}
+
+/// Record which resources `version` of a bundle holds, for resource histories
+fn insert_resource_versions(conn: &Connection, codec: &Codec, bundle: &Bundle, version: u64) -> Result<()> {
+    for entry in &bundle.entry {
+        conn.execute(
+            "INSERT INTO resource_versions (bundle_id, version, resource_type, resource_id) VALUES (?1, ?2, ?3, ?4)",
+            params![bundle.id, version, entry.resource.resource_type(), codec.resource_id(entry.resource.id())],
+        )
+        .map_err(sql_error(format!("cannot index bundle {}", bundle.id)))?;
+    }
+    Ok(())
+}
+
+/// Index the resources of the current version of a bundle
+fn insert_current_resources(conn: &Connection, codec: &Codec, bundle: &Bundle) -> Result<()> {
+    for entry in &bundle.entry {
+        conn.execute(
+            "INSERT INTO bundle_resources (bundle_id, resource_type, resource_id) VALUES (?1, ?2, ?3)",
+            params![bundle.id, entry.resource.resource_type(), codec.resource_id(entry.resource.id())],
+        )
+        .map_err(sql_error(format!("cannot index bundle {}", bundle.id)))?;
+    }
+    insert_search_terms(conn, codec, bundle)
+}
+
+fn insert_search_terms(conn: &Connection, codec: &Codec, bundle: &Bundle) -> Result<()> {
+    let last_updated = bundle.meta.as_ref().map(|meta| meta.last_updated.as_str()).unwrap_or_default();
+    for entry in &bundle.entry {
+        let resource_id = codec.resource_id(entry.resource.id());
+        for term in index_terms(&entry.resource, last_updated) {
+            let term = codec.term(term);
+            conn.execute(
+                "INSERT INTO search_index (bundle_id, resource_type, resource_id, param, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![bundle.id, entry.resource.resource_type(), resource_id, term.param, term.value],
+            )
+            .map_err(sql_error(format!("cannot index bundle {}", bundle.id)))?;
+        }
+    }
+    Ok(())
+}
+
+/// The current version of a bundle, `None` if it does not exist or is deleted
+fn current_bundle(conn: &Connection, codec: &Codec, bundle_id: &str) -> Result<Option<Bundle>> {
+    let json: Option<String> = conn
+        .query_row("SELECT json FROM bundles WHERE id = ?1", params![bundle_id], |row| row.get(0))
+        .optional()
+        .map_err(sql_error(format!("cannot read bundle {}", bundle_id)))?;
+    json.map(|json| codec.decode(&json)).transpose()
+}
+
+fn is_held(conn: &Connection, codec: &Codec, patient_id: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM legal_holds WHERE patient_id = ?1)",
+        params![codec.resource_id(patient_id)],
+        |row| row.get(0),
+    )
+    .map_err(sql_error("cannot read legal holds"))
+}
+
+/// The encoded statistics row, `None` until it has been built
+fn saved_statistics(conn: &Connection) -> Result<Option<String>> {
+    conn.query_row("SELECT json FROM statistics WHERE id = 1", [], |row| row.get(0))
+        .optional()
+        .map_err(sql_error("cannot read statistics"))
+}
+
+fn save_statistics(conn: &Connection, codec: &Codec, stats: &BundleStats) -> Result<()> {
+    conn.execute("INSERT OR REPLACE INTO statistics (id, json) VALUES (1, ?1)", params![codec.encode(stats)?])
+        .map_err(sql_error("cannot save statistics"))?;
+    Ok(())
+}
+
+/// Replace `previous` by `current` in the saved statistics. Missing
+/// statistics are left to be rebuilt in full when first read.
+fn update_statistics(conn: &Connection, codec: &Codec, previous: Option<&Bundle>, current: Option<&Bundle>) -> Result<()> {
+    let Some(json) = saved_statistics(conn)? else {
+        return Ok(());
+    };
+    let mut stats: BundleStats = codec.decode(&json)?;
+    if let Some(previous) = previous {
+        stats.remove_bundle(previous);
+    }
+    if let Some(current) = current {
+        stats.add_bundle(current);
+    }
+    save_statistics(conn, codec, &stats)
+}
+
+/// Build search terms for bundles stored before the search index existed
+fn index_unindexed(conn: &Connection) -> Result<()> {
+    let mut statement = conn
+        .prepare(
+            "SELECT json FROM bundles WHERE id IN (SELECT bundle_id FROM bundle_resources)
+             AND id NOT IN (SELECT bundle_id FROM search_index)",
+        )
+        .map_err(sql_error("cannot find unindexed bundles"))?;
+    let rows: Vec<String> = statement
+        .query_map([], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(sql_error("cannot find unindexed bundles"))?;
+    let codec = Codec::default();
+    for json in rows {
+        insert_search_terms(conn, &codec, &codec.decode(&json)?)?;
+    }
+    Ok(())
+}
+
+/// Wrapped index keys saved by [`save_index_key`]
+#[cfg(feature = "encryption")]
+fn stored_keys(conn: &Connection) -> Result<Vec<String>> {
+    let mut statement = conn
+        .prepare("SELECT value FROM store_keys ORDER BY kid")
+        .map_err(sql_error("cannot read store keys"))?;
+    let keys = statement
+        .query_map([], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(sql_error("cannot read store keys"))?;
+    Ok(keys)
+}
+
+/// Replace the saved index keys with the one wrapped by the current master key
+#[cfg(feature = "encryption")]
+fn save_index_key(conn: &Connection, codec: &Codec) -> Result<()> {
+    let (kid, wrapped) = (codec.master_key_id()?, codec.wrapped_index_key()?);
+    conn.execute("DELETE FROM store_keys", [])
+        .and_then(|_| conn.execute("INSERT INTO store_keys (kid, value) VALUES (?1, ?2)", params![kid, wrapped]))
+        .map_err(sql_error("cannot save store keys"))?;
+    Ok(())
+}
+
+/// Seal the bundles of a store that was unencrypted and rebuild its indexes
+/// with hashed ids and terms
+#[cfg(feature = "encryption")]
+fn seal_existing(conn: &Connection, codec: &Codec) -> Result<()> {
+    let plain = Codec::default();
+    conn.execute_batch("DELETE FROM bundle_resources; DELETE FROM search_index; DELETE FROM resource_versions;")
+        .map_err(sql_error("cannot clear indexes"))?;
+
+    let mut statement = conn
+        .prepare("SELECT bundle_id, version, json FROM bundle_versions WHERE json IS NOT NULL")
+        .map_err(sql_error("cannot read bundle versions"))?;
+    let versions: Vec<(String, i64, String)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(sql_error("cannot read bundle versions"))?;
+    for (bundle_id, version, json) in versions {
+        let bundle: Bundle = plain.decode(&json)?;
+        insert_resource_versions(conn, codec, &bundle, version as u64)?;
+        conn.execute(
+            "UPDATE bundle_versions SET json = ?3 WHERE bundle_id = ?1 AND version = ?2",
+            params![bundle_id, version, codec.encode(&bundle)?],
+        )
+        .map_err(sql_error(format!("cannot seal bundle {}", bundle_id)))?;
+    }
+
+    let mut statement = conn
+        .prepare("SELECT id, json FROM bundles")
+        .map_err(sql_error("cannot read bundles"))?;
+    let bundles: Vec<(String, String)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(sql_error("cannot read bundles"))?;
+    for (bundle_id, json) in bundles {
+        let bundle: Bundle = plain.decode(&json)?;
+        insert_current_resources(conn, codec, &bundle)?;
+        conn.execute("UPDATE bundles SET json = ?2 WHERE id = ?1", params![bundle_id, codec.encode(&bundle)?])
+            .map_err(sql_error(format!("cannot seal bundle {}", bundle_id)))?;
+    }
+
+    if let Some(json) = saved_statistics(conn)? {
+        save_statistics(conn, codec, &plain.decode::<BundleStats>(&json)?)?;
+    }
+
+    let mut statement = conn
+        .prepare("SELECT patient_id FROM legal_holds")
+        .map_err(sql_error("cannot read legal holds"))?;
+    let holds: Vec<String> = statement
+        .query_map([], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(sql_error("cannot read legal holds"))?;
+    conn.execute("DELETE FROM legal_holds", []).map_err(sql_error("cannot seal legal holds"))?;
+    for patient_id in holds {
+        conn.execute("INSERT INTO legal_holds (patient_id) VALUES (?1)", params![codec.resource_id(&patient_id)])
+            .map_err(sql_error("cannot seal legal holds"))?;
+    }
+    Ok(())
+}
+
+/// Re-wrap every sealed record and the index key with the current master key
+#[cfg(feature = "encryption")]
+fn rewrap_all(conn: &Connection, codec: &Codec) -> Result<usize> {
+    let mut rewrapped = 0;
+    for (select, update) in [
+        (
+            "SELECT bundle_id, version, json FROM bundle_versions WHERE json IS NOT NULL",
+            "UPDATE bundle_versions SET json = ?3 WHERE bundle_id = ?1 AND version = ?2",
+        ),
+        (
+            "SELECT id, 0, json FROM bundles",
+            "UPDATE bundles SET json = ?3 WHERE id = ?1 AND ?2 = 0",
+        ),
+        (
+            "SELECT '', id, json FROM statistics",
+            "UPDATE statistics SET json = ?3 WHERE id = ?2 AND ?1 = ''",
+        ),
+    ] {
+        let mut statement = conn.prepare(select).map_err(sql_error("cannot read bundles"))?;
+        let rows: Vec<(String, i64, String)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(sql_error("cannot read bundles"))?;
+        for (bundle_id, version, json) in rows {
+            if let Some(json) = codec.rewrap_json(&json)? {
+                conn.execute(update, params![bundle_id, version, json])
+                    .map_err(sql_error(format!("cannot re-wrap bundle {}", bundle_id)))?;
+                rewrapped += 1;
+            }
+        }
+    }
+    save_index_key(conn, codec)?;
+    Ok(rewrapped)
+}
+
+/// Take the database write lock up front, so a version check and the write
+/// that depends on it cannot interleave with another process
+fn write_transaction(conn: &mut Connection) -> Result<Transaction<'_>> {
+    conn.transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(sql_error("cannot start transaction"))
+}
+
+/// Version number of the current bundle, `None` if it does not exist or is deleted
+fn current_version(conn: &Connection, bundle_id: &str) -> Result<Option<u64>> {
+    let version: Option<i64> = conn
+        .query_row(
+            "SELECT version FROM bundle_versions
+             WHERE bundle_id = ?1 AND json IS NOT NULL
+             AND version = (SELECT MAX(version) FROM bundle_versions WHERE bundle_id = ?1)",
+            params![bundle_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sql_error(format!("cannot read versions of bundle {}", bundle_id)))?;
+    Ok(version.map(|version| version as u64))
+}
+
+fn next_version(conn: &Connection, bundle_id: &str) -> Result<u64> {
+    let latest: i64 = conn
+        .query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM bundle_versions WHERE bundle_id = ?1",
+            params![bundle_id],
+            |row| row.get(0),
+        )
+        .map_err(sql_error(format!("cannot read versions of bundle {}", bundle_id)))?;
+    Ok(latest as u64 + 1)
+}
+
+fn history(conn: &Connection, codec: &Codec, bundle_id: &str) -> Result<Vec<HistoryEntry<Bundle>>> {
+    let mut statement = conn
+        .prepare(
+            "SELECT version, last_updated, json FROM bundle_versions
+             WHERE bundle_id = ?1 ORDER BY version DESC",
+        )
+        .map_err(sql_error(format!("cannot read history of bundle {}", bundle_id)))?;
+    let rows = statement
+        .query_map(params![bundle_id], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?))
+        })
+        .map_err(sql_error(format!("cannot read history of bundle {}", bundle_id)))?;
+
+    rows.map(|row| {
+        let (version, last_updated, json) = row.map_err(sql_error(format!("cannot read history of bundle {}", bundle_id)))?;
+        Ok(HistoryEntry {
+            bundle_id: bundle_id.to_string(),
+            version_id: version.to_string(),
+            last_updated,
+            content: json.map(|json| codec.decode(&json)).transpose()?,
+        })
+    })
+    .collect()
+}
+
+fn sql_error(context: impl Into<String>) -> impl FnOnce(rusqlite::Error) -> Error {
+    move |err| Error::storage(context).with_source(err)
+}
+
+fn connection(pool: &Pool<SqliteConnectionManager>) -> Result<PooledConnection<SqliteConnectionManager>> {
+    pool.get().map_err(pool_error("no database connection available"))
+}
+
+fn pool_error(context: impl Into<String>) -> impl FnOnce(r2d2::Error) -> Error {
+    move |err| Error::storage(context).with_source(err)
+}
+
+/// Settings every pooled connection starts with
+fn init_connection(conn: &mut Connection) -> rusqlite::Result<()> {
+    // Overwrite deleted content, so that purged, sealed and replaced
+    // records leave no plaintext behind in free pages
+    conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA secure_delete = ON;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        crate::storage::conformance::run(&SqliteStore::open_in_memory().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_bundles_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundles.db");
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        store.store_bundle(bundle.clone()).await.unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        let stored = store.get_bundle(&bundle.id).await.unwrap().unwrap();
+        assert_eq!(stored.content_hash(), bundle.content_hash());
+        assert_eq!(stored.meta.unwrap().version_id, "1");
+        assert_eq!(store.search_by_patient("patient-123").await.unwrap().len(), 1);
+        assert_eq!(store.bundle_history(&bundle.id).await.unwrap().len(), 1);
+        let stats = store.get_statistics().await.unwrap();
+        assert_eq!(stats.bundles_per_day.get("2024-07-30"), Some(&1));
+
+        // Statistics missing from a database written by an older version are rebuilt
+        connection(&store.pool).unwrap().execute("DELETE FROM statistics", []).unwrap();
+        drop(store);
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.get_statistics().await.unwrap(), stats);
+        store.delete_bundle(&bundle.id).await.unwrap();
+        assert_eq!(store.get_statistics().await.unwrap(), BundleStats::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reads_run_alongside_a_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("bundles.db")).unwrap();
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        store.store_bundle(bundle.clone()).await.unwrap();
+
+        // A write transaction left open on one connection does not block readers
+        let mut writer = connection(&store.pool).unwrap();
+        let tx = write_transaction(&mut writer).unwrap();
+        tx.execute("DELETE FROM legal_holds", []).unwrap();
+        let reads = async { tokio::join!(store.get_bundle(&bundle.id), store.search_by_patient("patient-123")) };
+        let (stored, found) = tokio::time::timeout(std::time::Duration::from_secs(2), reads).await.unwrap();
+        assert_eq!(stored.unwrap().unwrap().content_hash(), bundle.content_hash());
+        assert_eq!(found.unwrap().len(), 1);
+        tx.rollback().unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted_conformance() {
+        let store = SqliteStore::open_in_memory().unwrap().with_encryption(MasterKey::generate().unwrap()).await.unwrap();
+        crate::storage::conformance::run(&store).await;
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encryption_at_rest_and_key_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundles.db");
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let master = MasterKey::generate().unwrap().to_bytes();
+        let open = |key: &[u8]| SqliteStore::open(&path).unwrap().with_encryption(MasterKey::from_bytes(key).unwrap());
+
+        // Bundles stored before encryption was enabled are sealed with the rest
+        SqliteStore::open(&path).unwrap().store_bundle(bundle.clone()).await.unwrap();
+        open(&master).await.unwrap().store_bundle(bundle.clone()).await.unwrap();
+        let raw = String::from_utf8_lossy(&std::fs::read(&path).unwrap()).into_owned();
+        for phi in ["patient-123", "MRN-0012345", "Doe", "dr-smith", "2024-07-30"] {
+            assert!(!raw.contains(phi), "{} stored in cleartext", phi);
+        }
+
+        // Without the key nothing can be read or written
+        let locked = SqliteStore::open(&path).unwrap();
+        assert!(matches!(locked.get_bundle(&bundle.id).await, Err(Error::Crypto { .. })));
+        assert!(matches!(locked.store_bundle(bundle.clone()).await, Err(Error::Crypto { .. })));
+        drop(locked);
+
+        let store = open(&master).await.unwrap();
+        assert_eq!(store.search_by_patient("patient-123").await.unwrap().len(), 1);
+        let query = SearchQuery::parse("Observation", "subject.identifier=MRN-0012345&date=2024-07").unwrap();
+        assert_eq!(store.search(&query).await.unwrap().total, 1);
+
+        let rotated = MasterKey::generate().unwrap().to_bytes();
+        assert_eq!(store.rotate_master_key(MasterKey::from_bytes(&rotated).unwrap()).await.unwrap(), 4);
+        drop(store);
+
+        assert!(matches!(open(&master).await, Err(Error::Crypto { .. })));
+        let store = open(&rotated).await.unwrap();
+        assert_eq!(store.get_bundle(&bundle.id).await.unwrap().unwrap().content_hash(), bundle.content_hash());
+        assert_eq!(store.bundle_history(&bundle.id).await.unwrap().len(), 2);
+        assert_eq!(store.resource_history("Patient", "patient-123").await.unwrap().len(), 2);
+        assert_eq!(store.search(&query).await.unwrap().total, 1);
+        assert_eq!(store.get_statistics().await.unwrap().resources_per_practitioner.get("dr-smith"), Some(&2));
+    }
+}
@@ -0,0 +1,234 @@
+//! Statistics over the current version of every stored bundle. Backends
+//! keep one [`BundleStats`] up to date as bundles are written and deleted
+//! instead of rescanning the store for every request.
+
+use super::search::{bundle_patients, collect_references};
+use crate::models::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// How many entries the text report lists for each ranking
+const REPORT_TOP: usize = 10;
+
+/// Counts over the current bundles of a store. Serializes to JSON for
+/// dashboards. Keys are resource ids without their type, codes as the first
+/// coding's `code` or else the concept's `text`, and days as `YYYY-MM-DD`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BundleStats {
+    pub total_bundles: usize,
+    pub patient_count: usize,
+    pub practitioner_count: usize,
+    pub encounter_count: usize,
+    pub observation_count: usize,
+    pub condition_count: usize,
+    pub medication_count: usize,
+    pub other_count: usize,
+    /// Bundles by the day of their `timestamp`, or of `meta.lastUpdated`
+    /// when they have none
+    pub bundles_per_day: BTreeMap<String, usize>,
+    /// Resources referring to each practitioner
+    pub resources_per_practitioner: BTreeMap<String, usize>,
+    /// Encounters by `class` code
+    pub encounters_per_class: BTreeMap<String, usize>,
+    /// Conditions by code
+    pub condition_codes: BTreeMap<String, usize>,
+    /// Medication requests and statements by medication
+    pub medication_frequency: BTreeMap<String, usize>,
+    /// Size of the canonical JSON of the bundles holding or referring to
+    /// each patient
+    pub bytes_per_patient: BTreeMap<String, u64>,
+}
+
+impl std::fmt::Display for BundleStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Bundle Statistics:")?;
+        writeln!(f, "  Total Bundles: {}", self.total_bundles)?;
+        writeln!(f, "  Patients: {}", self.patient_count)?;
+        writeln!(f, "  Practitioners: {}", self.practitioner_count)?;
+        writeln!(f, "  Encounters: {}", self.encounter_count)?;
+        writeln!(f, "  Observations: {}", self.observation_count)?;
+        writeln!(f, "  Conditions: {}", self.condition_count)?;
+        writeln!(f, "  Medications: {}", self.medication_count)?;
+        writeln!(f, "  Other Resources: {}", self.other_count)?;
+
+        let sections = [
+            ("Bundles per day", self.bundles_per_day.iter().map(|(day, count)| (day.as_str(), *count)).collect()),
+            ("Resources per practitioner", top(&self.resources_per_practitioner, REPORT_TOP)),
+            ("Encounters per class", top(&self.encounters_per_class, REPORT_TOP)),
+            ("Top conditions", self.top_conditions(REPORT_TOP)),
+            ("Top medications", self.top_medications(REPORT_TOP)),
+        ];
+        for (title, counts) in sections {
+            if !counts.is_empty() {
+                writeln!(f, "{}:", title)?;
+                for (key, count) in counts {
+                    writeln!(f, "  {}: {}", key, count)?;
+                }
+            }
+        }
+        if !self.bytes_per_patient.is_empty() {
+            writeln!(f, "Storage per patient:")?;
+            for (patient, bytes) in &self.bytes_per_patient {
+                writeln!(f, "  {}: {}", patient, crate::utils::format_file_size(*bytes))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BundleStats {
+    /// Add `count` resources of the given type
+    pub fn add_resources(&mut self, resource_type: &str, count: usize) {
+        *self.type_count(resource_type) += count;
+    }
+
+    /// Count a bundle that became current
+    pub fn add_bundle(&mut self, bundle: &Bundle) {
+        self.apply(bundle, true);
+    }
+
+    /// Stop counting a bundle that was replaced or deleted. `bundle` must be
+    /// the version that was added, as stored.
+    pub fn remove_bundle(&mut self, bundle: &Bundle) {
+        self.apply(bundle, false);
+    }
+
+    /// The `n` most frequent condition codes, most frequent first
+    pub fn top_conditions(&self, n: usize) -> Vec<(&str, usize)> {
+        top(&self.condition_codes, n)
+    }
+
+    /// The `n` most frequent medications, most frequent first
+    pub fn top_medications(&self, n: usize) -> Vec<(&str, usize)> {
+        top(&self.medication_frequency, n)
+    }
+
+    fn type_count(&mut self, resource_type: &str) -> &mut usize {
+        match resource_type {
+            "Patient" => &mut self.patient_count,
+            "Practitioner" => &mut self.practitioner_count,
+            "Encounter" => &mut self.encounter_count,
+            "Observation" => &mut self.observation_count,
+            "Condition" => &mut self.condition_count,
+            "MedicationRequest" => &mut self.medication_count,
+            _ => &mut self.other_count,
+        }
+    }
+
+    fn apply(&mut self, bundle: &Bundle, add: bool) {
+        let step = |counter: &mut usize| *counter = if add { *counter + 1 } else { counter.saturating_sub(1) };
+
+        step(&mut self.total_bundles);
+        let day = Some(bundle.timestamp.as_str())
+            .filter(|timestamp| !timestamp.is_empty())
+            .or_else(|| bundle.meta.as_ref().map(|meta| meta.last_updated.as_str()));
+        if let Some(day) = day.and_then(|day| day.get(..10)) {
+            tally(&mut self.bundles_per_day, day, 1, add);
+        }
+
+        for entry in &bundle.entry {
+            let resource = &entry.resource;
+            let resource_type = resource.resource_type();
+            let json = serde_json::to_value(resource).expect("resource serialization cannot fail");
+            step(self.type_count(resource_type));
+
+            let mut practitioners = Vec::new();
+            if resource_type != "Practitioner" {
+                collect_references(&json, "Practitioner/", &mut practitioners);
+            }
+            practitioners.sort();
+            practitioners.dedup();
+            for practitioner in &practitioners {
+                tally(&mut self.resources_per_practitioner, practitioner, 1, add);
+            }
+
+            let counts = match resource_type {
+                "Encounter" => Some((&mut self.encounters_per_class, json["class"]["code"].as_str())),
+                "Condition" => Some((&mut self.condition_codes, concept_key(&json["code"]))),
+                "MedicationRequest" | "MedicationStatement" => {
+                    Some((&mut self.medication_frequency, concept_key(&json["medicationCodeableConcept"])))
+                }
+                _ => None,
+            };
+            if let Some((counts, Some(key))) = counts {
+                tally(counts, key, 1, add);
+            }
+        }
+
+        let bytes = content_size(bundle);
+        for patient in &bundle_patients(bundle) {
+            tally(&mut self.bytes_per_patient, patient, bytes, add);
+        }
+    }
+}
+
+/// Add `amount` to, or take it from, the count under `key`, dropping counts
+/// that reach zero
+fn tally<T>(counts: &mut BTreeMap<String, T>, key: &str, amount: T, add: bool)
+where
+    T: Copy + Default + PartialEq + std::ops::Add<Output = T> + std::ops::Sub<Output = T> + PartialOrd,
+{
+    let counter = counts.entry(key.to_string()).or_default();
+    *counter = match add {
+        true => *counter + amount,
+        false if *counter > amount => *counter - amount,
+        false => T::default(),
+    };
+    if *counter == T::default() {
+        counts.remove(key);
+    }
+}
+
+/// The `n` largest counts, ties broken by key
+fn top(counts: &BTreeMap<String, usize>, n: usize) -> Vec<(&str, usize)> {
+    let mut ranked: Vec<(&str, usize)> = counts.iter().map(|(key, count)| (key.as_str(), *count)).collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    ranked.truncate(n);
+    ranked
+}
+
+/// The key of a CodeableConcept: its first coding's code, else its text
+fn concept_key(concept: &Value) -> Option<&str> {
+    concept["coding"][0]["code"]
+        .as_str()
+        .or_else(|| concept["text"].as_str())
+        .filter(|key| !key.is_empty())
+}
+
+/// Size of the bundle's JSON without the server-maintained `meta`, so that
+/// restamping a bundle does not change the size attributed to it
+fn content_size(bundle: &Bundle) -> u64 {
+    let content = Bundle { meta: None, ..bundle.clone() };
+    serde_json::to_vec(&content).map(|json| json.len() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analytics_add_and_remove() {
+        let bundle = crate::FHIRHandler::new()
+            .parse_fhir_json(include_str!("../../FHIR/FHIRBundle.json"))
+            .unwrap();
+        let mut stats = BundleStats::default();
+        stats.add_bundle(&bundle);
+
+        assert_eq!(stats.total_bundles, 1);
+        assert_eq!(stats.bundles_per_day.get("2024-07-30"), Some(&1));
+        assert_eq!(stats.resources_per_practitioner.get("dr-smith"), Some(&2));
+        assert_eq!(stats.encounters_per_class.get("AMB"), Some(&1));
+        assert_eq!(stats.top_conditions(1), vec![("Hypertension", 1)]);
+        assert_eq!(stats.top_medications(1), vec![("Amoxicillin 250 mg capsule", 1)]);
+        assert_eq!(stats.bytes_per_patient.get("patient-123"), Some(&content_size(&bundle)));
+        assert!(stats.to_string().contains("Storage per patient:"));
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(serde_json::from_str::<BundleStats>(&json).unwrap(), stats);
+
+        stats.remove_bundle(&bundle);
+        assert_eq!(stats, BundleStats::default());
+    }
+}
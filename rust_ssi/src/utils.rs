@@ -1,3 +1,4 @@
+use crate::error::{Error, ParseError};
 use sha2::{Sha256, Digest};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -14,7 +15,8 @@ pub fn sha256_hash_string(data: &str) -> String {
     sha256_hash(data.as_bytes())
 }
 
-/// Get current timestamp in ISO 8601 format
+/// Get current timestamp in ISO 8601 format, as a UTC instant such as
+/// `2024-07-30T09:15:00Z`
 pub fn get_current_timestamp() -> String {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -23,7 +25,7 @@ pub fn get_current_timestamp() -> String {
     
     chrono::DateTime::from_timestamp(now as i64, 0)
         .unwrap()
-        .to_rfc3339()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
 }
 
 /// Get current timestamp as Unix timestamp
@@ -85,14 +87,16 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
 }
 
 /// Convert hex string to bytes
-pub fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    if hex.len() % 2 != 0 {
-        return Err("Hex string must have even length".into());
+pub fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, Error> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(ParseError::new("", "Hex string must have even length").into());
     }
     
     let mut bytes = Vec::new();
     for i in (0..hex.len()).step_by(2) {
-        let byte = u8::from_str_radix(&hex[i..i+2], 16)?;
+        let byte = hex.get(i..i+2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or_else(|| ParseError::new("", format!("Invalid hex digit at offset {}", i)))?;
         bytes.push(byte);
     }
     
@@ -172,7 +176,7 @@ mod tests {
         let id2 = generate_random_id();
         
         assert!(id1.starts_with("id-"));
-        assert!(id1.len() == 20); // "id-" + 16 chars
+        assert!(id1.len() == 19); // "id-" + 16 chars
         assert_ne!(id1, id2);
     }
     
@@ -183,6 +187,8 @@ mod tests {
         let converted = hex_to_bytes(&hex).unwrap();
         
         assert_eq!(original, converted);
+        assert!(matches!(hex_to_bytes("abc"), Err(Error::Parse(_))));
+        assert!(matches!(hex_to_bytes("zz"), Err(Error::Parse(_))));
     }
     
     #[test]
@@ -0,0 +1,114 @@
+//! Verification payloads printed as QR codes on rendered documents, so a
+//! pharmacist can check a printed summary against the bundle it came from.
+
+use crate::error::{Error, ParseError, Result};
+use crate::models::Bundle;
+use serde::{Deserialize, Serialize};
+
+/// Current payload format version
+pub const PAYLOAD_VERSION: u32 = 1;
+
+/// What a document's QR code encodes: the bundle hash, who issued it and
+/// where it is anchored on Hedera
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VerificationPayload {
+    #[serde(rename = "v")]
+    pub version: u32,
+    /// `0x`-prefixed SHA-256 of the canonical bundle JSON
+    pub sha256: String,
+    /// DID of the issuing provider
+    #[serde(rename = "iss")]
+    pub issuer_did: String,
+    /// Hedera file or topic id holding the record, e.g. `0.0.4821`
+    #[serde(rename = "ref")]
+    pub ledger_ref: String,
+}
+
+impl VerificationPayload {
+    pub fn for_bundle(bundle: &Bundle, issuer_did: impl Into<String>, ledger_ref: impl Into<String>) -> Self {
+        Self {
+            version: PAYLOAD_VERSION,
+            sha256: bundle.content_hash(),
+            issuer_did: issuer_did.into(),
+            ledger_ref: ledger_ref.into(),
+        }
+    }
+
+    /// The compact JSON string stored in the QR code
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("payload serialization cannot fail")
+    }
+
+    pub fn decode(payload: &str) -> Result<Self> {
+        serde_json::from_str(payload.trim())
+            .map_err(|err| ParseError::new("qr", format!("invalid verification payload: {}", err)).into())
+    }
+}
+
+/// Check a decoded QR payload against `bundle`, returning the payload's
+/// issuer and ledger reference when the bundle hash matches
+pub fn verify(payload: &str, bundle: &Bundle) -> Result<VerificationPayload> {
+    let payload = VerificationPayload::decode(payload)?;
+    if payload.version != PAYLOAD_VERSION {
+        return Err(Error::Validation(vec![format!(
+            "unsupported verification payload version {}",
+            payload.version
+        )]));
+    }
+
+    let actual = bundle.content_hash();
+    if !actual.eq_ignore_ascii_case(&payload.sha256) {
+        return Err(Error::Validation(vec![format!(
+            "bundle hashes to {} but the QR code records {}",
+            actual, payload.sha256
+        )]));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FHIRHandler;
+
+    fn sample_bundle() -> Bundle {
+        FHIRHandler::new()
+            .parse_fhir_json(include_str!("../FHIR/FHIRBundle.json"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify_round_trip() {
+        let bundle = sample_bundle();
+        let payload = VerificationPayload::for_bundle(&bundle, "did:hedera:testnet:0.0.7654321", "0.0.9999999");
+
+        let verified = verify(&payload.encode(), &bundle).unwrap();
+        assert_eq!(verified, payload);
+        assert_eq!(verified.sha256, crate::utils::sha256_hash(&serde_json::to_vec(&bundle).unwrap()));
+    }
+
+    #[test]
+    fn test_verify_rejects_modified_bundle() {
+        let mut bundle = sample_bundle();
+        let payload = VerificationPayload::for_bundle(&bundle, "did:hedera:testnet:0.0.7654321", "0.0.9999999").encode();
+        bundle.timestamp = "2024-08-01T00:00:00Z".to_string();
+
+        assert!(matches!(verify(&payload, &bundle), Err(Error::Validation(_))));
+        assert!(matches!(verify("not a payload", &bundle), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_verify_covers_tags_but_not_version() {
+        let mut bundle = sample_bundle();
+        bundle.set_claim_type(crate::models::ClaimType::LabResults);
+        let payload = VerificationPayload::for_bundle(&bundle, "did:hedera:testnet:0.0.7654321", "0.0.9999999").encode();
+
+        let meta = bundle.meta.as_mut().unwrap();
+        meta.version_id = "2".to_string();
+        meta.last_updated = "2024-08-01T00:00:00Z".to_string();
+        assert!(verify(&payload, &bundle).is_ok());
+
+        bundle.meta.as_mut().unwrap().tag[0].code = "PRESCRIPTION".to_string();
+        assert!(matches!(verify(&payload, &bundle), Err(Error::Validation(_))));
+    }
+}
@@ -0,0 +1,144 @@
+#![cfg(feature = "cli")]
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+const SAMPLE_BUNDLE: &str = include_str!("../FHIR/FHIRBundle.json");
+
+fn rust_ssi() -> Command {
+    Command::cargo_bin("rust_ssi").unwrap()
+}
+
+/// The sample bundle without the intent of its MedicationRequest, which
+/// fails strict parsing
+fn bundle_without_intent() -> String {
+    let mut bundle: Value = serde_json::from_str(SAMPLE_BUNDLE).unwrap();
+    bundle["entry"][5]["resource"].as_object_mut().unwrap().remove("intent");
+    bundle.to_string()
+}
+
+fn write_bundles(dir: &Path, bundles: &[(&str, &str)]) {
+    for (name, json) in bundles {
+        fs::write(dir.join(name), json).unwrap();
+    }
+}
+
+#[test]
+fn test_validate_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    write_bundles(dir.path(), &[("a.json", SAMPLE_BUNDLE), ("b.json", &bundle_without_intent())]);
+    fs::write(dir.path().join("notes.txt"), "not a bundle").unwrap();
+
+    rust_ssi()
+        .arg("validate")
+        .arg(dir.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a.json: valid").and(predicate::str::contains("b.json: valid")))
+        .stderr(predicate::str::contains("2 bundle(s) processed: 2 succeeded, 0 invalid, 0 failed"));
+
+    rust_ssi()
+        .args(["validate", "--strict"])
+        .arg(dir.path())
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("b.json: invalid: entry[5].resource.intent: missing required element"))
+        .stderr(predicate::str::contains("2 bundle(s) processed: 1 succeeded, 1 invalid, 0 failed"));
+}
+
+#[test]
+fn test_failures_outrank_invalid_bundles() {
+    let dir = tempfile::tempdir().unwrap();
+    write_bundles(dir.path(), &[("a.json", "{ not json")]);
+
+    rust_ssi().arg("validate").arg(dir.path().join("a.json")).assert().code(3);
+    rust_ssi()
+        .arg("validate")
+        .arg(dir.path().join("a.json"))
+        .arg(dir.path().join("missing.json"))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("1 invalid, 1 failed"));
+    rust_ssi().args(["parse", "--format", "pdf"]).arg(dir.path()).assert().code(1);
+}
+
+#[test]
+fn test_parse_directory_writes_one_file_per_bundle() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out");
+    write_bundles(dir.path(), &[("a.json", SAMPLE_BUNDLE), ("b.json", SAMPLE_BUNDLE)]);
+
+    rust_ssi().args(["parse", "--format", "json", "-o"]).arg(&output).arg(dir.path()).assert().success();
+
+    let original: Value = serde_json::from_str(SAMPLE_BUNDLE).unwrap();
+    for name in ["a.json", "b.json"] {
+        let written: Value = serde_json::from_str(&fs::read_to_string(output.join(name)).unwrap()).unwrap();
+        assert_eq!(written, original);
+    }
+}
+
+#[test]
+fn test_encrypt_creates_a_private_key_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = dir.path().join("ehr.key");
+    let sealed = dir.path().join("bundle.ehre");
+    write_bundles(dir.path(), &[("bundle.json", SAMPLE_BUNDLE)]);
+
+    rust_ssi()
+        .arg("encrypt")
+        .arg(dir.path().join("bundle.json"))
+        .arg("--key-file")
+        .arg(&key)
+        .arg("-o")
+        .arg(&sealed)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Generated new key"));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    rust_ssi()
+        .arg("decrypt")
+        .arg(&sealed)
+        .arg("--key-file")
+        .arg(&key)
+        .assert()
+        .success()
+        .stdout(SAMPLE_BUNDLE);
+}
+
+#[test]
+fn test_decrypt_ciphertext_with_separate_nonce() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = dir.path().join("ehr.key");
+    let ciphertext = dir.path().join("bundle.bin");
+    rust_ssi().arg("encrypt").arg("--key-file").arg(&key).write_stdin("").assert().success();
+    let key_bytes = rust_ssi::utils::hex_to_bytes(&fs::read_to_string(&key).unwrap()).unwrap();
+    let encryption = rust_ssi::EHREncryption::from_key(&key_bytes).unwrap();
+    let (encrypted, nonce) = encryption.encrypt(SAMPLE_BUNDLE.as_bytes()).unwrap();
+    fs::write(&ciphertext, &encrypted).unwrap();
+
+    rust_ssi()
+        .arg("decrypt")
+        .arg(&ciphertext)
+        .arg("--key-file")
+        .arg(&key)
+        .args(["--nonce", &rust_ssi::utils::bytes_to_hex(&nonce)])
+        .assert()
+        .success()
+        .stdout(SAMPLE_BUNDLE);
+    rust_ssi()
+        .arg("decrypt")
+        .arg(&ciphertext)
+        .arg("--key-file")
+        .arg(&key)
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("Not an encrypted EHR envelope"));
+}